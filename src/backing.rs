@@ -0,0 +1,141 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// A source of bytes a mainfile or idx file can be read from other than a file, such as a cursor
+/// over an embedded cache or a reader that fetches ranges over the network.
+pub trait Source: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> Source for T {}
+
+/// What the kernel is told about how a file is about to be read.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Advice {
+    Normal,
+    /// The file is read front to back, so read ahead further.
+    Sequential,
+    /// The data is read once, so its pages can be reclaimed first.
+    NoReuse,
+    /// The data won't be read again soon, so drop its pages now.
+    DontNeed,
+}
+
+/// The bytes behind a mainfile or idx file. Files can be written to, other sources are only read.
+pub enum Backing {
+    File(File),
+    Reader {
+        reader: Box<dyn Source>,
+        /// Sources don't change underneath the filesystem, so their length is taken once.
+        len: u64,
+    },
+}
+
+impl Backing {
+    /// Wraps a source, seeking to its end once to learn its length.
+    pub fn from_reader<R: Source + 'static>(mut reader: R) -> io::Result<Backing> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        Ok(Backing::Reader { reader: Box::new(reader), len })
+    }
+
+    /// Gets the length of the bytes.
+    pub fn len(&self) -> io::Result<u64> {
+        match self {
+            Backing::File(file) => Ok(file.metadata()?.len()),
+            Backing::Reader { len, .. } => Ok(*len),
+        }
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Gets the file, if the bytes are backed by one.
+    pub fn as_file(&mut self) -> Option<&mut File> {
+        match self {
+            Backing::File(file) => Some(file),
+            Backing::Reader { .. } => None,
+        }
+    }
+
+    /// Gets another handle on the file, if the bytes are backed by one.
+    pub fn try_clone_file(&self) -> Option<File> {
+        match self {
+            Backing::File(file) => file.try_clone().ok(),
+            Backing::Reader { .. } => None,
+        }
+    }
+
+    /// Flushes writes to disk. Other sources are never written, so there is nothing to do.
+    pub fn sync_all(&self) -> io::Result<()> {
+        match self {
+            Backing::File(file) => file.sync_all(),
+            Backing::Reader { .. } => Ok(()),
+        }
+    }
+}
+
+/// Advises the kernel about how a whole file is about to be read. This is a hint that only Linux
+/// is given, and one the kernel may ignore, so nothing comes of failing to give it.
+pub fn advise(file: &File, advice: Advice) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        let advice = match advice {
+            Advice::Normal => libc::POSIX_FADV_NORMAL,
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::NoReuse => libc::POSIX_FADV_NOREUSE,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+
+        // Safe since the descriptor is open for as long as the file is borrowed
+        unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, advice);
+}
+
+impl Read for Backing {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Backing::File(file) => file.read(buf),
+            Backing::Reader { reader, .. } => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for Backing {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Backing::File(file) => file.seek(pos),
+            Backing::Reader { reader, .. } => reader.seek(pos),
+        }
+    }
+}
+
+impl Write for Backing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Backing::File(file) => file.write(buf),
+            Backing::Reader { .. } => Err(io::Error::new(io::ErrorKind::Unsupported, "the source is read-only")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Backing::File(file) => file.flush(),
+            Backing::Reader { .. } => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Backing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backing::File(file) => f.debug_tuple("File").field(file).finish(),
+            Backing::Reader { len, .. } => f.debug_struct("Reader").field("len", len).finish(),
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::io::{self, Read, Write};
+use scapefs::container;
+use scapefs::debug;
+use scapefs::filesystem::{FileSystem, FsError};
+use scapefs::reference_table::ReferenceTable;
+
+/// How wide the list of indices or groups is, in characters.
+const LIST_WIDTH: usize = 24;
+
+const KEYS: &str = "up/down move  enter open  backspace back  h hexdump  q quit";
+
+/// A key the browser acts on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Key {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Open,
+    Back,
+    Hexdump,
+    Quit,
+    Other,
+}
+
+impl Key {
+    /// Parses the keys in a read from a terminal in raw mode.
+    fn parse(bytes: &[u8]) -> Vec<Key> {
+        let mut keys = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let (key, len) = match &bytes[i..] {
+                [0x1b, b'[', b'A', ..] => (Key::Up, 3),
+                [0x1b, b'[', b'B', ..] => (Key::Down, 3),
+                [0x1b, b'[', b'C', ..] => (Key::Open, 3),
+                [0x1b, b'[', b'D', ..] => (Key::Back, 3),
+                [0x1b, b'[', b'5', b'~', ..] => (Key::PageUp, 4),
+                [0x1b, b'[', b'6', b'~', ..] => (Key::PageDown, 4),
+                [b'k', ..] => (Key::Up, 1),
+                [b'j', ..] => (Key::Down, 1),
+                [b'\r', ..] | [b'\n', ..] | [b'l', ..] => (Key::Open, 1),
+                [0x7f, ..] | [0x08, ..] => (Key::Back, 1),
+                [b'h', ..] => (Key::Hexdump, 1),
+                [b'q', ..] | [0x03, ..] => (Key::Quit, 1),
+                _ => (Key::Other, 1),
+            };
+            keys.push(key);
+            i += len;
+        }
+        keys
+    }
+}
+
+/// The state of the browser: which index is open, if any, and what is selected in it.
+struct Browser {
+    fs: FileSystem,
+    index: Option<u32>,
+    table: Option<ReferenceTable>,
+    items: Vec<u32>,
+    selected: usize,
+    top: usize,
+    /// The selection in the list of indices, to go back to when leaving an index.
+    parent: usize,
+    hexdump: bool,
+}
+
+impl Browser {
+    fn new(fs: FileSystem) -> Browser {
+        let mut browser = Browser { fs, index: None, table: None, items: Vec::new(), selected: 0, top: 0, parent: 0, hexdump: false };
+        browser.items = browser.fs.indices();
+        browser
+    }
+
+    /// Acts on a key, and returns whether the browser carries on.
+    fn handle(&mut self, key: Key, rows: usize) -> bool {
+        let last = self.items.len().saturating_sub(1);
+        match key {
+            Key::Up => self.selected = self.selected.saturating_sub(1),
+            Key::Down => self.selected = (self.selected + 1).min(last),
+            Key::PageUp => self.selected = self.selected.saturating_sub(rows),
+            Key::PageDown => self.selected = (self.selected + rows).min(last),
+            Key::Open => {
+                if let (None, Some(&index)) = (self.index, self.items.get(self.selected)) {
+                    self.open(index);
+                }
+            }
+            Key::Back => {
+                if self.index.take().is_some() {
+                    self.table = None;
+                    self.items = self.fs.indices();
+                    self.selected = self.parent;
+                    self.top = 0;
+                }
+            }
+            Key::Hexdump => self.hexdump = !self.hexdump,
+            Key::Quit => return false,
+            Key::Other => {}
+        }
+
+        // Keep the selection in view
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if rows > 0 && self.selected >= self.top + rows {
+            self.top = self.selected + 1 - rows;
+        }
+        true
+    }
+
+    fn open(&mut self, index: u32) {
+        let Some(idx) = self.fs.index(index) else {
+            return;
+        };
+        let groups = (0..idx.last_entry() as u32).filter(|group| idx.entry(*group).is_some_and(|entry| entry.size() > 0)).collect();
+
+        self.parent = self.selected;
+        self.index = Some(index);
+        self.table = if index == 255 { None } else { self.fs.reference_table(index).ok() };
+        self.items = groups;
+        self.selected = 0;
+        self.top = 0;
+    }
+
+    /// Renders the whole screen as lines of exactly `width` characters.
+    fn render(&mut self, width: usize, height: usize) -> Vec<String> {
+        let rows = height.saturating_sub(2);
+        let title = match self.index {
+            Some(index) => format!("scapefs  {}  idx {}", self.fs.path().display(), index),
+            None => format!("scapefs  {}", self.fs.path().display()),
+        };
+
+        let details = match self.items.get(self.selected).copied() {
+            Some(item) => self.details(item).unwrap_or_else(|e| vec![format!("error: {}", e)]),
+            None => vec!["nothing here".to_string()],
+        };
+
+        let mut lines = vec![fit(&title, width)];
+        for row in 0..rows {
+            let item = match self.items.get(self.top + row) {
+                Some(item) => format!("{} {}", if self.top + row == self.selected { ">" } else { " " }, self.label(*item)),
+                None => String::new(),
+            };
+            let detail = details.get(row).map_or("", |line| line.as_str());
+            let right = width.saturating_sub(LIST_WIDTH + 3);
+            lines.push(fit(&format!("{} | {}", fit(&item, LIST_WIDTH), fit(detail, right)), width));
+        }
+        lines.push(fit(KEYS, width));
+        lines
+    }
+
+    fn label(&self, item: u32) -> String {
+        match self.index {
+            Some(_) => format!("group {}", item),
+            None => format!("idx {}", item),
+        }
+    }
+
+    /// Describes the selected index or group.
+    fn details(&mut self, item: u32) -> Result<Vec<String>, FsError> {
+        let Some(index) = self.index else {
+            let entries = self.fs.index(item).ok_or(FsError::IndexNotFound)?.last_entry();
+            let mut lines = vec![format!("index {}", item), format!("entries:      {}", entries)];
+            if item != 255 {
+                match self.fs.reference_table(item) {
+                    Ok(table) => {
+                        lines.push(format!("table:        protocol {}, revision {}", table.version(), table.revision()));
+                        lines.push(format!("groups:       {}", table.folder_ids().len()));
+                    }
+                    Err(e) => lines.push(format!("table:        {}", e)),
+                }
+            }
+            return Ok(lines);
+        };
+
+        let container = self.fs.read_container(index, item)?;
+        if self.hexdump {
+            return Ok(debug::hexdump(&container, 0).lines().map(str::to_string).collect());
+        }
+
+        let entry = self.fs.index(index).ok_or(FsError::IndexNotFound)?.try_entry(item)?;
+        let size = self.fs.group_size(index, item)?;
+        let crc = container::crc(&container)?;
+        let mut lines = vec![
+            format!("group {} of index {}", item, index),
+            format!("stored:       {} bytes at block {}", entry.size(), entry.block()),
+            format!("compression:  {:?}", size.compression),
+            format!("compressed:   {} bytes", size.compressed),
+            format!("uncompressed: {} bytes", size.uncompressed),
+            format!("crc:          {:08x}", crc),
+            format!("version:      {}", container::version(&container)?.map_or("none".to_string(), |version| version.to_string())),
+        ];
+        if let Some(folder) = self.table.as_ref().and_then(|table| table.lookup(item as i32)) {
+            lines.push(format!("table crc:    {:08x}{}", folder.crc32() as u32, if folder.crc32() as u32 == crc { "" } else { ", does not match" }));
+            lines.push(format!("table version: {}", folder.version()));
+        }
+        Ok(lines)
+    }
+}
+
+/// Cuts off or pads a line to exactly `width` characters.
+fn fit(line: &str, width: usize) -> String {
+    let mut fitted: String = line.chars().take(width).collect();
+    let len = fitted.chars().count();
+    fitted.extend(std::iter::repeat_n(' ', width - len));
+    fitted
+}
+
+/// Puts the terminal in raw mode on the alternate screen until dropped.
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enter() -> io::Result<RawMode> {
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush()?;
+        Ok(RawMode(original))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+/// Gets the width and height of the terminal, or 80 by 24 if it can't be asked.
+fn terminal_size() -> (usize, usize) {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 && size.ws_row > 0 {
+        (size.ws_col as usize, size.ws_row as usize)
+    } else {
+        (80, 24)
+    }
+}
+
+/// Browses a cache in the terminal until the user quits.
+pub fn run(fs: FileSystem) -> io::Result<()> {
+    let _raw = RawMode::enter()?;
+    let mut browser = Browser::new(fs);
+    let mut stdin = io::stdin();
+    let mut buffer = [0u8; 64];
+
+    loop {
+        let (width, height) = terminal_size();
+        let screen = browser.render(width, height).join("\r\n");
+        print!("\x1b[H{}", screen);
+        io::stdout().flush()?;
+
+        let read = stdin.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        for key in Key::parse(&buffer[..read]) {
+            if !browser.handle(key, height.saturating_sub(2)) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use scapefs::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+    use super::{Browser, Key};
+
+    #[test]
+    fn the_browser_walks_indices_and_groups() {
+        assert_eq!(Key::parse(b"\x1b[Bj\x1b[5~q"), vec![Key::Down, Key::Down, Key::PageUp, Key::Quit]);
+
+        let dir = TestDir::new("browse");
+        let mut cache = FileSystem::new_writable(&dir).unwrap();
+        for group in 0..8 {
+            cache.write_group(3, group * 2, &[group as u8; 40], CompressionType::Gzip, Some(7)).unwrap();
+        }
+        drop(cache);
+
+        let mut browser = Browser::new(FileSystem::new(&dir).unwrap());
+        let screen = browser.render(80, 6);
+        assert_eq!(screen.len(), 6);
+        assert!(screen.iter().all(|line| line.chars().count() == 80));
+        assert!(screen[1].starts_with("> idx 3                  | index 3"));
+
+        // Only four rows fit, so moving down past them scrolls
+        browser.handle(Key::Open, 4);
+        for _ in 0..5 {
+            browser.handle(Key::Down, 4);
+        }
+        let screen = browser.render(80, 6);
+        assert!(screen[4].starts_with("> group 10"));
+        assert!(browser.render(80, 12).iter().any(|line| line.contains("| version:      7 ")));
+
+        browser.handle(Key::Hexdump, 4);
+        assert!(browser.render(80, 6)[1].contains("| 00000000  02 00 00 00"));
+
+        browser.handle(Key::Back, 4);
+        assert!(browser.render(80, 6)[1].starts_with("> idx 3"));
+        assert!(!browser.handle(Key::Quit, 4));
+    }
+}
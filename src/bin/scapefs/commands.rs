@@ -0,0 +1,212 @@
+use std::collections::BTreeSet;
+use scapefs::container;
+use scapefs::filesystem::{FileSystem, FsError};
+use scapefs::reference_table::ReferenceTable;
+use crate::output::{Output, Value};
+
+/// Lists the indices of a cache with their entry counts and reference tables.
+pub fn ls_indices(fs: &mut FileSystem) -> Result<Output, FsError> {
+    let mut output = Output::new("indices", &["index", "entries", "groups", "revision"])
+        .with("path", fs.path().display().to_string())
+        .with("missing", fs.missing_indices());
+
+    for index in fs.indices() {
+        let entries = fs.index(index).unwrap().last_entry();
+        let table = if index == 255 { None } else { fs.reference_table(index).ok() };
+        output.row(vec![index.into(), entries.into(), table.as_ref().map(|table| table.folder_ids().len()).into(),
+            table.as_ref().map(ReferenceTable::revision).into()]);
+    }
+    Ok(output)
+}
+
+/// Lists the groups of an index with their size and first block.
+pub fn ls_groups(fs: &mut FileSystem, index: u32) -> Result<Output, FsError> {
+    let mut output = Output::new("groups", &["group", "size", "block"]).with("index", index);
+
+    let idx = fs.index(index).ok_or(FsError::IndexNotFound)?;
+    for group in 0..idx.last_entry() as u32 {
+        if let Some(entry) = idx.entry(group).filter(|entry| entry.size() > 0) {
+            output.row(vec![group.into(), entry.size().into(), entry.block().into()]);
+        }
+    }
+    Ok(output)
+}
+
+/// Lists every group of one index, or of all of them, with what a spreadsheet of the cache needs:
+/// its name hash, version and crc from the reference table, and its sizes and compression from
+/// its container header. What can't be found is left empty.
+pub fn manifest(fs: &mut FileSystem, index: Option<u32>) -> Result<Output, FsError> {
+    let mut output = Output::new("groups", &["index", "group", "name_hash", "version", "crc", "compressed", "uncompressed", "compression"]);
+    let indices = match index {
+        Some(index) => vec![index],
+        None => fs.indices().into_iter().filter(|index| *index != 255).collect(),
+    };
+
+    for index in indices {
+        let count = fs.index(index).ok_or(FsError::IndexNotFound)?.last_entry() as u32;
+        let table = fs.reference_table(index).ok();
+        for group in 0..count {
+            let stored = fs.index(index).unwrap().entry(group).is_some_and(|entry| entry.size() > 0);
+            let folder = table.as_ref().and_then(|table| table.lookup(group as i32));
+            if !stored && folder.is_none() {
+                continue;
+            }
+
+            let size = if stored { fs.group_size(index, group).ok() } else { None };
+            let name = folder.filter(|_| table.as_ref().unwrap().flags().has_names()).map(|folder| folder.name_hash());
+            output.row(vec![index.into(), group.into(), name.into(), folder.map(|folder| folder.version()).into(),
+                folder.map(|folder| folder.crc32() as u32).into(), size.map(|size| size.compressed).into(),
+                size.map(|size| size.uncompressed).into(), size.map(|size| format!("{:?}", size.compression).to_lowercase()).into()]);
+        }
+    }
+    Ok(output)
+}
+
+/// Describes a group: where it is stored, its container and what its reference table says,
+/// with the files the table lists in it as rows.
+pub fn info(fs: &mut FileSystem, index: u32, group: u32) -> Result<Output, FsError> {
+    let entry = fs.index(index).ok_or(FsError::IndexNotFound)?.try_entry(group)?;
+    let container = fs.read_container(index, group)?;
+    let size = fs.group_size(index, group)?;
+    let table = if index == 255 { None } else { fs.reference_table(index).ok() };
+    let folder = table.as_ref().and_then(|table| table.lookup(group as i32));
+
+    let mut output = Output::new("files", &["file", "name_hash"])
+        .with("index", index)
+        .with("group", group)
+        .with("size", entry.size())
+        .with("block", entry.block())
+        .with("compression", format!("{:?}", size.compression).to_lowercase())
+        .with("compressed", size.compressed)
+        .with("uncompressed", size.uncompressed)
+        .with("crc", container::crc(&container)?)
+        .with("version", container::version(&container)?.map(|version| version as u32))
+        .with("table_crc", folder.map(|folder| folder.crc32() as u32))
+        .with("table_version", folder.map(|folder| folder.version()))
+        .with("decodes", container::decode(&container).err().map_or(Value::Bool(true), |e| Value::Str(e.to_string())));
+
+    if let Some(folder) = folder {
+        for file in folder.file_ids() {
+            output.row(vec![file.into(), folder.file(file).unwrap().name_hash().into()]);
+        }
+    }
+    Ok(output)
+}
+
+/// Verifies every group, with the ones that fail as rows.
+pub fn verify(fs: &mut FileSystem) -> Result<Output, FsError> {
+    let report = fs.verify_all(4)?;
+    let mut output = Output::new("failures", &["index", "group", "error"])
+        .with("ok", report.is_ok())
+        .with("indices", report.indices.clone())
+        .with("groups", report.groups)
+        .with("missing", report.missing.clone());
+
+    for failure in &report.failures {
+        let error = failure.result.as_ref().err().map(|e| e.to_string());
+        output.row(vec![failure.index.into(), failure.group.into(), error.into()]);
+    }
+    Ok(output)
+}
+
+/// Compares the reference tables of two caches, with every group that was added, removed or
+/// changed as a row. An index without a table counts as having an empty one.
+pub fn diff(old: &mut FileSystem, new: &mut FileSystem) -> Result<Output, FsError> {
+    let indices: BTreeSet<u32> = old.indices().into_iter().chain(new.indices()).filter(|index| *index != 255).collect();
+    let mut output = Output::new("changes", &["index", "group", "change", "old_crc", "new_crc", "old_version", "new_version"]);
+
+    let mut changed = 0;
+    for index in indices {
+        let (old_table, new_table) = (table_or_empty(old, index)?, table_or_empty(new, index)?);
+        let diff = ReferenceTable::diff(&old_table, &new_table);
+        if !diff.is_empty() {
+            changed += 1;
+        }
+
+        let folder = |table: &ReferenceTable, id: i32| table.lookup(id).map(|folder| (folder.crc32() as u32, folder.version()));
+        let mut rows: Vec<(i32, &str)> = diff.added.iter().map(|id| (*id, "added")).collect();
+        rows.extend(diff.removed.iter().map(|id| (*id, "removed")));
+        rows.extend(diff.changed.iter().map(|change| (change.id, "changed")));
+        rows.sort_unstable();
+
+        for (id, change) in rows {
+            let (before, after) = (folder(&old_table, id), folder(&new_table, id));
+            output.row(vec![index.into(), id.into(), change.into(), before.map(|f| f.0).into(), after.map(|f| f.0).into(),
+                before.map(|f| f.1).into(), after.map(|f| f.1).into()]);
+        }
+    }
+
+    Ok(output.with("indices_changed", changed as u32))
+}
+
+fn table_or_empty(fs: &mut FileSystem, index: u32) -> Result<ReferenceTable, FsError> {
+    match fs.reference_table(index) {
+        Ok(table) => Ok(table),
+        Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => Ok(ReferenceTable::new(6)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Adds up the sizes of the groups of every index, and how much of the data files they take.
+pub fn stats(fs: &mut FileSystem) -> Result<Output, FsError> {
+    let mut output = Output::new("indices", &["index", "groups", "files", "compressed", "uncompressed", "ratio", "disk"]);
+    let (mut groups, mut compressed, mut uncompressed, mut disk) = (0, 0, 0, 0);
+
+    for index in fs.indices().into_iter().filter(|index| *index != 255) {
+        let size = fs.index_size(index)?;
+        let usage = fs.index_disk_usage(index)?;
+        groups += size.groups;
+        compressed += size.compressed;
+        uncompressed += size.uncompressed;
+        disk += usage.bytes;
+        let files = fs.reference_table(index).ok().map(|table| table.stats().files);
+        output.row(vec![index.into(), size.groups.into(), files.into(), size.compressed.into(), size.uncompressed.into(),
+            size.ratio().into(), usage.bytes.into()]);
+    }
+
+    Ok(output.with("groups", groups).with("compressed", compressed).with("uncompressed", uncompressed).with("disk", disk)
+        .with("missing", fs.missing_indices()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use scapefs::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn commands_give_stable_json() {
+        let dir = TestDir::new("commands");
+        for name in ["old", "new"] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            let mut cache = FileSystem::new_writable(dir.join(name)).unwrap();
+            cache.write_group(3, 0, b"same", CompressionType::None, Some(1)).unwrap();
+            cache.write_group(3, 1, name.as_bytes(), CompressionType::None, Some(1)).unwrap();
+            cache.refresh_reference_tables().unwrap();
+        }
+        let mut old = FileSystem::new(dir.join("old")).unwrap();
+        let mut new = FileSystem::new(dir.join("new")).unwrap();
+
+        let output = super::ls_groups(&mut new, 3).unwrap();
+        assert_eq!(output.json(), "{\"index\":3,\"groups\":[{\"group\":0,\"size\":11,\"block\":1},{\"group\":1,\"size\":10,\"block\":2}]}\n");
+        assert_eq!(output.text(), "index: 3\ngroup  size  block\n0      11    1\n1      10    2\n");
+
+        let json = super::verify(&mut new).unwrap().json();
+        assert_eq!(json, "{\"ok\":true,\"indices\":[3],\"groups\":2,\"missing\":[],\"failures\":[]}\n");
+
+        let json = super::info(&mut new, 3, 1).unwrap().json();
+        assert!(json.starts_with("{\"index\":3,\"group\":1,\"size\":10,\"block\":2,\"compression\":\"none\",\"compressed\":8,\"uncompressed\":3,"));
+        assert!(json.ends_with(",\"version\":1,\"table_crc\":3811962320,\"table_version\":1,\"decodes\":true,\"files\":[{\"file\":0,\"name_hash\":0}]}\n"));
+
+        let csv = super::manifest(&mut new, Some(3)).unwrap().csv();
+        assert_eq!(csv, "index,group,name_hash,version,crc,compressed,uncompressed,compression\r\n\
+            3,0,,1,3466435638,9,4,none\r\n3,1,,1,3811962320,8,3,none\r\n");
+        let mut quoted = crate::output::Output::new("rows", &["error"]);
+        quoted.row(vec!["bad \"crc\", 2".into()]);
+        assert_eq!(quoted.csv(), "error\r\n\"bad \"\"crc\"\", 2\"\r\n");
+
+        let diff = super::diff(&mut old, &mut new).unwrap();
+        assert_eq!(diff.rows.len(), 1);
+        assert!(diff.json().starts_with("{\"indices_changed\":1,\"changes\":[{\"index\":3,\"group\":1,\"change\":\"changed\","));
+    }
+}
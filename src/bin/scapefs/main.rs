@@ -0,0 +1,239 @@
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process;
+use scapefs::container;
+use scapefs::debug;
+use scapefs::filesystem::{FileSystem, FsError};
+
+#[cfg(all(feature = "tui", target_os = "linux"))]
+mod browse;
+mod commands;
+mod output;
+#[cfg(test)]
+#[path = "../../test_dir.rs"]
+mod test_dir;
+
+use output::{Format, Output};
+
+const USAGE: &str = "\
+usage: scapefs shell <cache>
+       scapefs browse <cache>
+       scapefs ls <cache> [index] [--json | --csv]
+       scapefs manifest <cache> [index] [--json | --csv]
+       scapefs info <cache> <index> <group> [--json | --csv]
+       scapefs verify <cache> [--json | --csv]
+       scapefs diff <old cache> <new cache> [--json | --csv]
+       scapefs stats <cache> [--json | --csv]";
+
+const HELP: &str = "\
+cd idx <index>   go into an index (also: cd <index>, cd .., cd /)
+ls               list the indices, or the groups of the current index
+cat <group>      write the decompressed data of a group
+crc <group>      show the CRC of a group, and the one its reference table lists
+hexdump <group>  show the raw container of a group as a hexdump
+info <group>     describe the idx entry, block chain and container of a group
+help             show this
+exit             leave the shell (also: quit)
+";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let format = if args.iter().any(|arg| arg == "--csv") {
+        Format::Csv
+    } else if args.iter().any(|arg| arg == "--json") {
+        Format::Json
+    } else {
+        Format::Text
+    };
+    args.retain(|arg| arg != "--json" && arg != "--csv");
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let result = match args.as_slice() {
+        ["shell", path] => Shell::new(open(path)).run(io::stdin().lock(), &mut io::stdout()).map_err(FsError::Io),
+        ["browse", path] => browse(open(path)).map_err(FsError::Io),
+        ["ls", path] => report(commands::ls_indices(&mut open(path)), format),
+        ["ls", path, index] => number(index).and_then(|index| report(commands::ls_groups(&mut open(path), index), format)),
+        ["manifest", path] => report(commands::manifest(&mut open(path), None), format),
+        ["manifest", path, index] => number(index).and_then(|index| report(commands::manifest(&mut open(path), Some(index)), format)),
+        ["info", path, index, group] => number(index).and_then(|index| {
+            number(group).and_then(|group| report(commands::info(&mut open(path), index, group), format))
+        }),
+        ["verify", path] => {
+            let output = commands::verify(&mut open(path));
+            let ok = output.as_ref().is_ok_and(|output| output.rows.is_empty());
+            report(output, format).map(|_| if !ok { process::exit(1) })
+        }
+        ["diff", old, new] => report(commands::diff(&mut open(old), &mut open(new)), format),
+        ["stats", path] => report(commands::stats(&mut open(path)), format),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Opens a cache read-only, or exits if it can't be.
+fn open(path: &str) -> FileSystem {
+    match FileSystem::new(path) {
+        Ok(fs) => fs,
+        Err(e) => {
+            eprintln!("can't open {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints the output of a command in the format that was asked for.
+fn report(output: Result<Output, FsError>, format: Format) -> Result<(), FsError> {
+    io::stdout().write_all(output?.render(format).as_bytes()).map_err(FsError::Io)
+}
+
+#[cfg(all(feature = "tui", target_os = "linux"))]
+fn browse(fs: FileSystem) -> io::Result<()> {
+    browse::run(fs)
+}
+
+#[cfg(not(all(feature = "tui", target_os = "linux")))]
+fn browse(_fs: FileSystem) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "scapefs was built without the tui feature, which needs Linux"))
+}
+
+/// An open cache and where in it the shell is.
+struct Shell {
+    fs: FileSystem,
+    index: Option<u32>,
+}
+
+impl Shell {
+    fn new(fs: FileSystem) -> Shell {
+        Shell { fs, index: None }
+    }
+
+    /// Reads commands a line at a time until the input ends or the shell is left.
+    fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        let mut lines = input.lines();
+        loop {
+            match self.index {
+                Some(index) => write!(out, "idx{}> ", index)?,
+                None => write!(out, "/> ")?,
+            }
+            out.flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => return writeln!(out),
+            };
+            match self.execute(&line, out) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(e) => writeln!(out, "error: {}", e)?,
+            }
+        }
+    }
+
+    /// Runs a single command, and returns whether the shell carries on.
+    fn execute<W: Write>(&mut self, line: &str, out: &mut W) -> Result<bool, FsError> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["exit"] | ["quit"] => return Ok(false),
+            ["help"] => out.write_all(HELP.as_bytes()).map_err(FsError::Io)?,
+            ["cd", "/"] | ["cd", ".."] | ["cd"] => self.index = None,
+            ["cd", "idx", index] | ["cd", index] => {
+                let index = number(index)?;
+                if !self.fs.has_index(index) {
+                    return Err(FsError::IndexNotFound);
+                }
+                self.index = Some(index);
+            }
+            ["ls"] => self.list(out)?,
+            ["cat", group] => {
+                let container = self.fs.read_container(self.current()?, number(group)?)?;
+                out.write_all(&container::decode(&container)?).map_err(FsError::Io)?;
+                writeln!(out).map_err(FsError::Io)?;
+            }
+            ["crc", group] => {
+                let (index, group) = (self.current()?, number(group)?);
+                let crc = container::crc(&self.fs.read_container(index, group)?)?;
+                let listed = self.fs.reference_table(index).ok().and_then(|table| table.lookup(group as i32).map(|folder| folder.crc32() as u32));
+                match listed {
+                    Some(listed) if listed == crc => writeln!(out, "{:08x}, as listed", crc),
+                    Some(listed) => writeln!(out, "{:08x}, but the table lists {:08x}", crc, listed),
+                    None => writeln!(out, "{:08x}, not listed in a table", crc),
+                }.map_err(FsError::Io)?;
+            }
+            ["hexdump", group] => {
+                let container = self.fs.read_container(self.current()?, number(group)?)?;
+                out.write_all(debug::hexdump(&container, 0).as_bytes()).map_err(FsError::Io)?;
+            }
+            ["info", group] => {
+                let description = self.fs.describe_group(self.current()?, number(group)?)?;
+                out.write_all(description.as_bytes()).map_err(FsError::Io)?;
+            }
+            _ => writeln!(out, "unknown command, try help").map_err(FsError::Io)?,
+        }
+        Ok(true)
+    }
+
+    /// Lists the indices with the number of entries in each, or the groups of the current
+    /// index with their size and first block.
+    fn list<W: Write>(&mut self, out: &mut W) -> Result<(), FsError> {
+        let Some(index) = self.index else {
+            for index in self.fs.indices() {
+                let entries = self.fs.index(index).unwrap().last_entry();
+                writeln!(out, "idx {:<4} {} entries", index, entries).map_err(FsError::Io)?;
+            }
+            for index in self.fs.missing_indices() {
+                writeln!(out, "idx {:<4} missing", index).map_err(FsError::Io)?;
+            }
+            return Ok(());
+        };
+
+        let idx = self.fs.index(index).ok_or(FsError::IndexNotFound)?;
+        for group in 0..idx.last_entry() as u32 {
+            match idx.entry(group) {
+                Some(entry) if entry.size() > 0 => writeln!(out, "{:<6} {} bytes at block {}", group, entry.size(), entry.block()).map_err(FsError::Io)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn current(&self) -> Result<u32, FsError> {
+        self.index.ok_or(FsError::IndexNotFound)
+    }
+}
+
+fn number(word: &str) -> Result<u32, FsError> {
+    word.parse().map_err(|_| FsError::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("not a number: {}", word))))
+}
+
+#[cfg(test)]
+mod tests {
+    use scapefs::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+    use super::Shell;
+
+    #[test]
+    fn the_shell_keeps_the_cache_open_between_commands() {
+        let dir = TestDir::new("shell");
+        let mut cache = FileSystem::new_writable(&dir).unwrap();
+        cache.write_group(2, 10, b"hello shell", CompressionType::Gzip, None).unwrap();
+        drop(cache);
+
+        let mut shell = Shell::new(FileSystem::new(&dir).unwrap());
+        let mut out = Vec::new();
+        shell.run(&b"ls\ncat 10\ncd idx 2\nls\ncat 10\ncd 9\nfrobnicate\nexit\nls\n"[..], &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.starts_with("/> idx 2    11 entries\n"));
+        assert!(out.contains("/> error: the index does not exist\n/> idx2> 10     "));
+        assert!(out.contains("idx2> hello shell\nidx2> error: the index does not exist\nidx2> unknown command, try help\nidx2> "));
+        assert!(out.ends_with("idx2> "));
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt::Write as _;
+
+/// A single value in the output of a command.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    List(Vec<Value>),
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Value {
+        Value::Bool(value)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Value {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Value {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Value {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(value: i32) -> Value {
+        Value::Int(value as i64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Value {
+        Value::Float(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Value {
+        Value::Str(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Value {
+        Value::Str(value)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Value {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Value {
+        Value::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl Value {
+    fn text(&self) -> String {
+        match self {
+            Value::Null => "-".to_string(),
+            Value::Bool(value) => value.to_string(),
+            Value::Int(value) => value.to_string(),
+            Value::Float(value) => format!("{:.3}", value),
+            Value::Str(value) => value.clone(),
+            Value::List(values) => values.iter().map(Value::text).collect::<Vec<String>>().join(","),
+        }
+    }
+
+    fn json(&self, out: &mut String) {
+        match self {
+            Value::Null => out.push_str("null"),
+            Value::Bool(value) => { let _ = write!(out, "{}", value); }
+            Value::Int(value) => { let _ = write!(out, "{}", value); }
+            Value::Float(value) if value.is_finite() => { let _ = write!(out, "{}", value); }
+            Value::Float(_) => out.push_str("null"),
+            Value::Str(value) => json_string(value, out),
+            Value::List(values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    value.json(out);
+                }
+                out.push(']');
+            }
+        }
+    }
+}
+
+/// Quotes a CSV field if it has a separator, a quote or a line break in it.
+fn csv_field(value: &str, out: &mut String) {
+    if value.contains([',', '"', '\n', '\r']) {
+        out.push('"');
+        out.push_str(&value.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(value);
+    }
+}
+
+fn json_string(value: &str, out: &mut String) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// How the output of a command is printed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+/// What a command found: a few values about the whole, and a list of rows that all have the
+/// same fields. Fields come out in the order they are given, so scripts can rely on it.
+#[derive(Debug)]
+pub struct Output {
+    pub summary: Vec<(&'static str, Value)>,
+    /// What the rows are a list of, which is their key in JSON.
+    pub rows_name: &'static str,
+    pub fields: &'static [&'static str],
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Output {
+    pub fn new(rows_name: &'static str, fields: &'static [&'static str]) -> Output {
+        Output { summary: Vec::new(), rows_name, fields, rows: Vec::new() }
+    }
+
+    pub fn with(mut self, key: &'static str, value: impl Into<Value>) -> Output {
+        self.summary.push((key, value.into()));
+        self
+    }
+
+    pub fn row(&mut self, values: Vec<Value>) {
+        debug_assert_eq!(values.len(), self.fields.len());
+        self.rows.push(values);
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Text => self.text(),
+            Format::Json => self.json(),
+            Format::Csv => self.csv(),
+        }
+    }
+
+    /// Renders the summary as `key: value` lines followed by the rows as aligned columns.
+    pub fn text(&self) -> String {
+        let mut out = String::new();
+        for (key, value) in &self.summary {
+            let _ = writeln!(out, "{}: {}", key, value.text());
+        }
+        if self.rows.is_empty() {
+            return out;
+        }
+
+        let cells: Vec<Vec<String>> = self.rows.iter().map(|row| row.iter().map(Value::text).collect()).collect();
+        let widths: Vec<usize> = (0..self.fields.len())
+            .map(|i| cells.iter().map(|row| row[i].len()).chain(Some(self.fields[i].len())).max().unwrap())
+            .collect();
+        let line = |values: Vec<&str>| values.iter().zip(&widths).map(|(value, width)| format!("{:<1$}", value, width)).collect::<Vec<String>>().join("  ");
+
+        let _ = writeln!(out, "{}", line(self.fields.to_vec()).trim_end());
+        for row in &cells {
+            let _ = writeln!(out, "{}", line(row.iter().map(String::as_str).collect()).trim_end());
+        }
+        out
+    }
+
+    /// Renders everything as one JSON object: the summary values, then the rows as a list of
+    /// objects under `rows_name`.
+    pub fn json(&self) -> String {
+        let mut out = String::from("{");
+        for (key, value) in &self.summary {
+            json_string(key, &mut out);
+            out.push(':');
+            value.json(&mut out);
+            out.push(',');
+        }
+
+        json_string(self.rows_name, &mut out);
+        out.push_str(":[");
+        for (i, row) in self.rows.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push('{');
+            for (j, (field, value)) in self.fields.iter().zip(row).enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+                json_string(field, &mut out);
+                out.push(':');
+                value.json(&mut out);
+            }
+            out.push('}');
+        }
+        out.push_str("]}\n");
+        out
+    }
+
+    /// Renders the rows as CSV, with the fields as the header line. The summary is left out, as
+    /// it doesn't fit in the columns; nulls are empty fields.
+    pub fn csv(&self) -> String {
+        let mut out = String::new();
+        let mut line = |values: Vec<String>| {
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                csv_field(value, &mut out);
+            }
+            out.push_str("\r\n");
+        };
+
+        line(self.fields.iter().map(|field| field.to_string()).collect());
+        for row in &self.rows {
+            line(row.iter().map(|value| if *value == Value::Null { String::new() } else { value.text() }).collect());
+        }
+        out
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use crate::backing::{self, Advice};
+use crate::container;
+use crate::filesystem::{FileSystem, FsError};
+use crate::filter::GroupFilter;
+use crate::resume::Journal;
+
+/// What a bulk operation does when a single group can't be processed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum OnError {
+    /// Stop at the first bad group and return its error.
+    #[default]
+    Abort,
+    /// Carry on with the other groups and list the bad ones in the result.
+    Skip,
+}
+
+/// What bulk operations, which read most of the cache once, tell the kernel about the data files.
+/// This matters on servers, where reading a cache of several gigabytes would otherwise push
+/// everything else out of the page cache.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum AccessHint {
+    /// Tell the kernel nothing.
+    #[default]
+    Normal,
+    /// Ask for more read-ahead while the operation runs.
+    Sequential,
+    /// Ask for read-ahead, and for the pages read to be reclaimed first and dropped afterwards.
+    DropBehind,
+}
+
+/// Keeps the access hint of a bulk operation in place until it is dropped, whether or not the
+/// operation finished. It holds its own handles on the data files, so the filesystem stays free
+/// to use.
+pub(crate) struct BulkRead {
+    files: Vec<File>,
+    hint: AccessHint,
+}
+
+impl BulkRead {
+    pub(crate) fn new(files: Vec<File>, hint: AccessHint) -> BulkRead {
+        for file in &files {
+            match hint {
+                AccessHint::Normal => {}
+                AccessHint::Sequential => backing::advise(file, Advice::Sequential),
+                AccessHint::DropBehind => {
+                    backing::advise(file, Advice::Sequential);
+                    backing::advise(file, Advice::NoReuse);
+                }
+            }
+        }
+
+        BulkRead { files, hint }
+    }
+}
+
+impl Drop for BulkRead {
+    fn drop(&mut self) {
+        for file in &self.files {
+            if self.hint == AccessHint::DropBehind {
+                backing::advise(file, Advice::DontNeed);
+            }
+            if self.hint != AccessHint::Normal {
+                backing::advise(file, Advice::Normal);
+            }
+        }
+    }
+}
+
+/// A group that a bulk operation skipped, and why.
+#[derive(Debug)]
+pub struct GroupFailure {
+    pub index: u32,
+    pub group: u32,
+    pub error: FsError,
+}
+
+/// What `FileSystem::extract_dir_resumable` did.
+#[derive(Debug, Default)]
+pub struct ExtractReport {
+    /// The number of groups that were decompressed and written.
+    pub written: usize,
+    /// The number of groups whose file was already there from an earlier run.
+    pub skipped: usize,
+    /// The groups that were skipped because they couldn't be read or decompressed.
+    pub failures: Vec<GroupFailure>,
+    /// The indices the filter covers that have no idx file, in order. They are skipped.
+    pub missing: Vec<u32>,
+}
+
+/// The name of the journal `extract_dir_resumable` keeps in the folder it extracts into.
+const EXTRACT_JOURNAL: &str = "extract.journal";
+
+/// How many groups `extract_dir_resumable` extracts between syncing its journal.
+const CHECKPOINT_INTERVAL: usize = 64;
+
+impl OnError {
+    /// Handles the result of processing a single group, turning the error into a failure entry
+    /// when skipping.
+    pub(crate) fn handle<T>(self, index: u32, group: u32, result: Result<T, FsError>, failures: &mut Vec<GroupFailure>) -> Result<Option<T>, FsError> {
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(error) if self == OnError::Skip => {
+                failures.push(GroupFailure { index, group, error });
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl FileSystem {
+    /// Decompresses every group of an index, in ascending order, and hands it to a callback.
+    /// Groups that can't be read or decompressed abort the extraction or are skipped and
+    /// returned, depending on `on_error`. Errors returned by the callback always abort.
+    pub fn extract_index<F>(&mut self, index: u32, on_error: OnError, mut f: F) -> Result<Vec<GroupFailure>, FsError>
+        where F: FnMut(u32, Vec<u8>) -> Result<(), FsError> {
+        let _bulk = self.bulk_read();
+        let mut failures = Vec::new();
+        self.extract_groups(index, &GroupFilter::new(), on_error, &mut failures, |_, group, data| f(group, data))?;
+        Ok(failures)
+    }
+
+    /// Decompresses every group a filter matches, in cache order, and hands it to a callback
+    /// along with its index, like `extract_index`. An index the filter names that has no idx
+    /// file fails with `FsError::IndexNotFound`, or is skipped and returned as a failure of its
+    /// reference table, group `index` in index 255, depending on `on_error`.
+    pub fn extract_matching<F>(&mut self, filter: &GroupFilter, on_error: OnError, mut f: F) -> Result<Vec<GroupFailure>, FsError>
+        where F: FnMut(u32, u32, Vec<u8>) -> Result<(), FsError> {
+        let _bulk = self.bulk_read();
+        let mut failures = Vec::new();
+        for index in filter.indices_of(self) {
+            if !self.has_index(index) {
+                on_error.handle(255, index, Err::<(), _>(FsError::IndexNotFound), &mut failures)?;
+                continue;
+            }
+            self.extract_groups(index, filter, on_error, &mut failures, &mut f)?;
+        }
+
+        Ok(failures)
+    }
+
+    /// Decompresses every group a filter matches into a file of its own, named
+    /// `<index>/<group>.dat` inside a folder, creating the folders as needed. A journal in the
+    /// folder records the CRC of the container each file came from and the size and CRC of the
+    /// file, so an interrupted run can be resumed by calling this again: a group whose file is
+    /// still the one recorded for its current container is skipped. The journal is kept
+    /// afterwards, so extracting an updated cache into the same folder only writes the groups
+    /// that changed. Indices the filter names that have no idx file are skipped and listed in
+    /// the report.
+    pub fn extract_dir_resumable<P: AsRef<Path>>(&mut self, dir: P, filter: &GroupFilter, on_error: OnError) -> Result<ExtractReport, FsError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(FsError::Io)?;
+        let _bulk = self.bulk_read();
+
+        // Only the last record of each group counts, so the journal is compacted to those
+        let mut done: BTreeMap<(u32, u32), [u64; 3]> = BTreeMap::new();
+        for record in Journal::read(dir.join(EXTRACT_JOURNAL))? {
+            if let [index, group, crc, length, data_crc] = record.as_slice() {
+                done.insert((*index as u32, *group as u32), [*crc, *length, *data_crc]);
+            }
+        }
+        let mut journal = Journal::create(dir.join(EXTRACT_JOURNAL))?;
+        for ((index, group), [crc, length, data_crc]) in &done {
+            journal.append(&[*index as u64, *group as u64, *crc, *length, *data_crc])?;
+        }
+        journal.checkpoint()?;
+
+        let mut report = ExtractReport::default();
+        for index in filter.indices_of(self) {
+            let Some(idx) = self.index(index) else {
+                report.missing.push(index);
+                continue;
+            };
+            let count = idx.last_entry() as u32;
+            let table = if filter.needs_names() { self.reference_table(index).ok() } else { None };
+            let folder = dir.join(index.to_string());
+            fs::create_dir_all(&folder).map_err(FsError::Io)?;
+
+            for group in (0..count).filter(|group| filter.matches(index, *group, table.as_ref())) {
+                let container = match self.read_container(index, group) {
+                    Err(FsError::EntryNotFound) => continue,
+                    result => result,
+                };
+                let container = match on_error.handle(index, group, container, &mut report.failures)? {
+                    Some(container) => container,
+                    None => continue,
+                };
+
+                let crc = container::crc(&container)? as u64;
+                let path = folder.join(format!("{}.dat", group));
+                if let Some([_, length, data_crc]) = done.get(&(index, group)).filter(|record| record[0] == crc) {
+                    if fs::read(&path).is_ok_and(|data| data.len() as u64 == *length && crc32fast::hash(&data) as u64 == *data_crc) {
+                        report.skipped += 1;
+                        continue;
+                    }
+                }
+
+                let data = match on_error.handle(index, group, self.decode_group(&container), &mut report.failures)? {
+                    Some(data) => data,
+                    None => continue,
+                };
+
+                // Written under another name first, so an interruption never leaves a truncated file
+                let partial = path.with_extension("partial");
+                fs::write(&partial, &data).map_err(FsError::Io)?;
+                fs::rename(&partial, &path).map_err(FsError::Io)?;
+                journal.append(&[index as u64, group as u64, crc, data.len() as u64, crc32fast::hash(&data) as u64])?;
+
+                report.written += 1;
+                if report.written % CHECKPOINT_INTERVAL == 0 {
+                    journal.checkpoint()?;
+                }
+            }
+        }
+
+        journal.checkpoint()?;
+        Ok(report)
+    }
+
+    fn extract_groups<F>(&mut self, index: u32, filter: &GroupFilter, on_error: OnError, failures: &mut Vec<GroupFailure>, mut f: F) -> Result<(), FsError>
+        where F: FnMut(u32, u32, Vec<u8>) -> Result<(), FsError> {
+        let count = self.index(index).ok_or(FsError::IndexNotFound)?.last_entry() as u32;
+        let table = if filter.needs_names() { self.reference_table(index).ok() } else { None };
+
+        for group in (0..count).filter(|group| filter.matches(index, *group, table.as_ref())) {
+            let data = match self.read_container(index, group) {
+                Err(FsError::EntryNotFound) => continue,
+                result => result.and_then(|container| self.decode_group(&container)),
+            };
+
+            if let Some(data) = on_error.handle(index, group, data, failures)? {
+                f(index, group, data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decompresses a group straight into a writer, and flushes it. Only the compressed container
+    /// is held in memory. Returns the number of bytes written.
+    pub fn extract_to<W: Write>(&mut self, index: u32, group: u32, writer: &mut W) -> Result<u64, FsError> {
+        let container = self.read_container(index, group)?;
+        let written = self.decode_group_to(&container, writer)?;
+        writer.flush().map_err(FsError::Io)?;
+        Ok(written)
+    }
+
+    /// Decompresses every group of an index into a writer of its own, like `extract_to`, in
+    /// ascending order. The writer of a group is only made once its container was read, but a
+    /// group that turns out not to decompress may have written part of its data before it is
+    /// skipped. Errors of the writers or of making them always abort.
+    pub fn extract_index_to<F, W>(&mut self, index: u32, on_error: OnError, mut writer_for: F) -> Result<Vec<GroupFailure>, FsError>
+        where F: FnMut(u32) -> Result<W, FsError>, W: Write {
+        let _bulk = self.bulk_read();
+        let count = self.index(index).ok_or(FsError::IndexNotFound)?.last_entry() as u32;
+        let mut failures = Vec::new();
+
+        for group in 0..count {
+            let container = match self.read_container(index, group) {
+                Err(FsError::EntryNotFound) => continue,
+                result => result,
+            };
+            let container = match on_error.handle(index, group, container, &mut failures)? {
+                Some(container) => container,
+                None => continue,
+            };
+
+            let mut writer = writer_for(group)?;
+            match self.decode_group_to(&container, &mut writer) {
+                Err(e @ FsError::Io(_)) => return Err(e),
+                result => { on_error.handle(index, group, result, &mut failures)?; }
+            }
+            writer.flush().map_err(FsError::Io)?;
+        }
+
+        Ok(failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::filter::GroupFilter;
+    use crate::test_dir::TestDir;
+    use super::{AccessHint, OnError};
+
+    #[test]
+    fn extract_skips_corrupt_groups() {
+        let dir = TestDir::new("bulk");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.set_access_hint(AccessHint::DropBehind);
+        for group in 0..3 {
+            fs.write_group(4, group, &[group as u8; 100], CompressionType::Gzip, None).unwrap();
+        }
+
+        // A gzip container that claims to hold more data than its stream does
+        let mut broken = container::encode(&[9u8; 100], CompressionType::Gzip, None).unwrap();
+        broken[5..9].copy_from_slice(&200u32.to_be_bytes());
+        fs.write_container(4, 1, &broken).unwrap();
+
+        assert!(fs.extract_index(4, OnError::Abort, |_, _| Ok(())).is_err());
+
+        let mut extracted = Vec::new();
+        let failures = fs.extract_index(4, OnError::Skip, |group, data| {
+            extracted.push((group, data));
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(extracted, vec![(0, vec![0u8; 100]), (2, vec![2u8; 100])]);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].group, 1);
+        assert!(matches!(&failures[0].error, FsError::Decompression(e) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+        assert!(std::error::Error::source(&failures[0].error).is_some());
+    }
+
+    #[test]
+    fn extract_streams_into_writers() {
+        let dir = TestDir::new("extract");
+        fs::create_dir_all(dir.join("out")).unwrap();
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        let data: Vec<u8> = (0..50000u32).map(|i| (i % 251) as u8).collect();
+        fs.write_group(6, 0, &data, CompressionType::Bzip2, None).unwrap();
+        fs.write_group(6, 2, b"small", CompressionType::Gzip, None).unwrap();
+        fs.write_container(6, 3, &[2, 0, 0, 0, 4, 0, 0, 0, 9, 0x1F, 0x8B, 0x08, 0x00]).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(fs.extract_to(6, 0, &mut out).unwrap(), 50000);
+        assert_eq!(out, data);
+
+        let failures = fs.extract_index_to(6, OnError::Skip, |group| {
+            fs::File::create(dir.join("out").join(group.to_string())).map_err(FsError::Io)
+        }).unwrap();
+        assert_eq!(failures.iter().map(|f| f.group).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(fs::read(dir.join("out/0")).unwrap(), data);
+        assert_eq!(fs::read(dir.join("out/2")).unwrap(), b"small");
+    }
+
+    #[test]
+    fn resumed_extract_skips_finished_files() {
+        let dir = TestDir::new("extract-dir");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        for group in 0..4 {
+            fs.write_group(2, group, &vec![group as u8; 400], CompressionType::Gzip, None).unwrap();
+        }
+        let out = dir.join("out");
+        let report = fs.extract_dir_resumable(&out, &GroupFilter::new().with_indices(&[2]), OnError::Abort).unwrap();
+        assert_eq!((report.written, report.skipped), (4, 0));
+        assert_eq!(fs::read(out.join("2/3.dat")).unwrap(), vec![3u8; 400]);
+
+        // A damaged file and a changed group are written again, everything else is kept
+        fs::write(out.join("2/1.dat"), b"truncated").unwrap();
+        fs.write_group(2, 2, b"changed", CompressionType::Gzip, None).unwrap();
+        let report = fs.extract_dir_resumable(&out, &GroupFilter::new(), OnError::Abort).unwrap();
+        assert_eq!((report.written, report.skipped), (2, 2));
+        assert_eq!(fs::read(out.join("2/1.dat")).unwrap(), vec![1u8; 400]);
+        assert_eq!(fs::read(out.join("2/2.dat")).unwrap(), b"changed");
+        assert!(out.join("extract.journal").exists());
+    }
+}
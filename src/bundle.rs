@@ -0,0 +1,342 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use crate::container;
+use crate::filesystem::{CompressionType, FileSystem, FsError};
+use crate::js5::Remote;
+use crate::resume::Journal;
+
+/// The magic a bundle starts with.
+const MAGIC: [u8; 4] = *b"JS5B";
+
+/// The version of the bundle layout written by `export_bundle`.
+const VERSION: u8 = 1;
+
+/// The size of a table of contents entry: the index, group, offset and length of a container.
+const ENTRY_SIZE: u64 = 1 + 4 + 8 + 4;
+
+/// How many containers `export_bundle_resumable` writes between syncing the file and its
+/// journal. Whatever was written since the last sync is written again after an interruption.
+const CHECKPOINT_INTERVAL: usize = 256;
+
+/// A whole cache in a single file, for shipping it around without thousands of loose files.
+///
+/// A bundle starts with a magic and version, then a table of contents listing the index, group,
+/// offset and length of every container, followed by the raw containers themselves. The master
+/// checksum table is stored as group 255 of index 255, so a bundle can serve as a `Remote` and
+/// be synced from like a server.
+#[derive(Debug)]
+pub struct Bundle<R: Read + Seek> {
+    reader: R,
+    toc: BTreeMap<(u32, u32), (u64, u32)>,
+}
+
+impl Bundle<File> {
+    /// Opens a bundle file.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Bundle<File>, FsError> {
+        Bundle::new(File::open(path).map_err(|_| FsError::FileNotFound)?)
+    }
+}
+
+impl<R: Read + Seek> Bundle<R> {
+    /// Reads the table of contents of a bundle.
+    pub fn new(mut reader: R) -> Result<Bundle<R>, FsError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC || reader.read_u8()? != VERSION {
+            return Err(FsError::CorruptedData);
+        }
+
+        let read_toc = |reader: &mut R| -> std::io::Result<BTreeMap<(u32, u32), (u64, u32)>> {
+            let count = reader.read_u32::<BigEndian>()?;
+            let mut toc = BTreeMap::new();
+            for _ in 0..count {
+                let index = reader.read_u8()? as u32;
+                let group = reader.read_u32::<BigEndian>()?;
+                let offset = reader.read_u64::<BigEndian>()?;
+                let length = reader.read_u32::<BigEndian>()?;
+                toc.insert((index, group), (offset, length));
+            }
+            Ok(toc)
+        };
+
+        let toc = read_toc(&mut reader)?;
+        Ok(Bundle { reader, toc })
+    }
+
+    /// Gets the index and group of every container in the bundle, in order.
+    pub fn groups(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.toc.keys().copied()
+    }
+
+    /// Gets the number of containers in the bundle.
+    pub fn len(&self) -> usize {
+        self.toc.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.toc.is_empty()
+    }
+
+    /// Reads the raw container of a group.
+    pub fn read_container(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        let (offset, length) = *self.toc.get(&(index, group)).ok_or(FsError::EntryNotFound)?;
+
+        let mut data = vec![0u8; length as usize];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl<R: Read + Seek> Remote for Bundle<R> {
+    fn fetch(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        self.read_container(index, group)
+    }
+}
+
+impl FileSystem {
+    /// Packs every container of the cache into a bundle file, along with a master checksum table
+    /// generated from the reference tables. Returns the number of containers in the bundle.
+    pub fn export_bundle<P: AsRef<Path>>(&mut self, dest: P) -> Result<usize, FsError> {
+        let groups = self.bundle_groups();
+        let checksums = container::encode(&self.checksum_table()?.encode(), CompressionType::None, None)?;
+
+        let write = |fs: &mut FileSystem, out: &mut BufWriter<File>| -> Result<(), FsError> {
+            let io = |_| FsError::WriteFailed;
+            out.write_all(&MAGIC).map_err(io)?;
+            out.write_u8(VERSION).map_err(io)?;
+            out.write_u32::<BigEndian>(groups.len() as u32).map_err(io)?;
+
+            // The table of contents is filled in once the offsets are known
+            let toc_start = 4 + 1 + 4;
+            out.write_all(&vec![0u8; groups.len() * ENTRY_SIZE as usize]).map_err(io)?;
+
+            let mut offset = toc_start + groups.len() as u64 * ENTRY_SIZE;
+            let mut toc = Vec::with_capacity(groups.len());
+            for &(index, group) in &groups {
+                let data = if (index, group) == (255, 255) { checksums.clone() } else { fs.read_container(index, group)? };
+                out.write_all(&data).map_err(io)?;
+                toc.push((index, group, offset, data.len() as u32));
+                offset += data.len() as u64;
+            }
+
+            out.seek(SeekFrom::Start(toc_start)).map_err(io)?;
+            for (index, group, offset, length) in toc {
+                out.write_u8(index as u8).map_err(io)?;
+                out.write_u32::<BigEndian>(group).map_err(io)?;
+                out.write_u64::<BigEndian>(offset).map_err(io)?;
+                out.write_u32::<BigEndian>(length).map_err(io)?;
+            }
+
+            out.flush().map_err(io)?;
+            out.get_ref().sync_all().map_err(io)
+        };
+
+        let mut out = BufWriter::new(File::create(dest).map_err(FsError::Io)?);
+        write(self, &mut out)?;
+        Ok(groups.len())
+    }
+
+    /// Packs the cache into a bundle file like `export_bundle`, but keeps track of the containers
+    /// written so far, so a run that gets interrupted can be resumed by calling this again.
+    ///
+    /// The bundle is built as the file with a ".partial" suffix, next to a journal with a
+    /// ".journal" suffix that lists the containers that reached the disk. A resumed run keeps
+    /// the containers the journal lists for as long as they are still the ones that go there,
+    /// with the same size and CRC, and writes the rest. The bundle is moved in place and the
+    /// journal deleted once it is complete.
+    pub fn export_bundle_resumable<P: AsRef<Path>>(&mut self, dest: P) -> Result<usize, FsError> {
+        let dest = dest.as_ref();
+        let groups = self.bundle_groups();
+        let checksums = container::encode(&self.checksum_table()?.encode(), CompressionType::None, None)?;
+        let with_suffix = |suffix: &str| {
+            let mut name = dest.as_os_str().to_owned();
+            name.push(suffix);
+            PathBuf::from(name)
+        };
+        let (partial, journal) = (with_suffix(".partial"), with_suffix(".journal"));
+
+        // Keep the containers the journal lists for as long as they match the ones that go there
+        let toc_start = 4 + 1 + 4;
+        let data_start = toc_start + groups.len() as u64 * ENTRY_SIZE;
+        let partial_len = fs::metadata(&partial).map_or(0, |metadata| metadata.len());
+        let mut toc = Vec::with_capacity(groups.len());
+        let mut kept = Vec::new();
+        let mut offset = data_start;
+        for (record, &(index, group)) in Journal::read(&journal)?.into_iter().zip(&groups) {
+            let length = match record.as_slice() {
+                [i, g, o, length, crc] if (*i, *g, *o) == (index as u64, group as u64, offset) && offset + length <= partial_len => {
+                    let current = if (index, group) == (255, 255) { container::crc(&checksums)? } else { self.crc(index, group)? };
+                    let size = if (index, group) == (255, 255) { checksums.len() as u64 } else { self.index(index).unwrap().entry(group).map_or(0, |entry| entry.size() as u64) };
+                    if current as u64 != *crc || size != *length {
+                        break;
+                    }
+                    *length
+                }
+                _ => break,
+            };
+
+            toc.push((index, group, offset, length as u32));
+            offset += length;
+            kept.push(record);
+        }
+
+        let io = |_| FsError::WriteFailed;
+        let file = OpenOptions::new().create(true).write(true).truncate(false).open(&partial).map_err(FsError::Io)?;
+        file.set_len(offset).map_err(io)?;
+        let mut journal = Journal::create(&journal)?;
+        for record in &kept {
+            journal.append(record)?;
+        }
+        journal.checkpoint()?;
+        let mut out = BufWriter::new(file);
+        if toc.is_empty() {
+            out.write_all(&MAGIC).map_err(io)?;
+            out.write_u8(VERSION).map_err(io)?;
+            out.write_u32::<BigEndian>(groups.len() as u32).map_err(io)?;
+            out.write_all(&vec![0u8; groups.len() * ENTRY_SIZE as usize]).map_err(io)?;
+        }
+        out.seek(SeekFrom::Start(offset)).map_err(io)?;
+
+        for (i, &(index, group)) in groups.iter().enumerate().skip(toc.len()) {
+            let data = if (index, group) == (255, 255) { checksums.clone() } else { self.read_container(index, group)? };
+            out.write_all(&data).map_err(io)?;
+            journal.append(&[index as u64, group as u64, offset, data.len() as u64, container::crc(&data)? as u64])?;
+            toc.push((index, group, offset, data.len() as u32));
+            offset += data.len() as u64;
+
+            // The containers have to be on disk before the journal says they are
+            if (i + 1) % CHECKPOINT_INTERVAL == 0 {
+                out.flush().map_err(io)?;
+                out.get_ref().sync_data().map_err(io)?;
+                journal.checkpoint()?;
+            }
+        }
+
+        out.seek(SeekFrom::Start(toc_start)).map_err(io)?;
+        for (index, group, offset, length) in toc {
+            out.write_u8(index as u8).map_err(io)?;
+            out.write_u32::<BigEndian>(group).map_err(io)?;
+            out.write_u64::<BigEndian>(offset).map_err(io)?;
+            out.write_u32::<BigEndian>(length).map_err(io)?;
+        }
+        out.flush().map_err(io)?;
+        out.get_ref().sync_all().map_err(io)?;
+        drop(out);
+
+        fs::rename(&partial, dest).map_err(FsError::Io)?;
+        journal.remove()?;
+        Ok(groups.len())
+    }
+
+    /// Gets the groups that go into a bundle in order, the master checksum table last.
+    fn bundle_groups(&mut self) -> Vec<(u32, u32)> {
+        let mut groups = Vec::new();
+        for index in self.indices() {
+            let idx = self.index(index).unwrap();
+            for group in 0..idx.last_entry() as u32 {
+                if idx.entry(group).is_some_and(|entry| entry.size() > 0) {
+                    groups.push((index, group));
+                }
+            }
+        }
+
+        groups.push((255, 255));
+        groups
+    }
+
+    /// Writes every container of a bundle into the cache as-is, reference tables last. The
+    /// master checksum table in the bundle is skipped, since the cache generates its own.
+    /// Returns the number of containers written.
+    pub fn import_bundle<R: Read + Seek>(&mut self, bundle: &mut Bundle<R>) -> Result<usize, FsError> {
+        let mut groups: Vec<(u32, u32)> = bundle.groups().filter(|key| *key != (255, 255)).collect();
+        groups.sort_by_key(|(index, _)| *index == 255);
+
+        for &(index, group) in &groups {
+            let data = bundle.read_container(index, group)?;
+            self.write_container(index, group, &data)?;
+            self.mark_clean(index, group);
+        }
+
+        Ok(groups.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::reference_table::ReferenceTable;
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+    use super::Bundle;
+
+    #[test]
+    fn bundle_round_trips_a_cache() {
+        let base = TestDir::new("bundle");
+        fs::create_dir_all(base.join("cache")).unwrap();
+
+        let mut cache = FileSystem::new_writable(base.join("cache")).unwrap();
+        cache.write_reference_table(2, &ReferenceTable::new(6)).unwrap();
+        let groups: Vec<ContainerUpdate> = (0..3)
+            .map(|g| ContainerUpdate::new(2, g * 2, container::encode(&vec![g as u8; 800], CompressionType::Gzip, Some(1)).unwrap()))
+            .collect();
+        cache.apply_update(&groups).unwrap();
+        assert_eq!(cache.export_bundle(base.join("cache.js5b")).unwrap(), 5);
+
+        let mut bundle = Bundle::open(base.join("cache.js5b")).unwrap();
+        assert_eq!(bundle.groups().collect::<Vec<_>>(), vec![(2, 0), (2, 2), (2, 4), (255, 2), (255, 255)]);
+        assert_eq!(bundle.read_container(2, 4).unwrap(), cache.read_container(2, 4).unwrap());
+
+        fs::create_dir_all(base.join("imported")).unwrap();
+        let mut imported = FileSystem::new_writable(base.join("imported")).unwrap();
+        assert_eq!(imported.import_bundle(&mut bundle).unwrap(), 4);
+        assert_eq!(imported.checksum_table().unwrap(), cache.checksum_table().unwrap());
+
+        // A bundle can be synced from like any other remote
+        fs::create_dir_all(base.join("synced")).unwrap();
+        let mut synced = FileSystem::new_writable(base.join("synced")).unwrap();
+        assert_eq!(synced.sync(&mut bundle).unwrap().groups, 3);
+    }
+
+    #[test]
+    fn interrupted_pack_resumes() {
+        let base = TestDir::new("pack");
+        fs::create_dir_all(base.join("cache")).unwrap();
+
+        let mut cache = FileSystem::new_writable(base.join("cache")).unwrap();
+        for group in 0..6 {
+            cache.write_group(3, group, &vec![group as u8; 900], CompressionType::Gzip, Some(1)).unwrap();
+        }
+        cache.export_bundle(base.join("plain.js5b")).unwrap();
+        assert_eq!(cache.export_bundle_resumable(base.join("full.js5b")).unwrap(), 7);
+        let full = fs::read(base.join("full.js5b")).unwrap();
+        assert_eq!(full, fs::read(base.join("plain.js5b")).unwrap());
+        assert!(!base.join("full.js5b.journal").exists());
+
+        // Interrupted halfway through the fourth container, after the first three reached the disk
+        let toc: Vec<((u32, u32), (u64, u32))> = Bundle::open(base.join("full.js5b")).unwrap().toc.into_iter().collect();
+        let (_, (offset, length)) = toc[2];
+        let mut partial = full[..(offset + length as u64) as usize].to_vec();
+        partial.extend(&[0xAA; 100]);
+        partial[toc[0].1 .0 as usize + 20] ^= 0xFF;
+        fs::write(base.join("resumed.js5b.partial"), partial).unwrap();
+        let journal: String = toc[..3].iter().map(|&((index, group), (offset, length))| {
+            let crc = container::crc(&cache.read_container(index, group).unwrap()).unwrap();
+            format!("{} {} {} {} {}\n", index, group, offset, length, crc)
+        }).collect();
+        fs::write(base.join("resumed.js5b.journal"), journal + "3 3 12").unwrap();
+
+        // The containers the journal lists are kept as they are, so the flipped byte survives
+        cache.export_bundle_resumable(base.join("resumed.js5b")).unwrap();
+        let resumed = fs::read(base.join("resumed.js5b")).unwrap();
+        assert_eq!(resumed.len(), full.len());
+        let differences: Vec<usize> = (0..full.len()).filter(|&i| full[i] != resumed[i]).collect();
+        assert_eq!(differences, vec![toc[0].1 .0 as usize + 20]);
+        assert!(!base.join("resumed.js5b.partial").exists() && !base.join("resumed.js5b.journal").exists());
+    }
+}
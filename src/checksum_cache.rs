@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::container;
+use crate::digest::Digests;
+use crate::filesystem::{FileSystem, FsError, IndexEntry};
+use crate::whirlpool;
+
+/// What hashing a container found, as far as verification goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupDigest {
+    /// The CRC32 of the container, excluding the version trailer.
+    pub crc32: u32,
+    /// The whirlpool digest of the container, excluding the version trailer, if it was needed.
+    pub whirlpool: Option<Vec<u8>>,
+    /// The CRC32 of the decompressed data, or `None` if the container is encrypted.
+    pub uncompressed_crc32: Option<u32>,
+}
+
+impl GroupDigest {
+    /// Hashes a container with the given digests, and its whirlpool digest too if asked for.
+    /// Returns `None` for the digest if the container doesn't decompress.
+    pub(crate) fn of(data: &[u8], digests: &Digests, with_whirlpool: bool) -> Result<Option<GroupDigest>, FsError> {
+        let length = container::length(data)?;
+        let crc32 = digests.crc32(&data[..length]);
+        let whirlpool = if with_whirlpool { Some(digests.whirlpool(&data[..length]).to_vec()) } else { None };
+
+        let uncompressed_crc32 = if container::looks_encrypted(data) {
+            None
+        } else {
+            match container::decode(data) {
+                Ok(decoded) => Some(digests.crc32(&decoded)),
+                Err(_) => return Ok(None),
+            }
+        };
+
+        Ok(Some(GroupDigest { crc32, whirlpool, uncompressed_crc32 }))
+    }
+}
+
+/// The size and modification time of a data file, or `None` if there is no such file.
+type DataStamp = Option<(u64, u128)>;
+
+/// The size and first block of a container, and its digest.
+type Cached = (u32, u32, GroupDigest);
+
+/// Digests of the containers of a cache kept in a file next to it, so checking an unchanged
+/// cache again doesn't have to read and hash every container. Each digest is kept with the size
+/// and first block of its container, and the whole file with the size and modification time of
+/// the data files. Blocks can be rewritten in place, so any change to the data files makes every
+/// digest stale.
+///
+/// The file is plain text. The first line holds the stamps of the data files, and every other
+/// line the index, group, size, first block, CRC, decompressed CRC and whirlpool digest of one
+/// container, with `-` for a missing value.
+#[derive(Debug)]
+pub struct ChecksumCache {
+    path: PathBuf,
+    stamps: [DataStamp; 2],
+    digests: HashMap<(u32, u32), Cached>,
+}
+
+impl ChecksumCache {
+    /// Loads the digests kept in a file, or starts afresh if there is no such file. Lines that
+    /// don't parse are ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ChecksumCache, FsError> {
+        let path = path.as_ref().to_path_buf();
+        let mut cache = ChecksumCache { path, stamps: [None, None], digests: HashMap::new() };
+
+        if cache.path.exists() {
+            let file = File::open(&cache.path).map_err(|_| FsError::FileNotFound)?;
+            let mut lines = BufReader::new(file).lines();
+            if let Some(line) = lines.next() {
+                cache.stamps = parse_stamps(&line?).unwrap_or_default();
+            }
+            for line in lines {
+                if let Some((key, value)) = parse_digest(&line?) {
+                    cache.digests.insert(key, value);
+                }
+            }
+        }
+
+        Ok(cache)
+    }
+
+    /// Writes the digests to the file they were loaded from. The file is replaced at once, so
+    /// an interrupted save leaves the old one.
+    pub fn save(&self) -> Result<(), FsError> {
+        let mut text = String::new();
+        let stamp = |stamp: &DataStamp| stamp.map_or("- -".to_string(), |(len, modified)| format!("{} {}", len, modified));
+        let _ = writeln!(text, "{} {}", stamp(&self.stamps[0]), stamp(&self.stamps[1]));
+
+        let mut keys: Vec<&(u32, u32)> = self.digests.keys().collect();
+        keys.sort_unstable();
+        for key in keys {
+            let (size, block, digest) = &self.digests[key];
+            let uncompressed = digest.uncompressed_crc32.map_or("-".to_string(), |crc| crc.to_string());
+            let whirlpool = digest.whirlpool.as_ref().map_or("-".to_string(), |d| d.iter().map(|b| format!("{:02x}", b)).collect());
+            let _ = writeln!(text, "{} {} {} {} {} {} {}", key.0, key.1, size, block, digest.crc32, uncompressed, whirlpool);
+        }
+
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let write = || -> std::io::Result<()> {
+            let mut file = File::create(&temporary)?;
+            file.write_all(text.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temporary, &self.path)
+        };
+        write().map_err(FsError::Io)
+    }
+
+    /// Gets the file the digests are kept in.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Gets the number of containers with a digest.
+    pub fn len(&self) -> usize {
+        self.digests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+
+    /// Forgets every digest.
+    pub fn clear(&mut self) {
+        self.digests.clear();
+    }
+
+    /// Forgets every digest if the data files of a cache changed since they were taken.
+    pub(crate) fn check_stamps(&mut self, fs: &FileSystem) {
+        let stamps = data_stamps(fs);
+        if stamps != self.stamps {
+            self.digests.clear();
+            self.stamps = stamps;
+        }
+    }
+
+    /// Gets the digest of a container, if it was taken while the container had the same size
+    /// and first block.
+    pub(crate) fn get(&self, index: u32, group: u32, entry: &IndexEntry) -> Option<&GroupDigest> {
+        match self.digests.get(&(index, group)) {
+            Some((size, block, digest)) if *size == entry.size() && *block == entry.block() => Some(digest),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, index: u32, group: u32, entry: &IndexEntry, digest: GroupDigest) {
+        self.digests.insert((index, group), (entry.size(), entry.block(), digest));
+    }
+}
+
+impl FileSystem {
+    /// Loads the checksum cache kept next to the data file, named like it with a ".sums"
+    /// suffix, or starts an empty one there.
+    pub fn checksum_cache(&self) -> Result<ChecksumCache, FsError> {
+        ChecksumCache::load(self.path().join(format!("{}.sums", self.naming().data_file())))
+    }
+}
+
+fn data_stamps(fs: &FileSystem) -> [DataStamp; 2] {
+    let stamp = |name: &str| -> DataStamp {
+        let metadata = fs::metadata(fs.path().join(name)).ok()?;
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+        Some((metadata.len(), modified))
+    };
+    [stamp(fs.naming().data_file()), stamp(&fs.naming().secondary_data_file())]
+}
+
+fn parse_stamps(line: &str) -> Option<[DataStamp; 2]> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let stamp = |len: &str, modified: &str| -> Option<DataStamp> {
+        if len == "-" {
+            return Some(None);
+        }
+        Some(Some((len.parse().ok()?, modified.parse().ok()?)))
+    };
+
+    match fields.as_slice() {
+        [len, modified, secondary_len, secondary_modified] => Some([stamp(len, modified)?, stamp(secondary_len, secondary_modified)?]),
+        _ => None,
+    }
+}
+
+fn parse_digest(line: &str) -> Option<((u32, u32), Cached)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let [index, group, size, block, crc32, uncompressed, whirlpool] = fields.as_slice() else {
+        return None;
+    };
+
+    let uncompressed_crc32 = if *uncompressed == "-" { None } else { Some(uncompressed.parse().ok()?) };
+    let whirlpool = if *whirlpool == "-" {
+        None
+    } else {
+        if whirlpool.len() != whirlpool::DIGEST_LENGTH * 2 || !whirlpool.is_ascii() {
+            return None;
+        }
+        Some((0..whirlpool.len()).step_by(2).map(|i| u8::from_str_radix(&whirlpool[i..i + 2], 16)).collect::<Result<Vec<u8>, _>>().ok()?)
+    };
+
+    let digest = GroupDigest { crc32: crc32.parse().ok()?, whirlpool, uncompressed_crc32 };
+    Some(((index.parse().ok()?, group.parse().ok()?), (size.parse().ok()?, block.parse().ok()?, digest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+
+    #[test]
+    fn unchanged_containers_are_not_hashed_again() {
+        let dir = TestDir::new("sums");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        let groups: Vec<ContainerUpdate> = (0..5)
+            .map(|g| ContainerUpdate::new(3, g, container::encode(&[g as u8; 300], CompressionType::Gzip, Some(1)).unwrap()))
+            .collect();
+        fs.apply_update(&groups).unwrap();
+
+        let mut cache = fs.checksum_cache().unwrap();
+        assert!(cache.is_empty());
+        assert!(fs.verify_all_cached(&mut cache, 2).unwrap().is_ok());
+        assert_eq!(cache.len(), 5);
+        cache.save().unwrap();
+        assert!(dir.join("main_file_cache.dat2.sums").exists());
+
+        // Lines that don't parse are dropped
+        let path = cache.path().to_path_buf();
+        let text = fs::read_to_string(&path).unwrap();
+        let line = |group: u32| text.lines().find(|line| line.starts_with(&format!("3 {} ", group))).unwrap().to_string();
+        fs::write(&path, text.replace(&line(2), "3 2 not a digest")).unwrap();
+        assert_eq!(fs.checksum_cache().unwrap().len(), 4);
+
+        // A digest that disagrees with the table shows the container wasn't read again
+        let (crc, wrong) = (container::crc(&groups[1].container).unwrap(), container::crc(&groups[2].container).unwrap());
+        fs::write(&path, text.replace(&line(1), &line(1).replacen(&crc.to_string(), &wrong.to_string(), 1))).unwrap();
+        let mut cache = fs.checksum_cache().unwrap();
+        let report = fs.verify_all_cached(&mut cache, 1).unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert!(matches!(report.failures[0].result, Err(FsError::CrcMismatch)) && report.failures[0].group == 1);
+
+        // Writing to the cache makes every digest stale
+        fs.write_group(4, 0, b"new", CompressionType::None, None).unwrap();
+        assert!(fs.verify_all_cached(&mut cache, 1).unwrap().is_ok());
+        assert_eq!(cache.len(), 6);
+    }
+}
@@ -0,0 +1,186 @@
+use std::io::{Cursor, Read};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use crate::filesystem::FsError;
+use crate::whirlpool;
+
+/// The master checksum table, served to clients as group 255 of index 255. It lists the CRC and
+/// revision of the reference table of every index, so clients can tell which ones are stale.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumTable {
+    entries: Vec<ChecksumTableEntry>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumTableEntry {
+    crc32: i32,
+    revision: u32,
+    whirlpool: Vec<u8>,
+}
+
+impl ChecksumTableEntry {
+    pub fn new(crc32: i32, revision: u32, whirlpool: Vec<u8>) -> ChecksumTableEntry {
+        ChecksumTableEntry { crc32, revision, whirlpool }
+    }
+
+    /// Gets the CRC32 of the reference table container.
+    pub fn crc32(&self) -> i32 {
+        self.crc32
+    }
+
+    /// Gets the revision of the reference table.
+    pub fn revision(&self) -> u32 {
+        self.revision
+    }
+
+    /// Gets the whirlpool digest of the reference table container, or an empty slice if the
+    /// entry has none.
+    pub fn whirlpool(&self) -> &[u8] {
+        &self.whirlpool
+    }
+}
+
+/// The ways the checksum table has been encoded over the years.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum ChecksumFormat {
+    /// Just a CRC per index, as served by the earliest js5 servers.
+    Crc,
+    /// A CRC and revision per index.
+    #[default]
+    CrcRevision,
+    /// An entry count, then a CRC, revision and whirlpool digest per index, followed by a
+    /// digest of the table itself. Servers RSA-sign that last digest, which isn't checked here.
+    Whirlpool,
+}
+
+impl ChecksumTable {
+    /// Decodes a checksum table made of a CRC and revision per index.
+    pub fn decode(data: &[u8]) -> Result<ChecksumTable, FsError> {
+        ChecksumTable::decode_with(data, ChecksumFormat::CrcRevision)
+    }
+
+    /// Decodes a checksum table in a specific format. Formats without revisions or digests
+    /// leave them zeroed or empty.
+    pub fn decode_with(data: &[u8], format: ChecksumFormat) -> Result<ChecksumTable, FsError> {
+        let invalid = || FsError::CorruptedData;
+
+        let (count, entry_len) = match format {
+            ChecksumFormat::Crc => (data.len() / 4, 4),
+            ChecksumFormat::CrcRevision => (data.len() / 8, 8),
+            ChecksumFormat::Whirlpool => (*data.first().ok_or_else(invalid)? as usize, 8 + whirlpool::DIGEST_LENGTH),
+        };
+
+        let start = if format == ChecksumFormat::Whirlpool { 1 } else { 0 };
+        let body = start + count * entry_len;
+        let exact = format != ChecksumFormat::Whirlpool;
+        if data.len() < body || (exact && data.len() != body) {
+            return Err(invalid());
+        }
+
+        let mut r = Cursor::new(&data[start..body]);
+        let mut table = ChecksumTable::default();
+
+        for _ in 0..count {
+            let crc32 = r.read_i32::<BigEndian>()?;
+            let revision = if format == ChecksumFormat::Crc { 0 } else { r.read_u32::<BigEndian>()? };
+            let mut whirlpool = Vec::new();
+            if format == ChecksumFormat::Whirlpool {
+                whirlpool.resize(whirlpool::DIGEST_LENGTH, 0);
+                r.read_exact(&mut whirlpool)?;
+            }
+            table.entries.push(ChecksumTableEntry::new(crc32, revision, whirlpool));
+        }
+
+        Ok(table)
+    }
+
+    /// Encodes the table as a CRC and revision per index.
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_with(ChecksumFormat::CrcRevision).expect("only the whirlpool format has limits")
+    }
+
+    /// Encodes the table in a specific format. The whirlpool format can hold at most 255 entries,
+    /// and its trailing digest is written unsigned, prefixed with a zero byte as the client
+    /// expects once the RSA block is decrypted. More entries than that, or a digest that isn't
+    /// `whirlpool::DIGEST_LENGTH` bytes long, are a `FormatOverflow`. Entries without a digest,
+    /// like those of indices without a reference table, get one of zeros.
+    pub fn encode_with(&self, format: ChecksumFormat) -> Result<Vec<u8>, FsError> {
+        let mut w = Vec::with_capacity(self.entries.len() * 8);
+        if format == ChecksumFormat::Whirlpool {
+            if self.entries.len() > 255 {
+                return Err(FsError::FormatOverflow);
+            }
+            w.push(self.entries.len() as u8);
+        }
+
+        for entry in &self.entries {
+            w.write_i32::<BigEndian>(entry.crc32).unwrap();
+            if format == ChecksumFormat::Crc {
+                continue;
+            }
+
+            w.write_u32::<BigEndian>(entry.revision).unwrap();
+            if format == ChecksumFormat::Whirlpool {
+                match entry.whirlpool.len() {
+                    0 => w.extend(&[0u8; whirlpool::DIGEST_LENGTH]),
+                    whirlpool::DIGEST_LENGTH => w.extend(&entry.whirlpool),
+                    _ => return Err(FsError::FormatOverflow),
+                }
+            }
+        }
+
+        if format == ChecksumFormat::Whirlpool {
+            let digest = whirlpool::digest(&w);
+            w.push(0);
+            w.extend(&digest);
+        }
+
+        Ok(w)
+    }
+
+    /// Gets the entries of the table, where the position of an entry is its index id.
+    pub fn entries(&self) -> &[ChecksumTableEntry] {
+        &self.entries
+    }
+
+    /// Gets the entry of a specific index, if the table covers it.
+    pub fn entry(&self, index: u32) -> Option<&ChecksumTableEntry> {
+        self.entries.get(index as usize)
+    }
+
+    /// Appends the entry of the next index.
+    pub fn push(&mut self, entry: ChecksumTableEntry) {
+        self.entries.push(entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filesystem::FsError;
+    use super::{ChecksumFormat, ChecksumTable, ChecksumTableEntry};
+
+    #[test]
+    fn every_format_round_trips() {
+        let mut table = ChecksumTable::default();
+        table.push(ChecksumTableEntry::new(-5, 12, vec![1u8; 64]));
+        table.push(ChecksumTableEntry::new(77, 3, vec![2u8; 64]));
+
+        for format in &[ChecksumFormat::Crc, ChecksumFormat::CrcRevision, ChecksumFormat::Whirlpool] {
+            let decoded = ChecksumTable::decode_with(&table.encode_with(*format).unwrap(), *format).unwrap();
+            assert_eq!(decoded.entries().len(), 2);
+            assert_eq!(decoded.entry(0).unwrap().crc32(), -5);
+            assert_eq!(decoded.entry(1).unwrap().revision(), if *format == ChecksumFormat::Crc { 0 } else { 3 });
+        }
+
+        let whirlpool = table.encode_with(ChecksumFormat::Whirlpool).unwrap();
+        assert_eq!(whirlpool.len(), 1 + 2 * 72 + 65);
+        assert_eq!(ChecksumTable::decode_with(&whirlpool, ChecksumFormat::Whirlpool).unwrap(), table);
+
+        // Neither a short digest nor a 256th index fits, rather than being cut off
+        table.push(ChecksumTableEntry::new(1, 1, vec![3u8; 20]));
+        assert!(matches!(table.encode_with(ChecksumFormat::Whirlpool), Err(FsError::FormatOverflow)));
+        let mut full = ChecksumTable::default();
+        (0..256).for_each(|_| full.push(ChecksumTableEntry::default()));
+        assert!(matches!(full.encode_with(ChecksumFormat::Whirlpool), Err(FsError::FormatOverflow)));
+        assert_eq!(full.encode_with(ChecksumFormat::Crc).unwrap().len(), 256 * 4);
+    }
+}
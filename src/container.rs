@@ -0,0 +1,567 @@
+use std::io::{self, Chain, Cursor, Read, Seek, SeekFrom, Take, Write};
+use flate2::bufread;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use bzip2::bufread::BzDecoder as BufBzDecoder;
+use bzip2::read::BzDecoder;
+use bzip2::write::BzEncoder;
+use crate::filesystem::{CompressionType, FsError};
+use crate::xtea;
+
+/// Gets the length of a raw container as indicated by its header, which is everything except the
+/// optional 2-byte version trailer: the 5-byte header, the 4-byte decompressed length (only when
+/// compressed) and the compressed payload.
+pub fn length(container: &[u8]) -> Result<usize, FsError> {
+    if container.len() < 5 {
+        return Err(FsError::CorruptedData);
+    }
+
+    let payload = u32::from_be_bytes([container[1], container[2], container[3], container[4]]) as usize;
+    let header = if container[0] == 0 { 5 } else { 9 };
+
+    match payload.checked_add(header) {
+        Some(length) if length <= container.len() => Ok(length),
+        _ => Err(FsError::CorruptedData),
+    }
+}
+
+/// Gets the version stored in the 2-byte trailer of a container, if it has one.
+pub fn version(container: &[u8]) -> Result<Option<u16>, FsError> {
+    let length = length(container)?;
+
+    if container.len() >= length + 2 {
+        Ok(Some(u16::from_be_bytes([container[length], container[length + 1]])))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Computes the CRC32 of a raw container the same way the client does. The version trailer is
+/// not part of the checksummed range, so the result matches the CRC listed in reference tables.
+pub fn crc(container: &[u8]) -> Result<u32, FsError> {
+    let length = length(container)?;
+    Ok(crc32fast::hash(&container[..length]))
+}
+
+/// Decompresses a raw container into the data it holds. Encrypted containers must be
+/// decrypted first.
+pub fn decode(container: &[u8]) -> Result<Vec<u8>, FsError> {
+    decode_limited(container, u64::MAX)
+}
+
+/// Decompresses a raw container like `decode`, but fails with `FsError::TooLarge` instead if it
+/// holds more than `limit` bytes, before anything is decompressed.
+pub fn decode_limited(container: &[u8], limit: u64) -> Result<Vec<u8>, FsError> {
+    let mut out = Vec::new();
+    decode_to_limited(container, &mut out, limit)?;
+    Ok(out)
+}
+
+/// What `decode_with` found in a container.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decoded {
+    /// The data the container holds.
+    pub data: Vec<u8>,
+    /// The number of bytes of the payload after the end of the compressed stream.
+    pub trailing: usize,
+}
+
+/// Decompresses a raw container into the data it holds like `decode`, and counts the bytes of
+/// the payload that come after the end of the compressed stream, which some repacked caches
+/// have. Unless lenient, a container with such bytes fails with `FsError::CorruptedData`;
+/// `decode` and `decode_to` ignore them.
+pub fn decode_with(container: &[u8], lenient: bool) -> Result<Decoded, FsError> {
+    let mut data = Vec::new();
+    let (_, trailing) = decode_stream(container, &mut data, u64::MAX)?;
+    if trailing > 0 && !lenient {
+        return Err(FsError::CorruptedData);
+    }
+    Ok(Decoded { data, trailing })
+}
+
+/// Decompresses a raw container into a writer as it goes, so the decompressed data is never held
+/// in memory as a whole. Returns the number of bytes written. Errors of the writer are returned
+/// as `FsError::Io`, those of the compressed stream as `FsError::Decompression`, after which the
+/// writer may have been given part of the data.
+pub fn decode_to<W: Write>(container: &[u8], writer: &mut W) -> Result<u64, FsError> {
+    decode_to_limited(container, writer, u64::MAX)
+}
+
+/// Decompresses a raw container into a writer like `decode_to`, but fails with
+/// `FsError::TooLarge` without writing anything if it holds more than `limit` bytes.
+pub fn decode_to_limited<W: Write>(container: &[u8], writer: &mut W, limit: u64) -> Result<u64, FsError> {
+    decode_stream(container, writer, limit).map(|(written, _)| written)
+}
+
+/// Decompresses a raw container into a writer, and returns the number of bytes written and the
+/// number of payload bytes left after the compressed stream. The decompressed length in the
+/// header is checked against `limit` before any of it is written.
+fn decode_stream<W: Write>(container: &[u8], writer: &mut W, limit: u64) -> Result<(u64, usize), FsError> {
+    let length = length(container)?;
+
+    let compression = CompressionType::from_code(container[0]);
+    if compression == CompressionType::None {
+        if length as u64 - 5 > limit {
+            return Err(FsError::TooLarge);
+        }
+        writer.write_all(&container[5..length]).map_err(FsError::Io)?;
+        return Ok((length as u64 - 5, 0));
+    }
+
+    if length < 9 {
+        return Err(FsError::CorruptedData);
+    }
+    let real_size = u32::from_be_bytes([container[5], container[6], container[7], container[8]]) as u64;
+    if real_size > limit {
+        return Err(FsError::TooLarge);
+    }
+    let payload = &container[9..length];
+    if !has_codec_magic(compression, payload) {
+        return Err(FsError::Encrypted);
+    }
+
+    // The decoders read from the payload itself, so what they leave of it is what follows the stream
+    match compression {
+        CompressionType::Gzip => {
+            let mut decoder = bufread::GzDecoder::new(payload);
+            let written = pump(&mut decoder, real_size, writer)?;
+            Ok((written, decoder.into_inner().len()))
+        }
+        // The "BZh1" header is stripped from the stored stream, so put it back in front
+        CompressionType::Bzip2 => {
+            let mut decoder = BufBzDecoder::new((&b"BZh1"[..]).chain(payload));
+            let written = pump(&mut decoder, real_size, writer)?;
+            Ok((written, decoder.into_inner().get_ref().1.len()))
+        }
+        CompressionType::Lzma => Err(FsError::UnsupportedCompression),
+        CompressionType::None => unreachable!(),
+    }
+}
+
+/// Copies the decompressed length in the header from a decoder into a writer, then runs the
+/// decoder to the end of its stream.
+fn pump<R: Read, W: Write>(decoder: &mut R, real_size: u64, writer: &mut W) -> Result<u64, FsError> {
+    let mut buffer = [0u8; 8192];
+    let mut written = 0;
+    while written < real_size {
+        let want = buffer.len().min((real_size - written) as usize);
+        let read = match decoder.read(&mut buffer[..want]) {
+            Ok(0) => return Err(FsError::Decompression(io::Error::new(io::ErrorKind::UnexpectedEof, "compressed stream ended early"))),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(FsError::Decompression(e)),
+        };
+
+        writer.write_all(&buffer[..read]).map_err(FsError::Io)?;
+        written += read as u64;
+    }
+
+    // Anything the stream holds past the decompressed length in the header is ignored, and so
+    // is a stream that breaks off after it
+    let _ = io::copy(decoder, &mut io::sink());
+    Ok(written)
+}
+
+/// The decompressor behind a `ContainerReader`.
+enum Source<T: AsRef<[u8]>> {
+    Plain(Cursor<T>),
+    Gzip(GzDecoder<Take<Cursor<T>>>),
+    Bzip2(BzDecoder<Chain<&'static [u8], Take<Cursor<T>>>>),
+}
+
+/// A `Read + Seek` view of the decompressed data of a container. Uncompressed containers are
+/// read straight from the container bytes. Compressed ones are decompressed lazily, keeping what
+/// was decompressed so far around so the reader can also seek backwards.
+pub struct ContainerReader<T: AsRef<[u8]>> {
+    source: Source<T>,
+    buffer: Vec<u8>,
+    start: u64,
+    len: u64,
+    position: u64,
+}
+
+impl<T: AsRef<[u8]>> ContainerReader<T> {
+    /// Creates a reader over a raw container. Encrypted containers must be decrypted first.
+    pub fn new(container: T) -> Result<ContainerReader<T>, FsError> {
+        let bytes = container.as_ref();
+        let length = length(bytes)?;
+        let compression = CompressionType::from_code(bytes[0]);
+        if looks_encrypted(bytes) {
+            return Err(FsError::Encrypted);
+        }
+
+        let (start, len) = match compression {
+            CompressionType::None => (5, length as u64 - 5),
+            _ if length < 9 => return Err(FsError::CorruptedData),
+            _ => (9, u32::from_be_bytes([bytes[5], bytes[6], bytes[7], bytes[8]]) as u64),
+        };
+
+        let mut cursor = Cursor::new(container);
+        cursor.set_position(start);
+        let payload = cursor.take(length as u64 - start);
+
+        let source = match compression {
+            CompressionType::None => Source::Plain(payload.into_inner()),
+            CompressionType::Gzip => Source::Gzip(GzDecoder::new(payload)),
+            // The "BZh1" header is stripped from the stored stream, so put it back in front
+            CompressionType::Bzip2 => Source::Bzip2(BzDecoder::new((&b"BZh1"[..]).chain(payload))),
+            CompressionType::Lzma => return Err(FsError::UnsupportedCompression),
+        };
+
+        Ok(ContainerReader { source, buffer: Vec::new(), start, len, position: 0 })
+    }
+
+    /// Gets the length of the decompressed data.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Checks if the container holds no data at all.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Decompresses until at least `target` bytes are buffered or the stream ends.
+    fn fill_to(&mut self, target: u64) -> std::io::Result<()> {
+        let target = target.min(self.len) as usize;
+
+        while self.buffer.len() < target {
+            let filled = self.buffer.len();
+            self.buffer.resize(target.max(filled + 8192).min(self.len as usize), 0);
+
+            let read = match &mut self.source {
+                Source::Gzip(decoder) => decoder.read(&mut self.buffer[filled..]),
+                Source::Bzip2(decoder) => decoder.read(&mut self.buffer[filled..]),
+                Source::Plain(_) => unreachable!(),
+            };
+
+            match read {
+                Ok(0) => {
+                    self.buffer.truncate(filled);
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "compressed stream ended early"));
+                }
+                Ok(n) => self.buffer.truncate(filled + n),
+                Err(e) => {
+                    self.buffer.truncate(filled);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: AsRef<[u8]>> Read for ContainerReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.len {
+            return Ok(0);
+        }
+
+        let count = (buf.len() as u64).min(self.len - self.position) as usize;
+
+        if let Source::Plain(cursor) = &mut self.source {
+            cursor.set_position(self.start + self.position);
+            cursor.read_exact(&mut buf[..count])?;
+        } else {
+            self.fill_to(self.position + count as u64)?;
+            let from = self.position as usize;
+            buf[..count].copy_from_slice(&self.buffer[from..from + count]);
+        }
+
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl<T: AsRef<[u8]>> Seek for ContainerReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek to a negative position")),
+        }
+    }
+}
+
+/// How hard the encoder tries to compress, from 0 (store) to 9 (smallest output). Only gzip
+/// has a size/speed trade-off: the client always decompresses bzip2 with a 100k block size, so
+/// bzip2 containers are written the same way regardless of the level.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompressionLevel(u32);
+
+impl CompressionLevel {
+    pub const FASTEST: CompressionLevel = CompressionLevel(1);
+    pub const BEST: CompressionLevel = CompressionLevel(9);
+    /// The level Jagex used to build its caches, which is the java.util.zip default.
+    pub const JAGEX: CompressionLevel = CompressionLevel(6);
+
+    /// Creates a level, clamping it to the 0 to 9 range.
+    pub fn new(level: u32) -> CompressionLevel {
+        CompressionLevel(level.min(9))
+    }
+
+    pub fn level(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for CompressionLevel {
+    fn default() -> Self {
+        CompressionLevel::JAGEX
+    }
+}
+
+/// Which codec the encoder uses for a container.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompressionMode {
+    /// Always compress with this codec.
+    Fixed(CompressionType),
+    /// Try every allowed codec and keep the smallest container.
+    Best,
+}
+
+impl From<CompressionType> for CompressionMode {
+    fn from(compression: CompressionType) -> Self {
+        CompressionMode::Fixed(compression)
+    }
+}
+
+/// The codecs every client revision can decode. Lzma was added later and can't be encoded here.
+pub const COMPATIBLE_CODECS: [CompressionType; 3] = [CompressionType::None, CompressionType::Gzip, CompressionType::Bzip2];
+
+/// Compresses data with each of the candidate codecs and returns the smallest container. On a
+/// tie the codec listed first wins, so list the cheapest codec to decompress first.
+pub fn encode_best(data: &[u8], candidates: &[CompressionType], level: CompressionLevel, version: Option<u16>) -> Result<Vec<u8>, FsError> {
+    let mut best: Option<Vec<u8>> = None;
+
+    for compression in candidates {
+        let container = encode_with_level(data, *compression, level, version)?;
+        if best.as_ref().is_none_or(|b| container.len() < b.len()) {
+            best = Some(container);
+        }
+    }
+
+    best.ok_or(FsError::UnsupportedCompression)
+}
+
+/// Compresses data into a raw container at the default level, optionally followed by a version
+/// trailer.
+pub fn encode(data: &[u8], compression: CompressionType, version: Option<u16>) -> Result<Vec<u8>, FsError> {
+    encode_with_level(data, compression, CompressionLevel::default(), version)
+}
+
+/// Compresses data into a raw container at a specific level, optionally followed by a version
+/// trailer.
+pub fn encode_with_level(data: &[u8], compression: CompressionType, level: CompressionLevel, version: Option<u16>) -> Result<Vec<u8>, FsError> {
+    let payload = match compression {
+        CompressionType::None => data.to_vec(),
+        CompressionType::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::new(level.level()));
+            encoder.write_all(data).map_err(FsError::Io)?;
+            encoder.finish().map_err(FsError::Io)?
+        }
+        CompressionType::Bzip2 => {
+            // The client only understands 100k blocks, and expects the "BZh1" header stripped
+            let mut encoder = BzEncoder::new(Vec::new(), bzip2::Compression::new(1));
+            encoder.write_all(data).map_err(FsError::Io)?;
+            let mut stream = encoder.finish().map_err(FsError::Io)?;
+            stream.drain(..4);
+            stream
+        }
+        CompressionType::Lzma => return Err(FsError::UnsupportedCompression),
+    };
+
+    let mut container = Vec::with_capacity(payload.len() + 11);
+    container.push(compression.code());
+    container.extend(&(payload.len() as u32).to_be_bytes());
+    if compression != CompressionType::None {
+        container.extend(&(data.len() as u32).to_be_bytes());
+    }
+    container.extend(&payload);
+
+    if let Some(version) = version {
+        container.extend(&version.to_be_bytes());
+    }
+
+    Ok(container)
+}
+
+/// How much data `estimate_compressed_size` compresses at most, spread over a few samples.
+const ESTIMATE_SAMPLE_SIZE: usize = 256 * 1024;
+
+/// Estimates how long the container for some data would be, without its version trailer, for
+/// planning how much a pack will grow. Data of up to 256 KiB is compressed in full, so the
+/// estimate is exact. Larger data is estimated from four samples spread across it.
+pub fn estimate_compressed_size(data: &[u8], compression: CompressionType, level: CompressionLevel) -> Result<usize, FsError> {
+    let header = if compression == CompressionType::None { 5 } else { 9 };
+    if compression == CompressionType::None || data.len() <= ESTIMATE_SAMPLE_SIZE {
+        return Ok(encode_with_level(data, compression, level, None)?.len());
+    }
+
+    let sample = ESTIMATE_SAMPLE_SIZE / 4;
+    let stride = (data.len() - sample) / 3;
+    let mut compressed = 0;
+    for i in 0..4 {
+        compressed += encode_with_level(&data[i * stride..i * stride + sample], compression, level, None)?.len() - header;
+    }
+
+    Ok(header + (compressed as u64 * data.len() as u64 / ESTIMATE_SAMPLE_SIZE as u64) as usize)
+}
+
+/// Decrypts the body of a raw container in place. The 5-byte header and the version trailer
+/// are never encrypted, everything in between is.
+pub fn decrypt(container: &mut [u8], keys: &[i32; 4]) -> Result<(), FsError> {
+    let length = length(container)?;
+
+    if !xtea::is_empty(keys) {
+        xtea::decrypt(&mut container[5..length], keys);
+    }
+
+    Ok(())
+}
+
+/// Checks whether a set of keys decrypts a container into something that looks like a valid
+/// compressed stream. Only the first two blocks are decrypted, so this is cheap enough to run
+/// over large numbers of candidate keys. Uncompressed containers carry no structure to check
+/// the keys against, so they never validate.
+pub fn validate_keys(container: &[u8], keys: &[i32; 4]) -> bool {
+    let length = match length(container) {
+        Ok(length) => length,
+        Err(_) => return false,
+    };
+
+    // Decompressed length plus 12 bytes of stream header is plenty to recognize the codec
+    const HEAD_LEN: usize = 16;
+    if length < 5 + HEAD_LEN {
+        return false;
+    }
+    let mut head = [0u8; HEAD_LEN];
+    head.copy_from_slice(&container[5..5 + HEAD_LEN]);
+    xtea::decrypt(&mut head, keys);
+
+    let real_size = i32::from_be_bytes([head[0], head[1], head[2], head[3]]);
+    if real_size <= 0 {
+        return false;
+    }
+
+    has_codec_magic(CompressionType::from_code(container[0]), &head[4..])
+}
+
+/// Guesses whether a container is XTEA-encrypted, which is the case when it is compressed but
+/// the compressed stream doesn't start the way its codec's streams always do. Uncompressed
+/// containers have no magic to check, so they are never reported as encrypted.
+pub fn looks_encrypted(container: &[u8]) -> bool {
+    let length = match length(container) {
+        Ok(length) => length,
+        Err(_) => return false,
+    };
+
+    match CompressionType::from_code(container[0]) {
+        CompressionType::None => false,
+        compression => length >= 9 && !has_codec_magic(compression, &container[9..length]),
+    }
+}
+
+/// Checks if a compressed stream starts with the magic bytes of its codec.
+fn has_codec_magic(compression: CompressionType, stream: &[u8]) -> bool {
+    match compression {
+        CompressionType::None => false,
+        // Jagex strips the "BZh1" file header, so the stream starts with the block magic
+        CompressionType::Bzip2 => stream.starts_with(&[0x31, 0x41, 0x59, 0x26, 0x53, 0x59]),
+        CompressionType::Gzip => stream.starts_with(&[0x1F, 0x8B, 0x08]),
+        CompressionType::Lzma => stream.first().is_some_and(|props| *props < 9 * 5 * 5),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use flate2::{Compression, write::GzEncoder};
+    use crate::filesystem::{CompressionType, FsError};
+    use crate::xtea;
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let data = b"the quick brown fox jumps over the lazy dog".repeat(20);
+
+        for compression in &[CompressionType::None, CompressionType::Gzip, CompressionType::Bzip2] {
+            let container = super::encode(&data, *compression, Some(3)).unwrap();
+            assert_eq!(super::version(&container).unwrap(), Some(3));
+            assert_eq!(super::decode(&container).unwrap(), data);
+            assert_eq!(super::decode_limited(&container, data.len() as u64).unwrap(), data);
+            assert!(matches!(super::decode_limited(&container, data.len() as u64 - 1), Err(FsError::TooLarge)));
+
+            let mut reader = super::ContainerReader::new(&container).unwrap();
+            let mut tail = Vec::new();
+            reader.seek(SeekFrom::Start(800)).unwrap();
+            reader.read_to_end(&mut tail).unwrap();
+            assert_eq!(tail, &data[800..]);
+
+            let mut head = [0u8; 10];
+            reader.seek(SeekFrom::Start(5)).unwrap();
+            reader.read_exact(&mut head).unwrap();
+            assert_eq!(head, data[5..15]);
+        }
+
+        let stored = super::encode_with_level(&data, CompressionType::Gzip, super::CompressionLevel::new(0), None).unwrap();
+        let best = super::encode_with_level(&data, CompressionType::Gzip, super::CompressionLevel::BEST, None).unwrap();
+        assert!(best.len() < stored.len());
+        assert_eq!(super::decode(&stored).unwrap(), super::decode(&best).unwrap());
+
+        let smallest = super::encode_best(&data, &super::COMPATIBLE_CODECS, super::CompressionLevel::BEST, None).unwrap();
+        assert!(smallest.len() <= best.len());
+        assert_eq!(super::decode(&smallest).unwrap(), data);
+    }
+
+    #[test]
+    fn trailing_bytes_after_the_stream() {
+        let data = b"repacked by some tool".repeat(10);
+
+        for compression in &[CompressionType::Gzip, CompressionType::Bzip2] {
+            let mut container = super::encode(&data, *compression, None).unwrap();
+            assert_eq!(super::decode_with(&container, false).unwrap().trailing, 0);
+
+            let payload = u32::from_be_bytes([container[1], container[2], container[3], container[4]]);
+            container[1..5].copy_from_slice(&(payload + 3).to_be_bytes());
+            container.extend(&[7, 7, 7]);
+
+            assert!(matches!(super::decode_with(&container, false), Err(FsError::CorruptedData)));
+            assert_eq!(super::decode_with(&container, true).unwrap(), super::Decoded { data: data.clone(), trailing: 3 });
+            assert_eq!(super::decode(&container).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn validate_keys_on_encrypted_gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"some map data that is long enough to compress").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut container = vec![2u8];
+        container.extend(&(compressed.len() as u32).to_be_bytes());
+        container.extend(&46u32.to_be_bytes());
+        container.extend(&compressed);
+
+        let keys = [0x1234_5678, -0x0102_0304, 42, -1];
+        let length = super::length(&container).unwrap();
+        xtea::encrypt(&mut container[5..length], &keys);
+
+        assert!(super::validate_keys(&container, &keys));
+        assert!(!super::validate_keys(&container, &[1, 2, 3, 4]));
+        assert!(super::looks_encrypted(&container));
+        assert!(matches!(super::decode(&container), Err(FsError::Encrypted)));
+
+        super::decrypt(&mut container, &keys).unwrap();
+        assert_eq!(&container[9..], &compressed[..]);
+        assert!(!super::looks_encrypted(&container));
+    }
+}
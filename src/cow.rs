@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use crate::filesystem::{FileSystem, FsError};
+use crate::options::FileSystemOptions;
+
+impl FileSystem {
+    /// Turns on copy-on-write mode. From then on, written containers are kept in memory instead of
+    /// going to the files, and reads see them as if they had. This works on read-only filesystems
+    /// too. The writes can later be applied with `commit`, copied into a new cache along with
+    /// everything else with `commit_to`, or thrown away with `discard_writes`.
+    ///
+    /// Only reads of containers see the layer. Anything that walks the idx files, such as the
+    /// bulk operations, only finds the groups and indices that are in the files.
+    pub fn begin_copy_on_write(&mut self) {
+        if self.cow_layer().is_none() {
+            self.replace_cow_layer(Some(BTreeMap::new()));
+        }
+    }
+
+    /// Checks if copy-on-write mode is on.
+    pub fn is_copy_on_write(&self) -> bool {
+        self.cow_layer().is_some()
+    }
+
+    /// Gets the groups written in copy-on-write mode so far, in cache order.
+    pub fn pending_writes(&self) -> Vec<(u32, u32)> {
+        self.cow_layer().map(|layer| layer.keys().copied().collect()).unwrap_or_default()
+    }
+
+    /// Throws away the writes made in copy-on-write mode and turns it off.
+    pub fn discard_writes(&mut self) {
+        for (index, group) in self.replace_cow_layer(None).unwrap_or_default().into_keys() {
+            self.invalidate_crc(index, group);
+            self.mark_clean(index, group);
+        }
+    }
+
+    /// Writes the containers written in copy-on-write mode to the files, and turns it off. They
+    /// are written in cache order. If one fails, it and the ones after it are kept in the layer.
+    pub fn commit(&mut self) -> Result<(), FsError> {
+        if !self.writable() {
+            return Err(FsError::ReadOnly);
+        }
+
+        let mut layer = self.replace_cow_layer(None).unwrap_or_default();
+        while let Some(((index, group), container)) = layer.pop_first() {
+            if let Err(e) = self.write_container(index, group, &container) {
+                layer.insert((index, group), container);
+                self.replace_cow_layer(Some(layer));
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs an operation as a dry run: whatever it writes goes to a copy-on-write layer that is
+    /// thrown away afterwards, along with the dirty marks it changed. An operation that already
+    /// runs in copy-on-write mode sees the writes made before, which are kept.
+    pub(crate) fn dry_run<T, F>(&mut self, operation: F) -> Result<T, FsError> where F: FnOnce(&mut FileSystem) -> Result<T, FsError> {
+        let previous = self.cow_layer().cloned();
+        self.replace_cow_layer(Some(previous.clone().unwrap_or_default()));
+        let dirty = self.dirty_groups().into_iter().collect();
+
+        let result = operation(self);
+
+        for (index, group) in self.replace_cow_layer(previous).unwrap_or_default().into_keys() {
+            self.invalidate_crc(index, group);
+        }
+        self.replace_dirty(dirty);
+        result
+    }
+
+    /// Copies the cache as it reads in copy-on-write mode into a new one in another folder,
+    /// creating it if needed, and returns it opened for writing. The new cache is named the same
+    /// and has the same block size and idx format. This filesystem keeps its layer.
+    pub fn commit_to<P: AsRef<Path>>(&mut self, dest: P) -> Result<FileSystem, FsError> {
+        fs::create_dir_all(dest.as_ref()).map_err(FsError::Io)?;
+        let options = FileSystemOptions::new().with_writable(true).with_naming(self.naming().clone())
+            .with_block_size(self.mainfile().block_size()).with_index_format(self.index_format());
+        let mut target = FileSystem::open_with(dest, options)?;
+
+        // Groups of the layer can lie past the end of an idx file, or in an index without one
+        let mut counts: BTreeMap<u32, u32> = BTreeMap::new();
+        for index in self.indices() {
+            counts.insert(index, self.index(index).unwrap().last_entry() as u32);
+        }
+        for (index, group) in self.pending_writes() {
+            let count = counts.entry(index).or_default();
+            *count = (*count).max(group + 1);
+        }
+
+        for (index, count) in counts {
+            for group in 0..count {
+                match self.read_container(index, group) {
+                    Ok(container) => {
+                        target.write_container(index, group, &container)?;
+                        target.mark_clean(index, group);
+                    }
+                    Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        target.sync_all()?;
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn writes_stay_in_the_layer_until_committed() {
+        let base = TestDir::new("cow");
+        fs::create_dir_all(base.join("live")).unwrap();
+
+        let mut writer = FileSystem::new_writable(base.join("live")).unwrap();
+        writer.write_group(2, 0, b"original", CompressionType::Gzip, None).unwrap();
+        drop(writer);
+        let original = fs::read(base.join("live/main_file_cache.dat2")).unwrap();
+
+        let mut preview = FileSystem::new(base.join("live")).unwrap();
+        preview.begin_copy_on_write();
+        preview.write_group(2, 0, b"previewed", CompressionType::Gzip, None).unwrap();
+        preview.write_group(9, 4, b"new", CompressionType::None, None).unwrap();
+        assert_eq!(preview.pending_writes(), vec![(2, 0), (9, 4)]);
+        assert_eq!(preview.read_container_range(9, 4, 5, 10).unwrap(), b"new");
+        assert_eq!(fs::read(base.join("live/main_file_cache.dat2")).unwrap(), original);
+
+        let mut copy = preview.commit_to(base.join("copy")).unwrap();
+        assert_eq!(copy.indices(), vec![2, 9]);
+        assert_eq!(copy.read_container(2, 0).unwrap(), preview.read_container(2, 0).unwrap());
+        assert!(preview.commit().is_err());
+
+        preview.discard_writes();
+        assert!(!preview.is_copy_on_write());
+        assert_eq!(crate::container::decode(&preview.read_container(2, 0).unwrap()).unwrap(), b"original");
+
+        let mut writer = FileSystem::new_writable(base.join("live")).unwrap();
+        writer.begin_copy_on_write();
+        writer.write_group(2, 1, b"committed", CompressionType::None, None).unwrap();
+        writer.commit().unwrap();
+        assert_eq!(FileSystem::new(base.join("live")).unwrap().read_container(2, 1).unwrap()[5..], b"committed"[..]);
+    }
+}
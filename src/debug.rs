@@ -0,0 +1,175 @@
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use crate::container;
+use crate::filesystem::{BlockHeader, CompressionType, FileSystem, FsError, IndexFormat};
+
+/// How many bytes of a payload the descriptions show.
+const PREVIEW_LEN: usize = 64;
+
+/// Renders bytes as a hexdump of 16 bytes a line, each line starting with the offset of its
+/// first byte counted from `offset` and ending with the bytes as ASCII.
+pub fn hexdump(bytes: &[u8], offset: usize) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", offset + i * 16);
+        for column in 0..16 {
+            match line.get(column) {
+                Some(b) => { let _ = write!(out, " {:02x}", b); }
+                None => out.push_str("   "),
+            }
+        }
+        let text: String = line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }).collect();
+        let _ = writeln!(out, "  |{}|", text);
+    }
+    out
+}
+
+/// Describes a block of the data file: the fields of its header, with the bytes they were read
+/// from, and the start of its payload. `big` says whether the header has a 4-byte entry id.
+pub fn describe_block(block: u32, data: &[u8], format: IndexFormat, big: bool) -> String {
+    let header_len = format.block_header_len(big);
+    if data.len() < header_len {
+        return format!("block {}: only {} bytes, too short for a {}-byte header\n{}", block, data.len(), header_len, hexdump(data, 0));
+    }
+
+    let header = BlockHeader::from_block_in(format, big, data);
+    let id_len = if big { 4 } else { 2 };
+    let next_len = format.record_len() / 2;
+
+    let mut out = format!("block {}\n", block);
+    let _ = writeln!(out, "  entry:      {} (bytes 0..{})", header.entry_id(), id_len);
+    let _ = writeln!(out, "  sequence:   {} (bytes {}..{})", header.next_seq(), id_len, id_len + 2);
+    let _ = writeln!(out, "  next block: {} (bytes {}..{})", header.next_block(), id_len + 2, id_len + 2 + next_len);
+    let _ = writeln!(out, "  index:      {} (byte {})", header.index_id(), header_len - 1);
+    let payload = &data[header_len..];
+    out.push_str(&hexdump(&payload[..payload.len().min(PREVIEW_LEN)], header_len));
+    out
+}
+
+/// Describes a raw container: its compression and lengths, its version trailer and anything
+/// after it, whether it looks encrypted and whether it decodes, and the start of its payload.
+pub fn describe_container(data: &[u8]) -> String {
+    if data.len() < 5 {
+        return format!("container: only {} bytes, too short for a header\n{}", data.len(), hexdump(data, 0));
+    }
+
+    let compression = CompressionType::from_code(data[0]);
+    let payload = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+    let header_len = if compression == CompressionType::None { 5 } else { 9 };
+
+    let mut out = String::new();
+    let _ = writeln!(out, "compression:  {:?} (byte 0 = {})", compression, data[0]);
+    let _ = writeln!(out, "payload:      {} bytes (bytes 1..5)", payload);
+    if compression != CompressionType::None {
+        match data.get(5..9) {
+            Some(length) => { let _ = writeln!(out, "uncompressed: {} bytes (bytes 5..9)", u32::from_be_bytes([length[0], length[1], length[2], length[3]])); }
+            None => { let _ = writeln!(out, "uncompressed: missing, the container ends at byte {}", data.len()); }
+        }
+    }
+
+    match container::length(data) {
+        Ok(length) => {
+            let _ = match data.len() - length {
+                0 => writeln!(out, "version:      none"),
+                1 => writeln!(out, "version:      none, 1 stray byte after the payload"),
+                2 => writeln!(out, "version:      {} (bytes {}..{})", u16::from_be_bytes([data[length], data[length + 1]]), length, length + 2),
+                extra => writeln!(out, "version:      {}?, {} bytes after the payload", u16::from_be_bytes([data[length], data[length + 1]]), extra),
+            };
+            if compression != CompressionType::None && container::looks_encrypted(data) {
+                let _ = writeln!(out, "stream:       no {:?} magic, likely encrypted", compression);
+            }
+            let _ = match container::decode_with(data, true) {
+                Ok(decoded) if decoded.trailing > 0 => writeln!(out, "decodes:      {} bytes, with {} bytes after the stream", decoded.data.len(), decoded.trailing),
+                Ok(decoded) => writeln!(out, "decodes:      {} bytes", decoded.data.len()),
+                Err(e) => writeln!(out, "decodes:      no, {}", e),
+            };
+        }
+        Err(_) => {
+            let _ = writeln!(out, "length:       header says {} bytes, but there are only {}", header_len as u64 + payload as u64, data.len());
+        }
+    }
+
+    let end = data.len().min(header_len + PREVIEW_LEN);
+    if header_len < end {
+        out.push_str(&hexdump(&data[header_len..end], header_len));
+    }
+    out
+}
+
+impl FileSystem {
+    /// Describes everything about a group that goes into reading it: its idx entry, the header
+    /// of every block its chain is expected to take, and its container as `describe_container`
+    /// does, or why it can't be read. The chain is followed without checking it, so a broken
+    /// one shows where it goes wrong.
+    pub fn describe_group(&mut self, index: u32, group: u32) -> Result<String, FsError> {
+        let entry = self.index(index).ok_or(FsError::IndexNotFound)?.try_entry(group)?;
+        let mut out = format!("group {} of index {}\n", group, index);
+        let _ = writeln!(out, "idx entry:    {} bytes from block {}", entry.size(), entry.block());
+        if entry.size() == 0 {
+            return Ok(out);
+        }
+
+        let big = group > 0xFFFF;
+        let (data_file, entry) = self.route(entry);
+        let format = data_file.format();
+        let expected = (entry.size() as u64).div_ceil((data_file.block_size() - format.block_header_len(big)) as u64);
+        let num_blocks = data_file.num_blocks().unwrap_or(0);
+
+        let mut block = entry.block();
+        let mut visited = HashSet::new();
+        for seq in 0..expected {
+            if block == 0 || block as u64 >= num_blocks || !visited.insert(block) {
+                let _ = writeln!(out, "  part {}: block {} is not a valid next block", seq, block);
+                break;
+            }
+            let header = BlockHeader::from_block_in(format, big, &data_file.read_block(block)?);
+            let fits = header.entry_id() == group && header.next_seq() as u64 == seq & 0xFFFF;
+            let _ = writeln!(out, "  part {}: block {}, entry {}, sequence {}, index {}, next {}{}", seq, block, header.entry_id(),
+                header.next_seq(), header.index_id(), header.next_block(), if fits { "" } else { "  <- does not fit" });
+            block = header.next_block();
+        }
+
+        match self.read_container(index, group) {
+            Ok(container) => out.push_str(&describe_container(&container)),
+            Err(e) => { let _ = writeln!(out, "read fails: {}", e); }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use crate::filesystem::{CompressionType, FileSystem, IndexFormat};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn descriptions_point_at_what_is_wrong() {
+        assert_eq!(super::hexdump(b"RuneScape\x00\x01", 16), "00000010  52 75 6e 65 53 63 61 70 65 00 01                 |RuneScape..|\n");
+
+        let mut encrypted = crate::container::encode(&[5u8; 100], CompressionType::Gzip, Some(3)).unwrap();
+        encrypted[9..13].copy_from_slice(&[1, 2, 3, 4]);
+        let description = super::describe_container(&encrypted);
+        assert!(description.contains("version:      3 "));
+        assert!(description.contains("likely encrypted"));
+
+        let dir = TestDir::new("debug");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_group(2, 1, &[7u8; 1500], CompressionType::None, None).unwrap();
+
+        let block = fs.mainfile().read_block(1).unwrap();
+        assert!(super::describe_block(1, &block, IndexFormat::Standard, false).contains("next block: 2 (bytes 4..7)"));
+
+        // Break the link from the second block to the third
+        let mut second = fs.mainfile().read_block(2).unwrap();
+        second[4..7].copy_from_slice(&[0, 0, 9]);
+        let mut file = OpenOptions::new().write(true).open(dir.join("main_file_cache.dat2")).unwrap();
+        file.seek(SeekFrom::Start(2 * 520)).unwrap();
+        file.write_all(&second).unwrap();
+        let description = fs.describe_group(2, 1).unwrap();
+        assert!(description.contains("part 1: block 2, entry 1, sequence 1, index 2, next 9\n"));
+        assert!(description.contains("part 2: block 9 is not a valid next block"));
+        assert!(description.contains("read fails: "));
+    }
+}
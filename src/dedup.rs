@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use crate::container;
+use crate::filesystem::{FileSystem, FsError};
+use crate::whirlpool;
+
+/// Groups whose containers hold exactly the same bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateSet {
+    /// The index and group of every copy, in cache order.
+    pub groups: Vec<(u32, u32)>,
+    /// The size of one copy, without its version trailer.
+    pub size: usize,
+}
+
+impl DuplicateSet {
+    /// Gets the number of bytes that storing a single copy would save.
+    pub fn savings(&self) -> usize {
+        self.size * (self.groups.len() - 1)
+    }
+}
+
+/// The duplicated containers of a cache, as found by `FileSystem::dedup_report`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DedupReport {
+    /// Every set of identical containers, the ones that waste the most space first.
+    pub duplicates: Vec<DuplicateSet>,
+    /// The number of containers that were compared.
+    pub containers: usize,
+}
+
+impl DedupReport {
+    /// Gets the number of bytes that storing each container only once would save.
+    pub fn potential_savings(&self) -> usize {
+        self.duplicates.iter().map(|set| set.savings()).sum()
+    }
+}
+
+impl FileSystem {
+    /// Finds groups that are stored more than once under different indices or group ids. The
+    /// containers are compared by their whirlpool digest, leaving out the version trailer since
+    /// copies of the same data are often versioned differently. Reference tables are left out.
+    pub fn dedup_report(&mut self) -> Result<DedupReport, FsError> {
+        let mut sets: HashMap<[u8; whirlpool::DIGEST_LENGTH], DuplicateSet> = HashMap::new();
+        let mut report = DedupReport::default();
+
+        for index in self.indices().into_iter().filter(|index| *index != 255) {
+            let count = self.index(index).unwrap().last_entry() as u32;
+            for group in 0..count {
+                let data = match self.read_container(index, group) {
+                    Ok(data) => data,
+                    Err(FsError::EntryNotFound) => continue,
+                    Err(e) => return Err(e),
+                };
+
+                let size = container::length(&data)?;
+                sets.entry(whirlpool::digest(&data[..size]))
+                    .or_insert_with(|| DuplicateSet { groups: Vec::new(), size })
+                    .groups.push((index, group));
+                report.containers += 1;
+            }
+        }
+
+        report.duplicates = sets.into_values().filter(|set| set.groups.len() > 1).collect();
+        report.duplicates.sort_by(|a, b| b.savings().cmp(&a.savings()).then_with(|| a.groups.cmp(&b.groups)));
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn report_finds_copies_across_indices() {
+        let dir = TestDir::new("dedup");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_group(6, 0, &[1u8; 500], CompressionType::Gzip, Some(1)).unwrap();
+        fs.write_group(6, 1, &[2u8; 500], CompressionType::Gzip, Some(1)).unwrap();
+        fs.write_group(7, 4, &[1u8; 500], CompressionType::Gzip, Some(9)).unwrap();
+        fs.write_group(7, 5, &[1u8; 500], CompressionType::Gzip, None).unwrap();
+
+        let report = fs.dedup_report().unwrap();
+        assert_eq!(report.containers, 4);
+        assert_eq!(report.duplicates.len(), 1);
+        assert_eq!(report.duplicates[0].groups, vec![(6, 0), (7, 4), (7, 5)]);
+        assert_eq!(report.potential_savings(), report.duplicates[0].size * 2);
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use crate::filesystem::{FileSystem, FsError};
+use crate::options::FileSystemOptions;
+
+/// The name of the folder inside the cache that `defragment` builds the new files in.
+const WORK_FOLDER: &str = ".defragment";
+
+/// What `FileSystem::defragment` did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DefragReport {
+    /// The number of containers that were rewritten.
+    pub containers: usize,
+    /// The size of the data files before and after.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// The number of containers that start at another block than before.
+    pub moved: usize,
+    /// The number of blocks those containers take up.
+    pub blocks_moved: u64,
+}
+
+/// A container of the cache, and where it is now.
+struct Placement {
+    index: u32,
+    group: u32,
+    size: u32,
+    block: u32,
+}
+
+impl FileSystem {
+    /// Rewrites the cache so that every container is one unbroken run of blocks, in index and
+    /// group order, and the space of replaced or removed containers is given back. Groups kept
+    /// in the secondary data file move into the main one.
+    ///
+    /// The old chains are read by a number of threads, each with its own handles on the files,
+    /// while this thread writes the new files in order as the containers come in. The output
+    /// is the same however many threads there are. The new files are built in a folder inside
+    /// the cache and only replace the old ones once they are complete and synced.
+    pub fn defragment(&mut self, threads: usize) -> Result<DefragReport, FsError> {
+        if !self.writable() {
+            return Err(FsError::ReadOnly);
+        }
+
+        self.sync_all()?;
+        let naming = self.naming().clone();
+        let block_size = self.mainfile().block_size();
+        let format = self.index_format();
+        let plan = self.plan_defragment()?;
+        let groups: Vec<(u32, u32)> = self.placements().iter().map(|p| (p.index, p.group)).collect();
+
+        let work = self.path().join(WORK_FOLDER);
+        let _ = fs::remove_dir_all(&work);
+        fs::create_dir_all(&work).map_err(FsError::Io)?;
+        let options = FileSystemOptions::new().with_writable(true).with_naming(naming.clone()).with_block_size(block_size).with_index_format(format);
+        let mut target = FileSystem::open_with(&work, options)?;
+
+        let pool = self.reader_pool(threads)?;
+
+        // Readers send containers as they finish them, which are written back in order. The
+        // receiver belongs to the scope, so that failing drops it and stops the readers instead
+        // of leaving them blocked on a full channel that the scope waits for
+        let next = AtomicUsize::new(0);
+        let written = thread::scope(|scope| -> Result<usize, FsError> {
+            let (sender, receiver) = mpsc::sync_channel(pool.size() * 4);
+            for _ in 0..pool.size() {
+                let (next, sender, groups, pool) = (&next, sender.clone(), &groups, &pool);
+                scope.spawn(move || loop {
+                    let i = next.fetch_add(1, Ordering::SeqCst);
+                    let (index, group) = match groups.get(i) {
+                        Some(key) => *key,
+                        None => break,
+                    };
+
+                    if sender.send((i, pool.read_container(index, group))).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(sender);
+
+            let mut waiting = BTreeMap::new();
+            let mut written = 0;
+            for (i, container) in receiver.iter() {
+                waiting.insert(i, container);
+                while let Some(container) = waiting.remove(&written) {
+                    let (index, group) = groups[written];
+                    target.write_container(index, group, &container?)?;
+                    target.mark_clean(index, group);
+                    written += 1;
+                }
+            }
+
+            Ok(written)
+        })?;
+
+        target.sync_all()?;
+        drop(target);
+
+        let report = DefragReport { containers: written, bytes_after: self.data_file_size(&work), ..plan };
+
+        // Swap the new files in. Index files that had no containers left are gone from the new set
+        let swap = || -> std::io::Result<()> {
+            let secondary = self.path().join(naming.secondary_data_file());
+            if secondary.exists() {
+                fs::remove_file(secondary)?;
+            }
+            for entry in fs::read_dir(&work)? {
+                let entry = entry?;
+                fs::rename(entry.path(), self.path().join(entry.file_name()))?;
+            }
+            fs::remove_dir(&work)
+        };
+        swap().map_err(FsError::Io)?;
+
+        self.reload()?;
+        Ok(report)
+    }
+
+    /// Works out what `defragment` would do, without writing anything: how many containers
+    /// would be rewritten and moved, and how large the data files would be afterwards. The
+    /// report is the one the real run would give, as the new layout only depends on the sizes
+    /// of the containers. This works on read-only filesystems too.
+    pub fn plan_defragment(&mut self) -> Result<DefragReport, FsError> {
+        let block_size = self.mainfile().block_size();
+        let format = self.index_format();
+        let placements = self.placements();
+
+        let mut report = DefragReport {
+            containers: placements.len(),
+            bytes_before: self.data_file_size(self.path()),
+            ..DefragReport::default()
+        };
+
+        // Mirrors MainFile::write_entry on an empty file: every chain starts at the first block
+        // after the end of the file, which never is block 0, and the file ends with the last
+        // byte of the last block written
+        let mut end = 0u64;
+        for placement in &placements {
+            let available = (block_size - format.block_header_len(placement.group > 0xFFFF)) as u64;
+            let blocks = (placement.size as u64).div_ceil(available);
+            let first = end.div_ceil(block_size as u64).max(1);
+            let rest = placement.size as u64 - (blocks - 1) * available;
+            end = (first + blocks - 1) * block_size as u64 + format.block_header_len(placement.group > 0xFFFF) as u64 + rest;
+
+            if first != placement.block as u64 {
+                report.moved += 1;
+                report.blocks_moved += blocks;
+            }
+        }
+        report.bytes_after = end;
+
+        Ok(report)
+    }
+
+    /// Gets every container of the cache in the order `defragment` writes them.
+    fn placements(&mut self) -> Vec<Placement> {
+        let mut placements = Vec::new();
+        for index in self.indices() {
+            let idx = self.index(index).unwrap();
+            for group in 0..idx.last_entry() as u32 {
+                if let Some(entry) = idx.entry(group).filter(|entry| entry.size() > 0) {
+                    placements.push(Placement { index, group, size: entry.size(), block: entry.block() });
+                }
+            }
+        }
+
+        placements
+    }
+
+    /// Gets the size of the data files of the cache in a folder.
+    fn data_file_size(&self, path: &Path) -> u64 {
+        [self.naming().data_file().to_string(), self.naming().secondary_data_file()].iter()
+            .filter_map(|file| fs::metadata(path.join(file)).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn defragment_reclaims_replaced_chains() {
+        let base = TestDir::new("defrag");
+        let mut files = Vec::new();
+
+        for threads in [1, 4] {
+            let dir = base.join(threads.to_string());
+            fs::create_dir_all(&dir).unwrap();
+            let mut fs = FileSystem::new_writable(&dir).unwrap();
+
+            // Shrinking groups leaves the ends of their chains behind, and growing them again
+            // continues each chain at the end of the file
+            for (round, size) in [4000, 600, 1500].iter().enumerate() {
+                for group in 0..6u32 {
+                    fs.write_group(group % 2, group, &vec![round as u8 + group as u8; *size], CompressionType::None, None).unwrap();
+                }
+            }
+
+            let plan = fs.plan_defragment().unwrap();
+            let before = fs::read(dir.join("main_file_cache.dat2")).unwrap();
+            let report = fs.defragment(threads).unwrap();
+            assert_eq!(report, plan);
+            assert_eq!(report.containers, 6);
+            assert!(report.moved > 0 && report.blocks_moved >= report.moved as u64);
+            assert_eq!(report.bytes_before, before.len() as u64);
+            assert!(report.bytes_after * 2 < report.bytes_before);
+            for group in 0..6u32 {
+                assert_eq!(fs.read_container(group % 2, group).unwrap()[5..], vec![2 + group as u8; 1500][..]);
+            }
+
+            let again = fs.plan_defragment().unwrap();
+            assert_eq!((again.moved, again.bytes_after), (0, report.bytes_after));
+
+            files.push(fs::read(dir.join("main_file_cache.dat2")).unwrap());
+        }
+
+        assert_eq!(files[0], files[1]);
+    }
+
+    #[test]
+    fn defragment_fails_on_a_broken_chain() {
+        let dir = TestDir::new("defrag-broken");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        for group in 0..40u32 {
+            fs.write_group(1, group, &[group as u8; 100], CompressionType::None, None).unwrap();
+        }
+
+        // Pointing the first group at the chain of the second makes its headers disagree
+        let block = fs.index(1).unwrap().entry(1).unwrap().block();
+        fs.index(1).unwrap().write_entry(0, 105, block).unwrap();
+        assert!(fs.defragment(2).is_err());
+        assert_eq!(fs.read_container(1, 39).unwrap()[5..], [39u8; 100][..]);
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt;
+use std::sync::Arc;
+use crate::container;
+use crate::filesystem::{FileSystem, FsError};
+use crate::whirlpool;
+
+/// A hash function the checks and reference tables of a cache are computed with. The built-in
+/// `Crc32` and `Whirlpool` are used unless others are installed, such as hardware-accelerated
+/// ones. Whatever is installed must give the same results as the built-in ones, or every group
+/// of a real cache fails to verify.
+pub trait Digest: Send + Sync {
+    type Output;
+
+    fn digest(&self, data: &[u8]) -> Self::Output;
+}
+
+/// The CRC32 the client checks containers with, as computed by `crc32fast`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Crc32;
+
+impl Digest for Crc32 {
+    type Output = u32;
+
+    fn digest(&self, data: &[u8]) -> u32 {
+        crc32fast::hash(data)
+    }
+}
+
+/// The whirlpool digest reference tables and the master checksum table list.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Whirlpool;
+
+impl Digest for Whirlpool {
+    type Output = [u8; whirlpool::DIGEST_LENGTH];
+
+    fn digest(&self, data: &[u8]) -> [u8; whirlpool::DIGEST_LENGTH] {
+        whirlpool::digest(data)
+    }
+}
+
+/// The digests installed on a filesystem.
+#[derive(Clone)]
+pub(crate) struct Digests {
+    crc32: Arc<dyn Digest<Output = u32>>,
+    whirlpool: Arc<dyn Digest<Output = [u8; whirlpool::DIGEST_LENGTH]>>,
+}
+
+impl Digests {
+    pub(crate) fn crc32(&self, data: &[u8]) -> u32 {
+        self.crc32.digest(data)
+    }
+
+    pub(crate) fn whirlpool(&self, data: &[u8]) -> [u8; whirlpool::DIGEST_LENGTH] {
+        self.whirlpool.digest(data)
+    }
+
+    /// Computes the CRC32 of a raw container like `container::crc` does.
+    pub(crate) fn container_crc(&self, container: &[u8]) -> Result<u32, FsError> {
+        Ok(self.crc32(&container[..container::length(container)?]))
+    }
+
+    pub(crate) fn with_crc32(mut self, crc32: Arc<dyn Digest<Output = u32>>) -> Digests {
+        self.crc32 = crc32;
+        self
+    }
+
+    pub(crate) fn with_whirlpool(mut self, whirlpool: Arc<dyn Digest<Output = [u8; whirlpool::DIGEST_LENGTH]>>) -> Digests {
+        self.whirlpool = whirlpool;
+        self
+    }
+}
+
+impl Default for Digests {
+    fn default() -> Digests {
+        Digests { crc32: Arc::new(Crc32), whirlpool: Arc::new(Whirlpool) }
+    }
+}
+
+impl fmt::Debug for Digests {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Digests")
+    }
+}
+
+impl FileSystem {
+    /// Installs the CRC32 used to verify containers and to generate reference tables and the
+    /// master checksum table, in place of the built-in one. Cached CRCs are dropped, as they
+    /// were taken with the digest installed before.
+    pub fn set_crc32_digest(&mut self, crc32: Arc<dyn Digest<Output = u32>>) {
+        let digests = self.digest_set().clone().with_crc32(crc32);
+        self.replace_digests(digests);
+    }
+
+    /// Installs the whirlpool digest used like `set_crc32_digest` does the CRC32.
+    pub fn set_whirlpool_digest(&mut self, whirlpool: Arc<dyn Digest<Output = [u8; whirlpool::DIGEST_LENGTH]>>) {
+        let digests = self.digest_set().clone().with_whirlpool(whirlpool);
+        self.replace_digests(digests);
+    }
+
+    /// Gets the installed CRC32, which is `Crc32` unless another was installed.
+    pub fn crc32_digest(&self) -> Arc<dyn Digest<Output = u32>> {
+        self.digest_set().crc32.clone()
+    }
+
+    /// Gets the installed whirlpool digest, which is `Whirlpool` unless another was installed.
+    pub fn whirlpool_digest(&self) -> Arc<dyn Digest<Output = [u8; whirlpool::DIGEST_LENGTH]>> {
+        self.digest_set().whirlpool.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::merged::MergedStore;
+    use crate::options::FileSystemOptions;
+    use crate::test_dir::TestDir;
+    use super::{Crc32, Digest};
+
+    /// The built-in CRC32, counting how often it is used, or always wrong.
+    struct Counting(AtomicUsize, bool);
+
+    impl Digest for Counting {
+        type Output = u32;
+
+        fn digest(&self, data: &[u8]) -> u32 {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Crc32.digest(data) ^ self.1 as u32
+        }
+    }
+
+    #[test]
+    fn installed_digests_are_used_to_verify_and_generate() {
+        let dir = TestDir::new("digest");
+        let mut cache = FileSystem::new_writable(&dir).unwrap();
+        let counting = Arc::new(Counting(AtomicUsize::new(0), false));
+        cache.set_crc32_digest(counting.clone());
+        cache.write_group(3, 0, b"first", CompressionType::Gzip, Some(1)).unwrap();
+        cache.write_group(3, 1, b"second", CompressionType::None, Some(1)).unwrap();
+        cache.refresh_reference_tables().unwrap();
+        assert!(counting.0.load(Ordering::Relaxed) > 0);
+        drop(cache);
+
+        let used = counting.0.load(Ordering::Relaxed);
+        let mut cache = FileSystem::open_with(&dir, FileSystemOptions::new().with_crc32_digest(counting.clone())).unwrap();
+        assert!(cache.verify_all(2).unwrap().is_ok());
+        assert!(counting.0.load(Ordering::Relaxed) > used);
+
+        // A digest that disagrees with the one the tables were made with fails every group
+        cache.set_crc32_digest(Arc::new(Counting(AtomicUsize::new(0), true)));
+        let report = cache.verify_all(1).unwrap();
+        assert_eq!(report.failures.len(), 2);
+        assert!(report.failures.iter().all(|failure| matches!(failure.result, Err(FsError::CrcMismatch))));
+    }
+
+    #[test]
+    fn downloads_and_merged_tables_use_installed_digests() {
+        let (dir, target) = (TestDir::new("digest-remote"), TestDir::new("digest-target"));
+        let mut remote = FileSystem::new_writable(&dir).unwrap();
+        remote.write_group(3, 0, b"first", CompressionType::Gzip, Some(1)).unwrap();
+        remote.refresh_reference_tables().unwrap();
+
+        // Downloads are checked with the digest of the cache they go into
+        let mut cache = FileSystem::new_writable(&target).unwrap();
+        cache.set_crc32_digest(Arc::new(Counting(AtomicUsize::new(0), true)));
+        assert!(matches!(cache.sync(&mut remote), Err(FsError::CrcMismatch)));
+        let counting = Arc::new(Counting(AtomicUsize::new(0), false));
+        cache.set_crc32_digest(counting.clone());
+        cache.sync(&mut remote).unwrap();
+        assert!(counting.0.load(Ordering::Relaxed) > 0);
+
+        let used = counting.0.load(Ordering::Relaxed);
+        let mut merged = MergedStore::new(vec![cache]);
+        assert_eq!(merged.checksum_table().unwrap(), remote.checksum_table().unwrap());
+        assert!(counting.0.load(Ordering::Relaxed) > used);
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::filesystem::FsError;
+use crate::js5::Remote;
+
+/// A cap on how fast a download may go. Servers that rate limit their clients tend to drop
+/// connections that go over, which costs more than staying just under.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RateLimit {
+    pub requests_per_second: Option<f64>,
+    pub bytes_per_second: Option<f64>,
+}
+
+impl RateLimit {
+    /// No cap at all.
+    pub fn unlimited() -> RateLimit {
+        RateLimit::default()
+    }
+
+    pub fn with_requests_per_second(mut self, requests: f64) -> RateLimit {
+        self.requests_per_second = Some(requests);
+        self
+    }
+
+    pub fn with_bytes_per_second(mut self, bytes: f64) -> RateLimit {
+        self.bytes_per_second = Some(bytes);
+        self
+    }
+}
+
+/// Paces requests so that, counted from the first one, they never go over a rate limit. One
+/// limiter can be shared by several connections to cap them together.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    start: Option<Instant>,
+    requests: u64,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> RateLimiter {
+        RateLimiter { limit, start: None, requests: 0, bytes: 0 }
+    }
+
+    /// Takes a request from the budget, returning how long to wait before sending it.
+    pub fn acquire(&mut self) -> Duration {
+        let start = *self.start.get_or_insert_with(Instant::now);
+
+        let mut due = 0.0f64;
+        if let Some(requests) = self.limit.requests_per_second {
+            due = due.max(self.requests as f64 / requests);
+        }
+        if let Some(bytes) = self.limit.bytes_per_second {
+            due = due.max(self.bytes as f64 / bytes);
+        }
+        self.requests += 1;
+
+        (start + Duration::from_secs_f64(due)).saturating_duration_since(Instant::now())
+    }
+
+    /// Counts the bytes of a response against the budget, which slows down the requests after it.
+    pub fn consume(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+/// Sleeps out whatever the limiter asks for, then fetches a group and counts its size.
+fn fetch_limited<R: Remote>(remote: &mut R, limiter: &Mutex<RateLimiter>, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+    let wait = limiter.lock().unwrap().acquire();
+    thread::sleep(wait);
+
+    let data = remote.fetch(index, group)?;
+    limiter.lock().unwrap().consume(data.len());
+    Ok(data)
+}
+
+/// A remote that is held to a rate limit.
+#[derive(Debug)]
+pub struct Throttled<R: Remote> {
+    remote: R,
+    limiter: Arc<Mutex<RateLimiter>>,
+}
+
+impl<R: Remote> Throttled<R> {
+    pub fn new(remote: R, limit: RateLimit) -> Throttled<R> {
+        Throttled::shared(remote, Arc::new(Mutex::new(RateLimiter::new(limit))))
+    }
+
+    /// Holds a remote to a limiter that other remotes may share.
+    pub fn shared(remote: R, limiter: Arc<Mutex<RateLimiter>>) -> Throttled<R> {
+        Throttled { remote, limiter }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.remote
+    }
+}
+
+impl<R: Remote> Remote for Throttled<R> {
+    fn fetch(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        fetch_limited(&mut self.remote, &self.limiter, index, group)
+    }
+}
+
+/// Several connections to the same server, which `fetch_all` spreads the requests over with one
+/// thread per connection. Every connection takes the next group as soon as it is done with the
+/// last one, and all of them are held to one shared rate limit.
+#[derive(Debug)]
+pub struct ConnectionPool<R: Remote + Send> {
+    connections: Vec<R>,
+    limiter: Mutex<RateLimiter>,
+}
+
+impl<R: Remote + Send> ConnectionPool<R> {
+    /// Creates a pool from connections that are already open. Panics if there are none.
+    pub fn new(connections: Vec<R>, limit: RateLimit) -> ConnectionPool<R> {
+        assert!(!connections.is_empty(), "a connection pool needs at least one connection");
+        ConnectionPool { connections, limiter: Mutex::new(RateLimiter::new(limit)) }
+    }
+
+    /// Opens a number of connections with the same function, such as a js5 handshake.
+    pub fn connect<F, E>(count: usize, mut connect: F, limit: RateLimit) -> Result<ConnectionPool<R>, E>
+        where F: FnMut() -> Result<R, E> {
+        let connections = (0..count.max(1)).map(|_| connect()).collect::<Result<Vec<R>, E>>()?;
+        Ok(ConnectionPool::new(connections, limit))
+    }
+
+    /// Gets the number of connections.
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    pub fn into_inner(self) -> Vec<R> {
+        self.connections
+    }
+}
+
+impl<R: Remote + Send> Remote for ConnectionPool<R> {
+    fn fetch(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        fetch_limited(&mut self.connections[0], &self.limiter, index, group)
+    }
+
+    fn fetch_all(&mut self, groups: &[(u32, u32)]) -> Vec<Result<Vec<u8>, FsError>> {
+        let next = AtomicUsize::new(0);
+        let (connections, limiter) = (&mut self.connections, &self.limiter);
+
+        let mut fetched: Vec<(usize, Result<Vec<u8>, FsError>)> = thread::scope(|scope| {
+            let workers: Vec<_> = connections.iter_mut().map(|connection| {
+                let next = &next;
+                scope.spawn(move || {
+                    let mut fetched = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        match groups.get(i) {
+                            Some(&(index, group)) => fetched.push((i, fetch_limited(connection, limiter, index, group))),
+                            None => break fetched,
+                        }
+                    }
+                })
+            }).collect();
+
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+        });
+
+        fetched.sort_by_key(|(i, _)| *i);
+        fetched.into_iter().map(|(_, result)| result).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use crate::filesystem::FsError;
+    use crate::js5::Remote;
+    use super::{ConnectionPool, RateLimit};
+
+    /// A remote that answers with the group id and notes which connection served it.
+    struct Numbered {
+        id: usize,
+        served: Arc<Mutex<HashSet<usize>>>,
+    }
+
+    impl Remote for Numbered {
+        fn fetch(&mut self, _: u32, group: u32) -> Result<Vec<u8>, FsError> {
+            self.served.lock().unwrap().insert(self.id);
+            std::thread::sleep(Duration::from_millis(2));
+            Ok(vec![group as u8; 10])
+        }
+    }
+
+    #[test]
+    fn pool_spreads_requests_within_the_limit() {
+        let served = Arc::new(Mutex::new(HashSet::new()));
+        let mut id = 0;
+        let mut pool = ConnectionPool::connect(3, || -> Result<Numbered, ()> {
+            id += 1;
+            Ok(Numbered { id, served: served.clone() })
+        }, RateLimit::unlimited().with_requests_per_second(100.0)).unwrap();
+
+        let groups: Vec<(u32, u32)> = (0..11).map(|g| (2, g)).collect();
+        let start = Instant::now();
+        let results = pool.fetch_all(&groups);
+
+        // The eleventh request is due a tenth of a second after the first
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        for (group, result) in results.into_iter().enumerate() {
+            assert_eq!(result.unwrap(), vec![group as u8; 10]);
+        }
+        assert!(served.lock().unwrap().len() > 1);
+    }
+}
@@ -1,12 +1,23 @@
-use std::{io::ErrorKind, path::PathBuf};
-use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::fs::OpenOptions;
 use std::error::Error;
 use std::fs;
 use std::fmt;
-use std::io::{Seek, Read, SeekFrom};
-use std::collections::HashMap;
-use flate2::read::GzDecoder;
-use bzip2::read::BzDecoder;
+use std::io::{Seek, Read, SeekFrom, Write};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::SystemTime;
+use crate::backing::{Backing, Source};
+use crate::bulk::{AccessHint, BulkRead};
+use crate::checksum_table::{ChecksumTable, ChecksumTableEntry};
+use crate::container::{self, CompressionLevel, CompressionMode, ContainerReader};
+use crate::digest::Digests;
+use crate::hooks::HookSet;
+use crate::js5;
+use crate::naming::FileNaming;
+use crate::offsets::InvalidEntry;
+use crate::options::FileSystemOptions;
+use crate::reference_table::ReferenceTable;
+use crate::transform::TransformSlot;
 
 #[derive(Debug)]
 pub enum FsError {
@@ -15,36 +26,106 @@ pub enum FsError {
     NoFileHandle,
     MalformedDataSequence,
     CorruptedData,
+    IndexNotFound,
+    EntryNotFound,
+    UnsupportedCompression,
+    ReadOnly,
+    WriteFailed,
+    CrcMismatch,
+    RemoteFailed,
+    Encrypted,
+    Locked,
+    FormatOverflow,
+    UnsupportedVersion,
+    Io(std::io::Error),
+    Decompression(std::io::Error),
+    MissingFlagData,
+    MissingDataFile,
+    TooLarge,
 }
 impl Error for FsError {
     fn description(&self) -> &str {
-        match *self {
+        match self {
             FsError::FileNotFound => "the folder does not exist or cannot be read from",
             FsError::InvalidDirectory => "the specified directory is not a valid directory",
             FsError::NoFileHandle => "the filesystem did not load a file yet",
             FsError::MalformedDataSequence => "the data sequence did not complete correctly",
             FsError::CorruptedData => "the data was corrupt",
+            FsError::IndexNotFound => "the index does not exist",
+            FsError::EntryNotFound => "the entry does not exist in the index",
+            FsError::UnsupportedCompression => "the compression type is not supported",
+            FsError::ReadOnly => "the filesystem was not opened for writing",
+            FsError::WriteFailed => "the data could not be written",
+            FsError::CrcMismatch => "the data does not match the expected checksum",
+            FsError::RemoteFailed => "the data could not be fetched from the remote",
+            FsError::Encrypted => "the data appears to be encrypted",
+            FsError::Locked => "the filesystem is locked by another writer",
+            FsError::FormatOverflow => "the data does not fit in the format it is written in",
+            FsError::UnsupportedVersion => "the format version is not supported",
+            FsError::Io(_) => "an IO operation failed",
+            FsError::Decompression(_) => "the compressed data could not be decompressed",
+            FsError::MissingFlagData => "a flag was set without the data it stands for",
+            FsError::MissingDataFile => "the data file of the cache does not exist",
+            FsError::TooLarge => "the data is larger than allowed",
+        }
+    }
+
+    /// Gets the IO error behind an `Io` or `Decompression` error, which tells apart causes such as
+    /// a denied permission, a stream that ended early or one that is corrupt.
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FsError::Io(e) | FsError::Decompression(e) => Some(e),
+            _ => None,
         }
     }
 }
 impl fmt::Display for FsError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
+        match self {
             FsError::FileNotFound => write!(f, "the folder specified could not be found or read from"),
             FsError::InvalidDirectory => write!(f, "the specified directory is not a valid directory"),
             FsError::NoFileHandle => write!(f, "the filesystem did not load a file yet"),
             FsError::MalformedDataSequence => write!(f, "the data sequence did not complete correctly"),
             FsError::CorruptedData => write!(f, "the data was corrupt"),
+            FsError::IndexNotFound => write!(f, "the index does not exist"),
+            FsError::EntryNotFound => write!(f, "the entry does not exist in the index"),
+            FsError::UnsupportedCompression => write!(f, "the compression type is not supported"),
+            FsError::ReadOnly => write!(f, "the filesystem was not opened for writing"),
+            FsError::WriteFailed => write!(f, "the data could not be written"),
+            FsError::CrcMismatch => write!(f, "the data does not match the expected checksum"),
+            FsError::RemoteFailed => write!(f, "the data could not be fetched from the remote"),
+            FsError::Encrypted => write!(f, "the data appears to be encrypted and must be decrypted first"),
+            FsError::Locked => write!(f, "the filesystem is already opened for writing by another process"),
+            FsError::FormatOverflow => write!(f, "the size, position or an id of the data does not fit in the format it is written in"),
+            FsError::UnsupportedVersion => write!(f, "the format version is not supported"),
+            FsError::Io(e) => write!(f, "an IO operation failed: {}", e),
+            FsError::Decompression(e) => write!(f, "the compressed data could not be decompressed: {}", e),
+            FsError::MissingFlagData => write!(f, "a reference table flag was set while not every folder has the data it stands for"),
+            FsError::MissingDataFile => write!(f, "the folder has idx files but no data file, open it as metadata only to inspect them"),
+            FsError::TooLarge => write!(f, "the data decompresses to more than the filesystem allows"),
         }
     }
 }
 impl From<FsError> for std::io::Error {
     fn from(other: FsError) -> std::io::Error {
-        std::io::Error::new(ErrorKind::Other, other)
+        match other {
+            FsError::Io(e) => e,
+            other => std::io::Error::other(other),
+        }
+    }
+}
+/// Running out of data means it was cut short, which is how the decoders see most corruption.
+/// Every other IO error is kept as it is.
+impl From<std::io::Error> for FsError {
+    fn from(other: std::io::Error) -> FsError {
+        match other.kind() {
+            std::io::ErrorKind::UnexpectedEof => FsError::CorruptedData,
+            _ => FsError::Io(other),
+        }
     }
 }
 
-#[derive(Debug,Clone)]
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub enum CompressionType {
     /// The archive is not compressed and the raw data is the real data.
     None,
@@ -61,18 +142,131 @@ pub enum CompressionType {
 pub struct FileSystem {
     path: PathBuf,
     mainfile: MainFile,
-    indices: HashMap<u32, IndexFile>
+    secondary: Option<MainFile>,
+    indices: HashMap<u32, IndexFile>,
+    crcs: HashMap<(u32, u32), u32>,
+    writable: bool,
+    compression_levels: HashMap<u32, CompressionLevel>,
+    allowed_codecs: HashMap<u32, Vec<CompressionType>>,
+    download_retries: usize,
+    /// The most a group may decompress to, if there is a limit.
+    max_decompressed_size: Option<u64>,
+    access_hint: AccessHint,
+    index_format: IndexFormat,
+    stamps: HashMap<Option<u32>, Option<FileStamp>>,
+    lock: LockMode,
+    skipped_files: Vec<PathBuf>,
+    invalid_entries: Option<Vec<InvalidEntry>>,
+    naming: FileNaming,
+    /// Whether the filesystem may be opened without a data file, to only look at the idx files.
+    metadata_only: bool,
+    /// The groups written since their reference tables were last brought up to date.
+    dirty: BTreeSet<(u32, u32)>,
+    /// The containers written in copy-on-write mode, which haven't reached the files.
+    cow: Option<BTreeMap<(u32, u32), Vec<u8>>>,
+    hooks: HookSet,
+    transform: TransformSlot,
+    digests: Digests,
+}
+
+/// How a writable filesystem claims the advisory lock on its mainfile, which keeps two writers
+/// from interleaving block appends. Read-only filesystems never lock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum LockMode {
+    /// Fail with `FsError::Locked` if another writer holds the lock.
+    #[default]
+    Try,
+    /// Wait for other writers to release the lock.
+    Wait,
+    /// Don't lock at all, for callers that coordinate writers themselves.
+    None,
+}
+
+/// Claims the advisory lock on a mainfile the way `lock` says to.
+fn lock_file(file: &fs::File, lock: LockMode) -> Result<(), FsError> {
+    match lock {
+        LockMode::Try => file.try_lock().map_err(|e| match e {
+            std::fs::TryLockError::WouldBlock => FsError::Locked,
+            std::fs::TryLockError::Error(e) => FsError::Io(e),
+        }),
+        LockMode::Wait => file.lock().map_err(FsError::Io),
+        LockMode::None => Ok(()),
+    }
+}
+
+/// The size and modification time of a cache file, used to notice other processes writing it.
+type FileStamp = (u64, Option<SystemTime>);
+
+/// The size of a block in the mainfile, as used by every Jagex cache.
+pub const DEFAULT_BLOCK_SIZE: usize = 520;
+
+/// With a secondary data file present, this bit of an idx block number selects it instead of
+/// being part of the number.
+const SECONDARY_FLAG: u32 = 0x800000;
+
+/// How the idx files of a cache store their entries. The width of a block number in the idx
+/// files and in the block headers of the data file go together, so this applies to both.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum IndexFormat {
+    /// Jagex's format: 6-byte records with a 24-bit size and first block, which caps the data
+    /// file at 0xFFFFFF blocks, or half as many with a secondary data file.
+    #[default]
+    Standard,
+    /// 8-byte records with a 32-bit size and first block, and a 32-bit next block in every
+    /// block header, for caches that outgrow the standard format. The client can't read these.
+    Extended,
+}
+
+impl IndexFormat {
+    /// Gets the length of a record in an idx file.
+    pub fn record_len(self) -> usize {
+        match self {
+            IndexFormat::Standard => 6,
+            IndexFormat::Extended => 8,
+        }
+    }
+
+    /// Gets the largest size or block number a record can hold.
+    pub fn max_value(self) -> u32 {
+        match self {
+            IndexFormat::Standard => 0xFFFFFF,
+            IndexFormat::Extended => u32::MAX,
+        }
+    }
+
+    /// Gets the length of the header of a block, which is longer for entry ids above 65535.
+    pub fn block_header_len(self, big: bool) -> usize {
+        (if big { 4 } else { 2 }) + 2 + self.record_len() / 2 + 1
+    }
+
+    /// Gets the bit of a block number that selects the secondary data file.
+    fn secondary_flag(self) -> u32 {
+        match self {
+            IndexFormat::Standard => SECONDARY_FLAG,
+            IndexFormat::Extended => 0x80000000,
+        }
+    }
+}
+
+/// Reads a big-endian number of up to four bytes.
+fn read_be(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |value, b| (value << 8) | *b as u32)
 }
 
 #[derive(Debug)]
 pub struct MainFile {
-    file: Option<File>
+    file: Option<Backing>,
+    block_size: usize,
+    max_block: u32,
+    legacy_index_ids: bool,
+    format: IndexFormat,
 }
 
 #[derive(Debug)]
 pub struct IndexFile {
     id: u32,
-    file: File
+    file: Backing,
+    format: IndexFormat,
 }
 
 #[derive(Debug,Clone)]
@@ -80,7 +274,7 @@ pub struct IndexEntry {
     index: u8,
     id: u32,
     size: u32,
-    offset: u64
+    block: u32,
 }
 
 #[derive(Debug,Clone)]
@@ -92,18 +286,33 @@ pub struct EntryHeader {
 
 impl EntryHeader {
 
-    pub fn from_bytes(bytes: [u8; 9]) -> Result<EntryHeader, std::io::Error> {
+    pub fn from_bytes(bytes: [u8; 9]) -> Result<EntryHeader, FsError> {
         // Parse the 9 bytes of important info
         let compression_type = bytes[0];
         let raw_size: u32 = ((bytes[1] as u32) << 24) | ((bytes[2] as u32) << 16) | ((bytes[3] as u32) << 8) | (bytes[4] as u32);
         let real_size: u32 = ((bytes[5] as u32) << 24) | ((bytes[6] as u32) << 16) | ((bytes[7] as u32) << 8) | (bytes[8] as u32);
 
         // Return the new entry header
-        return Ok(EntryHeader {
-            raw_size: raw_size,
-            real_size: real_size,
+        Ok(EntryHeader {
+            raw_size,
+            real_size,
             compression: CompressionType::from_code(compression_type)
-        });
+        })
+    }
+
+    /// Gets the length of the stored (possibly compressed) payload.
+    pub fn raw_size(&self) -> u32 {
+        self.raw_size
+    }
+
+    /// Gets the length of the data after decompression. Meaningless for uncompressed entries.
+    pub fn real_size(&self) -> u32 {
+        self.real_size
+    }
+
+    /// Gets the type of compression the entry uses.
+    pub fn compression(&self) -> CompressionType {
+        self.compression
     }
 
 }
@@ -129,31 +338,61 @@ impl CompressionType {
             _ => CompressionType::None
         }
     }
+
+    /// Gets the header field value for this type of compression.
+    pub fn code(&self) -> u8 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::Bzip2 => 1,
+            CompressionType::Gzip => 2,
+            CompressionType::Lzma => 3,
+        }
+    }
 }
 
 impl BlockHeader {
-    pub fn from_block(big: bool, data: [u8; 520]) -> BlockHeader {
-        match big {
-            true => {
-                BlockHeader {
-                    big: true,
-                    entry_id: ((data[0] as u32) << 24) | ((data[1] as u32) << 16) | ((data[2] as u32) << 8) | (data[3] as u32),
-                    next_seq: (((data[4] as u32) << 8) | (data[5] as u32)) as i32,
-                    next_block: ((data[6] as u32) << 16) | ((data[7] as u32) << 8) | (data[8] as u32),
-                    index_id: data[9] as u8
-                }
-            },
-            false => {
-                BlockHeader {
-                    big: false,
-                    entry_id: ((data[0] as u32) << 8) | (data[1] as u32),
-                    next_seq: (((data[2] as u32) << 8) | (data[3] as u32)) as i32,
-                    next_block: ((data[4] as u32) << 16) | ((data[5] as u32) << 8) | (data[6] as u32),
-                    index_id: data[7] as u8
-                }
-            }
+    pub fn from_block(big: bool, data: &[u8]) -> BlockHeader {
+        BlockHeader::from_block_in(IndexFormat::Standard, big, data)
+    }
+
+    /// Parses the header of a block of a cache in a specific idx format, which decides how wide
+    /// the next block number is.
+    pub fn from_block_in(format: IndexFormat, big: bool, data: &[u8]) -> BlockHeader {
+        let id_len = if big { 4 } else { 2 };
+        let block_len = format.record_len() / 2;
+        BlockHeader {
+            big,
+            entry_id: read_be(&data[..id_len]),
+            next_seq: read_be(&data[id_len..id_len + 2]) as i32,
+            next_block: read_be(&data[id_len + 2..id_len + 2 + block_len]),
+            index_id: data[id_len + 2 + block_len],
         }
     }
+
+    /// Gets whether this block uses the longer header for entry ids above 65535.
+    pub fn big(&self) -> bool {
+        self.big
+    }
+
+    /// Gets the id of the entry this block belongs to.
+    pub fn entry_id(&self) -> u32 {
+        self.entry_id
+    }
+
+    /// Gets the id of the index this block belongs to.
+    pub fn index_id(&self) -> u8 {
+        self.index_id
+    }
+
+    /// Gets the sequence number of this block within the entry chain.
+    pub fn next_seq(&self) -> i32 {
+        self.next_seq
+    }
+
+    /// Gets the block id of the next block in the chain, or 0 if this is the last one.
+    pub fn next_block(&self) -> u32 {
+        self.next_block
+    }
 }
 
 impl IndexEntry {
@@ -172,57 +411,127 @@ impl IndexEntry {
         self.size
     }
 
-    /// Gets the absolute offset of the very first 520-byte block of this
-    /// entry in the main data file.
-    pub fn offset(&self) -> u64 {
-        self.offset
+    /// Gets the absolute offset of the very first block of this entry in the main
+    /// data file, given the block size of that file.
+    pub fn offset(&self, block_size: usize) -> u64 {
+        self.block as u64 * block_size as u64
     }
 
     pub fn block(&self) -> u32 {
-        (self.offset / 520u64) as u32
+        self.block
     }
 }
 
 impl IndexFile {
+    /// Gets the id of the index.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Creates a read-only index over a source other than a file, for `FileSystem::from_parts`.
+    pub fn from_reader<R: Source + 'static>(id: u32, reader: R) -> Result<IndexFile, FsError> {
+        Ok(IndexFile { id, file: Backing::from_reader(reader).map_err(FsError::Io)?, format: IndexFormat::Standard })
+    }
+
+    /// Gets the backing main_file_cache.idx file, or whatever else the index is read from.
+    pub fn file(&mut self) -> &mut Backing {
+        &mut self.file
+    }
+
     pub fn last_entry(&self) -> u64 {
-         self.file.metadata().unwrap().len() / 6u64
+         self.file.len().unwrap_or(0) / self.format.record_len() as u64
     }
 
+    /// Gets an entry like `try_entry`, for callers that don't care why there is none.
     pub fn entry(&mut self, id: u32) -> Option<IndexEntry> {
-        let ref mut file = self.file;
-        let mut tmp: [u8; 6] = [0; 6];
+        self.try_entry(id).ok()
+    }
 
-        // Seek to the proper position and read into the temp buffer
-        let seek_offset = id as u64 * 6u64;
-        let res1 = file.seek(SeekFrom::Start(seek_offset));
-        let res2 = file.read(&mut tmp);
+    /// Reads an entry from the idx file. An id past the end of the file is
+    /// `FsError::EntryNotFound`, while a record the file ends halfway through is
+    /// `FsError::CorruptedData`. Unused slots are read as entries of size 0.
+    pub fn try_entry(&mut self, id: u32) -> Result<IndexEntry, FsError> {
+        let len = self.format.record_len();
+        let mut tmp: [u8; 8] = [0; 8];
 
-        // Check if the seek and read operation succeeded
-        if res1.is_err() || res2.is_err() {
-            return None;
+        let seek_offset = id as u64 * len as u64;
+        if seek_offset >= self.file.len().map_err(FsError::Io)? {
+            return Err(FsError::EntryNotFound);
         }
 
-        // Check if the operations returned the expected results
-        if res1.unwrap() != seek_offset || res2.unwrap() != 6 {
-            return None;
+        // Seek to the proper position and read into the temp buffer
+        self.file.seek(SeekFrom::Start(seek_offset)).map_err(FsError::Io)?;
+        self.file.read_exact(&mut tmp[..len])?;
+
+        // Decode the size and first block from the temp buffer
+        let size = read_be(&tmp[..len / 2]);
+        let block = read_be(&tmp[len / 2..len]);
+
+        Ok(IndexEntry {index: self.id as u8, id, size, block})
+    }
+
+    /// Writes the size and first block of an entry, growing the index file if needed.
+    pub fn write_entry(&mut self, id: u32, size: u32, block: u32) -> Result<(), FsError> {
+        // Both fields are stored as 24-bit integers, unless the format is extended
+        if size > self.format.max_value() || block > self.format.max_value() {
+            return Err(FsError::FormatOverflow);
         }
 
-        // Decode the size and offset from the temp buffer
-        let size: u32 = ((tmp[0] as u32) << 16) | ((tmp[1] as u32) << 8) | (tmp[2] as u32);
-        let offset: u64 = ((tmp[3] as u64) << 16) | ((tmp[4] as u64) << 8) | (tmp[5] as u64);
+        let len = self.format.record_len();
+        let mut tmp = Vec::with_capacity(len);
+        tmp.extend(&size.to_be_bytes()[4 - len / 2..]);
+        tmp.extend(&block.to_be_bytes()[4 - len / 2..]);
 
-        Some(IndexEntry {index: self.id as u8, id: id, size: size, offset: offset * 520u64})
+        self.file.seek(SeekFrom::Start(id as u64 * len as u64)).map_err(FsError::Io)?;
+        self.file.write_all(&tmp).map_err(FsError::Io)
     }
 }
 
 impl FileSystem {
-    pub fn new(string: &'static str) -> Result<FileSystem, FsError> {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<FileSystem, FsError> {
+        FileSystem::open_with(path, FileSystemOptions::new())
+    }
+
+    /// Opens a filesystem for both reading and writing. The main file is created if it does not
+    /// exist yet, and index files are created when a container is first written to them.
+    pub fn new_writable<P: AsRef<Path>>(path: P) -> Result<FileSystem, FsError> {
+        FileSystem::open_with(path, FileSystemOptions::new().with_writable(true))
+    }
+
+    /// Opens a filesystem for writing like `new_writable`, with a specific way of taking the
+    /// writer lock.
+    pub fn new_writable_locked<P: AsRef<Path>>(path: P, lock: LockMode) -> Result<FileSystem, FsError> {
+        FileSystem::open_with(path, FileSystemOptions::new().with_writable(true).with_lock(lock))
+    }
+
+    /// Opens a filesystem whose files are named differently from Jagex's caches.
+    pub fn new_named<P: AsRef<Path>>(path: P, writable: bool, naming: FileNaming) -> Result<FileSystem, FsError> {
+        FileSystem::open_with(path, FileSystemOptions::new().with_writable(writable).with_naming(naming))
+    }
+
+    /// Opens a filesystem like `new` or `new_writable`, and checks every idx entry against the
+    /// length of the data file. The entries that point beyond it are kept in `invalid_entries`.
+    pub fn new_validated<P: AsRef<Path>>(path: P, writable: bool) -> Result<FileSystem, FsError> {
+        FileSystem::open_with(path, FileSystemOptions::new().with_writable(writable).with_validation(true))
+    }
+
+    /// Creates a read-only filesystem from a mainfile and idx files that aren't files in a folder,
+    /// such as ones made with `MainFile::from_reader`. There is no folder behind it, so `reload`
+    /// fails, and anything that writes files next to the cache has nowhere to put them.
+    pub fn from_parts(mainfile: MainFile, indices: Vec<IndexFile>) -> FileSystem {
+        FileSystem { path: PathBuf::new(), mainfile, secondary: None, indices: indices.into_iter().map(|index| (index.id, index)).collect(),
+            crcs: HashMap::new(), writable: false, compression_levels: HashMap::new(), allowed_codecs: HashMap::new(),
+            download_retries: js5::DEFAULT_DOWNLOAD_RETRIES, max_decompressed_size: None, access_hint: AccessHint::default(), index_format: IndexFormat::Standard, stamps: HashMap::new(),
+            lock: LockMode::None, skipped_files: Vec::new(), invalid_entries: None, naming: FileNaming::default(), metadata_only: false, dirty: BTreeSet::new(), cow: None, hooks: HookSet::default(), transform: TransformSlot::default(), digests: Digests::default()}
+    }
+
+    pub(crate) fn open(path: &Path, writable: bool, lock: LockMode, mut naming: FileNaming, metadata_only: bool) -> Result<FileSystem, FsError> {
         // Declare some nice variables!!!
-        let path = PathBuf::from(string);
+        let path = path.to_path_buf();
         let metadata = fs::metadata(&path);
 
         // Make sure the folder exists
-        if !metadata.is_ok() {
+        if metadata.is_err() {
             return Err(FsError::FileNotFound);
         }
 
@@ -231,32 +540,186 @@ impl FileSystem {
             return Err(FsError::InvalidDirectory);
         }
 
-        // Create mainfile path
-        let mut mainfile_path = PathBuf::from(string);
-        mainfile_path.push("main_file_cache.dat2");
+        // Create mainfile path, preferring the first name that exists. A new one gets the first name
+        let data = naming.data_candidates().find(|data| path.join(data).is_file())
+            .unwrap_or_else(|| naming.data_file()).to_string();
+        naming.resolve(&data);
+        let mut mainfile_path = path.clone();
+        mainfile_path.push(naming.data_file());
 
         // Find all valid index files
         let mut indices: HashMap<u32, IndexFile> = HashMap::new();
-        let entries = fs::read_dir(&path).unwrap();
+        let mut skipped_files = Vec::new();
+        let entries = fs::read_dir(&path).map_err(FsError::Io)?;
         for entry in entries {
-            let e = entry.unwrap();
-            let fname = e.file_name().into_string().unwrap();
-
-            // Is this an index?
-            if fname.starts_with("main_file_cache.idx") {
-                // Parse the index id into an integer
-                let idx = fname[19..].parse::<u32>().unwrap();
+            let e = entry.map_err(FsError::Io)?;
+            let fname = e.file_name();
+
+            // Is this an index? Backups such as "main_file_cache.idx2.bak" look like one, but aren't
+            let idx = match naming.parse_index(&fname) {
+                Some(idx) => idx,
+                None => {
+                    if naming.resembles_index(&fname) {
+                        skipped_files.push(e.path());
+                    }
+                    continue;
+                }
+            };
 
-                // Add the index file to our map with indices
-                indices.insert(idx, IndexFile {id: idx, file: File::open(e.path()).unwrap()});
+            // Add the index file to our map with indices
+            match OpenOptions::new().read(true).write(writable).open(e.path()) {
+                Ok(file) => { indices.insert(idx, IndexFile {id: idx, file: Backing::File(file), format: IndexFormat::Standard}); }
+                Err(_) => skipped_files.push(e.path()),
             }
         }
 
         // Create the filesystem object and return it
-        let file = File::open(mainfile_path).ok();
-        let mainfile = MainFile{file: file};
+        // Without a data file nothing but the idx files can be read, which only makes sense when asked for
+        let file = match OpenOptions::new().read(true).write(writable).create(writable).truncate(false).open(mainfile_path) {
+            Ok(file) => Some(file),
+            Err(_) if metadata_only => None,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(FsError::MissingDataFile),
+            Err(e) => return Err(FsError::Io(e)),
+        };
+        if let (true, Some(file)) = (writable, &file) {
+            lock_file(file, lock)?;
+        }
+        let mut mainfile = MainFile{file: file.map(Backing::File), block_size: DEFAULT_BLOCK_SIZE, max_block: 0xFFFFFF, legacy_index_ids: naming.is_legacy(),
+            format: IndexFormat::Standard};
+
+        // Newer caches keep some large groups in a secondary data file, which is only opened if it exists
+        let secondary_path = path.join(naming.secondary_data_file());
+        let secondary = if secondary_path.is_file() {
+            let file = OpenOptions::new().read(true).write(writable).open(secondary_path).ok().map(Backing::File);
+            mainfile.max_block = SECONDARY_FLAG - 1;
+            Some(MainFile{file, block_size: DEFAULT_BLOCK_SIZE, max_block: SECONDARY_FLAG - 1, legacy_index_ids: false, format: IndexFormat::Standard})
+        } else {
+            None
+        };
+
+        let mut fs = FileSystem {path, mainfile, secondary, indices, crcs: HashMap::new(), writable, compression_levels: HashMap::new(),
+            allowed_codecs: HashMap::new(), download_retries: js5::DEFAULT_DOWNLOAD_RETRIES, max_decompressed_size: None, access_hint: AccessHint::default(), index_format: IndexFormat::Standard,
+            stamps: HashMap::new(), lock, skipped_files,
+            invalid_entries: None, naming, metadata_only, dirty: BTreeSet::new(), cow: None, hooks: HookSet::default(), transform: TransformSlot::default(), digests: Digests::default()};
+        fs.restamp_all();
+        Ok(fs)
+    }
+
+    /// Reopens the mainfile and rediscovers the idx files, picking up files that another process
+    /// created, replaced or removed. Settings such as compression levels are kept, but memoized
+    /// CRCs are dropped since they may describe the old files.
+    pub fn reload(&mut self) -> Result<(), FsError> {
+        // The new handles are all opened before the old ones are given up, so failing to leaves
+        // the filesystem as it was. The writer lock is only moved over once they are
+        let mut fresh = FileSystem::open(&self.path, self.writable, LockMode::None, self.naming.clone(), self.metadata_only)?;
+        if self.writable {
+            self.move_lock(&fresh.mainfile)?;
+        }
+
+        std::mem::swap(&mut self.mainfile, &mut fresh.mainfile);
+        self.mainfile.block_size = fresh.mainfile.block_size;
+        std::mem::swap(&mut self.secondary, &mut fresh.secondary);
+        if let (Some(secondary), Some(old)) = (&mut self.secondary, &fresh.secondary) {
+            secondary.block_size = old.block_size;
+        }
+        std::mem::swap(&mut self.indices, &mut fresh.indices);
+        std::mem::swap(&mut self.stamps, &mut fresh.stamps);
+        std::mem::swap(&mut self.skipped_files, &mut fresh.skipped_files);
+        self.crcs.clear();
+        self.set_index_format(self.index_format);
+        if self.invalid_entries.is_some() {
+            self.invalid_entries = Some(self.validate_offsets());
+        }
+        Ok(())
+    }
+
+    /// Moves the writer lock from the mainfile onto the handle `reload` is replacing it with. The
+    /// old handle would keep the new one from locking, so it lets go first, and takes the lock
+    /// back if the new one can't have it.
+    fn move_lock(&mut self, to: &MainFile) -> Result<(), FsError> {
+        let (Some(Backing::File(new)), LockMode::Try | LockMode::Wait) = (&to.file, self.lock) else {
+            return Ok(());
+        };
+        let old = match &self.mainfile.file {
+            Some(Backing::File(old)) => Some(old),
+            _ => None,
+        };
+
+        if let Some(old) = old {
+            old.unlock().map_err(FsError::Io)?;
+        }
+        lock_file(new, self.lock).inspect_err(|_| {
+            if let Some(old) = old {
+                let _ = lock_file(old, self.lock);
+            }
+        })
+    }
+
+    /// Checks if the mainfile or any idx file was changed on disk since it was opened, other than
+    /// through this filesystem.
+    pub fn modified_externally(&self) -> bool {
+        self.stamps.iter().any(|(file, stamp)| self.current_stamp(*file) != *stamp)
+    }
+
+    /// Reloads the filesystem if another process changed its files, so it doesn't keep serving
+    /// memoized data about the old ones. Returns whether it reloaded.
+    pub fn refresh_if_modified(&mut self) -> Result<bool, FsError> {
+        if !self.modified_externally() {
+            return Ok(false);
+        }
+
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Reads the current stamp of the mainfile (`None`) or an idx file from disk.
+    fn current_stamp(&self, file: Option<u32>) -> Option<FileStamp> {
+        let name = match file {
+            None => self.naming.data_file().to_string(),
+            Some(index) => self.naming.index_file(index),
+        };
+
+        fs::metadata(self.path.join(name)).ok().map(|m| (m.len(), m.modified().ok()))
+    }
 
-        Ok(FileSystem {path: path, mainfile: mainfile, indices: indices})
+    /// Records the current stamp of a file after this filesystem opened or wrote it.
+    fn restamp(&mut self, file: Option<u32>) {
+        let stamp = self.current_stamp(file);
+        self.stamps.insert(file, stamp);
+    }
+
+    fn restamp_all(&mut self) {
+        self.restamp(None);
+        for index in self.indices() {
+            self.restamp(Some(index));
+        }
+    }
+
+    /// Gets the files that are named like index files but were skipped when opening, because the
+    /// name doesn't end in a plain index id or the file couldn't be opened.
+    pub fn skipped_files(&self) -> &[PathBuf] {
+        &self.skipped_files
+    }
+
+    /// Checks the idx entries against the data file now, and again whenever the filesystem reloads.
+    pub(crate) fn validate_on_open(&mut self) {
+        self.invalid_entries = Some(self.validate_offsets());
+    }
+
+    /// Gets the idx entries that pointed beyond the end of the data file when the filesystem was
+    /// opened with `new_validated`, or last reloaded. Empty for filesystems opened otherwise.
+    pub fn invalid_entries(&self) -> &[InvalidEntry] {
+        self.invalid_entries.as_deref().unwrap_or(&[])
+    }
+
+    /// Gets the names of the files this filesystem consists of.
+    pub fn naming(&self) -> &FileNaming {
+        &self.naming
+    }
+
+    /// Gets the path of the folder this filesystem was opened from.
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     /// Gets the mainfile, that is, the main_file_cache.dat2 entry in the folder
@@ -270,6 +733,518 @@ impl FileSystem {
     pub fn index(&mut self, index: u32) -> Option<&mut IndexFile> {
         self.indices.get_mut(&index)
     }
+
+    /// Gets the ids of the indices that have an idx file, in ascending order.
+    pub fn indices(&self) -> Vec<u32> {
+        let mut ids: Vec<u32> = self.indices.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Checks if an index has an idx file.
+    pub fn has_index(&self, index: u32) -> bool {
+        self.indices.contains_key(&index)
+    }
+
+    /// Gets the number of indices that have an idx file.
+    pub fn index_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    /// Gets the indices that have a reference table but no idx file, in order, as partial dumps
+    /// often do. Operations over the whole cache skip these and report them.
+    pub fn missing_indices(&mut self) -> Vec<u32> {
+        let Some(tables) = self.indices.get_mut(&255) else {
+            return Vec::new();
+        };
+
+        let count = tables.last_entry() as u32;
+        let listed: Vec<u32> = (0..count).filter(|index| tables.entry(*index).is_some_and(|entry| entry.size() > 0)).collect();
+        listed.into_iter().filter(|index| *index != 255 && !self.indices.contains_key(index)).collect()
+    }
+
+    /// Flushes every write made so far to disk, for both the mainfile and the idx files.
+    pub fn sync_all(&mut self) -> Result<(), FsError> {
+        if !self.writable {
+            return Ok(());
+        }
+
+        if let Some(file) = self.mainfile.file() {
+            file.sync_all().map_err(FsError::Io)?;
+        }
+        for index in self.indices.values() {
+            index.file.sync_all().map_err(FsError::Io)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the raw (still compressed) container bytes of a group, including the version
+    /// trailer if the group has one.
+    pub fn read_container(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        let result = self.load_container(index, group);
+        self.hooks.read(index, group, &result);
+        result
+    }
+
+    fn load_container(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        if let Some(container) = self.cow.as_ref().and_then(|cow| cow.get(&(index, group))) {
+            return Ok(container.clone());
+        }
+
+        let entry = self.index(index).ok_or(FsError::IndexNotFound)?.try_entry(group)?;
+
+        // Unused slots in the index are zeroed out
+        if entry.size() == 0 {
+            return Err(FsError::EntryNotFound);
+        }
+
+        let (data_file, entry) = self.route(entry);
+        let stored = data_file.read_entry(entry)?;
+        match self.transform.get() {
+            Some(transform) => transform.decode(index, group, stored),
+            None => Ok(stored),
+        }
+    }
+
+    /// Gets the secondary data file (main_file_cache.dat2m), if the cache has one.
+    pub fn secondary_mainfile(&mut self) -> Option<&mut MainFile> {
+        self.secondary.as_mut()
+    }
+
+    /// Picks the data file an entry lives in, and the entry as seen from within that file.
+    pub(crate) fn route(&mut self, entry: IndexEntry) -> (&mut MainFile, IndexEntry) {
+        match &mut self.secondary {
+            Some(secondary) if entry.block & self.index_format.secondary_flag() != 0 => {
+                secondary.block_size = self.mainfile.block_size;
+                (secondary, IndexEntry { block: entry.block & !self.index_format.secondary_flag(), ..entry })
+            }
+            _ => (&mut self.mainfile, entry),
+        }
+    }
+
+    /// Checks if the filesystem was opened for writing.
+    pub fn writable(&self) -> bool {
+        self.writable
+    }
+
+    /// Writes the raw container bytes of a group, creating the index file if it doesn't exist.
+    /// The container is stored as-is, so it should already include its version trailer.
+    pub fn write_container(&mut self, index: u32, group: u32, container: &[u8]) -> Result<(), FsError> {
+        let result = self.store_container(index, group, container);
+        self.hooks.written(index, group, container, &result);
+        result
+    }
+
+    fn store_container(&mut self, index: u32, group: u32, container: &[u8]) -> Result<(), FsError> {
+        // Blocks only have room for an 8-bit index id
+        if index > 255 {
+            return Err(FsError::IndexNotFound);
+        }
+
+        // Checked before any block is written, so an oversized container leaves nothing behind
+        if container.len() as u64 > self.index_format.max_value() as u64 {
+            return Err(FsError::FormatOverflow);
+        }
+
+        // Copy-on-write keeps the files as they are, so it works on read-only filesystems too
+        if let Some(cow) = self.cow.as_mut() {
+            cow.insert((index, group), container.to_vec());
+            self.invalidate_crc(index, group);
+            self.dirty.insert((index, group));
+            return Ok(());
+        }
+
+        if !self.writable {
+            return Err(FsError::ReadOnly);
+        }
+
+        let transformed = match self.transform.get() {
+            Some(transform) => Some(transform.encode(index, group, container.to_vec())?),
+            None => None,
+        };
+        let container = transformed.as_deref().unwrap_or(container);
+        if container.len() as u64 > self.index_format.max_value() as u64 {
+            return Err(FsError::FormatOverflow);
+        }
+
+        if !self.indices.contains_key(&index) {
+            let mut index_path = self.path.clone();
+            index_path.push(self.naming.index_file(index));
+
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(index_path)
+                .map_err(FsError::Io)?;
+            self.indices.insert(index, IndexFile {id: index, file: Backing::File(file), format: self.index_format});
+        }
+
+        let index_file = self.indices.get_mut(&index).unwrap();
+        // Groups are always written to the primary data file, so a chain in the secondary one is dropped
+        let has_secondary = self.secondary.is_some();
+        let flag = self.index_format.secondary_flag();
+        let existing = match index_file.try_entry(group) {
+            Ok(entry) => Some(entry).filter(|e| e.size() > 0 && !(has_secondary && e.block & flag != 0)),
+            Err(FsError::EntryNotFound) => None,
+            Err(e) => return Err(e),
+        };
+        let block = self.mainfile.write_entry(index as u8, group, container, existing.as_ref())?;
+        index_file.write_entry(group, container.len() as u32, block)?;
+
+        self.restamp(None);
+        self.restamp(Some(index));
+        self.invalidate_crc(index, group);
+        self.dirty.insert((index, group));
+        Ok(())
+    }
+
+    /// Gets the groups written through this filesystem since their reference tables were last
+    /// brought up to date by `refresh_reference_tables` or `apply_update`, in cache order.
+    /// Reference tables written directly show up as groups of index 255.
+    pub fn dirty_groups(&self) -> Vec<(u32, u32)> {
+        self.dirty.iter().copied().collect()
+    }
+
+    /// Checks if a group was written since its reference table was last brought up to date.
+    pub fn is_dirty(&self, index: u32, group: u32) -> bool {
+        self.dirty.contains(&(index, group))
+    }
+
+    /// Forgets which groups were written, for callers that keep the reference tables up to date
+    /// themselves.
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+
+    pub(crate) fn transform_slot(&self) -> &TransformSlot {
+        &self.transform
+    }
+
+    pub(crate) fn replace_transform(&mut self, transform: TransformSlot) -> TransformSlot {
+        self.crcs.clear();
+        std::mem::replace(&mut self.transform, transform)
+    }
+
+    pub(crate) fn digest_set(&self) -> &Digests {
+        &self.digests
+    }
+
+    pub(crate) fn replace_digests(&mut self, digests: Digests) -> Digests {
+        self.crcs.clear();
+        std::mem::replace(&mut self.digests, digests)
+    }
+
+    pub(crate) fn installed_hooks(&self) -> &HookSet {
+        &self.hooks
+    }
+
+    pub(crate) fn replace_hooks(&mut self, hooks: HookSet) -> HookSet {
+        std::mem::replace(&mut self.hooks, hooks)
+    }
+
+    /// Gets the containers written in copy-on-write mode, if it is on.
+    pub(crate) fn cow_layer(&self) -> Option<&BTreeMap<(u32, u32), Vec<u8>>> {
+        self.cow.as_ref()
+    }
+
+    /// Turns copy-on-write mode on with an empty layer, or off, returning the layer it had.
+    pub(crate) fn replace_cow_layer(&mut self, layer: Option<BTreeMap<(u32, u32), Vec<u8>>>) -> Option<BTreeMap<(u32, u32), Vec<u8>>> {
+        std::mem::replace(&mut self.cow, layer)
+    }
+
+    /// Marks a group as described by its reference table again.
+    pub(crate) fn mark_clean(&mut self, index: u32, group: u32) {
+        self.dirty.remove(&(index, group));
+    }
+
+    /// Replaces the set of dirty groups, returning the one it had.
+    pub(crate) fn replace_dirty(&mut self, dirty: BTreeSet<(u32, u32)>) -> BTreeSet<(u32, u32)> {
+        std::mem::replace(&mut self.dirty, dirty)
+    }
+
+    /// Gets the compression level used when this filesystem encodes groups of an index.
+    pub fn compression_level(&self, index: u32) -> CompressionLevel {
+        self.compression_levels.get(&index).copied().unwrap_or_default()
+    }
+
+    /// Sets the compression level used when this filesystem encodes groups of an index. Index
+    /// 255 controls the level of reference tables.
+    pub fn set_compression_level(&mut self, index: u32, level: CompressionLevel) {
+        self.compression_levels.insert(index, level);
+    }
+
+    /// Gets the codecs `CompressionMode::Best` may pick from for groups of an index. By default
+    /// these are all the codecs any client can decode.
+    pub fn allowed_codecs(&self, index: u32) -> &[CompressionType] {
+        self.allowed_codecs.get(&index).map(|c| c.as_slice()).unwrap_or(&container::COMPATIBLE_CODECS)
+    }
+
+    /// Restricts the codecs `CompressionMode::Best` may pick from for groups of an index, for
+    /// clients that only handle some of them.
+    pub fn set_allowed_codecs(&mut self, index: u32, codecs: Vec<CompressionType>) {
+        self.allowed_codecs.insert(index, codecs);
+    }
+
+    /// Gets how many times `sync` asks again for a download that fails its CRC check.
+    pub fn download_retries(&self) -> usize {
+        self.download_retries
+    }
+
+    /// Sets how many times `sync` asks again for a download that fails its CRC check, before it
+    /// gives up with `FsError::CrcMismatch`.
+    pub fn set_download_retries(&mut self, retries: usize) {
+        self.download_retries = retries;
+    }
+
+    /// Gets the most a group may decompress to, if there is a limit.
+    pub fn max_decompressed_size(&self) -> Option<u64> {
+        self.max_decompressed_size
+    }
+
+    /// Limits how much a group may decompress to, so that a corrupt or hostile header can't make
+    /// reading it allocate gigabytes. Groups that would decompress to more fail with
+    /// `FsError::TooLarge` before anything is decompressed.
+    pub fn set_max_decompressed_size(&mut self, max: Option<u64>) {
+        self.max_decompressed_size = max;
+    }
+
+    /// Decompresses a container of this cache with `container::decode_limited`, up to the limit
+    /// that is set.
+    pub(crate) fn decode_group(&self, container: &[u8]) -> Result<Vec<u8>, FsError> {
+        container::decode_limited(container, self.max_decompressed_size.unwrap_or(u64::MAX))
+    }
+
+    /// Decompresses a container into a writer like `decode_group`.
+    pub(crate) fn decode_group_to<W: Write>(&self, container: &[u8], writer: &mut W) -> Result<u64, FsError> {
+        container::decode_to_limited(container, writer, self.max_decompressed_size.unwrap_or(u64::MAX))
+    }
+
+    /// Gets what bulk operations tell the kernel about reading the data files.
+    pub fn access_hint(&self) -> AccessHint {
+        self.access_hint
+    }
+
+    /// Sets what bulk operations such as `extract_index`, `verify_index`, `search` and
+    /// `hash_index` tell the kernel about reading the data files. Only Linux is told anything.
+    pub fn set_access_hint(&mut self, hint: AccessHint) {
+        self.access_hint = hint;
+    }
+
+    /// Starts a bulk operation, giving the data files the access hint until the result is dropped.
+    pub(crate) fn bulk_read(&self) -> BulkRead {
+        let files = std::iter::once(&self.mainfile).chain(self.secondary.as_ref())
+            .filter_map(|data_file| data_file.file.as_ref().and_then(|file| file.try_clone_file()))
+            .collect();
+        BulkRead::new(files, self.access_hint)
+    }
+
+    /// Gets the format of the idx files, which is `IndexFormat::Standard` unless set otherwise.
+    pub fn index_format(&self) -> IndexFormat {
+        self.index_format
+    }
+
+    /// Sets the format of the idx files and the block headers. Like the block size, it can't be
+    /// told from the files, so it has to be set before anything is read or written.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the blocks of the data files are too small for the block headers of `format`.
+    pub fn set_index_format(&mut self, format: IndexFormat) {
+        for data_file in std::iter::once(&self.mainfile).chain(self.secondary.as_ref()) {
+            assert!(data_file.block_size > format.block_header_len(true), "a block must be larger than its header");
+        }
+        self.index_format = format;
+        let max_block = if self.secondary.is_some() { format.secondary_flag() - 1 } else { format.max_value() };
+        for data_file in std::iter::once(&mut self.mainfile).chain(self.secondary.as_mut()) {
+            data_file.format = format;
+            data_file.max_block = max_block;
+        }
+        for index in self.indices.values_mut() {
+            index.format = format;
+        }
+    }
+
+    /// Compresses data into a container at the compression level of the index and writes it.
+    pub fn write_group<M: Into<CompressionMode>>(&mut self, index: u32, group: u32, data: &[u8], mode: M, version: Option<u16>) -> Result<(), FsError> {
+        let container = self.encode_group(index, data, mode.into(), version)?;
+        self.write_container(index, group, &container)
+    }
+
+    /// Compresses data into a container the way `write_group` would for this index.
+    pub fn encode_group(&self, index: u32, data: &[u8], mode: CompressionMode, version: Option<u16>) -> Result<Vec<u8>, FsError> {
+        let level = self.compression_level(index);
+        match mode {
+            CompressionMode::Fixed(compression) => container::encode_with_level(data, compression, level, version),
+            CompressionMode::Best => container::encode_best(data, self.allowed_codecs(index), level, version),
+        }
+    }
+
+    /// Reads and decodes the reference table of an index, which is stored in index 255.
+    pub fn reference_table(&mut self, index: u32) -> Result<ReferenceTable, FsError> {
+        let mut reader = self.container_reader(255, index)?;
+        ReferenceTable::decode(&mut reader)
+    }
+
+    /// Opens a `Read + Seek` view of the decompressed data of a group, which decompresses lazily
+    /// as it is read.
+    pub fn container_reader(&mut self, index: u32, group: u32) -> Result<ContainerReader<Vec<u8>>, FsError> {
+        ContainerReader::new(self.read_container(index, group)?)
+    }
+
+    /// Encodes the reference table of an index and writes it to index 255. The table is
+    /// compressed the same way as the one it replaces, or with gzip if there was none.
+    pub fn write_reference_table(&mut self, index: u32, table: &ReferenceTable) -> Result<(), FsError> {
+        let compression = match self.read_container(255, index) {
+            Ok(old) => match CompressionType::from_code(old[0]) {
+                CompressionType::Lzma => CompressionType::Gzip,
+                compression => compression,
+            },
+            Err(_) => CompressionType::Gzip,
+        };
+
+        let data = table.encode()?;
+        self.write_group(255, index, &data, compression, None)
+    }
+
+    /// Builds the master checksum table from the reference tables in index 255. Indices
+    /// without a reference table get an empty entry.
+    pub fn checksum_table(&mut self) -> Result<ChecksumTable, FsError> {
+        let count = self.index(255).map_or(0, |idx| idx.last_entry()) as u32;
+        let mut table = ChecksumTable::default();
+
+        for index in 0..count {
+            let entry = match self.read_container(255, index) {
+                Ok(container) => {
+                    let reference_table = ReferenceTable::decode(&mut ContainerReader::new(&container)?)?;
+
+                    ChecksumTableEntry::new(self.digests.container_crc(&container)? as i32, reference_table.revision(),
+                        self.digests.whirlpool(&container).to_vec())
+                }
+                Err(FsError::EntryNotFound) => ChecksumTableEntry::default(),
+                Err(e) => return Err(e),
+            };
+
+            table.push(entry);
+        }
+
+        Ok(table)
+    }
+
+    /// Reads a range of the raw container bytes of a group, without loading the rest of it.
+    pub fn read_container_range(&mut self, index: u32, group: u32, offset: u32, len: u32) -> Result<Vec<u8>, FsError> {
+        if let Some(container) = self.cow.as_ref().and_then(|cow| cow.get(&(index, group))) {
+            let start = (offset as usize).min(container.len());
+            let end = (offset as usize).saturating_add(len as usize).min(container.len());
+            return Ok(container[start..end].to_vec());
+        }
+
+        // A transformed container can only be turned back as a whole
+        if self.transform.get().is_some() {
+            let container = self.read_container(index, group)?;
+            let start = (offset as usize).min(container.len());
+            let end = (offset as usize).saturating_add(len as usize).min(container.len());
+            return Ok(container[start..end].to_vec());
+        }
+
+        let entry = self.index(index).ok_or(FsError::IndexNotFound)?.try_entry(group)?;
+
+        if entry.size() == 0 {
+            return Err(FsError::EntryNotFound);
+        }
+
+        let (data_file, entry) = self.route(entry);
+        data_file.read_entry_range(entry, offset, len)
+    }
+
+    /// Computes the CRC32 of the raw container of a group, exactly as the client does when it
+    /// checks a download against the reference table.
+    pub fn container_crc(&mut self, index: u32, group: u32) -> Result<u32, FsError> {
+        let container = self.read_container(index, group)?;
+        self.digests.container_crc(&container)
+    }
+
+    /// Gets the CRC32 of the raw container of a group like `container_crc`, but remembers the
+    /// result so repeated lookups don't re-read and re-hash the container. Anything writing a
+    /// container must call `invalidate_crc` for it. Changes other processes make are noticed
+    /// through the size and modification time of the files, which reloads the filesystem.
+    pub fn crc(&mut self, index: u32, group: u32) -> Result<u32, FsError> {
+        let stale = [None, Some(index)].iter()
+            .any(|file| self.stamps.get(file).is_some_and(|stamp| self.current_stamp(*file) != *stamp));
+        if stale {
+            self.reload()?;
+        }
+
+        if let Some(crc) = self.crcs.get(&(index, group)) {
+            return Ok(*crc);
+        }
+
+        let crc = self.container_crc(index, group)?;
+        self.crcs.insert((index, group), crc);
+        Ok(crc)
+    }
+
+    /// Forgets the memoized CRC of a group, so the next call to `crc` recomputes it.
+    pub fn invalidate_crc(&mut self, index: u32, group: u32) {
+        self.crcs.remove(&(index, group));
+    }
+
+    /// Forgets all memoized CRCs.
+    pub fn clear_crcs(&mut self) {
+        self.crcs.clear();
+    }
+
+    /// Checks whether a set of XTEA keys decrypts the container of a group into a sane compressed
+    /// stream. Groups that can't be read never validate.
+    pub fn validate_keys(&mut self, index: u32, group: u32, keys: &[i32; 4]) -> bool {
+        match self.read_container(index, group) {
+            Ok(container) => container::validate_keys(&container, keys),
+            Err(_) => false,
+        }
+    }
+
+    /// Computes the whirlpool digest of the reference table container of an index, as it is
+    /// stored in index 255, with the installed whirlpool digest.
+    pub fn reference_table_digest(&mut self, index: u32) -> Result<[u8; 64], FsError> {
+        let container = self.read_container(255, index)?;
+        Ok(self.digests.whirlpool(&container))
+    }
+}
+
+/// Describes the cache for debugging: where it is, its data files and their sizes in blocks, and
+/// every index with the number of entry slots in its idx file.
+impl fmt::Display for FileSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "cache at {} ({})", self.path.display(), if self.writable { "writable" } else { "read-only" })?;
+
+        let data_files = [(self.naming.data_file().to_string(), Some(&self.mainfile)),
+            (self.naming.secondary_data_file(), self.secondary.as_ref())];
+        for (name, data_file) in &data_files {
+            match data_file.and_then(|data_file| data_file.num_blocks()) {
+                Some(blocks) => writeln!(f, "{}: {} blocks of {} bytes", name, blocks, self.mainfile.block_size)?,
+                None => writeln!(f, "{}: missing", name)?,
+            }
+        }
+
+        write!(f, "{} indices", self.indices.len())?;
+        for index in self.indices() {
+            write!(f, "\n  {}: {} entries", self.naming.index_file(index), self.indices[&index].last_entry())?;
+        }
+        Ok(())
+    }
+}
+
+/// Brings the reference tables up to date with `flush` if that was left to the drop. There is
+/// nowhere to return an error to, so failing to is only reported on stderr. Writes that are
+/// still in a copy-on-write layer are discarded.
+impl Drop for FileSystem {
+    fn drop(&mut self) {
+        if !self.writable || self.cow.is_some() || !self.has_pending_metadata() {
+            return;
+        }
+
+        eprintln!("scapefs: {} was dropped with reference tables out of date, flushing them", self.path.display());
+        if let Err(e) = self.flush() {
+            eprintln!("scapefs: flushing {} failed: {}", self.path.display(), e);
+        }
+    }
 }
 
 impl MainFile {
@@ -278,145 +1253,464 @@ impl MainFile {
         self.file.is_some()
     }
 
-    /// Gets the backing file, if existant. Returns a new instance with a fresh seek pointer.
-    pub fn file(&mut self) -> Option<&mut File> {
+    /// Creates a read-only mainfile over a source other than a file, for `FileSystem::from_parts`.
+    pub fn from_reader<R: Source + 'static>(reader: R) -> Result<MainFile, FsError> {
+        Ok(MainFile { file: Some(Backing::from_reader(reader).map_err(FsError::Io)?), block_size: DEFAULT_BLOCK_SIZE,
+            max_block: 0xFFFFFF, legacy_index_ids: false, format: IndexFormat::Standard })
+    }
+
+    /// Gets the backing file or other source, if existant.
+    pub fn file(&mut self) -> Option<&mut Backing> {
         self.file.as_mut()
     }
 
+    /// Gets the size of a block, which is 520 bytes unless the cache is a fork of the format.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Sets the size of a block, for forks of the format with bigger sectors. This has to be set
+    /// before anything is read, and must leave room for the longest block header of the format,
+    /// which is 10 bytes in the standard one and 11 in the extended one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the block size is not larger than the block header.
+    pub fn set_block_size(&mut self, block_size: usize) {
+        assert!(block_size > self.format.block_header_len(true), "a block must be larger than its header");
+        self.block_size = block_size;
+    }
+
+    /// Gets the idx format whose block header layout the file uses.
+    pub fn format(&self) -> IndexFormat {
+        self.format
+    }
+
+    /// Gets the id of the index a block belongs to from the one stored in its header, which
+    /// legacy caches number from 1.
+    pub(crate) fn index_of_block(&self, header: &BlockHeader) -> u8 {
+        if self.legacy_index_ids { header.index_id.wrapping_sub(1) } else { header.index_id }
+    }
+
     /// Calculates the number of data blocks in the mainfile (if existant). This is done by
-    /// taking the file size and dividing that by 520 (rouding up), because each block
-    /// takes up 520 bytes of data.
+    /// taking the file size and dividing that by the block size (rounding up).
     pub fn num_blocks(&self) -> Option<u64> {
-        match self.file {
-            Some(ref x) => Some((x.metadata().unwrap().len() + 519u64) / 520u64),
-            None => None
-        }
+        self.file.as_ref().map(|x| x.len().unwrap_or(0).div_ceil(self.block_size as u64))
     }
 
-    /// Reads a block of data, specified by the block id. The data is read at 520 * block_id
-    /// and is exactly 520 bytes big. It is not guaranteed all 520 bytes are occupied if the
-    /// block is the last one, thus possible to be trimmed.
-    pub fn read_block(&mut self, block: u32) -> Option<[u8; 520]> {
+    /// Reads a block of data, specified by the block id. The data is read at block_size *
+    /// block_id and is exactly one block big. It is not guaranteed the whole block is occupied
+    /// if the block is the last one, thus possible to be trimmed.
+    pub fn read_block(&mut self, block: u32) -> Result<Vec<u8>, FsError> {
         // Do we have a valid file?
-        if self.file.is_none() {
-            return None;
+        let file = self.file.as_mut().ok_or(FsError::NoFileHandle)?;
+        let mut data = vec![0u8; self.block_size];
+
+        // Seek to the right position and read the data. The last block may be trimmed,
+        // so we stop at the end of the file instead of requiring the whole block.
+        file.seek(SeekFrom::Start(block as u64 * self.block_size as u64)).map_err(FsError::Io)?;
+        let mut read = 0;
+        while read < data.len() {
+            match file.read(&mut data[read..]).map_err(FsError::Io)? {
+                0 => break,
+                n => read += n,
+            }
         }
 
-        let mut data: [u8; 520] = [0; 520];
-        let file = self.file().unwrap();
-
-        // Seek to the right position and read the data
-        file.seek(SeekFrom::Start(block as u64 * 520u64)).unwrap();
-        file.read(&mut data).unwrap();
-
-        return Some(data);
+        Ok(data)
     }
 
+    /// Reads a container header like `try_read_header`, for callers that don't care why it
+    /// can't be read.
     pub fn read_header(&mut self, entry: IndexEntry) -> Option<EntryHeader> {
-        // Do we have a valid file?
-        if self.file.is_none() {
-            return None;
-        }
+        self.try_read_header(entry).ok()
+    }
 
+    /// Reads the container header in the first block of an entry. A header the data file ends
+    /// before is `FsError::CorruptedData`.
+    pub fn try_read_header(&mut self, entry: IndexEntry) -> Result<EntryHeader, FsError> {
+        // Do we have a valid file?
+        let file = self.file.as_mut().ok_or(FsError::NoFileHandle)?;
         let mut hdr: [u8; 9] = [0; 9];
-        let file = self.file().unwrap();
 
         // Seek to the right position and read the data, skipping the block header at start
-        let block_header_len = if entry.id() > 0xFFFF { 10 } else { 8 };
-        file.seek(SeekFrom::Start(entry.offset() + block_header_len)).unwrap();
-        file.read(&mut hdr).unwrap();
+        let block_header_len = self.format.block_header_len(entry.id() > 0xFFFF) as u64;
+        file.seek(SeekFrom::Start(entry.offset(self.block_size) + block_header_len)).map_err(FsError::Io)?;
+        file.read_exact(&mut hdr)?;
 
-        return Some(EntryHeader::from_bytes(hdr).unwrap());
+        EntryHeader::from_bytes(hdr)
     }
 
     pub fn read_entry(&mut self, entry: IndexEntry) -> Result<Vec<u8>, FsError> {
+        let size = entry.size();
+        self.read_entry_range(entry, 0, size)
+    }
+
+    /// Reads a range of the raw data of an entry, without loading the blocks before or after it.
+    /// The range is cut off at the end of the entry, so fewer bytes than requested may be returned.
+    pub fn read_entry_range(&mut self, entry: IndexEntry, offset: u32, len: u32) -> Result<Vec<u8>, FsError> {
         // Do we have a valid file?
         if self.file.is_none() {
             return Err(FsError::NoFileHandle);
         }
 
+        let end = offset.saturating_add(len).min(entry.size());
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+
         // Create a vec with what we assume is the size. If not, the vec will
         // perfectly resize itself, so it's only an estimation to help us speed up.
-        let mut data: Vec<u8> = Vec::with_capacity(entry.size() as usize);
+        let mut data: Vec<u8> = Vec::with_capacity((end - offset) as usize);
+
+        let header_size = self.format.block_header_len(entry.id() > 65535) as u32;
+        let available_data = self.block_size as u32 - header_size;
 
         let mut current_block = entry.block();
-        let mut remaining = entry.size();
         let mut current_seq = 0; // We expect a next part to be '1'
+        let mut position = 0; // Offset in the entry of the current block's data
 
-        while remaining > 0 {
-            let block_data = self.read_block(current_block).unwrap();
-            let block_info = BlockHeader::from_block(entry.id() > 65535, block_data);
+        // A corrupt pointer could send us around in circles or off the end of the file
+        let num_blocks = self.num_blocks().unwrap_or(0);
+        let mut visited: HashSet<u32> = HashSet::new();
 
-            let header_size = if block_info.big {10} else {8};
-            let available_data = 520 - header_size;
-            let consumable = if remaining > available_data {available_data} else {remaining};
+        while position < end {
+            let (block_data, block_info) = self.read_chain_block(&entry, current_block, current_seq, num_blocks, &mut visited)?;
 
-            if block_info.big {
-                data.extend(&block_data[10..10+consumable as usize]);
-            } else {
-                data.extend(&block_data[8..8+consumable as usize]);
+            // Only copy the part of this block that overlaps with the range
+            let block_end = position.saturating_add(available_data).min(end);
+            if block_end > offset {
+                let from = offset.max(position) - position;
+                let to = block_end - position;
+                data.extend(&block_data[(header_size + from) as usize..(header_size + to) as usize]);
             }
 
-            remaining -= consumable;
+            position = block_end;
 
-            // Do some checks to validate this block.
-            if remaining > 0 {
-                if block_info.index_id != entry.index() || block_info.next_seq != current_seq {
-                    return Err(FsError::MalformedDataSequence);
-                }
+            // The chain pointer is authoritative, chains are only consecutive in unfragmented
+            // caches. A chain that ends before all data was read is broken.
+            if position < end && block_info.next_block == 0 {
+                return Err(FsError::MalformedDataSequence);
             }
 
-            current_block += 1;
+            current_block = block_info.next_block;
             current_seq += 1;
         }
 
         Ok(data)
     }
 
-    pub fn read_decompressed(&mut self, entry: IndexEntry) -> Result<Vec<u8>, FsError> {
-        let mut data = self.read_entry(entry.clone())?;
-        let header = self.read_header(entry).unwrap();
+    /// Reads a block that is expected to be part `seq` of the chain of an entry and validates
+    /// it. Blocks that were already visited or lie outside the file mean the chain is corrupt.
+    fn read_chain_block(&mut self, entry: &IndexEntry, block: u32, seq: u32, num_blocks: u64,
+                        visited: &mut HashSet<u32>) -> Result<(Vec<u8>, BlockHeader), FsError> {
+        if block == 0 || block as u64 >= num_blocks || !visited.insert(block) {
+            return Err(FsError::CorruptedData);
+        }
 
-        match header.compression {
-            CompressionType::None => {
-                Ok(data[5usize..(header.raw_size+5) as usize].to_vec())
-            }
-            CompressionType::Gzip => {
-                let mut cursor = std::io::Cursor::new(&mut data);
-                cursor.seek(SeekFrom::Current(9)).unwrap();
+        let block_data = self.read_block(block)?;
+        let block_info = BlockHeader::from_block_in(self.format, entry.id() > 65535, &block_data);
+
+        // A block that belongs to another entry means the chain is cross-linked, so the last
+        // block is no exception.
+        if block_info.entry_id != entry.id() || !self.index_id_matches(block_info.index_id, entry.index())
+            || block_info.next_seq != (seq & 0xFFFF) as i32 {
+            return Err(FsError::MalformedDataSequence);
+        }
+
+        Ok((block_data, block_info))
+    }
+
+    /// Checks if the index id stored in a block header is the one of an index. Legacy caches
+    /// number their stores from 1 in block headers, but some tools wrote them from 0, so both are
+    /// accepted there.
+    fn index_id_matches(&self, stored: u8, index: u8) -> bool {
+        stored == index || (self.legacy_index_ids && stored == index.wrapping_add(1))
+    }
 
-                let mut decoder = GzDecoder::new(cursor);
-                let mut out = Vec::<u8>::new();
-                out.resize(header.real_size as usize, 0);
+    /// Writes the data of an entry as a chain of blocks and returns the first block. Like the
+    /// client does, the blocks of the existing chain of the entry are reused as far as they go,
+    /// and any further blocks are appended to the end of the file.
+    pub fn write_entry(&mut self, index: u8, id: u32, data: &[u8], existing: Option<&IndexEntry>) -> Result<u32, FsError> {
+        if self.file.is_none() {
+            return Err(FsError::NoFileHandle);
+        }
 
-                match decoder.read_exact(out.as_mut_slice()) {
-                    Err(_) => return Err(FsError::CorruptedData),
-                    Ok(_) => return Ok(out),
+        let big = id > 0xFFFF;
+        let header_size = self.format.block_header_len(big);
+        let available_data = self.block_size - header_size;
+        let blocks_needed = data.len().div_ceil(available_data);
+
+        // Walk the old chain for as long as it is intact and still needed
+        let mut blocks: Vec<u32> = Vec::with_capacity(blocks_needed);
+        let mut visited: HashSet<u32> = HashSet::new();
+        if let Some(entry) = existing {
+            let mut block = entry.block();
+            while block != 0 && blocks.len() < blocks_needed && visited.insert(block) {
+                let block_info = BlockHeader::from_block_in(self.format, big, &self.read_block(block)?);
+                if block_info.entry_id != id || !self.index_id_matches(block_info.index_id, index) || block_info.next_seq != (blocks.len() & 0xFFFF) as i32 {
+                    break;
                 }
+
+                blocks.push(block);
+                block = block_info.next_block;
             }
-            CompressionType::Lzma => {
-                panic!("Lzma compression unsupported")
-            }
-            CompressionType::Bzip2 => {
-                // Patch the data so that the prefix is present
-                data[5] = b'B';
-                data[6] = b'Z';
-                data[7] = b'h';
-                data[8] = b'1';
-
-                let mut cursor = std::io::Cursor::new(&mut data);
-                cursor.seek(SeekFrom::Current(5)).unwrap();
-
-                let mut decoder = BzDecoder::new(cursor);
-                let mut out = Vec::<u8>::new();
-                out.resize(header.real_size as usize, 0);
-
-                match decoder.read_exact(out.as_mut_slice()) {
-                    Err(_) => return Err(FsError::CorruptedData),
-                    Ok(_) => return Ok(out),
-                }
+        }
+
+        let (max_block, block_number_len) = (self.max_block, self.format.record_len() / 2);
+        let file = self.file.as_mut().unwrap();
+
+        // Block 0 is never used, because a next block of 0 marks the end of a chain
+        let len = file.len().map_err(FsError::Io)?;
+        let mut free_block = (len.div_ceil(self.block_size as u64) as u32).max(1);
+        while blocks.len() < blocks_needed {
+            blocks.push(free_block);
+            free_block += 1;
+        }
+
+        // Block numbers are stored as 24-bit integers, of which a secondary data file takes one bit.
+        // Appended blocks are ascending, so the last one is the highest
+        if blocks.last().is_some_and(|block| *block > max_block) {
+            return Err(FsError::FormatOverflow);
+        }
+
+        let mut block_data: Vec<u8> = Vec::with_capacity(self.block_size);
+        for (seq, chunk) in data.chunks(available_data).enumerate() {
+            let block = blocks[seq];
+            let next_block = if seq + 1 < blocks_needed { blocks[seq + 1] } else { 0 };
+
+            block_data.clear();
+            if big {
+                block_data.extend(&id.to_be_bytes());
+            } else {
+                block_data.extend(&(id as u16).to_be_bytes());
             }
+            block_data.extend(&(seq as u16).to_be_bytes());
+            block_data.extend(&next_block.to_be_bytes()[4 - block_number_len..]);
+            block_data.push(if self.legacy_index_ids { index.wrapping_add(1) } else { index });
+            block_data.extend(chunk);
+
+            file.seek(SeekFrom::Start(block as u64 * self.block_size as u64)).map_err(FsError::Io)?;
+            file.write_all(&block_data).map_err(FsError::Io)?;
+        }
+
+        Ok(blocks.first().copied().unwrap_or(0))
+    }
+
+    pub fn read_decompressed(&mut self, entry: IndexEntry) -> Result<Vec<u8>, FsError> {
+        let data = self.read_entry(entry)?;
+        container::decode(&data)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use crate::options::FileSystemOptions;
+    use crate::test_dir::TestDir;
+    use super::{FileSystem, FsError, IndexFile, IndexFormat, LockMode, MainFile};
+
+    #[test]
+    fn rewrite_produces_readable_fragmented_chain() {
+        let dir = TestDir::new("chain");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+
+        fs.write_container(0, 1, &[1u8; 1000]).unwrap();
+        fs.write_container(0, 2, &[2u8; 100]).unwrap();
+
+        // Reuses the two blocks of group 1, then continues after group 2
+        let grown: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        fs.write_container(0, 1, &grown).unwrap();
+
+        assert_eq!(fs.read_container(0, 1).unwrap(), grown);
+        assert_eq!(fs.read_container_range(0, 1, 500, 600).unwrap(), &grown[500..1100]);
+        assert_eq!(fs.read_container_range(0, 1, 1400, 600).unwrap(), &grown[1400..]);
+        assert_eq!(fs.read_container(0, 2).unwrap(), vec![2u8; 100]);
+        assert_eq!(fs.index(0).unwrap().entry(1).unwrap().block(), 1);
+    }
+
+    #[test]
+    fn larger_blocks_round_trip() {
+        let dir = TestDir::new("blocksize");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.mainfile().set_block_size(2048);
+
+        let data: Vec<u8> = (0..5000).map(|i| (i * 7) as u8).collect();
+        fs.write_container(0, 70000, &data).unwrap();
+        assert_eq!(fs.read_container(0, 70000).unwrap(), data);
+        assert_eq!(fs.mainfile().num_blocks(), Some(4));
+    }
+
+    #[test]
+    fn flagged_entries_are_read_from_secondary_file() {
+        let dir = TestDir::new("dat2m");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_container(40, 0, &[4u8; 1200]).unwrap();
+        drop(fs);
+
+        // Move the data into the secondary file and flag the entry
+        fs::rename(dir.join("main_file_cache.dat2"), dir.join("main_file_cache.dat2m")).unwrap();
+        fs::write(dir.join("main_file_cache.dat2"), []).unwrap();
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        let block = fs.index(40).unwrap().entry(0).unwrap().block();
+        fs.index(40).unwrap().write_entry(0, 1200, block | 0x800000).unwrap();
+
+        assert_eq!(fs.read_container(40, 0).unwrap(), vec![4u8; 1200]);
+        assert_eq!(fs.read_container_range(40, 0, 1000, 500).unwrap(), vec![4u8; 200]);
+
+        // Rewriting the group moves it back to the primary file
+        fs.write_container(40, 0, &[5u8; 100]).unwrap();
+        assert_eq!(fs.index(40).unwrap().entry(0).unwrap().block(), 1);
+        assert_eq!(fs.read_container(40, 0).unwrap(), vec![5u8; 100]);
+    }
+
+    #[test]
+    fn open_skips_odd_index_names() {
+        let dir = TestDir::new("names");
+        for name in &["main_file_cache.idx3", "main_file_cache.idx255.bak", "main_file_cache.idx+1", "notes.txt"] {
+            fs::write(dir.join(name), []).unwrap();
         }
+        fs::write(dir.join("main_file_cache.idx3"), [0, 0, 10, 0, 0, 1]).unwrap();
+
+        // There is no data file, so only the idx files can be looked at
+        assert!(matches!(FileSystem::new(&dir), Err(FsError::MissingDataFile)));
+        let mut fs = FileSystem::open_with(&dir, FileSystemOptions::new().with_metadata_only(true)).unwrap();
+        assert!(matches!(fs.read_container(3, 0), Err(FsError::NoFileHandle)));
+        assert_eq!(fs.indices(), vec![3]);
+        assert!(fs.has_index(3) && !fs.has_index(255));
+        assert_eq!(fs.index_count(), 1);
+        assert_eq!(fs.skipped_files().len(), 2);
+    }
+
+    #[test]
+    fn entry_lookups_tell_failures_apart() {
+        let mut index = IndexFile::from_reader(2, Cursor::new(vec![0, 0, 10, 0, 0, 1, 0, 0, 20])).unwrap();
+        assert_eq!(index.try_entry(0).unwrap().size(), 10);
+        assert!(matches!(index.try_entry(1), Err(FsError::CorruptedData)));
+        assert!(matches!(index.try_entry(2), Err(FsError::EntryNotFound)));
+        assert!(index.entry(1).is_none());
     }
 
+    #[test]
+    fn caches_can_be_read_from_memory() {
+        let dir = TestDir::new("memory");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_container(3, 0, &[1u8; 1500]).unwrap();
+        fs.write_container(3, 1, &[2u8; 20]).unwrap();
+        drop(fs);
+
+        let read = |name: &str| Cursor::new(fs::read(dir.join(name)).unwrap());
+        let mainfile = MainFile::from_reader(read("main_file_cache.dat2")).unwrap();
+        let index = IndexFile::from_reader(3, read("main_file_cache.idx3")).unwrap();
+        let mut memory = FileSystem::from_parts(mainfile, vec![index]);
+
+        assert_eq!(memory.indices(), vec![3]);
+        assert_eq!(memory.read_container(3, 0).unwrap(), vec![1u8; 1500]);
+        assert_eq!(memory.read_container_range(3, 1, 5, 100).unwrap(), vec![2u8; 15]);
+        assert!(matches!(memory.write_container(3, 2, &[0u8; 4]), Err(FsError::ReadOnly)));
+    }
+
+    #[test]
+    fn summary_lists_files_and_indices() {
+        let dir = TestDir::new("summary");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_container(7, 2, &[1u8; 600]).unwrap();
+        fs.write_container(0, 0, &[2u8; 10]).unwrap();
+
+        let summary = fs.to_string();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert!(lines[0].ends_with("(writable)"));
+        assert_eq!(lines[1..], ["main_file_cache.dat2: 4 blocks of 520 bytes", "main_file_cache.dat2m: missing", "2 indices",
+            "  main_file_cache.idx0: 1 entries", "  main_file_cache.idx7: 3 entries"]);
+    }
+
+    #[test]
+    fn open_falls_back_to_legacy_data_file() {
+        let base = TestDir::new("legacy");
+        fs::create_dir_all(base.join("new")).unwrap();
+        let mut new = FileSystem::new_writable(base.join("new")).unwrap();
+        new.write_container(0, 4, &[0, 0, 0, 0, 1, 9]).unwrap();
+        drop(new);
+
+        fs::create_dir_all(base.join("old")).unwrap();
+        fs::copy(base.join("new/main_file_cache.dat2"), base.join("old/main_file_cache.dat")).unwrap();
+        fs::copy(base.join("new/main_file_cache.idx0"), base.join("old/main_file_cache.idx0")).unwrap();
+
+        let mut old = FileSystem::new(base.join("old")).unwrap();
+        assert_eq!(old.naming().data_file(), "main_file_cache.dat");
+        assert_eq!(old.read_container(0, 4).unwrap(), vec![0, 0, 0, 0, 1, 9]);
+    }
+
+    #[test]
+    fn second_writer_is_locked_out() {
+        let dir = TestDir::new("lock");
+
+        let mut writer = FileSystem::new_writable(&dir).unwrap();
+        assert!(matches!(FileSystem::new_writable(&dir), Err(FsError::Locked)));
+        assert!(FileSystem::new_writable_locked(&dir, LockMode::None).is_ok());
+        assert!(FileSystem::new(&dir).is_ok());
+
+        // Reloading keeps the lock, and a reload that fails keeps the old handles
+        writer.write_container(1, 0, &[0, 0, 0, 0, 1, 7]).unwrap();
+        writer.reload().unwrap();
+        assert!(matches!(FileSystem::new_writable(&dir), Err(FsError::Locked)));
+        fs::rename(dir.join("main_file_cache.dat2"), dir.join("moved")).unwrap();
+        fs::create_dir(dir.join("main_file_cache.dat2")).unwrap();
+        assert!(matches!(writer.reload(), Err(FsError::Io(_))));
+        assert_eq!(writer.read_container(1, 0).unwrap(), vec![0, 0, 0, 0, 1, 7]);
+        fs::remove_dir(dir.join("main_file_cache.dat2")).unwrap();
+        fs::rename(dir.join("moved"), dir.join("main_file_cache.dat2")).unwrap();
+
+        drop(writer);
+        assert!(FileSystem::new_writable(&dir).is_ok());
+    }
+
+    #[test]
+    fn memoized_crcs_notice_other_writers() {
+        let dir = TestDir::new("stamps");
+        let mut writer = FileSystem::new_writable(&dir).unwrap();
+        writer.write_container(1, 0, &[0, 0, 0, 0, 1, 7]).unwrap();
+
+        let mut reader = FileSystem::new(&dir).unwrap();
+        let old = reader.crc(1, 0).unwrap();
+        assert!(!reader.modified_externally());
+
+        writer.write_container(1, 0, &[0, 0, 0, 0, 2, 8, 9]).unwrap();
+        writer.write_container(1, 1, &[0u8; 800]).unwrap();
+        assert!(reader.modified_externally());
+        assert_ne!(reader.crc(1, 0).unwrap(), old);
+        assert_eq!(reader.crc(1, 0).unwrap(), writer.crc(1, 0).unwrap());
+    }
+
+    #[test]
+    fn extended_format_goes_past_the_standard_limits() {
+        let dir = TestDir::new("extended");
+        let full = (0x1000000u64 * 520, dir.join("standard"), dir.join("extended"));
+        fs::create_dir_all(&full.1).unwrap();
+        fs::create_dir_all(&full.2).unwrap();
+
+        // The data file is sparse, so this doesn't take gigabytes of disk
+        let mut standard = FileSystem::new_writable(&full.1).unwrap();
+        standard.write_container(2, 0, &[1u8; 100]).unwrap();
+        assert!(matches!(standard.write_container(2, 1, &vec![0u8; 0x1000000]), Err(FsError::FormatOverflow)));
+        standard.mainfile().file().unwrap().as_file().unwrap().set_len(full.0).unwrap();
+        assert!(matches!(standard.write_container(2, 1, &[2u8; 100]), Err(FsError::FormatOverflow)));
+        standard.write_container(2, 0, &[3u8; 100]).unwrap();
+
+        let mut extended = FileSystem::new_writable(&full.2).unwrap();
+        extended.set_index_format(IndexFormat::Extended);
+        extended.write_container(2, 0, &[1u8; 100]).unwrap();
+        extended.mainfile().file().unwrap().as_file().unwrap().set_len(full.0).unwrap();
+        extended.write_container(2, 1, &[2u8; 1200]).unwrap();
+        drop(extended);
+
+        let mut extended = FileSystem::new(&full.2).unwrap();
+        extended.set_index_format(IndexFormat::Extended);
+        assert_eq!(extended.index(2).unwrap().last_entry(), 2);
+        assert_eq!(extended.index(2).unwrap().entry(1).unwrap().block(), 0x1000000);
+        assert_eq!(extended.read_container(2, 0).unwrap(), vec![1u8; 100]);
+        assert_eq!(extended.read_container(2, 1).unwrap(), vec![2u8; 1200]);
+    }
 }
@@ -0,0 +1,220 @@
+use std::collections::{BTreeSet, HashSet};
+use std::ops::RangeInclusive;
+use crate::filesystem::FileSystem;
+use crate::names::NameDictionary;
+use crate::reference_table::ReferenceTable;
+
+/// Which groups a bulk operation works on. A new filter matches every group, and each kind of
+/// criterion narrows it down: by index, by ranges of group ids and by name. A group has to pass
+/// every kind that was given, and any one criterion of each kind.
+///
+/// Reference tables only hold the hashes of names, so names are matched against a dictionary of
+/// names that are known to be in the cache. A group matches a name pattern if one of the names
+/// in the dictionary matches the pattern and hashes to the name hash of the group in the
+/// reference table of its index. A pattern without `*` or `?` is an exact name, which is hashed
+/// itself, so it needs no dictionary. Groups without a table entry never match a name pattern.
+#[derive(Clone, Debug, Default)]
+pub struct GroupFilter {
+    indices: Option<BTreeSet<u32>>,
+    groups: Vec<RangeInclusive<u32>>,
+    patterns: Vec<String>,
+    dictionary: Vec<String>,
+    hashes: HashSet<i32>,
+}
+
+impl GroupFilter {
+    /// Creates a filter matching every group.
+    pub fn new() -> GroupFilter {
+        GroupFilter::default()
+    }
+
+    /// Adds indices whose groups match.
+    pub fn with_indices(mut self, indices: &[u32]) -> GroupFilter {
+        self.indices.get_or_insert_with(BTreeSet::new).extend(indices);
+        self
+    }
+
+    /// Adds a range of group ids that match.
+    pub fn with_groups(mut self, groups: RangeInclusive<u32>) -> GroupFilter {
+        self.groups.push(groups);
+        self
+    }
+
+    /// Adds a pattern for names that match, in which `*` stands for any run of characters and
+    /// `?` for any single one. Patterns and names are compared ignoring case.
+    pub fn with_name(mut self, pattern: &str) -> GroupFilter {
+        self.patterns.push(pattern.to_lowercase());
+        self.rehash();
+        self
+    }
+
+    /// Adds names to the dictionary that name patterns are resolved with.
+    pub fn with_dictionary<I, S>(mut self, names: I) -> GroupFilter where I: IntoIterator<Item = S>, S: Into<String> {
+        self.dictionary.extend(names.into_iter().map(|name| name.into().to_lowercase()));
+        self.rehash();
+        self
+    }
+
+    /// Adds the names of a `NameDictionary` to the dictionary that name patterns are resolved
+    /// with, such as a list of known names loaded with `NameDictionary::load`.
+    pub fn with_name_dictionary(self, dictionary: &NameDictionary) -> GroupFilter {
+        self.with_dictionary(dictionary.names())
+    }
+
+    /// Checks if groups of an index can match at all.
+    pub fn covers_index(&self, index: u32) -> bool {
+        self.indices.as_ref().is_none_or(|indices| indices.contains(&index))
+    }
+
+    /// Checks if a group matches, given the reference table of its index if it has one.
+    pub fn matches(&self, index: u32, group: u32, table: Option<&ReferenceTable>) -> bool {
+        if !self.covers_index(index) {
+            return false;
+        }
+        if !self.groups.is_empty() && !self.groups.iter().any(|range| range.contains(&group)) {
+            return false;
+        }
+        if self.patterns.is_empty() {
+            return true;
+        }
+
+        table.and_then(|table| table.lookup(group as i32))
+            .is_some_and(|folder| self.hashes.contains(&folder.name_hash()))
+    }
+
+    /// Checks if the filter looks at names, so the reference table is needed to apply it.
+    pub(crate) fn needs_names(&self) -> bool {
+        !self.patterns.is_empty()
+    }
+
+    /// Gets the indices of a cache the filter covers, sorted. Without any indices given, these
+    /// are all of the indices of the cache except the reference tables.
+    pub(crate) fn indices_of(&self, fs: &FileSystem) -> Vec<u32> {
+        match &self.indices {
+            Some(indices) => indices.iter().copied().collect(),
+            None => fs.indices().into_iter().filter(|index| *index != 255).collect(),
+        }
+    }
+
+    fn rehash(&mut self) {
+        let patterns = &self.patterns;
+        let exact = patterns.iter().filter(|pattern| !pattern.contains(['*', '?']));
+        self.hashes = self.dictionary.iter()
+            .filter(|name| patterns.iter().any(|pattern| glob(pattern.as_bytes(), name.as_bytes())))
+            .chain(exact)
+            .map(|name| ReferenceTable::hash_name(name))
+            .collect();
+    }
+}
+
+/// Matches a name against a pattern of `*` and `?`, backtracking to the last `*` on a mismatch.
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bulk::OnError;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::names::NameDictionary;
+    use crate::reference_table::ReferenceTable;
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+    use super::{glob, GroupFilter};
+
+    #[test]
+    fn filters_pick_groups_by_index_range_and_name() {
+        assert!(glob(b"m50_*", b"m50_51"));
+        assert!(glob(b"*_5?", b"m50_51"));
+        assert!(!glob(b"m50_*", b"l50_51"));
+        assert!(glob(b"a*b*c", b"aXbYbc"));
+
+        let dir = TestDir::new("filter");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        let groups: Vec<ContainerUpdate> = (0..6)
+            .map(|g| ContainerUpdate::new(5, g, container::encode(&[g as u8; 50], CompressionType::Gzip, None).unwrap()))
+            .collect();
+        fs.apply_update(&groups).unwrap();
+        fs.write_group(7, 0, b"other index", CompressionType::None, None).unwrap();
+
+        let names = ["m50_50", "m50_51", "l50_50", "m51_50", "m50_52", "unused"];
+        let mut table = fs.reference_table(5).unwrap();
+        table.set_flags(table.flags().with_names(true)).unwrap();
+        for (group, name) in names.iter().enumerate().take(5) {
+            table.lookup_mut(group as i32).unwrap().set_name_hash(ReferenceTable::hash_name(name));
+        }
+        fs.write_reference_table(5, &table).unwrap();
+        assert_eq!(ReferenceTable::hash_name("M50_50"), ReferenceTable::hash_name("m50_50"));
+
+        let filter = GroupFilter::new().with_indices(&[5]).with_name("m50_*").with_dictionary(names);
+        let mut extracted = Vec::new();
+        fs.extract_matching(&filter, OnError::Abort, |index, group, _| {
+            extracted.push((index, group));
+            Ok(())
+        }).unwrap();
+        assert_eq!(extracted, vec![(5, 0), (5, 1), (5, 4)]);
+
+        // Exact names need no dictionary, patterns can be resolved with a loaded one
+        let mut dictionary = NameDictionary::new();
+        dictionary.extend_from_list("l50_50\nm51_50\n");
+        let filter_by = |filter: GroupFilter, fs: &mut FileSystem| {
+            let mut groups = Vec::new();
+            fs.extract_matching(&filter, OnError::Abort, |_, group, _| {
+                groups.push(group);
+                Ok(())
+            }).unwrap();
+            groups
+        };
+        assert_eq!(filter_by(GroupFilter::new().with_name("M50_52").with_name("nope"), &mut fs), vec![4]);
+        assert_eq!(filter_by(GroupFilter::new().with_name("?5?_50").with_name_dictionary(&dictionary), &mut fs), vec![2, 3]);
+
+        let filter = filter.with_groups(1..=3);
+        let report = fs.verify_matching(&filter, 1).unwrap();
+        assert_eq!((report.indices.clone(), report.groups), (vec![5], 1));
+
+        let mut groups = Vec::new();
+        fs.extract_matching(&GroupFilter::new().with_groups(0..=0), OnError::Abort, |index, group, _| {
+            groups.push((index, group));
+            Ok(())
+        }).unwrap();
+        assert_eq!(groups, vec![(5, 0), (7, 0)]);
+
+        // Indices without an idx file are skipped under OnError::Skip
+        let filter = GroupFilter::new().with_indices(&[7, 9]);
+        assert!(fs.extract_matching(&filter, OnError::Abort, |_, _, _| Ok(())).is_err());
+        let failures = fs.extract_matching(&filter, OnError::Skip, |_, _, _| Ok(())).unwrap();
+        assert_eq!(failures.iter().map(|failure| (failure.index, failure.group)).collect::<Vec<_>>(), vec![(255, 9)]);
+        assert_eq!(fs.extract_dir_resumable(dir.join("out"), &filter, OnError::Abort).unwrap().missing, vec![9]);
+
+        let report = fs.recompress_matching(&GroupFilter::new().with_indices(&[5]).with_groups(2..=3), CompressionType::Bzip2.into(), OnError::Abort).unwrap();
+        assert_eq!(report.containers, 3);
+        assert_eq!(fs.read_container(5, 2).unwrap()[0], CompressionType::Bzip2 as u8);
+        assert_eq!(fs.read_container(5, 1).unwrap()[0], CompressionType::Gzip as u8);
+    }
+}
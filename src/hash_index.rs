@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use crate::container;
+use crate::filesystem::{FileSystem, FsError};
+use crate::whirlpool;
+
+/// Looks up groups by the hashes of their contents, for finding where a loose file came from.
+/// Built by `FileSystem::hash_index`.
+#[derive(Clone, Debug, Default)]
+pub struct HashIndex {
+    by_crc: HashMap<u32, Vec<(u32, u32)>>,
+    by_data_crc: HashMap<u32, Vec<(u32, u32)>>,
+    by_whirlpool: HashMap<[u8; whirlpool::DIGEST_LENGTH], Vec<(u32, u32)>>,
+}
+
+impl HashIndex {
+    /// Finds the groups whose container has a CRC32, computed the way reference tables list it.
+    pub fn find_by_crc(&self, crc: u32) -> &[(u32, u32)] {
+        self.by_crc.get(&crc).map_or(&[], |groups| groups.as_slice())
+    }
+
+    /// Finds the groups whose decompressed data has a CRC32. Encrypted groups can't be
+    /// decompressed, so they are never found this way.
+    pub fn find_by_data_crc(&self, crc: u32) -> &[(u32, u32)] {
+        self.by_data_crc.get(&crc).map_or(&[], |groups| groups.as_slice())
+    }
+
+    /// Finds the groups whose container, without its version trailer, has a whirlpool digest.
+    pub fn find_by_whirlpool(&self, digest: &[u8]) -> &[(u32, u32)] {
+        <[u8; whirlpool::DIGEST_LENGTH]>::try_from(digest).ok()
+            .and_then(|digest| self.by_whirlpool.get(&digest))
+            .map_or(&[], |groups| groups.as_slice())
+    }
+
+    /// Gets the number of different containers in the index.
+    pub fn len(&self) -> usize {
+        self.by_whirlpool.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_whirlpool.is_empty()
+    }
+}
+
+impl FileSystem {
+    /// Hashes every group of the cache into a `HashIndex`, reference tables included. Every
+    /// container is read and decompressed once, so this takes as long as extracting the cache.
+    pub fn hash_index(&mut self) -> Result<HashIndex, FsError> {
+        let _bulk = self.bulk_read();
+        let mut hashes = HashIndex::default();
+
+        for index in self.indices() {
+            let count = self.index(index).unwrap().last_entry() as u32;
+            for group in 0..count {
+                let data = match self.read_container(index, group) {
+                    Ok(data) => data,
+                    Err(FsError::EntryNotFound) => continue,
+                    Err(e) => return Err(e),
+                };
+
+                let key = (index, group);
+                let length = container::length(&data)?;
+                hashes.by_crc.entry(crc32fast::hash(&data[..length])).or_default().push(key);
+                hashes.by_whirlpool.entry(whirlpool::digest(&data[..length])).or_default().push(key);
+                if let Ok(decoded) = container::decode(&data) {
+                    hashes.by_data_crc.entry(crc32fast::hash(&decoded)).or_default().push(key);
+                }
+            }
+        }
+
+        Ok(hashes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+    use crate::whirlpool;
+
+    #[test]
+    fn find_groups_by_their_hashes() {
+        let dir = TestDir::new("hashes");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_group(5, 3, b"a loose model", CompressionType::Gzip, Some(2)).unwrap();
+        fs.write_group(5, 4, b"something else", CompressionType::Bzip2, None).unwrap();
+
+        let hashes = fs.hash_index().unwrap();
+        let stored = fs.read_container(5, 3).unwrap();
+        assert_eq!(hashes.find_by_crc(container::crc(&stored).unwrap()), &[(5, 3)]);
+        assert_eq!(hashes.find_by_data_crc(crc32fast::hash(b"something else")), &[(5, 4)]);
+        assert_eq!(hashes.find_by_whirlpool(&whirlpool::digest(&stored[..stored.len() - 2])), &[(5, 3)]);
+        assert!(hashes.find_by_data_crc(crc32fast::hash(b"not in the cache")).is_empty());
+    }
+}
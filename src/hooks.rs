@@ -0,0 +1,118 @@
+use std::fmt;
+use std::sync::Arc;
+use crate::filesystem::{FileSystem, FsError};
+
+/// Callbacks a filesystem makes as it reads and writes containers, for auditing, metrics or
+/// request logs. Every method does nothing by default, so an implementation only needs the ones
+/// it cares about. They are called on whichever thread does the reading or writing, after the
+/// fact, and can't change the outcome.
+pub trait Hooks: Send + Sync {
+    /// Called with the raw container of a group after it was read.
+    fn on_read(&self, _index: u32, _group: u32, _container: &[u8]) {}
+
+    /// Called with the raw container of a group after it was written, to the files or to the
+    /// copy-on-write layer.
+    fn on_write(&self, _index: u32, _group: u32, _container: &[u8]) {}
+
+    /// Called when reading or writing a group failed. Looking up a group or index that doesn't
+    /// exist is not an error here, as bulk operations do that for every unused slot.
+    fn on_error(&self, _index: u32, _group: u32, _error: &FsError) {}
+}
+
+/// The hooks installed on a filesystem, if any.
+#[derive(Clone, Default)]
+pub(crate) struct HookSet(Option<Arc<dyn Hooks>>);
+
+impl HookSet {
+    pub(crate) fn read(&self, index: u32, group: u32, result: &Result<Vec<u8>, FsError>) {
+        if let Some(hooks) = &self.0 {
+            match result {
+                Ok(container) => hooks.on_read(index, group, container),
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => {}
+                Err(e) => hooks.on_error(index, group, e),
+            }
+        }
+    }
+
+    pub(crate) fn written(&self, index: u32, group: u32, container: &[u8], result: &Result<(), FsError>) {
+        if let Some(hooks) = &self.0 {
+            match result {
+                Ok(()) => hooks.on_write(index, group, container),
+                Err(e) => hooks.on_error(index, group, e),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for HookSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "HookSet(installed)" } else { "HookSet(none)" })
+    }
+}
+
+impl From<Option<Arc<dyn Hooks>>> for HookSet {
+    fn from(hooks: Option<Arc<dyn Hooks>>) -> HookSet {
+        HookSet(hooks)
+    }
+}
+
+impl FileSystem {
+    /// Installs hooks that are called on every read and write of a container, replacing any
+    /// installed before, or removes them with `None`. Reads of part of a container, such as
+    /// those `read_container_range` makes, don't call them.
+    pub fn set_hooks(&mut self, hooks: Option<Arc<dyn Hooks>>) {
+        self.replace_hooks(hooks.into());
+    }
+
+    /// Gets the installed hooks, if any.
+    pub fn hooks(&self) -> Option<Arc<dyn Hooks>> {
+        self.installed_hooks().0.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::test_dir::TestDir;
+    use super::Hooks;
+
+    #[derive(Default)]
+    struct Log(Mutex<Vec<String>>);
+
+    impl Hooks for Log {
+        fn on_read(&self, index: u32, group: u32, container: &[u8]) {
+            self.0.lock().unwrap().push(format!("read {} {} {}", index, group, container.len()));
+        }
+
+        fn on_write(&self, index: u32, group: u32, container: &[u8]) {
+            self.0.lock().unwrap().push(format!("write {} {} {}", index, group, container.len()));
+        }
+
+        fn on_error(&self, index: u32, group: u32, error: &FsError) {
+            self.0.lock().unwrap().push(format!("error {} {} {}", index, group, error));
+        }
+    }
+
+    #[test]
+    fn hooks_see_every_container() {
+        let dir = TestDir::new("hooks");
+
+        let log = Arc::new(Log::default());
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.set_hooks(Some(log.clone()));
+        fs.write_group(2, 0, b"hooked", CompressionType::None, None).unwrap();
+        fs.read_container(2, 0).unwrap();
+        assert!(fs.read_container(2, 1).is_err());
+        assert!(fs.write_container(300, 0, b"").is_err());
+
+        // Readers of a pool share the hooks of the filesystem they came from
+        let pool = fs.reader_pool(1).unwrap();
+        pool.read_container(2, 0).unwrap();
+
+        fs.set_hooks(None);
+        fs.read_container(2, 0).unwrap();
+        let expected = ["write 2 0 11", "read 2 0 11", &format!("error 300 0 {}", FsError::IndexNotFound), "read 2 0 11"];
+        assert_eq!(*log.0.lock().unwrap(), expected);
+    }
+}
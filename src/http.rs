@@ -0,0 +1,137 @@
+use std::io::Write;
+use crate::container;
+use crate::filesystem::{CompressionType, FileSystem, FsError};
+
+/// A response to an HTTP request for cache data, independent of any web framework. Hand the
+/// parts to the framework of choice, or write it to a plain connection with `write_to`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    fn new(status: u16, body: Vec<u8>) -> HttpResponse {
+        HttpResponse { status, headers: Vec::new(), body }
+    }
+
+    fn header(mut self, name: &str, value: String) -> HttpResponse {
+        self.headers.push((name.to_string(), value));
+        self
+    }
+
+    /// Gets the reason phrase of the status code.
+    pub fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            404 => "Not Found",
+            _ => "Internal Server Error",
+        }
+    }
+
+    /// Writes the response as HTTP/1.1.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        write!(w, "HTTP/1.1 {} {}\r\n", self.status, self.reason())?;
+        for (name, value) in &self.headers {
+            write!(w, "{}: {}\r\n", name, value)?;
+        }
+        write!(w, "Content-Length: {}\r\n\r\n", self.body.len())?;
+        w.write_all(&self.body)
+    }
+}
+
+impl FileSystem {
+    /// Answers an HTTP request for cache data. `/<index>/<group>` serves the raw container of a
+    /// group as stored, and `/checksum` (or `/255/255`) the master checksum table.
+    ///
+    /// Containers carry an ETag made of their CRC and version, so clients can revalidate with
+    /// `If-None-Match` and get a 304 when nothing changed. A request that names the CRC it
+    /// expects with `?crc=` can only ever get that content, so a match is served as immutable.
+    pub fn http_response(&mut self, path: &str, if_none_match: Option<&str>) -> HttpResponse {
+        let (path, query) = match path.find('?') {
+            Some(at) => (&path[..at], Some(&path[at + 1..])),
+            None => (path, None),
+        };
+
+        let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let (index, group) = match parts.as_slice() {
+            ["checksum"] => (255, 255),
+            [index, group] => match (index.parse::<u32>(), group.parse::<u32>()) {
+                (Ok(index), Ok(group)) => (index, group),
+                _ => return HttpResponse::new(400, Vec::new()),
+            },
+            _ => return HttpResponse::new(400, Vec::new()),
+        };
+
+        let container = if index == 255 && group == 255 {
+            self.checksum_table().and_then(|table| container::encode(&table.encode(), CompressionType::None, None))
+        } else {
+            self.read_container(index, group)
+        };
+
+        let container = match container {
+            Ok(container) => container,
+            Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => return HttpResponse::new(404, Vec::new()),
+            Err(_) => return HttpResponse::new(500, Vec::new()),
+        };
+
+        let crc = match container::crc(&container) {
+            Ok(crc) => crc,
+            Err(_) => return HttpResponse::new(500, Vec::new()),
+        };
+        let version = container::version(&container).ok().flatten().unwrap_or(0);
+        let etag = format!("\"{:08x}-{}\"", crc, version);
+
+        let expected_crc = query.and_then(|q| q.split('&').find_map(|pair| pair.strip_prefix("crc=")))
+            .and_then(|crc| crc.parse::<i64>().ok());
+        let cache_control = match expected_crc {
+            Some(expected) if expected as u32 == crc => "public, max-age=31536000, immutable",
+            _ => "no-cache",
+        };
+
+        let response = if if_none_match == Some(etag.as_str()) {
+            HttpResponse::new(304, Vec::new())
+        } else {
+            HttpResponse::new(200, container).header("Content-Type", "application/octet-stream".to_string())
+        };
+
+        response.header("ETag", etag).header("Cache-Control", cache_control.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn serve_groups_with_caching_headers() {
+        let dir = TestDir::new("http");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_group(2, 8, &[1u8; 300], CompressionType::Gzip, Some(5)).unwrap();
+        let stored = fs.read_container(2, 8).unwrap();
+        let crc = container::crc(&stored).unwrap();
+
+        let response = fs.http_response("/2/8", None);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, stored);
+        let etag = response.headers.iter().find(|(name, _)| name == "ETag").unwrap().1.clone();
+        assert_eq!(etag, format!("\"{:08x}-5\"", crc));
+
+        assert_eq!(fs.http_response("/2/8", Some(&etag)).status, 304);
+        let immutable = fs.http_response(&format!("/2/8?crc={}", crc as i32), None);
+        assert!(immutable.headers.contains(&("Cache-Control".to_string(), "public, max-age=31536000, immutable".to_string())));
+
+        assert_eq!(fs.http_response("/2/9", None).status, 404);
+        assert_eq!(fs.http_response("/two/8", None).status, 400);
+        assert_eq!(fs.http_response("/checksum", None).status, 200);
+
+        let mut raw = Vec::new();
+        fs.http_response("/2/9", None).write_to(&mut raw).unwrap();
+        assert_eq!(raw, b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+    }
+}
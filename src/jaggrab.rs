@@ -0,0 +1,114 @@
+use std::io::{BufRead, Write};
+use crate::filesystem::{FileSystem, FsError};
+
+/// The archives of a legacy cache, as named in JAGGRAB requests. Their position is their group
+/// id in index 0, which is why the list starts at 1.
+pub const ARCHIVES: [&str; 9] = ["", "title", "config", "interface", "media", "versionlist", "textures", "wordenc", "sounds"];
+
+/// What a JAGGRAB client asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JaggrabRequest {
+    /// The CRCs of all archives, which the client checks its own copies against.
+    Crc,
+    /// An archive, by its group id in index 0.
+    Archive(u32),
+}
+
+impl JaggrabRequest {
+    /// Parses a request line like "JAGGRAB /title1612848556". Clients append a CRC or random
+    /// number to the name to get past caching proxies, which is ignored.
+    pub fn parse(line: &str) -> Option<JaggrabRequest> {
+        let path = line.trim_end().strip_prefix("JAGGRAB /")?;
+        let name = path.trim_end_matches(|c: char| c.is_ascii_digit() || c == '-');
+
+        if name == "crc" {
+            return Some(JaggrabRequest::Crc);
+        }
+
+        ARCHIVES.iter().skip(1).position(|archive| *archive == name).map(|i| JaggrabRequest::Archive(i as u32 + 1))
+    }
+}
+
+impl FileSystem {
+    /// Builds the CRC table JAGGRAB clients request: the CRC of every archive, where missing
+    /// archives and the unused slot 0 count as 0, followed by the checksum the client validates
+    /// the table with.
+    pub fn jaggrab_crc_table(&mut self) -> Result<Vec<u8>, FsError> {
+        let mut data = Vec::with_capacity(ARCHIVES.len() * 4 + 4);
+        let mut checksum: i32 = 1234;
+
+        for archive in 0..ARCHIVES.len() as u32 {
+            let crc = match self.read_container(0, archive) {
+                Ok(data) if archive > 0 => crc32fast::hash(&data) as i32,
+                Ok(_) | Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => 0,
+                Err(e) => return Err(e),
+            };
+
+            data.extend(&crc.to_be_bytes());
+            checksum = (checksum << 1).wrapping_add(crc);
+        }
+
+        data.extend(&checksum.to_be_bytes());
+        Ok(data)
+    }
+
+    /// Answers a JAGGRAB request with the bytes to send back. Archives are served the way they
+    /// are stored in index 0 of a legacy cache.
+    pub fn jaggrab_response(&mut self, request: &JaggrabRequest) -> Result<Vec<u8>, FsError> {
+        match request {
+            JaggrabRequest::Crc => self.jaggrab_crc_table(),
+            JaggrabRequest::Archive(archive) => self.read_container(0, *archive),
+        }
+    }
+
+    /// Serves one JAGGRAB request from a connection: reads the request line and the blank line
+    /// after it, then writes the response. The client closes the connection once it has read
+    /// everything, so the stream can be dropped afterwards.
+    pub fn serve_jaggrab<S: BufRead + Write>(&mut self, stream: &mut S) -> Result<(), FsError> {
+        let mut line = String::new();
+        stream.read_line(&mut line).map_err(|_| FsError::RemoteFailed)?;
+        let request = JaggrabRequest::parse(&line).ok_or(FsError::EntryNotFound)?;
+
+        // The request is terminated by an empty line
+        let mut blank = String::new();
+        stream.read_line(&mut blank).map_err(|_| FsError::RemoteFailed)?;
+
+        let response = self.jaggrab_response(&request)?;
+        stream.write_all(&response).map_err(FsError::Io)?;
+        stream.flush().map_err(FsError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filesystem::FileSystem;
+    use crate::naming::FileNaming;
+    use crate::test_dir::TestDir;
+    use super::JaggrabRequest;
+
+    #[test]
+    fn serve_archives_and_crc_table() {
+        assert_eq!(JaggrabRequest::parse("JAGGRAB /crc-1836914596\n"), Some(JaggrabRequest::Crc));
+        assert_eq!(JaggrabRequest::parse("JAGGRAB /media1612848556\n"), Some(JaggrabRequest::Archive(4)));
+        assert_eq!(JaggrabRequest::parse("GET /title HTTP/1.1"), None);
+
+        let dir = TestDir::new("jaggrab");
+        let mut fs = FileSystem::new_named(&dir, true, FileNaming::legacy()).unwrap();
+        fs.write_container(0, 1, b"title archive").unwrap();
+
+        let table = fs.jaggrab_crc_table().unwrap();
+        assert_eq!(table.len(), 40);
+        let crcs: Vec<i32> = table.chunks(4).map(|c| i32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect();
+        assert_eq!(crcs[1], crc32fast::hash(b"title archive") as i32);
+        let checksum = crcs[..9].iter().fold(1234i32, |c, crc| (c << 1).wrapping_add(*crc));
+        assert_eq!(crcs[9], checksum);
+
+        let mut stream = std::io::Cursor::new(b"JAGGRAB /title0\n\n".to_vec());
+        fs.serve_jaggrab(&mut stream).unwrap();
+        assert_eq!(&stream.get_ref()[17..], b"title archive");
+
+        // Legacy caches number their stores from 1 in block headers
+        let block = fs.mainfile().read_block(1).unwrap();
+        assert_eq!(block[7], 1);
+    }
+}
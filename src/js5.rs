@@ -0,0 +1,905 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Cursor, Read, Write};
+use crate::checksum_table::ChecksumTable;
+use crate::container;
+use crate::digest::Digests;
+use crate::filesystem::{CompressionType, FileSystem, FsError};
+use crate::reference_table::ReferenceTable;
+use crate::update::ContainerUpdate;
+
+/// The size of a block in the js5 response stream. Every block after the first one starts with
+/// a 0xFF separator byte.
+const BLOCK_SIZE: usize = 512;
+
+/// A response on the js5 stream, carrying the container of one group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Js5Response {
+    pub index: u32,
+    pub group: u32,
+    /// Whether this answers an urgent request rather than a prefetch one.
+    pub urgent: bool,
+    /// The raw container, without version trailer.
+    pub container: Vec<u8>,
+}
+
+/// Encodes a response the way a js5 server sends it: the index, group and container, split
+/// into blocks with a separator in front of every block after the first. Responses to prefetch
+/// requests have the top bit of the compression type set. A version trailer on the container
+/// is not sent. Groups js5 can't address, past index 255 or group 65535, are a
+/// `FormatOverflow`, as their ids would be cut off into those of another group.
+pub fn encode_response(index: u32, group: u32, urgent: bool, container: &[u8]) -> Result<Vec<u8>, FsError> {
+    if index > 0xFF || group > 0xFFFF {
+        return Err(FsError::FormatOverflow);
+    }
+
+    let length = container::length(container)?;
+    let mut data = Vec::with_capacity(3 + length);
+    data.push(index as u8);
+    data.extend(&(group as u16).to_be_bytes());
+    data.push(if urgent { container[0] } else { container[0] | 0x80 });
+    data.extend(&container[1..length]);
+
+    let mut out = Vec::with_capacity(data.len() + data.len() / (BLOCK_SIZE - 1) + 1);
+    out.extend(&data[..data.len().min(BLOCK_SIZE)]);
+    for chunk in data.get(BLOCK_SIZE..).unwrap_or(&[]).chunks(BLOCK_SIZE - 1) {
+        out.push(0xFF);
+        out.extend(chunk);
+    }
+
+    Ok(out)
+}
+
+/// XORs bytes of the js5 stream with the key negotiated for it. XORing twice restores the
+/// original bytes, so this both encrypts and decrypts.
+pub fn xor(data: &mut [u8], key: u8) {
+    if key != 0 {
+        data.iter_mut().for_each(|b| *b ^= key);
+    }
+}
+
+/// Encodes responses for a js5 server, XORing them with the key the client asked for.
+#[derive(Clone, Debug, Default)]
+pub struct Js5Encoder {
+    xor_key: u8,
+}
+
+impl Js5Encoder {
+    pub fn new() -> Js5Encoder {
+        Js5Encoder::default()
+    }
+
+    /// Sets the key that responses encoded from now on are XORed with, as requested by the
+    /// client with opcode 4. A key of 0 turns encryption off.
+    pub fn set_xor_key(&mut self, key: u8) {
+        self.xor_key = key;
+    }
+
+    /// Encodes a response like `encode_response` does, then XORs it.
+    pub fn encode(&self, index: u32, group: u32, urgent: bool, container: &[u8]) -> Result<Vec<u8>, FsError> {
+        let mut data = encode_response(index, group, urgent, container)?;
+        xor(&mut data, self.xor_key);
+        Ok(data)
+    }
+}
+
+/// Decodes js5 responses from a stream of bytes that arrives in arbitrary pieces, such as the
+/// traffic a proxy or sniffer sees. Feed it bytes as they come in and take out the responses
+/// that are complete.
+#[derive(Clone, Debug, Default)]
+pub struct Js5Decoder {
+    buffer: Vec<u8>,
+    xor_key: u8,
+}
+
+impl Js5Decoder {
+    pub fn new() -> Js5Decoder {
+        Js5Decoder::default()
+    }
+
+    /// Sets the key that bytes fed from now on are XORed with. Bytes that were fed before keep
+    /// the key that was in effect when they arrived.
+    pub fn set_xor_key(&mut self, key: u8) {
+        self.xor_key = key;
+    }
+
+    /// Adds bytes that were read from the stream.
+    pub fn feed(&mut self, data: &[u8]) {
+        let start = self.buffer.len();
+        self.buffer.extend(data);
+        xor(&mut self.buffer[start..], self.xor_key);
+    }
+
+    /// Gets the number of bytes fed that don't form a complete response yet.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Takes the next complete response out of the bytes fed so far, if there is one.
+    pub fn next_response(&mut self) -> Result<Option<Js5Response>, std::io::Error> {
+        if self.buffer.len() < 8 {
+            return Ok(None);
+        }
+
+        let header = &self.buffer[..8];
+        let compression = header[3] & 0x7F;
+        let payload = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let length = payload.saturating_add(if compression == 0 { 8 } else { 12 });
+
+        // Every block after the first one costs a separator byte
+        let separators = length.saturating_sub(BLOCK_SIZE).div_ceil(BLOCK_SIZE - 1);
+        let total = length.saturating_add(separators);
+        if self.buffer.len() < total {
+            return Ok(None);
+        }
+
+        let mut data = Vec::with_capacity(length);
+        data.extend(&self.buffer[..length.min(BLOCK_SIZE)]);
+        for block in self.buffer[BLOCK_SIZE.min(length)..total].chunks(BLOCK_SIZE) {
+            if block[0] != 0xFF {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "missing js5 block separator"));
+            }
+            data.extend(&block[1..]);
+        }
+        self.buffer.drain(..total);
+
+        let index = data[0] as u32;
+        let group = u16::from_be_bytes([data[1], data[2]]) as u32;
+        let urgent = data[3] & 0x80 == 0;
+        data[3] = compression;
+        data.drain(..3);
+
+        Ok(Some(Js5Response { index, group, urgent, container: data }))
+    }
+}
+
+/// A source of raw containers, such as a js5 server. `FileSystem::sync` downloads from one.
+pub trait Remote {
+    /// Fetches the raw container of a group. The version trailer may or may not be included.
+    fn fetch(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError>;
+
+    /// Fetches several groups, returning the results in the order they were asked for. Remotes
+    /// that can have more than one request in flight override this to not wait for each group
+    /// before asking for the next.
+    fn fetch_all(&mut self, groups: &[(u32, u32)]) -> Vec<Result<Vec<u8>, FsError>> {
+        groups.iter().map(|(index, group)| self.fetch(*index, *group)).collect()
+    }
+}
+
+/// How many requests of each priority the client keeps in flight by default. Servers disconnect
+/// clients that have many more outstanding.
+pub const DEFAULT_IN_FLIGHT_LIMIT: usize = 20;
+
+/// The opcode of the handshake a client opens a js5 connection with.
+const HANDSHAKE_OPCODE: u8 = 15;
+
+/// Why a js5 handshake failed.
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The connection failed or the peer sent something unexpected.
+    Io(std::io::Error),
+    /// The server runs a different build than the client asked for.
+    OutOfDate,
+    /// The server turned the client away with another response code, such as 7 when it is full.
+    Rejected(u8),
+}
+
+impl std::error::Error for HandshakeError {}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeError::Io(e) => write!(f, "the js5 handshake failed: {}", e),
+            HandshakeError::OutOfDate => write!(f, "the server runs a different build"),
+            HandshakeError::Rejected(code) => write!(f, "the server rejected the handshake with code {}", code),
+        }
+    }
+}
+
+impl From<std::io::Error> for HandshakeError {
+    fn from(e: std::io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+/// Accepts the handshake of a client on the server side. Clients asking for another build are
+/// told they are out of date. Accepted clients get the constant block some revisions send after
+/// the response code, which is empty for most. Returns the build of the client.
+pub fn accept_handshake<S: Read + Write>(stream: &mut S, build: u32, constants: &[u32]) -> Result<u32, HandshakeError> {
+    let mut request = [0u8; 5];
+    stream.read_exact(&mut request)?;
+    if request[0] != HANDSHAKE_OPCODE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not a js5 handshake").into());
+    }
+
+    let client_build = u32::from_be_bytes([request[1], request[2], request[3], request[4]]);
+    if client_build != build {
+        stream.write_all(&[6])?;
+        stream.flush()?;
+        return Err(HandshakeError::OutOfDate);
+    }
+
+    let mut response = vec![0u8];
+    for constant in constants {
+        response.extend(&constant.to_be_bytes());
+    }
+    stream.write_all(&response)?;
+    stream.flush()?;
+    Ok(client_build)
+}
+
+/// One number of the prefetch list some revisions send after the handshake, which the client
+/// shows the loading progress of its first downloads against. Each is a number of bytes as the
+/// js5 stream carries them: containers without their version trailer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Prefetch {
+    /// The size of every group of an index together.
+    Index(u32),
+    /// The size of one group.
+    Group(u32, u32),
+    /// The size of the group of an index with a name, looked up in its reference table.
+    Named(u32, String),
+}
+
+/// A js5 client over an established connection. The handshake is not part of this type, so the
+/// stream must already have been accepted by the server.
+///
+/// Requests can be sent directly, or queued with `enqueue` and sent by `pump`, which keeps the
+/// urgent and prefetch requests in flight within their limits like the game client does.
+#[derive(Debug)]
+pub struct Js5Client<S: Read + Write> {
+    stream: S,
+    xor_key: u8,
+    urgent: RequestQueue,
+    prefetch: RequestQueue,
+}
+
+/// The requests of one priority that wait to be sent or are waiting for their response.
+#[derive(Debug)]
+struct RequestQueue {
+    pending: VecDeque<(u32, u32)>,
+    in_flight: HashSet<(u32, u32)>,
+    limit: usize,
+}
+
+impl RequestQueue {
+    fn new() -> RequestQueue {
+        RequestQueue::new_with_limit(DEFAULT_IN_FLIGHT_LIMIT)
+    }
+
+    fn new_with_limit(limit: usize) -> RequestQueue {
+        RequestQueue { pending: VecDeque::new(), in_flight: HashSet::new(), limit }
+    }
+
+    fn contains(&self, key: &(u32, u32)) -> bool {
+        self.in_flight.contains(key) || self.pending.contains(key)
+    }
+}
+
+impl<S: Read + Write> Js5Client<S> {
+    pub fn new(stream: S) -> Js5Client<S> {
+        Js5Client { stream, xor_key: 0, urgent: RequestQueue::new(), prefetch: RequestQueue::new() }
+    }
+
+    /// Asks the server to XOR every response from now on with a key, and decrypts them from then
+    /// on. Responses the server sent before receiving this are decrypted wrongly, so only rotate
+    /// the key when nothing is in flight.
+    pub fn set_xor_key(&mut self, key: u8) -> Result<(), std::io::Error> {
+        self.stream.write_all(&[4, key, 0, 0])?;
+        self.stream.flush()?;
+        self.xor_key = key;
+        Ok(())
+    }
+
+    /// Reads bytes from the stream and decrypts them.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        self.stream.read_exact(buf)?;
+        xor(buf, self.xor_key);
+        Ok(())
+    }
+
+    /// Opens a connection with the js5 handshake for a client build. Revisions that send a block
+    /// of constants after accepting need its length in ints, which are then returned.
+    pub fn handshake(stream: S, build: u32, constants: usize) -> Result<(Js5Client<S>, Vec<u32>), HandshakeError> {
+        let mut client = Js5Client::new(stream);
+        let mut request = vec![HANDSHAKE_OPCODE];
+        request.extend(&build.to_be_bytes());
+        client.stream.write_all(&request)?;
+        client.stream.flush()?;
+
+        let mut code = [0u8; 1];
+        client.stream.read_exact(&mut code)?;
+        match code[0] {
+            0 => {}
+            6 => return Err(HandshakeError::OutOfDate),
+            code => return Err(HandshakeError::Rejected(code)),
+        }
+
+        let mut block = vec![0u8; constants * 4];
+        client.stream.read_exact(&mut block)?;
+        let constants = block.chunks(4).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect();
+        Ok((client, constants))
+    }
+
+    /// Finds the build a server runs by trying each build in turn, connecting anew for every
+    /// attempt since servers hang up on clients that are out of date. Returns the connected
+    /// client and the build that was accepted.
+    pub fn negotiate<F, I>(mut connect: F, builds: I, constants: usize) -> Result<(Js5Client<S>, u32), HandshakeError>
+        where F: FnMut() -> Result<S, std::io::Error>, I: IntoIterator<Item = u32> {
+        for build in builds {
+            match Js5Client::handshake(connect()?, build, constants) {
+                Ok((client, _)) => return Ok((client, build)),
+                Err(HandshakeError::OutOfDate) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(HandshakeError::OutOfDate)
+    }
+
+    /// Sets how many urgent and prefetch requests `pump` keeps in flight at most.
+    pub fn set_in_flight_limits(&mut self, urgent: usize, prefetch: usize) {
+        self.urgent.limit = urgent;
+        self.prefetch.limit = prefetch;
+    }
+
+    /// Queues a request for a group. Queuing a group that is already queued does nothing, except
+    /// that an urgent request for a group still waiting in the prefetch queue moves it over to
+    /// the urgent one.
+    pub fn enqueue(&mut self, index: u32, group: u32, urgent: bool) {
+        let key = (index, group);
+        if urgent {
+            if let Some(position) = self.prefetch.pending.iter().position(|k| *k == key) {
+                self.prefetch.pending.remove(position);
+            }
+            if !self.urgent.contains(&key) {
+                self.urgent.pending.push_back(key);
+            }
+        } else if !self.urgent.contains(&key) && !self.prefetch.contains(&key) {
+            self.prefetch.pending.push_back(key);
+        }
+    }
+
+    /// Sends queued requests, urgent ones first, for as long as their queue is below its in-flight
+    /// limit. Returns how many requests were sent.
+    pub fn pump(&mut self) -> Result<usize, std::io::Error> {
+        let mut sent = 0;
+
+        for urgent in [true, false] {
+            loop {
+                let queue = if urgent { &mut self.urgent } else { &mut self.prefetch };
+                if queue.in_flight.len() >= queue.limit {
+                    break;
+                }
+
+                let (index, group) = match queue.pending.pop_front() {
+                    Some(key) => key,
+                    None => break,
+                };
+                queue.in_flight.insert((index, group));
+
+                self.request(index, group, urgent)?;
+                sent += 1;
+            }
+        }
+
+        if sent > 0 {
+            self.stream.flush()?;
+        }
+        Ok(sent)
+    }
+
+    /// Reads the next response and takes its request off the queues, then sends whatever the
+    /// freed up slot allows.
+    pub fn receive(&mut self) -> Result<Js5Response, std::io::Error> {
+        let (index, group, container) = self.read_response()?;
+        let urgent = self.urgent.in_flight.remove(&(index, group));
+        if !urgent {
+            self.prefetch.in_flight.remove(&(index, group));
+        }
+
+        self.pump()?;
+        Ok(Js5Response { index, group, urgent, container })
+    }
+
+    /// Gets the number of queued requests that were not sent yet.
+    pub fn pending(&self) -> usize {
+        self.urgent.pending.len() + self.prefetch.pending.len()
+    }
+
+    /// Gets the number of sent requests that have not been answered yet.
+    pub fn in_flight(&self) -> usize {
+        self.urgent.in_flight.len() + self.prefetch.in_flight.len()
+    }
+
+    /// Gets the underlying stream.
+    pub fn stream(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Sends a request for a group. Urgent requests are served before prefetch requests.
+    pub fn request(&mut self, index: u32, group: u32, urgent: bool) -> Result<(), std::io::Error> {
+        if index > 0xFF || group > 0xFFFF {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "group cannot be addressed by js5"));
+        }
+
+        let mut request = [0u8; 4];
+        request[0] = if urgent { 1 } else { 0 };
+        request[1] = index as u8;
+        request[2..].copy_from_slice(&(group as u16).to_be_bytes());
+        self.stream.write_all(&request)
+    }
+
+    /// Reads the next response from the stream, returning the index, group and raw container
+    /// (without version trailer) it carries.
+    pub fn read_response(&mut self) -> Result<(u32, u32, Vec<u8>), std::io::Error> {
+        let mut header = [0u8; 8];
+        self.read_exact(&mut header)?;
+
+        let index = header[0] as u32;
+        let group = u16::from_be_bytes([header[1], header[2]]) as u32;
+        let payload = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+
+        // Prefetch responses have the top bit of the compression type set
+        header[3] &= 0x7F;
+        let length = payload.saturating_add(if header[3] == 0 { 5 } else { 9 });
+
+        // The compression type and length are part of the container. The length is only trusted
+        // as far as it goes, the data grows as it arrives
+        let mut data = Vec::with_capacity(length.min(BLOCK_SIZE * 64));
+        data.extend(&header[3..]);
+
+        // The first block holds the 8-byte header, the others start with a separator
+        let mut block_position = header.len();
+        while data.len() < length {
+            if block_position == BLOCK_SIZE {
+                let mut separator = [0u8; 1];
+                self.read_exact(&mut separator)?;
+                if separator[0] != 0xFF {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "missing js5 block separator"));
+                }
+                block_position = 1;
+            }
+
+            let chunk = (BLOCK_SIZE - block_position).min(length - data.len());
+            let start = data.len();
+            data.resize(start + chunk, 0);
+            self.read_exact(&mut data[start..])?;
+            block_position += chunk;
+        }
+
+        Ok((index, group, data))
+    }
+
+    /// Requests a group with urgent priority and waits for it to arrive.
+    pub fn fetch_group(&mut self, index: u32, group: u32) -> Result<Vec<u8>, std::io::Error> {
+        self.request(index, group, true)?;
+        self.stream.flush()?;
+
+        let (response_index, response_group, data) = self.read_response()?;
+        if response_index != index || response_group != group {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unexpected js5 response"));
+        }
+
+        Ok(data)
+    }
+}
+
+impl<S: Read + Write> Remote for Js5Client<S> {
+    fn fetch(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        self.fetch_group(index, group).map_err(|_| FsError::RemoteFailed)
+    }
+
+    /// Pipelines the requests through the queues, so that as many are in flight as the limits
+    /// allow. Once the connection fails, every group that hasn't arrived yet fails with it.
+    fn fetch_all(&mut self, groups: &[(u32, u32)]) -> Vec<Result<Vec<u8>, FsError>> {
+        let mut arrived = HashMap::new();
+        for (index, group) in groups {
+            self.enqueue(*index, *group, true);
+        }
+
+        let mut pumped = self.pump().is_ok();
+        while pumped && self.in_flight() > 0 {
+            match self.receive() {
+                Ok(response) => {
+                    arrived.insert((response.index, response.group), response.container);
+                }
+                Err(_) => pumped = false,
+            }
+        }
+
+        if !pumped {
+            self.urgent = RequestQueue::new_with_limit(self.urgent.limit);
+            self.prefetch = RequestQueue::new_with_limit(self.prefetch.limit);
+        }
+
+        groups.iter().map(|key| arrived.get(key).cloned().ok_or(FsError::RemoteFailed)).collect()
+    }
+}
+
+/// A local cache can serve as a remote too, which makes it possible to mirror one cache into
+/// another. The master checksum table is generated on the fly.
+impl Remote for FileSystem {
+    fn fetch(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        if index == 255 && group == 255 {
+            let table = self.checksum_table()?;
+            return container::encode(&table.encode(), CompressionType::None, None);
+        }
+
+        self.read_container(index, group)
+    }
+}
+
+/// How many times a download that fails its CRC check is asked for again by default.
+pub const DEFAULT_DOWNLOAD_RETRIES: usize = 3;
+
+/// Fetches groups and checks each one against the CRC it is expected to have, asking again for
+/// those that don't match until they do or the retries run out. Containers that don't even parse
+/// count as mismatches. Returns the result for each group in order, and how many groups were
+/// asked for again.
+pub(crate) fn fetch_verified<R: Remote>(remote: &mut R, groups: &[(u32, u32, i32)], retries: usize, digests: &Digests) -> (Vec<Result<Vec<u8>, FsError>>, usize) {
+    let mut results: Vec<Result<Vec<u8>, FsError>> = groups.iter().map(|_| Err(FsError::CrcMismatch)).collect();
+    let mut mismatched: Vec<usize> = (0..groups.len()).collect();
+    let mut retried = 0;
+
+    for attempt in 0..=retries {
+        if mismatched.is_empty() {
+            break;
+        }
+        if attempt > 0 {
+            retried += mismatched.len();
+        }
+
+        let keys: Vec<(u32, u32)> = mismatched.iter().map(|&i| (groups[i].0, groups[i].1)).collect();
+        let fetched = remote.fetch_all(&keys);
+        let mut again = Vec::new();
+        for (i, result) in mismatched.into_iter().zip(fetched) {
+            match result {
+                Ok(data) if digests.container_crc(&data).ok() != Some(groups[i].2 as u32) => again.push(i),
+                result => results[i] = result,
+            }
+        }
+
+        mismatched = again;
+    }
+
+    (results, retried)
+}
+
+/// What `FileSystem::sync` changed in the local cache.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    /// Indices whose reference table was out of date, in ascending order.
+    pub indices: Vec<u32>,
+    /// The number of group containers that were downloaded.
+    pub groups: usize,
+    /// The number of downloads that were asked for again after failing their CRC check.
+    pub retries: usize,
+}
+
+impl FileSystem {
+    /// Works out the prefetch list of the cache for the handshake of a js5 server, one number
+    /// for each entry of `list` in the same order. `Profile::prefetch_list` gives the entries of
+    /// the flavors with a known list, and `standard_prefetch_sizes` sizes it in one go; the
+    /// sizes come from this cache, so they are never out of date with it.
+    pub fn prefetch_sizes(&mut self, list: &[Prefetch]) -> Result<Vec<u32>, FsError> {
+        let mut sizes = Vec::with_capacity(list.len());
+        for prefetch in list {
+            let size = match prefetch {
+                Prefetch::Index(index) => self.index_size(*index)?.compressed,
+                Prefetch::Group(index, group) => self.group_size(*index, *group)?.compressed as u64,
+                Prefetch::Named(index, name) => {
+                    let hash = ReferenceTable::hash_name(name);
+                    let table = self.reference_table(*index)?;
+                    let folder = table.folder_ids().into_iter().find(|id| table.lookup(*id).unwrap().name_hash() == hash)
+                        .ok_or(FsError::EntryNotFound)?;
+                    self.group_size(*index, folder as u32)?.compressed as u64
+                }
+            };
+            sizes.push(size.try_into().map_err(|_| FsError::FormatOverflow)?);
+        }
+        Ok(sizes)
+    }
+
+    /// Brings the cache up to date with a remote. The master checksum table is compared against
+    /// the local one, and for every stale index only the groups whose CRC differs from the new
+    /// reference table are downloaded, all at once through `Remote::fetch_all`. Every download
+    /// is checked against the CRC the remote advertises for it and asked for again if it doesn't
+    /// match, up to `download_retries` times. Each index is applied as one update. Groups that
+    /// the remote no longer lists are left in place.
+    pub fn sync<R: Remote>(&mut self, remote: &mut R) -> Result<SyncReport, FsError> {
+        let remote_checksums = ChecksumTable::decode(&container::decode(&remote.fetch(255, 255)?)?)?;
+
+        let mut report = SyncReport::default();
+
+        for (index, remote_entry) in remote_checksums.entries().iter().enumerate() {
+            let index = index as u32;
+
+            // Indices the remote doesn't have are all zeroes
+            if remote_entry.crc32() == 0 && remote_entry.revision() == 0 {
+                continue;
+            }
+
+            match self.container_crc(255, index) {
+                Ok(crc) if crc as i32 == remote_entry.crc32() => continue,
+                Ok(_) | Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => {}
+                Err(e) => return Err(e),
+            }
+
+            let (mut tables, retried) = fetch_verified(remote, &[(255, index, remote_entry.crc32())], self.download_retries(), self.digest_set());
+            let table_container = tables.remove(0)?;
+            report.retries += retried;
+
+            let remote_table = ReferenceTable::decode(&mut Cursor::new(container::decode(&table_container)?))?;
+
+            let mut stale = Vec::new();
+            for group in remote_table.folder_ids() {
+                let folder = remote_table.lookup(group).unwrap();
+
+                match self.container_crc(index, group as u32) {
+                    Ok(crc) if crc as i32 == folder.crc32() => continue,
+                    Ok(_) | Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+
+                stale.push((index, group as u32, folder.crc32()));
+            }
+
+            let (containers, retried) = fetch_verified(remote, &stale, self.download_retries(), self.digest_set());
+            report.retries += retried;
+
+            let mut updates = Vec::new();
+            for (&(_, group, _), data) in stale.iter().zip(containers) {
+                let folder = remote_table.lookup(group as i32).unwrap();
+                let mut data = data?;
+
+                // The client stores groups with their version appended
+                if container::version(&data)?.is_none() {
+                    data.extend(&(folder.version() as u16).to_be_bytes());
+                }
+
+                updates.push(ContainerUpdate::new(index, group, data));
+            }
+
+            report.groups += updates.len();
+            report.indices.push(index);
+
+            updates.push(ContainerUpdate::new(255, index, table_container));
+            self.apply_update(&updates)?;
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::reference_table::ReferenceTable;
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+    use super::{HandshakeError, Js5Client, Js5Decoder, Js5Encoder, Prefetch, Remote};
+
+    #[test]
+    fn read_chunked_response() {
+        let data = container::encode(&[3u8; 1500], CompressionType::None, None).unwrap();
+
+        // Index and group, then the container split into 512-byte blocks
+        let mut stream = vec![5u8, 0, 9];
+        for (i, byte) in data.iter().enumerate() {
+            if i > 0 && (i + 3) % 511 == 1 {
+                stream.push(0xFF);
+            }
+            stream.push(*byte);
+        }
+
+        let mut client = Js5Client::new(Cursor::new(stream));
+        assert_eq!(client.read_response().unwrap(), (5, 9, data));
+    }
+
+    #[test]
+    fn decode_responses_fed_in_pieces() {
+        let big = container::encode(&[1u8; 3000], CompressionType::None, Some(9)).unwrap();
+        let small = container::encode(&[2u8; 20], CompressionType::Gzip, None).unwrap();
+
+        let mut stream = super::encode_response(3, 700, true, &big).unwrap();
+        stream.extend(super::encode_response(255, 3, false, &small).unwrap());
+
+        let mut decoder = Js5Decoder::new();
+        let mut responses = Vec::new();
+        for piece in stream.chunks(100) {
+            decoder.feed(piece);
+            while let Some(response) = decoder.next_response().unwrap() {
+                responses.push(response);
+            }
+        }
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!((responses[0].index, responses[0].group, responses[0].urgent), (3, 700, true));
+        assert_eq!(responses[0].container, &big[..big.len() - 2]);
+        assert_eq!((responses[1].index, responses[1].group, responses[1].urgent), (255, 3, false));
+        assert_eq!(responses[1].container, small);
+        assert_eq!(decoder.buffered(), 0);
+
+        // A group past 65535 would be sent as group 0 otherwise
+        assert!(matches!(super::encode_response(3, 0x10000, true, &small), Err(FsError::FormatOverflow)));
+        assert!(matches!(super::encode_response(256, 3, true, &small), Err(FsError::FormatOverflow)));
+
+        let mut client = Js5Client::new(Cursor::new(super::encode_response(255, 3, false, &small).unwrap()));
+        assert_eq!(client.read_response().unwrap(), (255, 3, small.clone()));
+
+        let mut encoder = Js5Encoder::new();
+        encoder.set_xor_key(0x5A);
+        let mut decoder = Js5Decoder::new();
+        decoder.set_xor_key(0x5A);
+        decoder.feed(&encoder.encode(3, 700, true, &big).unwrap());
+        assert_eq!(decoder.next_response().unwrap().unwrap().container, &big[..big.len() - 2]);
+
+        let mut client = Js5Client::new(Cursor::new(encoder.encode(255, 3, true, &small).unwrap()));
+        client.xor_key = 0x5A;
+        assert_eq!(client.read_response().unwrap(), (255, 3, small));
+    }
+
+    #[test]
+    fn queued_requests_respect_priority_and_limits() {
+        let mut client = Js5Client::new(Cursor::new(Vec::new()));
+        client.set_in_flight_limits(1, 2);
+        client.enqueue(2, 10, false);
+        client.enqueue(2, 11, false);
+        client.enqueue(2, 12, false);
+        client.enqueue(2, 11, true);
+        client.enqueue(2, 10, false);
+        assert_eq!(client.pending(), 3);
+
+        // The promoted request goes out first, then prefetches up to their limit
+        assert_eq!(client.pump().unwrap(), 3);
+        assert_eq!(client.stream().get_ref(), &[1, 2, 0, 11, 0, 2, 0, 10, 0, 2, 0, 12]);
+        assert_eq!(client.in_flight(), 3);
+        assert_eq!(client.pump().unwrap(), 0);
+
+        // Fetching several groups sends all requests before reading any response
+        let first = container::encode(&[1u8; 5], CompressionType::None, None).unwrap();
+        let second = container::encode(&[2u8; 5], CompressionType::None, None).unwrap();
+        let mut responses = super::encode_response(2, 11, true, &second).unwrap();
+        responses.extend(super::encode_response(2, 10, true, &first).unwrap());
+        let mut client = Js5Client::new(Loopback::new(responses));
+        let results = client.fetch_all(&[(2, 10), (2, 11), (2, 12)]);
+        assert_eq!(client.stream.written, vec![1, 2, 0, 10, 1, 2, 0, 11, 1, 2, 0, 12]);
+        assert_eq!(results[0].as_ref().unwrap(), &first);
+        assert_eq!(results[1].as_ref().unwrap(), &second);
+        assert!(results[2].is_err());
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    #[test]
+    fn handshake_detects_out_of_date_builds() {
+        let mut server = Cursor::new(vec![15, 0, 0, 0, 180]);
+        assert!(matches!(super::accept_handshake(&mut server, 181, &[]), Err(HandshakeError::OutOfDate)));
+        assert_eq!(&server.get_ref()[5..], &[6]);
+
+        let mut server = Cursor::new(vec![15, 0, 0, 0, 181]);
+        assert_eq!(super::accept_handshake(&mut server, 181, &[7, 8]).unwrap(), 181);
+        assert_eq!(&server.get_ref()[5..], &[0, 0, 0, 0, 7, 0, 0, 0, 8]);
+
+        let (_, constants) = Js5Client::handshake(Loopback::new(vec![0, 0, 0, 1, 2]), 181, 1).unwrap();
+        assert_eq!(constants, vec![258]);
+        // The server is on 181, so the first two attempts are told they are out of date
+        let mut replies = vec![6u8, 6, 0].into_iter();
+        let (client, build) = Js5Client::negotiate(|| Ok(Loopback::new(vec![replies.next().unwrap()])), 179.., 0).unwrap();
+        assert_eq!(build, 181);
+        assert_eq!(client.stream.written, vec![15, 0, 0, 0, 181]);
+    }
+
+    /// A stream that reads canned bytes and keeps what is written separately.
+    struct Loopback {
+        input: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+    }
+
+    impl Loopback {
+        fn new(input: Vec<u8>) -> Loopback {
+            Loopback { input: Cursor::new(input), written: Vec::new() }
+        }
+    }
+
+    impl std::io::Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl std::io::Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn prefetch_sizes_come_from_the_cache() {
+        let dir = TestDir::new("prefetch");
+        let mut cache = FileSystem::new_writable(&dir).unwrap();
+        cache.write_group(2, 0, &[1u8; 40], CompressionType::None, Some(3)).unwrap();
+        cache.write_group(2, 1, &[2u8; 60], CompressionType::None, Some(3)).unwrap();
+        cache.refresh_reference_tables().unwrap();
+        let mut table = cache.reference_table(2).unwrap();
+        table.set_flags(table.flags().with_names(true)).unwrap();
+        table.lookup_mut(1).unwrap().set_name_hash(ReferenceTable::hash_name("huffman"));
+        cache.write_reference_table(2, &table).unwrap();
+
+        let list = [Prefetch::Index(2), Prefetch::Group(2, 0), Prefetch::Named(2, "huffman".to_string())];
+        assert_eq!(cache.prefetch_sizes(&list).unwrap(), vec![110, 45, 65]);
+        assert!(matches!(cache.prefetch_sizes(&[Prefetch::Named(2, "title".to_string())]), Err(FsError::EntryNotFound)));
+    }
+
+    #[test]
+    fn sync_from_another_cache() {
+        let base = TestDir::new("sync");
+        fs::create_dir_all(base.join("remote")).unwrap();
+        fs::create_dir_all(base.join("local")).unwrap();
+
+        let mut remote = FileSystem::new_writable(base.join("remote")).unwrap();
+        remote.write_reference_table(1, &ReferenceTable::new(6)).unwrap();
+        let groups: Vec<ContainerUpdate> = (0..3)
+            .map(|g| ContainerUpdate::new(1, g, container::encode(&vec![g as u8; 900], CompressionType::Gzip, Some(1)).unwrap()))
+            .collect();
+        remote.apply_update(&groups).unwrap();
+
+        let mut local = FileSystem::new_writable(base.join("local")).unwrap();
+        let report = local.sync(&mut remote).unwrap();
+        assert_eq!(report.indices, vec![1]);
+        assert_eq!(report.groups, 3);
+        assert_eq!(local.read_container(1, 2).unwrap(), remote.read_container(1, 2).unwrap());
+        assert_eq!(local.checksum_table().unwrap(), remote.checksum_table().unwrap());
+
+        assert_eq!(local.sync(&mut remote).unwrap().groups, 0);
+    }
+
+    /// A remote that flips a byte in the first few containers it serves for a group.
+    struct Corrupting<'a> {
+        fs: &'a mut FileSystem,
+        group: u32,
+        times: usize,
+    }
+
+    impl Remote for Corrupting<'_> {
+        fn fetch(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+            let mut data = self.fs.fetch(index, group)?;
+            if index != 255 && group == self.group && self.times > 0 {
+                self.times -= 1;
+                let last = data.len() - 3;
+                data[last] ^= 1;
+            }
+            Ok(data)
+        }
+    }
+
+    #[test]
+    fn sync_asks_again_for_corrupted_downloads() {
+        let base = TestDir::new("retry");
+        fs::create_dir_all(base.join("remote")).unwrap();
+        let mut remote = FileSystem::new_writable(base.join("remote")).unwrap();
+        remote.write_reference_table(4, &ReferenceTable::new(6)).unwrap();
+        let group = container::encode(&[4u8; 100], CompressionType::None, Some(1)).unwrap();
+        remote.apply_update(&[ContainerUpdate::new(4, 0, group)]).unwrap();
+
+        fs::create_dir_all(base.join("first")).unwrap();
+        let mut local = FileSystem::new_writable(base.join("first")).unwrap();
+        let report = local.sync(&mut Corrupting { fs: &mut remote, group: 0, times: 2 }).unwrap();
+        assert_eq!(report.retries, 2);
+        assert_eq!(local.read_container(4, 0).unwrap(), remote.read_container(4, 0).unwrap());
+
+        fs::create_dir_all(base.join("second")).unwrap();
+        let mut local = FileSystem::new_writable(base.join("second")).unwrap();
+        local.set_download_retries(1);
+        let result = local.sync(&mut Corrupting { fs: &mut remote, group: 0, times: 2 });
+        assert!(matches!(result, Err(FsError::CrcMismatch)));
+    }
+}
@@ -1,7 +1,54 @@
+pub mod backing;
+pub mod bulk;
+pub mod bundle;
+pub mod checksum_cache;
+pub mod checksum_table;
+pub mod container;
+pub mod cow;
+pub mod debug;
+pub mod dedup;
+pub mod defrag;
+pub mod digest;
+pub mod download;
 pub mod filesystem;
+pub mod filter;
+pub mod hash_index;
+pub mod hooks;
+pub mod http;
+pub mod jaggrab;
+pub mod js5;
+pub mod merged;
+pub mod names;
+pub mod naming;
+pub mod objects;
+pub mod offsets;
+pub mod options;
+pub mod overlay;
+pub mod patch;
+pub mod pool;
+pub mod profile;
+pub mod recompress;
 pub mod reference_table;
+pub mod resume;
+pub mod salvage;
+pub mod search;
+pub mod sizes;
+pub mod snapshot;
+pub mod subset;
+#[cfg(test)]
+mod test_dir;
+pub mod transform;
+pub mod update;
+pub mod verify;
+#[cfg(feature = "watch")]
+pub mod watch;
+pub mod whirlpool;
+pub mod xtea;
 
-pub use filesystem::{FileSystem, FsError, MainFile};
+pub use checksum_table::{ChecksumFormat, ChecksumTable};
+pub use filesystem::{FileSystem, FsError, IndexFormat, LockMode, MainFile};
+pub use naming::FileNaming;
+pub use options::FileSystemOptions;
 pub use reference_table::ReferenceTable;
 
 #[test]
@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use crate::checksum_table::{ChecksumTable, ChecksumTableEntry};
+use crate::container::{self, ContainerReader};
+use crate::filesystem::{CompressionType, FileSystem, FsError};
+use crate::js5::Remote;
+use crate::options::FileSystemOptions;
+use crate::reference_table::ReferenceTable;
+
+/// A number of caches seen as one. Each group is read from the first cache that has it, so the
+/// caches go from most to least preferred: a partial dump of a newer revision followed by a full
+/// dump of an older one reads as the newer revision wherever the partial dump has something.
+/// Reference tables are groups of index 255 like any other, so each index gets its table from
+/// the first cache that has one.
+#[derive(Debug)]
+pub struct MergedStore {
+    stores: Vec<FileSystem>,
+}
+
+impl MergedStore {
+    /// Merges caches, the most preferred first.
+    pub fn new(stores: Vec<FileSystem>) -> MergedStore {
+        MergedStore { stores }
+    }
+
+    /// Gets the merged caches, the most preferred first.
+    pub fn stores(&mut self) -> &mut [FileSystem] {
+        &mut self.stores
+    }
+
+    /// Separates the caches again.
+    pub fn into_stores(self) -> Vec<FileSystem> {
+        self.stores
+    }
+
+    /// Gets every index of any of the caches, sorted.
+    pub fn indices(&self) -> Vec<u32> {
+        let mut indices: Vec<u32> = self.stores.iter().flat_map(|store| store.indices()).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+
+    /// Gets the position of the cache a group is read from, or `None` if none of them has it.
+    pub fn source_of(&mut self, index: u32, group: u32) -> Option<usize> {
+        self.stores.iter_mut().position(|store| {
+            store.index(index).and_then(|idx| idx.entry(group)).is_some_and(|entry| entry.size() > 0)
+        })
+    }
+
+    /// Reads the raw container of a group from the first cache that has it.
+    pub fn read_container(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        for store in &mut self.stores {
+            match store.read_container(index, group) {
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => continue,
+                result => return result,
+            }
+        }
+
+        Err(FsError::EntryNotFound)
+    }
+
+    /// Reads and decompresses a group like `read_container`, up to the decompressed size the
+    /// first cache allows.
+    pub fn read_group(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        let container = self.read_container(index, group)?;
+        let limit = self.stores.first().and_then(|store| store.max_decompressed_size());
+        container::decode_limited(&container, limit.unwrap_or(u64::MAX))
+    }
+
+    /// Reads and decodes the reference table of an index from the first cache that has one.
+    pub fn reference_table(&mut self, index: u32) -> Result<ReferenceTable, FsError> {
+        ReferenceTable::decode(&mut ContainerReader::new(self.read_container(255, index)?)?)
+    }
+
+    /// Generates the master checksum table of the merged view, from the reference table each
+    /// index gets. The CRCs and digests are taken with the ones installed on the first cache.
+    pub fn checksum_table(&mut self) -> Result<ChecksumTable, FsError> {
+        let count = self.stores.iter_mut().map(|store| store.index(255).map_or(0, |idx| idx.last_entry())).max().unwrap_or(0) as u32;
+        let digests = self.stores.first().map(|store| store.digest_set().clone()).unwrap_or_default();
+        let mut table = ChecksumTable::default();
+
+        for index in 0..count {
+            let entry = match self.read_container(255, index) {
+                Ok(container) => {
+                    let reference_table = ReferenceTable::decode(&mut ContainerReader::new(&container)?)?;
+
+                    ChecksumTableEntry::new(digests.container_crc(&container)? as i32, reference_table.revision(),
+                        digests.whirlpool(&container).to_vec())
+                }
+                Err(FsError::EntryNotFound) => ChecksumTableEntry::default(),
+                Err(e) => return Err(e),
+            };
+
+            table.push(entry);
+        }
+
+        Ok(table)
+    }
+
+    /// Copies the merged view into a single cache in another folder, creating it if needed, and
+    /// returns it opened for writing. The new cache is named the same and has the same block
+    /// size and idx format as the first of the caches.
+    ///
+    /// A reference table only describes the groups of the cache it came from, so the groups
+    /// that came from another cache than the table of their index are left dirty. Flushing the
+    /// new cache, which happens at the latest when it is dropped, brings the tables up to date
+    /// with them.
+    pub fn materialize_to<P: AsRef<Path>>(&mut self, dest: P) -> Result<FileSystem, FsError> {
+        fs::create_dir_all(dest.as_ref()).map_err(FsError::Io)?;
+        let mut options = FileSystemOptions::new().with_writable(true);
+        if let Some(first) = self.stores.first_mut() {
+            options = options.with_naming(first.naming().clone())
+                .with_block_size(first.mainfile().block_size()).with_index_format(first.index_format());
+        }
+        let mut target = FileSystem::open_with(dest, options)?;
+
+        let mut counts: BTreeMap<u32, u32> = BTreeMap::new();
+        for store in &mut self.stores {
+            for index in store.indices() {
+                let count = counts.entry(index).or_default();
+                *count = (*count).max(store.index(index).unwrap().last_entry() as u32);
+            }
+        }
+
+        for (index, count) in counts {
+            let table_source = if index == 255 { None } else { self.source_of(255, index) };
+            for group in 0..count {
+                let source = match self.source_of(index, group) {
+                    Some(source) => source,
+                    None => continue,
+                };
+
+                let container = self.stores[source].read_container(index, group)?;
+                target.write_container(index, group, &container)?;
+                if index == 255 || table_source == Some(source) {
+                    target.mark_clean(index, group);
+                }
+            }
+        }
+
+        target.sync_all()?;
+        Ok(target)
+    }
+}
+
+/// The merged view can be mirrored into another cache with `sync`.
+impl Remote for MergedStore {
+    fn fetch(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        if index == 255 && group == 255 {
+            let table = self.checksum_table()?;
+            return container::encode(&table.encode(), CompressionType::None, None);
+        }
+
+        self.read_container(index, group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+    use super::MergedStore;
+
+    #[test]
+    fn groups_come_from_the_first_store_that_has_them() {
+        let base = TestDir::new("merged");
+        fs::create_dir_all(base.join("old")).unwrap();
+        fs::create_dir_all(base.join("new")).unwrap();
+
+        let mut old = FileSystem::new_writable(base.join("old")).unwrap();
+        let groups: Vec<ContainerUpdate> = (0..3)
+            .map(|g| ContainerUpdate::new(2, g, container::encode(&[g as u8; 100], CompressionType::Gzip, Some(1)).unwrap()))
+            .collect();
+        old.apply_update(&groups).unwrap();
+        old.write_group(4, 0, b"only old", CompressionType::None, None).unwrap();
+        drop(old);
+
+        // The newer dump only has the group that changed, and a table that only knows of it
+        let mut new = FileSystem::new_writable(base.join("new")).unwrap();
+        new.apply_update(&[ContainerUpdate::new(2, 1, container::encode(b"newer", CompressionType::Gzip, Some(2)).unwrap())]).unwrap();
+        drop(new);
+
+        let stores = vec![FileSystem::new(base.join("new")).unwrap(), FileSystem::new(base.join("old")).unwrap()];
+        let mut merged = MergedStore::new(stores);
+        assert_eq!(merged.indices(), vec![2, 4, 255]);
+        assert_eq!(merged.read_group(2, 0).unwrap(), [0u8; 100]);
+        assert_eq!(merged.read_group(2, 1).unwrap(), b"newer");
+        assert_eq!(merged.source_of(4, 0), Some(1));
+        assert_eq!(merged.source_of(2, 3), None);
+
+        let mut single = merged.materialize_to(base.join("merged")).unwrap();
+        assert_eq!(single.dirty_groups(), vec![(2, 0), (2, 2), (4, 0)]);
+        assert_eq!(single.read_container(2, 1).unwrap(), merged.read_container(2, 1).unwrap());
+        single.flush().unwrap();
+        let table = single.reference_table(2).unwrap();
+        for group in 0..3 {
+            assert_eq!(table.lookup(group).unwrap().crc32(), single.container_crc(2, group as u32).unwrap() as i32);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use crate::filesystem::{FileSystem, FsError};
+use crate::reference_table::ReferenceTable;
+
+/// Names that are known to be in caches, looked up by their hash. Reference tables only hold the
+/// hashes of names, so lists of names found by the community are the only way to get them back.
+#[derive(Clone, Debug, Default)]
+pub struct NameDictionary {
+    /// Every name with each hash, in the order they were added.
+    names: HashMap<i32, Vec<String>>,
+}
+
+impl NameDictionary {
+    pub fn new() -> NameDictionary {
+        NameDictionary::default()
+    }
+
+    /// Adds a name, returning its hash. A name that only differs in case from one that was
+    /// already added is the same name, as it hashes the same way in every cache.
+    pub fn insert(&mut self, name: &str) -> i32 {
+        let hash = ReferenceTable::hash_name(name);
+        let names = self.names.entry(hash).or_default();
+        if !names.iter().any(|known| known.eq_ignore_ascii_case(name)) {
+            names.push(name.to_string());
+        }
+        hash
+    }
+
+    /// Adds every name in a list of one name a line, returning how many there were. Blank lines
+    /// and lines starting with `#` are skipped, and whitespace around names is trimmed.
+    pub fn extend_from_list(&mut self, list: &str) -> usize {
+        let mut added = 0;
+        for name in list.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            self.insert(name);
+            added += 1;
+        }
+        added
+    }
+
+    /// Loads a list of names from a file, as `extend_from_list` reads it.
+    pub fn load(path: impl AsRef<Path>) -> Result<NameDictionary, FsError> {
+        let list = fs::read_to_string(path).map_err(FsError::Io)?;
+        let mut dictionary = NameDictionary::new();
+        dictionary.extend_from_list(&list);
+        Ok(dictionary)
+    }
+
+    /// Gets the name with a hash, if one is known. When several are, this is the first one.
+    pub fn name(&self, hash: i32) -> Option<&str> {
+        self.names.get(&hash).and_then(|names| names.first()).map(String::as_str)
+    }
+
+    /// Gets every name with a hash, which is more than one if they collide.
+    pub fn candidates(&self, hash: i32) -> &[String] {
+        self.names.get(&hash).map_or(&[], Vec::as_slice)
+    }
+
+    /// Gets every name in the dictionary, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.values().flatten().map(String::as_str)
+    }
+
+    /// Gets the number of distinct hashes there are names for.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Looks up the name of every group and file of every reference table of a cache that has
+    /// names. Hashes of 0 are taken as having no name. Where the dictionary has more than one
+    /// name for a hash the first one is taken, and the others are reported as a collision.
+    pub fn resolve(&self, fs: &mut FileSystem) -> Result<ResolvedNames, FsError> {
+        let mut resolved = ResolvedNames::default();
+        for index in fs.indices().into_iter().filter(|index| *index != 255) {
+            let table = match fs.reference_table(index) {
+                Ok(table) => table,
+                Err(FsError::EntryNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            if !table.flags().has_names() {
+                continue;
+            }
+
+            for folder in table.folder_ids().into_iter().map(|id| table.lookup(id).unwrap()) {
+                let group = folder.id() as u32;
+                let files = folder.file_ids().into_iter().map(|id| (Some(id as u32), folder.file(id).unwrap().name_hash()));
+                for (file, hash) in std::iter::once((None, folder.name_hash())).chain(files).filter(|(_, hash)| *hash != 0) {
+                    let names = self.candidates(hash);
+                    if names.len() > 1 {
+                        resolved.collisions.push(Collision { index, group, file, hash, names: names.to_vec() });
+                    }
+                    match (names.first(), file) {
+                        (Some(name), None) => { resolved.groups.insert((index, group), name.clone()); }
+                        (Some(name), Some(file)) => { resolved.files.insert((index, group, file), name.clone()); }
+                        (None, _) => *resolved.unresolved.entry(index).or_insert(0) += 1,
+                    }
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Resolves the names of a cache, keeping what was resolved in a file so later runs don't
+    /// need the whole dictionary. The names in the file are tried first, and only if some hash
+    /// is left unresolved is the dictionary loaded from `list` and the file rewritten.
+    pub fn resolve_cached(fs: &mut FileSystem, list: impl AsRef<Path>, cache: impl AsRef<Path>) -> Result<ResolvedNames, FsError> {
+        let cache = cache.as_ref();
+        if let Ok(data) = fs::read_to_string(cache) {
+            let resolved = ResolvedNames::decode(&data)?.dictionary().resolve(fs)?;
+            if resolved.unresolved.is_empty() {
+                return Ok(resolved);
+            }
+        }
+
+        let resolved = NameDictionary::load(list)?.resolve(fs)?;
+        fs::write(cache, resolved.encode()).map_err(FsError::Io)?;
+        Ok(resolved)
+    }
+}
+
+/// The names of the groups and files of a cache, as found by `NameDictionary::resolve`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResolvedNames {
+    /// The names of groups, by index and group id.
+    pub groups: BTreeMap<(u32, u32), String>,
+    /// The names of files, by index, group and file id.
+    pub files: BTreeMap<(u32, u32, u32), String>,
+    /// How many name hashes no name was known for, by index. Indices where every hash was
+    /// resolved are left out.
+    pub unresolved: BTreeMap<u32, usize>,
+    /// The hashes more than one name was known for, in the order they were found.
+    pub collisions: Vec<Collision>,
+}
+
+/// A name hash in a reference table that more than one name of a dictionary hashes to, so it
+/// isn't known which of them is the real one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Collision {
+    pub index: u32,
+    pub group: u32,
+    /// The file the hash is of, or `None` if it is the hash of the group.
+    pub file: Option<u32>,
+    pub hash: i32,
+    pub names: Vec<String>,
+}
+
+impl ResolvedNames {
+    pub fn group_name(&self, index: u32, group: u32) -> Option<&str> {
+        self.groups.get(&(index, group)).map(String::as_str)
+    }
+
+    pub fn file_name(&self, index: u32, group: u32, file: u32) -> Option<&str> {
+        self.files.get(&(index, group, file)).map(String::as_str)
+    }
+
+    /// Gets a dictionary of just the names that were resolved.
+    pub fn dictionary(&self) -> NameDictionary {
+        let mut dictionary = NameDictionary::new();
+        for name in self.groups.values().chain(self.files.values()) {
+            dictionary.insert(name);
+        }
+        dictionary
+    }
+
+    /// Encodes the names as one "index group - name" line per group and one "index group file
+    /// name" line per file. The name is the rest of the line, so it may have spaces in it.
+    pub fn encode(&self) -> String {
+        let groups = self.groups.iter().map(|((index, group), name)| format!("{} {} - {}\n", index, group, name));
+        let files = self.files.iter().map(|((index, group, file), name)| format!("{} {} {} {}\n", index, group, file, name));
+        groups.chain(files).collect()
+    }
+
+    pub fn decode(data: &str) -> Result<ResolvedNames, FsError> {
+        let mut resolved = ResolvedNames::default();
+
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            let mut fields = line.splitn(4, ' ');
+            let (index, group, file, name) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(index), Some(group), Some(file), Some(name)) if !name.is_empty() => (index, group, file, name),
+                _ => return Err(FsError::CorruptedData),
+            };
+
+            let key = index.parse().ok().zip(group.parse().ok()).ok_or(FsError::CorruptedData)?;
+            if file == "-" {
+                resolved.groups.insert(key, name.to_string());
+            } else {
+                let file = file.parse().map_err(|_| FsError::CorruptedData)?;
+                resolved.files.insert((key.0, key.1, file), name.to_string());
+            }
+        }
+
+        Ok(resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::reference_table::{ReferenceTable, ReferenceTableFlags};
+    use crate::test_dir::TestDir;
+    use super::{Collision, NameDictionary, ResolvedNames};
+
+    #[test]
+    fn names_resolve_and_are_kept_between_runs() {
+        let dir = TestDir::new("name-dictionary");
+        let mut cache = FileSystem::new_writable(&dir).unwrap();
+        cache.write_group(5, 0, b"map", CompressionType::None, None).unwrap();
+        cache.write_group(5, 1, b"loc", CompressionType::None, None).unwrap();
+        cache.refresh_reference_tables().unwrap();
+
+        let mut table = cache.reference_table(5).unwrap();
+        table.set_flags(ReferenceTableFlags::new().with_names(true)).unwrap();
+        table.lookup_mut(0).unwrap().set_name_hash(ReferenceTable::hash_name("m50_50"));
+        table.lookup_mut(1).unwrap().set_name_hash(ReferenceTable::hash_name("l50_50"));
+        cache.write_reference_table(5, &table).unwrap();
+
+        let list = dir.join("names.txt");
+        fs::write(&list, "# known names\nM50_50\n\nunrelated name\n").unwrap();
+        let resolved = NameDictionary::load(&list).unwrap().resolve(&mut cache).unwrap();
+        assert_eq!(resolved.group_name(5, 0), Some("M50_50"));
+        assert_eq!(resolved.group_name(5, 1), None);
+        assert_eq!(resolved.unresolved, BTreeMap::from([(5, 1)]));
+
+        // The kept names don't resolve everything, so the list is loaded again
+        let kept = dir.join("resolved.txt");
+        fs::write(&kept, resolved.encode()).unwrap();
+        fs::write(&list, "m50_50\nl50_50\n").unwrap();
+        assert!(NameDictionary::resolve_cached(&mut cache, &list, &kept).unwrap().unresolved.is_empty());
+        fs::remove_file(&list).unwrap();
+        let resolved = NameDictionary::resolve_cached(&mut cache, &list, &kept).unwrap();
+        assert_eq!(resolved.group_name(5, 1), Some("l50_50"));
+        assert_eq!(ResolvedNames::decode(&resolved.encode()).unwrap(), resolved);
+        assert_eq!(resolved.encode(), "5 0 - m50_50\n5 1 - l50_50\n");
+
+        // "az" and "b[" hash the same, "L50_50" is just "l50_50" in another case
+        let mut dictionary = resolved.dictionary();
+        assert_eq!(dictionary.insert("L50_50"), ReferenceTable::hash_name("l50_50"));
+        assert_eq!(dictionary.insert("az"), dictionary.insert("b["));
+        table.lookup_mut(0).unwrap().set_name_hash(ReferenceTable::hash_name("az"));
+        cache.write_reference_table(5, &table).unwrap();
+        let resolved = dictionary.resolve(&mut cache).unwrap();
+        assert_eq!(resolved.collisions, vec![Collision { index: 5, group: 0, file: None, hash: ReferenceTable::hash_name("az"),
+            names: vec!["az".to_string(), "b[".to_string()] }]);
+    }
+}
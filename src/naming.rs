@@ -0,0 +1,112 @@
+use std::ffi::OsStr;
+
+/// The names of the files that make up a cache. Jagex names them "main_file_cache.dat2" and
+/// "main_file_cache.idx0" to "main_file_cache.idx255", but repacked caches don't always.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileNaming {
+    data: String,
+    data_fallbacks: Vec<String>,
+    index_prefix: String,
+    index_suffix: String,
+}
+
+impl FileNaming {
+    /// Creates a naming scheme from the name of the data file and a template for the names of
+    /// the idx files, in which "{}" stands for the index id. A template without "{}" gets the id
+    /// appended.
+    pub fn new(data: &str, index_template: &str) -> FileNaming {
+        let (index_prefix, index_suffix) = match index_template.find("{}") {
+            Some(at) => (&index_template[..at], &index_template[at + 2..]),
+            None => (index_template, ""),
+        };
+
+        FileNaming { data: data.to_string(), data_fallbacks: Vec::new(), index_prefix: index_prefix.to_string(),
+            index_suffix: index_suffix.to_string() }
+    }
+
+    /// The naming of older caches, whose data file is "main_file_cache.dat" rather than
+    /// "main_file_cache.dat2". The sectors inside are laid out the same way.
+    pub fn legacy() -> FileNaming {
+        FileNaming::new("main_file_cache.dat", "main_file_cache.idx{}")
+    }
+
+    /// Adds a name to fall back to when no data file with the name from `new` exists.
+    pub fn with_data_fallback(mut self, data: &str) -> FileNaming {
+        self.data_fallbacks.push(data.to_string());
+        self
+    }
+
+    /// Gets the name of the data file.
+    pub fn data_file(&self) -> &str {
+        &self.data
+    }
+
+    /// Checks if this names a legacy cache, whose block headers number the stores from 1.
+    pub fn is_legacy(&self) -> bool {
+        self.data == "main_file_cache.dat"
+    }
+
+    /// Gets the name of the secondary data file, which newer caches keep large groups in. It is
+    /// named after the data file with an "m" appended.
+    pub fn secondary_data_file(&self) -> String {
+        format!("{}m", self.data)
+    }
+
+    /// Gets the names the data file may have, in order of preference.
+    pub fn data_candidates(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.data.as_str()).chain(self.data_fallbacks.iter().map(|d| d.as_str()))
+    }
+
+    /// Settles on one of the data file candidates, once it is known which one exists.
+    pub(crate) fn resolve(&mut self, data: &str) {
+        self.data = data.to_string();
+        self.data_fallbacks.clear();
+    }
+
+    /// Gets the name of the idx file of an index.
+    pub fn index_file(&self, index: u32) -> String {
+        format!("{}{}{}", self.index_prefix, index, self.index_suffix)
+    }
+
+    /// Parses the index id out of an idx file name. Names that aren't valid UTF-8 or have
+    /// anything but digits in place of the id don't name an index.
+    pub fn parse_index(&self, name: &OsStr) -> Option<u32> {
+        let id = name.to_str()?.strip_prefix(self.index_prefix.as_str())?.strip_suffix(self.index_suffix.as_str())?;
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        id.parse().ok()
+    }
+
+    /// Checks if a file name starts like an idx file name, whether or not it names an index.
+    pub(crate) fn resembles_index(&self, name: &OsStr) -> bool {
+        name.to_string_lossy().starts_with(self.index_prefix.as_str())
+    }
+}
+
+/// Jagex's naming, falling back to the legacy data file name for older caches.
+impl Default for FileNaming {
+    fn default() -> Self {
+        FileNaming::new("main_file_cache.dat2", "main_file_cache.idx{}").with_data_fallback("main_file_cache.dat")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use super::FileNaming;
+
+    #[test]
+    fn index_names_round_trip() {
+        let naming = FileNaming::new("js5-data.bin", "js5-{}.idx");
+        assert_eq!(naming.index_file(12), "js5-12.idx");
+        assert_eq!(naming.parse_index(OsStr::new("js5-12.idx")), Some(12));
+        assert_eq!(naming.parse_index(OsStr::new("js5-12.idx.bak")), None);
+        assert_eq!(naming.parse_index(OsStr::new("js5-.idx")), None);
+
+        let default = FileNaming::default();
+        assert_eq!(default.parse_index(OsStr::new("main_file_cache.idx255")), Some(255));
+        assert_eq!(default.parse_index(OsStr::new("main_file_cache.idx-1")), None);
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::filesystem::{FileSystem, FsError};
+use crate::whirlpool;
+
+/// Which object every group of a cache is stored as, by the hex whirlpool digest of its
+/// container.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ObjectManifest {
+    pub entries: BTreeMap<(u32, u32), String>,
+}
+
+impl ObjectManifest {
+    /// Encodes the manifest as one "index group digest" line per group.
+    pub fn encode(&self) -> String {
+        self.entries.iter().map(|((index, group), digest)| format!("{} {} {}\n", index, group, digest)).collect()
+    }
+
+    pub fn decode(data: &str) -> Result<ObjectManifest, FsError> {
+        let mut entries = BTreeMap::new();
+
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            let mut fields = line.split(' ');
+            let parsed = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(index), Some(group), Some(digest), None) => index.parse().ok().zip(group.parse().ok()).map(|key| (key, digest)),
+                _ => None,
+            };
+
+            let (key, digest) = parsed.ok_or(FsError::CorruptedData)?;
+            if digest.len() != whirlpool::DIGEST_LENGTH * 2 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(FsError::CorruptedData);
+            }
+            entries.insert(key, digest.to_string());
+        }
+
+        Ok(ObjectManifest { entries })
+    }
+}
+
+/// What `FileSystem::export_objects` wrote.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ObjectExport {
+    pub manifest: ObjectManifest,
+    /// The number of objects that were new to the store.
+    pub written: usize,
+    /// The number of groups whose object was already in the store.
+    pub reused: usize,
+}
+
+/// Gets the path of an object in a store: the first two digits of its digest name a folder, so
+/// that no single folder ends up with every object.
+fn object_path(store: &Path, digest: &str) -> PathBuf {
+    store.join("objects").join(&digest[..2]).join(digest)
+}
+
+impl FileSystem {
+    /// Exports the cache into a content-addressed store. Every distinct container is written
+    /// once as `objects/<first two digits>/<digest>`, named after its whirlpool digest, and the
+    /// file "manifest" lists the digest of every group, reference tables included.
+    ///
+    /// Objects that are already in the store are left alone, so exporting many revisions of a
+    /// cache into one store only adds what changed between them. Only the manifest is replaced.
+    pub fn export_objects<P: AsRef<Path>>(&mut self, store: P) -> Result<ObjectExport, FsError> {
+        let store = store.as_ref();
+        let mut export = ObjectExport::default();
+
+        for index in self.indices() {
+            let count = self.index(index).unwrap().last_entry() as u32;
+            for group in 0..count {
+                let data = match self.read_container(index, group) {
+                    Ok(data) => data,
+                    Err(FsError::EntryNotFound) => continue,
+                    Err(e) => return Err(e),
+                };
+
+                let digest: String = whirlpool::digest(&data).iter().map(|b| format!("{:02x}", b)).collect();
+                let path = object_path(store, &digest);
+                if path.exists() {
+                    export.reused += 1;
+                } else {
+                    // Written under another name first, so a crash never leaves a truncated object
+                    let partial = path.with_extension("partial");
+                    fs::create_dir_all(path.parent().unwrap()).map_err(FsError::Io)?;
+                    fs::write(&partial, &data).map_err(FsError::Io)?;
+                    fs::rename(&partial, &path).map_err(FsError::Io)?;
+                    export.written += 1;
+                }
+
+                export.manifest.entries.insert((index, group), digest);
+            }
+        }
+
+        fs::write(store.join("manifest"), export.manifest.encode()).map_err(FsError::Io)?;
+        Ok(export)
+    }
+
+    /// Writes every group listed in the manifest of a content-addressed store into the cache,
+    /// reference tables last. Returns the number of groups written.
+    pub fn import_objects<P: AsRef<Path>>(&mut self, store: P) -> Result<usize, FsError> {
+        let store = store.as_ref();
+        let manifest = fs::read_to_string(store.join("manifest")).map_err(|_| FsError::FileNotFound)?;
+        let manifest = ObjectManifest::decode(&manifest)?;
+
+        let mut groups: Vec<(&(u32, u32), &String)> = manifest.entries.iter().collect();
+        groups.sort_by_key(|((index, _), _)| *index == 255);
+
+        for (&(index, group), digest) in &groups {
+            let data = fs::read(object_path(store, digest)).map_err(|_| FsError::FileNotFound)?;
+            self.write_container(index, group, &data)?;
+            self.mark_clean(index, group);
+        }
+
+        Ok(groups.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn export_stores_each_container_once() {
+        let base = TestDir::new("objects");
+        fs::create_dir_all(base.join("cache")).unwrap();
+
+        let mut cache = FileSystem::new_writable(base.join("cache")).unwrap();
+        cache.write_group(2, 0, &[1u8; 300], CompressionType::Gzip, Some(1)).unwrap();
+        cache.write_group(2, 1, &[1u8; 300], CompressionType::Gzip, Some(1)).unwrap();
+        cache.write_group(3, 0, &[2u8; 300], CompressionType::None, None).unwrap();
+
+        let export = cache.export_objects(base.join("store")).unwrap();
+        assert_eq!((export.written, export.reused), (2, 1));
+        assert_eq!(export.manifest.entries[&(2, 0)], export.manifest.entries[&(2, 1)]);
+
+        // A second revision only adds the container that changed
+        cache.write_group(3, 0, &[3u8; 300], CompressionType::None, None).unwrap();
+        let export = cache.export_objects(base.join("store")).unwrap();
+        assert_eq!((export.written, export.reused), (1, 2));
+
+        fs::create_dir_all(base.join("mirror")).unwrap();
+        let mut mirror = FileSystem::new_writable(base.join("mirror")).unwrap();
+        assert_eq!(mirror.import_objects(base.join("store")).unwrap(), 3);
+        assert_eq!(mirror.read_container(3, 0).unwrap(), cache.read_container(3, 0).unwrap());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::filesystem::FileSystem;
+
+/// Why an idx entry can't be read from the data file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OffsetProblem {
+    /// The first block of the entry is block 0, which is never used, or lies beyond the end of
+    /// the data file.
+    BlockOutOfRange,
+    /// The data file doesn't have enough blocks for an entry of this size.
+    SizeOutOfRange,
+}
+
+/// An idx entry that points beyond the end of the data file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidEntry {
+    pub index: u32,
+    pub group: u32,
+    /// The first block and the size as stored in the idx file.
+    pub block: u32,
+    pub size: u32,
+    pub problem: OffsetProblem,
+}
+
+impl FileSystem {
+    /// Checks every idx entry against the length of the data file it points into, and returns the
+    /// ones that can't be read in cache order. Only the idx files and the lengths of the data
+    /// files are looked at, so this is quick, but a chain that is broken inside the data file is
+    /// only found by reading it, as `verify_index` does.
+    pub fn validate_offsets(&mut self) -> Vec<InvalidEntry> {
+        let block_size = self.mainfile().block_size();
+        let format = self.index_format();
+        let mut invalid = Vec::new();
+
+        for index in self.indices() {
+            let count = self.index(index).unwrap().last_entry() as u32;
+            for group in 0..count {
+                let entry = match self.index(index).unwrap().entry(group) {
+                    Some(entry) if entry.size() > 0 => entry,
+                    _ => continue,
+                };
+
+                let (block, size) = (entry.block(), entry.size());
+                let available = (block_size - format.block_header_len(group > 0xFFFF)) as u64;
+                let (data_file, entry) = self.route(entry);
+                let num_blocks = data_file.num_blocks().unwrap_or(0);
+
+                // Block 0 is never part of a chain, so it doesn't count towards the room there is
+                let problem = if entry.block() == 0 || entry.block() as u64 >= num_blocks {
+                    OffsetProblem::BlockOutOfRange
+                } else if (size as u64).div_ceil(available) > num_blocks - 1 {
+                    OffsetProblem::SizeOutOfRange
+                } else {
+                    continue;
+                };
+
+                invalid.push(InvalidEntry { index, group, block, size, problem });
+            }
+        }
+
+        invalid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filesystem::{FileSystem, FsError};
+    use crate::test_dir::TestDir;
+    use super::{InvalidEntry, OffsetProblem};
+
+    #[test]
+    fn entries_beyond_the_data_file_are_flagged() {
+        let dir = TestDir::new("offsets");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        for group in 0..3 {
+            fs.write_container(4, group, &[group as u8; 1000]).unwrap();
+        }
+        assert!(fs.validate_offsets().is_empty());
+
+        fs.index(4).unwrap().write_entry(1, 1000, 500).unwrap();
+        fs.index(4).unwrap().write_entry(2, 4000, 5).unwrap();
+        drop(fs);
+
+        let mut fs = FileSystem::new_validated(&dir, false).unwrap();
+        assert_eq!(fs.invalid_entries(), &[
+            InvalidEntry { index: 4, group: 1, block: 500, size: 1000, problem: OffsetProblem::BlockOutOfRange },
+            InvalidEntry { index: 4, group: 2, block: 5, size: 4000, problem: OffsetProblem::SizeOutOfRange },
+        ]);
+        assert!(FileSystem::new(&dir).unwrap().invalid_entries().is_empty());
+
+        assert_eq!(fs.read_container(4, 0).unwrap(), vec![0u8; 1000]);
+        assert!(matches!(fs.read_container(4, 1), Err(FsError::CorruptedData)));
+        let entry = fs.index(4).unwrap().entry(1).unwrap();
+        assert!(matches!(fs.mainfile().try_read_header(entry), Err(FsError::CorruptedData)));
+    }
+}
@@ -0,0 +1,233 @@
+use std::path::Path;
+use std::sync::Arc;
+use crate::bulk::AccessHint;
+use crate::digest::{Digest, Digests};
+use crate::filesystem::{FileSystem, FsError, IndexFormat, LockMode, DEFAULT_BLOCK_SIZE};
+use crate::hooks::{HookSet, Hooks};
+use crate::js5;
+use crate::naming::FileNaming;
+use crate::profile::Profile;
+use crate::transform::{Transform, TransformSlot};
+use crate::whirlpool;
+
+/// Everything about how a filesystem is opened with `FileSystem::open_with`. The defaults open a
+/// standard cache read-only, as `FileSystem::new` does.
+#[derive(Clone, Debug)]
+pub struct FileSystemOptions {
+    writable: bool,
+    lock: LockMode,
+    naming: FileNaming,
+    block_size: usize,
+    index_format: IndexFormat,
+    validate: bool,
+    access_hint: AccessHint,
+    profile: Option<Profile>,
+    download_retries: usize,
+    max_decompressed_size: Option<u64>,
+    metadata_only: bool,
+    hooks: HookSet,
+    transform: TransformSlot,
+    digests: Digests,
+}
+
+impl Default for FileSystemOptions {
+    fn default() -> FileSystemOptions {
+        FileSystemOptions {
+            writable: false,
+            lock: LockMode::default(),
+            naming: FileNaming::default(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            index_format: IndexFormat::default(),
+            validate: false,
+            access_hint: AccessHint::default(),
+            profile: None,
+            download_retries: js5::DEFAULT_DOWNLOAD_RETRIES,
+            max_decompressed_size: None,
+            metadata_only: false,
+            hooks: HookSet::default(),
+            transform: TransformSlot::default(),
+            digests: Digests::default(),
+        }
+    }
+}
+
+impl FileSystemOptions {
+    /// Creates the options for opening a standard cache read-only.
+    pub fn new() -> FileSystemOptions {
+        FileSystemOptions::default()
+    }
+
+    /// Sets whether the cache is opened for writing, creating the mainfile if it doesn't exist.
+    pub fn with_writable(mut self, writable: bool) -> FileSystemOptions {
+        self.writable = writable;
+        self
+    }
+
+    /// Sets how a writable filesystem claims the writer lock. Read-only ones never lock.
+    pub fn with_lock(mut self, lock: LockMode) -> FileSystemOptions {
+        self.lock = lock;
+        self
+    }
+
+    /// Sets the names of the files of the cache.
+    pub fn with_naming(mut self, naming: FileNaming) -> FileSystemOptions {
+        self.naming = naming;
+        self
+    }
+
+    /// Sets the size of a block in the data files, as `MainFile::set_block_size` does.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the block size is not larger than the longest block header of the idx format.
+    pub fn with_block_size(mut self, block_size: usize) -> FileSystemOptions {
+        assert!(block_size > self.index_format.block_header_len(true), "a block must be larger than its header");
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the format of the idx files and block headers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the block size is not larger than the longest block header of the format.
+    pub fn with_index_format(mut self, index_format: IndexFormat) -> FileSystemOptions {
+        assert!(self.block_size > index_format.block_header_len(true), "a block must be larger than its header");
+        self.index_format = index_format;
+        self
+    }
+
+    /// Sets whether every idx entry is checked against the data file when opening, as
+    /// `FileSystem::new_validated` does.
+    pub fn with_validation(mut self, validate: bool) -> FileSystemOptions {
+        self.validate = validate;
+        self
+    }
+
+    /// Sets what bulk operations tell the kernel about reading the data files.
+    pub fn with_access_hint(mut self, access_hint: AccessHint) -> FileSystemOptions {
+        self.access_hint = access_hint;
+        self
+    }
+
+    /// Sets a flavor of the game whose codecs `CompressionMode::Best` is restricted to, as
+    /// `FileSystem::apply_profile` does.
+    pub fn with_profile(mut self, profile: Profile) -> FileSystemOptions {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Sets how many times `sync` asks again for a download that fails its CRC check.
+    pub fn with_download_retries(mut self, download_retries: usize) -> FileSystemOptions {
+        self.download_retries = download_retries;
+        self
+    }
+
+    /// Limits how much a group may decompress to, as `FileSystem::set_max_decompressed_size` does.
+    pub fn with_max_decompressed_size(mut self, max: u64) -> FileSystemOptions {
+        self.max_decompressed_size = Some(max);
+        self
+    }
+
+    /// Sets whether a cache without a data file can be opened, to look at its idx files only.
+    /// Otherwise opening it fails with `FsError::MissingDataFile`, and with this every read of a
+    /// container fails with `FsError::NoFileHandle` instead.
+    pub fn with_metadata_only(mut self, metadata_only: bool) -> FileSystemOptions {
+        self.metadata_only = metadata_only;
+        self
+    }
+
+    /// Sets the hooks called on every read and write of a container, as `FileSystem::set_hooks`
+    /// does.
+    pub fn with_hooks(mut self, hooks: Arc<dyn Hooks>) -> FileSystemOptions {
+        self.hooks = Some(hooks).into();
+        self
+    }
+
+    /// Sets the transform applied to every container read from or written to the data file, as
+    /// `FileSystem::set_transform` does.
+    pub fn with_transform(mut self, transform: Arc<dyn Transform>) -> FileSystemOptions {
+        self.transform = Some(transform).into();
+        self
+    }
+
+    /// Sets the CRC32 used to verify containers and generate tables, as
+    /// `FileSystem::set_crc32_digest` does.
+    pub fn with_crc32_digest(mut self, crc32: Arc<dyn Digest<Output = u32>>) -> FileSystemOptions {
+        self.digests = self.digests.with_crc32(crc32);
+        self
+    }
+
+    /// Sets the whirlpool digest, as `FileSystem::set_whirlpool_digest` does.
+    pub fn with_whirlpool_digest(mut self, whirlpool: Arc<dyn Digest<Output = [u8; whirlpool::DIGEST_LENGTH]>>) -> FileSystemOptions {
+        self.digests = self.digests.with_whirlpool(whirlpool);
+        self
+    }
+}
+
+impl FileSystem {
+    /// Opens a filesystem with every setting at once. The block size and idx format are applied
+    /// before the idx entries are validated, so validation is done against the right layout.
+    pub fn open_with<P: AsRef<Path>>(path: P, options: FileSystemOptions) -> Result<FileSystem, FsError> {
+        let lock = if options.writable { options.lock } else { LockMode::None };
+        let mut fs = FileSystem::open(path.as_ref(), options.writable, lock, options.naming, options.metadata_only)?;
+
+        fs.mainfile().set_block_size(options.block_size);
+        if let Some(secondary) = fs.secondary_mainfile() {
+            secondary.set_block_size(options.block_size);
+        }
+        fs.set_index_format(options.index_format);
+        fs.set_access_hint(options.access_hint);
+        fs.set_download_retries(options.download_retries);
+        fs.set_max_decompressed_size(options.max_decompressed_size);
+        fs.replace_hooks(options.hooks);
+        fs.replace_transform(options.transform);
+        fs.replace_digests(options.digests);
+        if let Some(profile) = options.profile {
+            fs.apply_profile(profile);
+        }
+        if options.validate {
+            fs.validate_on_open();
+        }
+
+        Ok(fs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::bulk::AccessHint;
+    use crate::filesystem::{CompressionType, FileSystem, FsError, IndexFormat};
+    use crate::profile::Profile;
+    use crate::test_dir::TestDir;
+    use super::FileSystemOptions;
+
+    #[test]
+    fn options_are_applied_when_opening() {
+        let dir = TestDir::new("options");
+        let options = FileSystemOptions::new().with_block_size(1024).with_index_format(IndexFormat::Extended);
+
+        let mut fs = FileSystem::open_with(&dir, options.clone().with_writable(true)).unwrap();
+        fs.write_group(2, 0, &[7u8; 3000], CompressionType::None, None).unwrap();
+        drop(fs);
+        assert_eq!(fs::metadata(dir.join("main_file_cache.idx2")).unwrap().len(), 8);
+
+        let mut fs = FileSystem::open_with(&dir, options.with_validation(true).with_profile(Profile::Osrs)
+            .with_access_hint(AccessHint::Sequential).with_max_decompressed_size(2000)).unwrap();
+        assert_eq!(fs.mainfile().block_size(), 1024);
+        assert_eq!(fs.mainfile().num_blocks(), Some(4));
+        assert!(fs.invalid_entries().is_empty());
+        assert_eq!(fs.access_hint(), AccessHint::Sequential);
+        assert!(!fs.allowed_codecs(2).contains(&CompressionType::Lzma));
+        assert_eq!(fs.read_container(2, 0).unwrap()[5..], [7u8; 3000][..]);
+        assert!(matches!(fs.extract_to(2, 0, &mut Vec::new()), Err(FsError::TooLarge)));
+        assert!(matches!(fs.write_group(2, 1, b"x", CompressionType::None, None), Err(FsError::ReadOnly)));
+    }
+
+    #[test]
+    #[should_panic(expected = "a block must be larger than its header")]
+    fn blocks_must_fit_the_extended_header() {
+        let _ = FileSystemOptions::new().with_block_size(11).with_index_format(IndexFormat::Extended);
+    }
+}
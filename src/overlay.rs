@@ -0,0 +1,167 @@
+use std::fs;
+use std::path::Path;
+use crate::container::{CompressionMode, ContainerReader};
+use crate::filesystem::{FileSystem, FsError};
+use crate::options::FileSystemOptions;
+use crate::reference_table::ReferenceTable;
+
+/// A cache seen through a second one laid over it. Groups are read from the layer when it has
+/// them and from the base otherwise, while writes only ever go to the layer, so the base stays
+/// as it was. The layer is an ordinary cache, usually only holding the groups that were changed,
+/// and can be kept as the set of changes to apply to the base later.
+#[derive(Debug)]
+pub struct Overlay {
+    base: FileSystem,
+    layer: FileSystem,
+}
+
+impl Overlay {
+    /// Lays a cache over another. The layer has to be writable.
+    pub fn new(base: FileSystem, layer: FileSystem) -> Result<Overlay, FsError> {
+        if !layer.writable() {
+            return Err(FsError::ReadOnly);
+        }
+
+        Ok(Overlay { base, layer })
+    }
+
+    /// Opens a cache read-only and lays the cache in another folder over it, creating the folder
+    /// if needed. The layer is named the same and has the same block size and idx format as the
+    /// base.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(base: P, layer: Q) -> Result<Overlay, FsError> {
+        let mut base = FileSystem::new(base)?;
+        fs::create_dir_all(layer.as_ref()).map_err(FsError::Io)?;
+
+        let options = FileSystemOptions::new().with_writable(true).with_naming(base.naming().clone())
+            .with_block_size(base.mainfile().block_size()).with_index_format(base.index_format());
+        let layer = FileSystem::open_with(layer, options)?;
+        Overlay::new(base, layer)
+    }
+
+    /// Gets the cache that is read from for everything the layer doesn't have.
+    pub fn base(&mut self) -> &mut FileSystem {
+        &mut self.base
+    }
+
+    /// Gets the cache that holds the changes.
+    pub fn layer(&mut self) -> &mut FileSystem {
+        &mut self.layer
+    }
+
+    /// Separates the base and the layer again.
+    pub fn into_parts(self) -> (FileSystem, FileSystem) {
+        (self.base, self.layer)
+    }
+
+    /// Gets every index of either cache, sorted.
+    pub fn indices(&self) -> Vec<u32> {
+        let mut indices = self.base.indices();
+        indices.extend(self.layer.indices());
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+
+    /// Checks if the layer has a group of its own, which hides the one in the base.
+    pub fn is_overridden(&mut self, index: u32, group: u32) -> bool {
+        self.layer.index(index).and_then(|idx| idx.entry(group)).is_some_and(|entry| entry.size() > 0)
+    }
+
+    /// Gets every group the layer has, in cache order.
+    pub fn overrides(&mut self) -> Vec<(u32, u32)> {
+        let mut overrides = Vec::new();
+        for index in self.layer.indices() {
+            let count = self.layer.index(index).unwrap().last_entry() as u32;
+            overrides.extend((0..count).filter(|group| self.is_overridden(index, *group)).map(|group| (index, group)));
+        }
+
+        overrides
+    }
+
+    /// Reads the raw container of a group from the layer, or from the base if the layer doesn't
+    /// have it.
+    pub fn read_container(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        match self.layer.read_container(index, group) {
+            Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => self.base.read_container(index, group),
+            result => result,
+        }
+    }
+
+    /// Reads and decompresses a group like `read_container`, up to the decompressed size the
+    /// base allows.
+    pub fn read_group(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        let container = self.read_container(index, group)?;
+        self.base.decode_group(&container)
+    }
+
+    /// Reads and decodes the reference table of an index, from the layer if it has one.
+    pub fn reference_table(&mut self, index: u32) -> Result<ReferenceTable, FsError> {
+        ReferenceTable::decode(&mut ContainerReader::new(self.read_container(255, index)?)?)
+    }
+
+    /// Writes the raw container of a group to the layer.
+    pub fn write_container(&mut self, index: u32, group: u32, container: &[u8]) -> Result<(), FsError> {
+        self.layer.write_container(index, group, container)
+    }
+
+    /// Compresses a group and writes it to the layer, like `FileSystem::write_group`.
+    pub fn write_group<M: Into<CompressionMode>>(&mut self, index: u32, group: u32, data: &[u8], mode: M, version: Option<u16>) -> Result<(), FsError> {
+        self.layer.write_group(index, group, data, mode, version)
+    }
+
+    /// Writes the reference table of an index to the layer.
+    pub fn write_reference_table(&mut self, index: u32, table: &ReferenceTable) -> Result<(), FsError> {
+        self.layer.write_reference_table(index, table)
+    }
+
+    /// Drops the group of the layer, so the one in the base shows through again. The blocks it
+    /// used stay in the data file of the layer until it is defragmented.
+    pub fn revert(&mut self, index: u32, group: u32) -> Result<(), FsError> {
+        if self.is_overridden(index, group) {
+            self.layer.index(index).unwrap().write_entry(group, 0, 0)?;
+            self.layer.invalidate_crc(index, group);
+            self.layer.mark_clean(index, group);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+    use super::Overlay;
+
+    #[test]
+    fn layer_hides_the_base_without_changing_it() {
+        let base = TestDir::new("overlay");
+        fs::create_dir_all(base.join("base")).unwrap();
+
+        let mut cache = FileSystem::new_writable(base.join("base")).unwrap();
+        for group in 0..3 {
+            cache.write_group(2, group, &[group as u8; 100], CompressionType::Gzip, None).unwrap();
+        }
+        drop(cache);
+        let original = fs::read(base.join("base/main_file_cache.dat2")).unwrap();
+
+        let mut overlay = Overlay::open(base.join("base"), base.join("mod")).unwrap();
+        overlay.write_group(2, 1, b"modded", CompressionType::Gzip, None).unwrap();
+        overlay.write_group(7, 0, b"new index", CompressionType::None, None).unwrap();
+
+        assert_eq!(overlay.read_group(2, 0).unwrap(), [0u8; 100]);
+        assert_eq!(overlay.read_group(2, 1).unwrap(), b"modded");
+        assert_eq!(overlay.indices(), vec![2, 7]);
+        assert_eq!(overlay.overrides(), vec![(2, 1), (7, 0)]);
+        assert_eq!(fs::read(base.join("base/main_file_cache.dat2")).unwrap(), original);
+
+        overlay.revert(2, 1).unwrap();
+        assert_eq!(overlay.read_group(2, 1).unwrap(), [1u8; 100]);
+        drop(overlay);
+
+        // The layer is a cache of its own, so the changes are still there when reopened
+        let mut overlay = Overlay::open(base.join("base"), base.join("mod")).unwrap();
+        assert_eq!(overlay.overrides(), vec![(7, 0)]);
+    }
+}
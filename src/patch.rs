@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use crate::checksum_table::ChecksumTable;
+use crate::filesystem::{FileSystem, FsError};
+use crate::resume::Journal;
+use crate::update::ContainerUpdate;
+
+/// The magic an encoded patch starts with.
+const MAGIC: [u8; 4] = *b"JS5P";
+
+/// The version of the patch layout written by `PatchFile::encode`.
+const VERSION: u8 = 1;
+
+/// How many bytes of a new container must match the old one before a delta copies them rather
+/// than repeating them.
+const MATCH_LENGTH: usize = 16;
+
+/// The file next to the cache `apply_patch` saves the containers it is about to write in, and
+/// the journal that lists them once they are all there.
+const PATCH_PENDING: &str = "patch.pending";
+const PATCH_JOURNAL: &str = "patch.journal";
+
+/// How a patch carries the new container of a group.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchData {
+    /// The whole new container.
+    Full(Vec<u8>),
+    /// A delta against the old container, made of copies out of it and inserted bytes.
+    Delta(Vec<u8>),
+}
+
+/// A changed or added container in a patch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatchEntry {
+    pub index: u32,
+    pub group: u32,
+    /// The CRC32 of the whole old container, trailer included, or `None` for a group that the
+    /// old cache doesn't have.
+    pub old_crc: Option<u32>,
+    /// The CRC32 of the whole new container, trailer included.
+    pub new_crc: u32,
+    pub data: PatchData,
+}
+
+/// The containers that changed between two revisions of a cache, reference tables included, for
+/// shipping an update without shipping the whole cache. Groups that the new revision no longer
+/// has are not part of a patch.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PatchFile {
+    pub entries: Vec<PatchEntry>,
+}
+
+impl PatchFile {
+    /// Gets the number of bytes of container data the patch carries.
+    pub fn data_len(&self) -> usize {
+        self.entries.iter().map(|entry| match &entry.data {
+            PatchData::Full(data) | PatchData::Delta(data) => data.len(),
+        }).sum()
+    }
+
+    /// Encodes the patch as a magic and version, then for every entry its index, group, flags,
+    /// CRCs and data.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(&MAGIC);
+        out.push(VERSION);
+        out.write_u32::<BigEndian>(self.entries.len() as u32).unwrap();
+
+        for entry in &self.entries {
+            let (delta, data) = match &entry.data {
+                PatchData::Full(data) => (false, data),
+                PatchData::Delta(data) => (true, data),
+            };
+
+            out.push(entry.index as u8);
+            out.write_u32::<BigEndian>(entry.group).unwrap();
+            out.push(entry.old_crc.is_some() as u8 | (delta as u8) << 1);
+            out.write_u32::<BigEndian>(entry.old_crc.unwrap_or(0)).unwrap();
+            out.write_u32::<BigEndian>(entry.new_crc).unwrap();
+            out.write_u32::<BigEndian>(data.len() as u32).unwrap();
+            out.extend(data);
+        }
+
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<PatchFile, FsError> {
+        let mut r = Cursor::new(data);
+        let decode = |r: &mut Cursor<&[u8]>| -> std::io::Result<Option<PatchFile>> {
+            let mut magic = [0u8; 4];
+            r.read_exact(&mut magic)?;
+            if magic != MAGIC || r.read_u8()? != VERSION {
+                return Ok(None);
+            }
+
+            let count = r.read_u32::<BigEndian>()?;
+            let mut entries = Vec::new();
+            for _ in 0..count {
+                let index = r.read_u8()? as u32;
+                let group = r.read_u32::<BigEndian>()?;
+                let flags = r.read_u8()?;
+                let old_crc = r.read_u32::<BigEndian>()?;
+                let new_crc = r.read_u32::<BigEndian>()?;
+
+                // Check the length against what is left first, a corrupt one could be gigabytes
+                let len = r.read_u32::<BigEndian>()? as u64;
+                if len > r.get_ref().len() as u64 - r.position() {
+                    return Ok(None);
+                }
+                let mut data = vec![0u8; len as usize];
+                r.read_exact(&mut data)?;
+
+                let old_crc = if flags & 1 != 0 { Some(old_crc) } else { None };
+                let data = if flags & 2 != 0 { PatchData::Delta(data) } else { PatchData::Full(data) };
+                entries.push(PatchEntry { index, group, old_crc, new_crc, data });
+            }
+
+            Ok(Some(PatchFile { entries }))
+        };
+
+        decode(&mut r).ok().flatten().ok_or(FsError::CorruptedData)
+    }
+}
+
+/// Makes a patch that turns one revision of a cache into another. Every container of the new
+/// cache that differs from the old one is part of it, and with `deltas` those that the old cache
+/// has are stored as a delta against it when that comes out smaller. Compressed containers
+/// rarely have much in common between revisions, so most of them stay whole either way.
+pub fn make_patch(old: &mut FileSystem, new: &mut FileSystem, deltas: bool) -> Result<PatchFile, FsError> {
+    let mut patch = PatchFile::default();
+
+    // Groups go before the reference tables that describe them
+    let mut indices = new.indices();
+    indices.sort_by_key(|index| *index == 255);
+
+    for index in indices {
+        let count = new.index(index).unwrap().last_entry() as u32;
+        for group in 0..count {
+            let data = match new.read_container(index, group) {
+                Ok(data) => data,
+                Err(FsError::EntryNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+
+            let previous = match old.read_container(index, group) {
+                Ok(previous) if previous == data => continue,
+                Ok(previous) => Some(previous),
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => None,
+                Err(e) => return Err(e),
+            };
+
+            let new_crc = crc32fast::hash(&data);
+            let data = match &previous {
+                Some(previous) if deltas => {
+                    let delta = make_delta(previous, &data);
+                    if delta.len() < data.len() { PatchData::Delta(delta) } else { PatchData::Full(data) }
+                }
+                _ => PatchData::Full(data),
+            };
+
+            patch.entries.push(PatchEntry { index, group, old_crc: previous.map(|p| crc32fast::hash(&p)), new_crc, data });
+        }
+    }
+
+    Ok(patch)
+}
+
+impl FileSystem {
+    /// Applies a patch made by `make_patch` and returns the resulting master checksum table.
+    ///
+    /// Every container the patch replaces must still be the one it was made against, and every
+    /// group it adds must not exist yet, or the patch is rejected with `FsError::CrcMismatch`.
+    /// The new containers are rebuilt and checked against their CRCs, then handed to
+    /// `apply_update` in copy-on-write mode, so a rejected patch leaves the cache untouched.
+    ///
+    /// What the update staged, the groups and the reference tables describing them, is then
+    /// saved next to the cache behind a journal before any of it goes to the data file. If
+    /// writing fails or is interrupted, the cache is left with some new containers next to old
+    /// tables, and `recover_patch` finishes the writes. It is called before every patch, so an
+    /// interrupted one is finished before the next is checked. In copy-on-write mode, the patch
+    /// goes to the layer like any other write and nothing is journaled.
+    pub fn apply_patch(&mut self, patch: &PatchFile) -> Result<ChecksumTable, FsError> {
+        if self.is_copy_on_write() {
+            let updates = self.patch_updates(patch)?;
+            return self.apply_update(&updates);
+        }
+        if !self.writable() {
+            return Err(FsError::ReadOnly);
+        }
+        self.recover_patch()?;
+
+        let updates = self.patch_updates(patch)?;
+        self.begin_copy_on_write();
+        let table = match self.apply_update(&updates) {
+            Ok(table) => table,
+            Err(e) => {
+                self.discard_writes();
+                return Err(e);
+            }
+        };
+        let staged = self.replace_cow_layer(None).unwrap_or_default();
+
+        // The containers have to be on disk before the journal says they are complete
+        let (journal, pending) = (self.path().join(PATCH_JOURNAL), self.path().join(PATCH_PENDING));
+        let mut data = Vec::new();
+        let mut records = Vec::with_capacity(staged.len());
+        for ((index, group), container) in &staged {
+            records.push([*index as u64, *group as u64, data.len() as u64, container.len() as u64, crc32fast::hash(container) as u64]);
+            data.extend(container);
+        }
+        let mut file = File::create(&pending).map_err(FsError::Io)?;
+        file.write_all(&data).map_err(FsError::Io)?;
+        file.sync_all().map_err(FsError::Io)?;
+        let mut journal = Journal::create(&journal)?;
+        for record in &records {
+            journal.append(record)?;
+        }
+        journal.append(&[records.len() as u64])?;
+        journal.checkpoint()?;
+
+        for ((index, group), container) in &staged {
+            self.write_container(*index, *group, container)?;
+        }
+        for (index, group) in staged.keys() {
+            self.mark_clean(*index, *group);
+        }
+
+        // Throwing the journal away before the containers reach the disk could lose them both
+        self.sync_all()?;
+        journal.remove()?;
+        fs::remove_file(&pending).map_err(FsError::Io)?;
+        Ok(table)
+    }
+
+    /// Finishes writing a patch that `apply_patch` was interrupted in, and returns whether there
+    /// was one. A patch whose journal wasn't complete hadn't written anything yet, so it is just
+    /// thrown away.
+    pub fn recover_patch(&mut self) -> Result<bool, FsError> {
+        let (journal, pending) = (self.path().join(PATCH_JOURNAL), self.path().join(PATCH_PENDING));
+        let records = Journal::read(&journal)?;
+        let complete = matches!(records.last().map(Vec::as_slice), Some([count]) if *count as usize + 1 == records.len());
+
+        if complete {
+            let data = fs::read(&pending).map_err(FsError::Io)?;
+            let mut written = Vec::with_capacity(records.len() - 1);
+            for record in &records[..records.len() - 1] {
+                let (index, group, container) = match record.as_slice() {
+                    [index, group, offset, length, crc] => {
+                        let container = offset.checked_add(*length).and_then(|end| data.get(*offset as usize..end as usize))
+                            .filter(|container| crc32fast::hash(container) as u64 == *crc).ok_or(FsError::CorruptedData)?;
+                        (*index as u32, *group as u32, container)
+                    }
+                    _ => return Err(FsError::CorruptedData),
+                };
+                self.write_container(index, group, container)?;
+                written.push((index, group));
+            }
+            for (index, group) in written {
+                self.mark_clean(index, group);
+            }
+            self.sync_all()?;
+        }
+
+        for path in [&pending, &journal] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(FsError::Io(e)),
+                _ => {}
+            }
+        }
+        Ok(complete)
+    }
+
+    /// Rebuilds the new containers of a patch and checks them, as `apply_patch` describes.
+    fn patch_updates(&mut self, patch: &PatchFile) -> Result<Vec<ContainerUpdate>, FsError> {
+        let mut updates = Vec::with_capacity(patch.entries.len());
+
+        for entry in &patch.entries {
+            let current = match self.read_container(entry.index, entry.group) {
+                Ok(current) => Some(current),
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => None,
+                Err(e) => return Err(e),
+            };
+
+            if current.as_ref().map(|c| crc32fast::hash(c)) != entry.old_crc {
+                return Err(FsError::CrcMismatch);
+            }
+
+            let data = match &entry.data {
+                PatchData::Full(data) => data.clone(),
+                PatchData::Delta(delta) => apply_delta(current.as_deref().ok_or(FsError::CorruptedData)?, delta)?,
+            };
+
+            if crc32fast::hash(&data) != entry.new_crc {
+                return Err(FsError::CrcMismatch);
+            }
+
+            updates.push(ContainerUpdate::new(entry.index, entry.group, data));
+        }
+
+        Ok(updates)
+    }
+}
+
+/// Encodes the new bytes as copies out of the old ones where runs of at least `MATCH_LENGTH`
+/// bytes match, and inserts for the rest. A copy is a 1 followed by the offset and length, an
+/// insert a 0 followed by the length and the bytes.
+pub fn make_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in old.chunks_exact(MATCH_LENGTH).enumerate() {
+        blocks.entry(block).or_insert(i * MATCH_LENGTH);
+    }
+
+    let mut delta = Vec::new();
+    let mut literal_start = 0;
+    let mut position = 0;
+
+    let flush = |delta: &mut Vec<u8>, literal: &[u8]| {
+        if !literal.is_empty() {
+            delta.push(0);
+            delta.write_u32::<BigEndian>(literal.len() as u32).unwrap();
+            delta.extend(literal);
+        }
+    };
+
+    while position + MATCH_LENGTH <= new.len() {
+        let offset = match blocks.get(&new[position..position + MATCH_LENGTH]) {
+            Some(offset) => *offset,
+            None => {
+                position += 1;
+                continue;
+            }
+        };
+
+        let length = old[offset..].iter().zip(&new[position..]).take_while(|(a, b)| a == b).count();
+        flush(&mut delta, &new[literal_start..position]);
+        delta.push(1);
+        delta.write_u32::<BigEndian>(offset as u32).unwrap();
+        delta.write_u32::<BigEndian>(length as u32).unwrap();
+
+        position += length;
+        literal_start = position;
+    }
+
+    flush(&mut delta, &new[literal_start..]);
+    delta
+}
+
+/// Rebuilds the new bytes from the old ones and a delta made by `make_delta`.
+pub fn apply_delta(old: &[u8], delta: &[u8]) -> Result<Vec<u8>, FsError> {
+    let mut r = Cursor::new(delta);
+    let mut out = Vec::new();
+
+    while (r.position() as usize) < delta.len() {
+        let op = r.read_u8()?;
+        let first = r.read_u32::<BigEndian>()? as usize;
+        match op {
+            0 => {
+                let start = r.position() as usize;
+                let end = start.checked_add(first).ok_or(FsError::CorruptedData)?;
+                out.extend(delta.get(start..end).ok_or(FsError::CorruptedData)?);
+                r.set_position(end as u64);
+            }
+            1 => {
+                let length = r.read_u32::<BigEndian>()? as usize;
+                let end = first.checked_add(length).ok_or(FsError::CorruptedData)?;
+                out.extend(old.get(first..end).ok_or(FsError::CorruptedData)?);
+            }
+            _ => return Err(FsError::CorruptedData),
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::reference_table::ReferenceTable;
+    use crate::test_dir::TestDir;
+    use crate::transform::Transform;
+    use crate::update::ContainerUpdate;
+    use super::{PatchData, PatchFile};
+
+    #[test]
+    fn patch_holds_only_changed_containers() {
+        let base = TestDir::new("patch");
+        fs::create_dir_all(base.join("old")).unwrap();
+        fs::create_dir_all(base.join("new")).unwrap();
+
+        let mut old = FileSystem::new_writable(base.join("old")).unwrap();
+        let mut new = FileSystem::new_writable(base.join("new")).unwrap();
+        let mut model: Vec<u8> = (0..2000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for fs in [&mut old, &mut new] {
+            fs.write_group(1, 0, &[1u8; 50], CompressionType::Gzip, Some(1)).unwrap();
+            fs.write_group(1, 1, &model, CompressionType::None, Some(1)).unwrap();
+        }
+
+        model[1000] ^= 0xFF;
+        new.write_group(1, 1, &model, CompressionType::None, Some(2)).unwrap();
+        new.write_group(1, 2, &[3u8; 50], CompressionType::Gzip, Some(1)).unwrap();
+
+        let patch = super::make_patch(&mut old, &mut new, true).unwrap();
+        let changed: Vec<(u32, u32, bool)> = patch.entries.iter()
+            .map(|e| (e.index, e.group, e.old_crc.is_some())).collect();
+        assert_eq!(changed, vec![(1, 1, true), (1, 2, false)]);
+
+        // One flipped byte costs a few copies and an insert rather than the whole container
+        let delta = match &patch.entries[0].data {
+            PatchData::Delta(delta) => delta,
+            PatchData::Full(_) => panic!("expected a delta"),
+        };
+        assert!(delta.len() < 100);
+        let rebuilt = super::apply_delta(&old.read_container(1, 1).unwrap(), delta).unwrap();
+        assert_eq!(rebuilt, new.read_container(1, 1).unwrap());
+        assert_eq!(container::decode(&rebuilt).unwrap(), model);
+
+        assert_eq!(PatchFile::decode(&patch.encode()).unwrap(), patch);
+
+        // A length that runs past the end of the patch is corrupt rather than allocated
+        let mut hostile = super::MAGIC.to_vec();
+        hostile.extend([super::VERSION, 0, 0, 0, 1, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        hostile.extend(u32::MAX.to_be_bytes());
+        assert!(matches!(PatchFile::decode(&hostile), Err(FsError::CorruptedData)));
+    }
+
+    #[test]
+    fn apply_patch_reproduces_the_new_revision() {
+        let base = TestDir::new("apply-patch");
+        fs::create_dir_all(base.join("old")).unwrap();
+        fs::create_dir_all(base.join("new")).unwrap();
+
+        let mut old = FileSystem::new_writable(base.join("old")).unwrap();
+        let mut new = FileSystem::new_writable(base.join("new")).unwrap();
+        for fs in [&mut old, &mut new] {
+            fs.write_reference_table(3, &ReferenceTable::new(6)).unwrap();
+            let group = container::encode(&[1u8; 400], CompressionType::None, Some(1)).unwrap();
+            fs.apply_update(&[ContainerUpdate::new(3, 0, group)]).unwrap();
+        }
+
+        let changed = (0..2).map(|g| ContainerUpdate::new(3, g, container::encode(&[g as u8 + 5; 400], CompressionType::None, Some(2)).unwrap()));
+        new.apply_update(&changed.collect::<Vec<_>>()).unwrap();
+
+        let patch = super::make_patch(&mut old, &mut new, true).unwrap();
+        assert_eq!(patch.entries.iter().map(|e| (e.index, e.group)).collect::<Vec<_>>(), vec![(3, 0), (3, 1), (255, 3)]);
+        assert_eq!(old.apply_patch(&patch).unwrap(), new.checksum_table().unwrap());
+        assert_eq!(old.read_container(3, 1).unwrap(), new.read_container(3, 1).unwrap());
+
+        // The cache is no longer the revision the patch was made against
+        let before = old.checksum_table().unwrap();
+        assert!(matches!(old.apply_patch(&patch), Err(FsError::CrcMismatch)));
+        assert_eq!(old.checksum_table().unwrap(), before);
+    }
+
+    /// Stores containers as they are, but fails to store the reference table of index 3.
+    struct FailingTable;
+
+    impl Transform for FailingTable {
+        fn decode(&self, _index: u32, _group: u32, stored: Vec<u8>) -> Result<Vec<u8>, FsError> {
+            Ok(stored)
+        }
+
+        fn encode(&self, index: u32, group: u32, container: Vec<u8>) -> Result<Vec<u8>, FsError> {
+            if (index, group) == (255, 3) { Err(FsError::WriteFailed) } else { Ok(container) }
+        }
+    }
+
+    #[test]
+    fn interrupted_patches_are_finished() {
+        let base = TestDir::new("recover-patch");
+        fs::create_dir_all(base.join("old")).unwrap();
+        fs::create_dir_all(base.join("new")).unwrap();
+
+        let mut old = FileSystem::new_writable(base.join("old")).unwrap();
+        let mut new = FileSystem::new_writable(base.join("new")).unwrap();
+        for fs in [&mut old, &mut new] {
+            fs.write_group(3, 0, &[1u8; 400], CompressionType::None, Some(1)).unwrap();
+            fs.refresh_reference_tables().unwrap();
+        }
+        new.write_group(3, 0, &[2u8; 400], CompressionType::None, Some(2)).unwrap();
+        new.refresh_reference_tables().unwrap();
+        let patch = super::make_patch(&mut old, &mut new, false).unwrap();
+
+        // The group is written, but its table isn't
+        old.set_transform(Some(Arc::new(FailingTable)));
+        assert!(matches!(old.apply_patch(&patch), Err(FsError::WriteFailed)));
+        old.set_transform(None);
+        assert_eq!(old.read_container(3, 0).unwrap(), new.read_container(3, 0).unwrap());
+        assert_ne!(old.read_container(255, 3).unwrap(), new.read_container(255, 3).unwrap());
+
+        assert!(old.recover_patch().unwrap());
+        assert_eq!(old.checksum_table().unwrap(), new.checksum_table().unwrap());
+        assert!(!old.recover_patch().unwrap());
+        assert!(!base.join("old").join(super::PATCH_JOURNAL).exists());
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use crate::container;
+use crate::filesystem::{FileSystem, FsError};
+use crate::options::FileSystemOptions;
+
+/// A number of read-only filesystems over the same cache, each with its own handles on the data
+/// and idx files, for reading from many threads at once. Every read seeks its handle first, so
+/// threads sharing one filesystem would take turns on it; with a pool they only wait when every
+/// reader is busy.
+#[derive(Debug)]
+pub struct ReaderPool {
+    idle: Mutex<Vec<FileSystem>>,
+    returned: Condvar,
+    size: usize,
+    /// The groups handed out by `read_group_shared`.
+    shared: Mutex<HashMap<(u32, u32), Shared>>,
+}
+
+/// A group kept by `read_group_shared`, with the CRC of the container it was decompressed from.
+type Shared = (u32, Arc<[u8]>);
+
+/// A reader taken from a `ReaderPool`, which goes back to it once dropped.
+#[derive(Debug)]
+pub struct PooledReader<'a> {
+    pool: &'a ReaderPool,
+    reader: Option<FileSystem>,
+}
+
+impl ReaderPool {
+    /// Gets the number of readers in the pool.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Takes a reader out of the pool, waiting for one to come back if all of them are taken.
+    pub fn get(&self) -> PooledReader<'_> {
+        let mut idle = self.lock();
+        loop {
+            if let Some(reader) = idle.pop() {
+                return PooledReader { pool: self, reader: Some(reader) };
+            }
+            idle = self.returned.wait(idle).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Reads a raw container with whichever reader is free.
+    pub fn read_container(&self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        self.get().read_container(index, group)
+    }
+
+    /// Reads and decompresses a group with whichever reader is free. The reader goes back to the
+    /// pool before the container is decompressed.
+    pub fn read_group(&self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+        let mut reader = self.get();
+        let (container, limit) = (reader.read_container(index, group)?, reader.max_decompressed_size());
+        drop(reader);
+        container::decode_limited(&container, limit.unwrap_or(u64::MAX))
+    }
+
+    /// Reads and decompresses a group like `read_group`, but keeps the data so every later call
+    /// for the group hands out the same buffer rather than a copy, for servers that send the
+    /// same groups to many sessions. A kept group is checked against the memoized CRC of its
+    /// container, as `FileSystem::crc` gives it, so a group written since is read again. The
+    /// groups are kept until `clear_shared` is called.
+    pub fn read_group_shared(&self, index: u32, group: u32) -> Result<Arc<[u8]>, FsError> {
+        let mut reader = self.get();
+        let crc = reader.crc(index, group)?;
+        if let Some((_, data)) = self.lock_shared().get(&(index, group)).filter(|(kept, _)| *kept == crc) {
+            return Ok(data.clone());
+        }
+
+        let container = reader.read_container(index, group)?;
+        let crc = reader.digest_set().container_crc(&container)?;
+        let limit = reader.max_decompressed_size();
+        drop(reader);
+
+        let data: Arc<[u8]> = container::decode_limited(&container, limit.unwrap_or(u64::MAX))?.into();
+        self.lock_shared().insert((index, group), (crc, data.clone()));
+        Ok(data)
+    }
+
+    /// Drops the groups kept by `read_group_shared`. Buffers that were handed out stay valid.
+    pub fn clear_shared(&self) {
+        self.lock_shared().clear();
+    }
+
+    fn lock_shared(&self) -> MutexGuard<'_, HashMap<(u32, u32), Shared>> {
+        self.shared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Readers are only ever taken out and put back under the lock, so a thread that panicked
+    /// while holding it can't have left the list half changed.
+    fn lock(&self) -> MutexGuard<'_, Vec<FileSystem>> {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Deref for PooledReader<'_> {
+    type Target = FileSystem;
+
+    fn deref(&self) -> &FileSystem {
+        self.reader.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledReader<'_> {
+    fn deref_mut(&mut self) -> &mut FileSystem {
+        self.reader.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledReader<'_> {
+    fn drop(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            self.pool.lock().push(reader);
+            self.pool.returned.notify_one();
+        }
+    }
+}
+
+impl FileSystem {
+    /// Opens a pool of read-only filesystems over the same folder as this one, with the same file
+    /// naming, block size, idx format, hooks and transform. There is always at least one reader. Writes made
+    /// through this filesystem afterwards are seen by the readers, as they read the same files.
+    /// A filesystem that wasn't opened from a folder can't be reopened, and gives
+    /// `FsError::NoFileHandle`.
+    pub fn reader_pool(&mut self, size: usize) -> Result<ReaderPool, FsError> {
+        if self.path().as_os_str().is_empty() {
+            return Err(FsError::NoFileHandle);
+        }
+
+        let options = FileSystemOptions::new().with_naming(self.naming().clone())
+            .with_block_size(self.mainfile().block_size()).with_index_format(self.index_format());
+
+        let size = size.max(1);
+        let mut readers = Vec::with_capacity(size);
+        for _ in 0..size {
+            let mut reader = FileSystem::open_with(self.path(), options.clone())?;
+            reader.set_hooks(self.hooks());
+            reader.set_transform(self.transform());
+            reader.replace_digests(self.digest_set().clone());
+            reader.set_max_decompressed_size(self.max_decompressed_size());
+            readers.push(reader);
+        }
+
+        Ok(ReaderPool { idle: Mutex::new(readers), returned: Condvar::new(), size, shared: Mutex::new(HashMap::new()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use crate::filesystem::{CompressionType, FileSystem, FsError, MainFile};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn pooled_readers_are_shared_between_threads() {
+        let dir = TestDir::new("pool");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        for group in 0..20u32 {
+            fs.write_group(3, group, &vec![group as u8; 700 * group as usize], CompressionType::Gzip, None).unwrap();
+        }
+
+        let pool = fs.reader_pool(2).unwrap();
+        assert_eq!(pool.size(), 2);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for group in 0..20u32 {
+                        assert_eq!(pool.read_group(3, group).unwrap(), vec![group as u8; 700 * group as usize]);
+                    }
+                });
+            }
+        });
+
+        // Readers read the same files, so they see what was written after they were opened
+        fs.write_group(3, 20, b"late", CompressionType::None, None).unwrap();
+        assert_eq!(pool.get().index(3).unwrap().last_entry(), 21);
+        assert_eq!(pool.read_group(3, 20).unwrap(), b"late");
+
+        // Shared reads hand out the same buffer until the group changes
+        let shared = pool.read_group_shared(3, 5).unwrap();
+        assert!(Arc::ptr_eq(&shared, &pool.read_group_shared(3, 5).unwrap()));
+        fs.write_group(3, 5, b"rewritten", CompressionType::None, None).unwrap();
+        assert_eq!(&pool.read_group_shared(3, 5).unwrap()[..], b"rewritten");
+        assert_eq!(shared[..], vec![5u8; 3500][..]);
+
+        let mainfile = MainFile::from_reader(std::io::Cursor::new(Vec::new())).unwrap();
+        assert!(matches!(FileSystem::from_parts(mainfile, Vec::new()).reader_pool(1), Err(FsError::NoFileHandle)));
+    }
+}
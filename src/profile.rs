@@ -0,0 +1,287 @@
+use std::ops::RangeInclusive;
+use crate::filesystem::{CompressionType, FileSystem, FsError};
+use crate::js5::Prefetch;
+
+/// A flavor of the game, and what its caches are expected to look like. The limits are what the
+/// clients of that flavor are known to use, so a cache that goes past them was most likely made
+/// for another flavor or repacked by a tool.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Profile {
+    /// Old School RuneScape.
+    Osrs,
+    /// RuneScape 2 at revision 667.
+    Rs2_667,
+    /// RuneScape 3 before its caches moved to SQLite files.
+    Rs3Legacy,
+}
+
+impl Profile {
+    /// Every known flavor, from the one with the fewest indices to the one with the most.
+    pub const ALL: [Profile; 3] = [Profile::Osrs, Profile::Rs2_667, Profile::Rs3Legacy];
+
+    /// Gets the highest index id the flavor uses, not counting the reference tables at 255.
+    pub fn max_index(self) -> u32 {
+        match self {
+            Profile::Osrs => 24,
+            Profile::Rs2_667 => 36,
+            Profile::Rs3Legacy => 61,
+        }
+    }
+
+    /// Gets the reference table protocol versions the flavor writes.
+    pub fn table_versions(self) -> RangeInclusive<u8> {
+        match self {
+            Profile::Osrs | Profile::Rs2_667 => 5..=6,
+            Profile::Rs3Legacy => 5..=7,
+        }
+    }
+
+    /// Checks if the reference tables of the flavor have whirlpool digests.
+    pub fn uses_whirlpool(self) -> bool {
+        self != Profile::Osrs
+    }
+
+    /// Gets the codecs the client of the flavor can decompress.
+    pub fn codecs(self) -> &'static [CompressionType] {
+        match self {
+            Profile::Osrs | Profile::Rs2_667 => &[CompressionType::None, CompressionType::Bzip2, CompressionType::Gzip],
+            Profile::Rs3Legacy => &[CompressionType::None, CompressionType::Bzip2, CompressionType::Gzip, CompressionType::Lzma],
+        }
+    }
+
+    /// Gets the prefetch list the js5 server of the flavor sends after the handshake, in the
+    /// order its client loads them in: the defaults, the native libraries, the shaders and the
+    /// configs, then the huffman table, the interfaces, the scripts and the world map. Old
+    /// School servers send no list, and the one of RS3 changes from build to build, so those
+    /// flavors have none.
+    pub fn prefetch_list(self) -> Option<Vec<Prefetch>> {
+        if self != Profile::Rs2_667 {
+            return None;
+        }
+
+        let natives = ["jaclib", "jaggl", "jagdx", "jagmisc", "sw3d", "hw3d", "jagtheora"];
+        let mut list = vec![Prefetch::Index(28)];
+        list.extend(natives.iter().map(|name| Prefetch::Named(30, name.to_string())));
+        list.extend([31, 26, 2, 16, 17, 18, 19, 20, 21, 22, 24, 25, 27, 29].iter().map(|index| Prefetch::Index(*index)));
+        list.push(Prefetch::Named(10, "huffman".to_string()));
+        list.extend([3, 12, 13].iter().map(|index| Prefetch::Index(*index)));
+        list.push(Prefetch::Named(23, "details".to_string()));
+        Some(list)
+    }
+}
+
+/// Something about a cache that doesn't match a `Profile`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProfileMismatch {
+    /// The cache has an index the flavor doesn't use.
+    UnexpectedIndex(u32),
+    /// The reference table of an index has a protocol version the flavor doesn't write.
+    TableVersion { index: u32, version: u8 },
+    /// The reference table of an index has whirlpool digests where the flavor has none, or the
+    /// other way around.
+    Whirlpool { index: u32, present: bool },
+    /// A group is compressed with a codec the client of the flavor can't decompress.
+    Compression { index: u32, group: u32, compression: CompressionType },
+}
+
+/// A guess at what game build a cache belongs to, from what `FileSystem::detect_revision` could
+/// see. Caches don't record the build they were made for, so the build is narrowed down to a
+/// flavor, and the highest reference table revision orders caches of the same flavor, as it only
+/// grows with every update.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RevisionEstimate {
+    /// The flavor the cache matches best, or `None` if the cache has no indices to go by.
+    pub profile: Option<Profile>,
+    /// How many things about the cache don't match each flavor, in the order of `Profile::ALL`.
+    pub mismatches: Vec<(Profile, usize)>,
+    /// The highest index id of the cache, not counting the reference tables at 255.
+    pub highest_index: Option<u32>,
+    /// The lowest and highest reference table protocol versions of the cache.
+    pub table_versions: Option<(u8, u8)>,
+    /// The highest revision of a reference table, if any table has one.
+    pub revision: Option<u32>,
+}
+
+impl FileSystem {
+    /// Estimates the build of the cache. The cache is checked against every known flavor, and
+    /// the one with the fewest mismatches wins, the one with the fewest indices on a tie, as a
+    /// cache that fits a smaller flavor fits the larger ones too. This reads the reference table
+    /// of every index and the header of every group, as `check_profile` does.
+    pub fn detect_revision(&mut self) -> Result<RevisionEstimate, FsError> {
+        let indices: Vec<u32> = self.indices().into_iter().filter(|index| *index != 255).collect();
+
+        let (mut table_versions, mut revision): (Option<(u8, u8)>, Option<u32>) = (None, None);
+        for &index in &indices {
+            let table = match self.reference_table(index) {
+                Ok(table) => table,
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+
+            let version = table.version();
+            table_versions = Some(table_versions.map_or((version, version), |(low, high)| (low.min(version), high.max(version))));
+            // Protocol 5 tables have no revision
+            if version >= 6 {
+                revision = Some(revision.map_or(table.revision(), |highest| highest.max(table.revision())));
+            }
+        }
+
+        let mut mismatches = Vec::with_capacity(Profile::ALL.len());
+        for profile in Profile::ALL {
+            mismatches.push((profile, self.check_profile(profile)?.len()));
+        }
+
+        let profile = if indices.is_empty() {
+            None
+        } else {
+            mismatches.iter().min_by_key(|(_, count)| *count).map(|(profile, _)| *profile)
+        };
+
+        Ok(RevisionEstimate { profile, mismatches, highest_index: indices.iter().copied().max(), table_versions, revision })
+    }
+
+    /// Checks the cache against what caches of a flavor look like, and returns everything that
+    /// doesn't match in cache order. The header of every group is read for its compression, but
+    /// nothing is decompressed.
+    pub fn check_profile(&mut self, profile: Profile) -> Result<Vec<ProfileMismatch>, FsError> {
+        let mut mismatches = Vec::new();
+
+        for index in self.indices() {
+            if index != 255 && index > profile.max_index() {
+                mismatches.push(ProfileMismatch::UnexpectedIndex(index));
+            }
+
+            if index != 255 {
+                match self.reference_table(index) {
+                    Ok(table) => {
+                        if !profile.table_versions().contains(&table.version()) {
+                            mismatches.push(ProfileMismatch::TableVersion { index, version: table.version() });
+                        }
+                        if table.flags().has_whirlpool() != profile.uses_whirlpool() {
+                            mismatches.push(ProfileMismatch::Whirlpool { index, present: table.flags().has_whirlpool() });
+                        }
+                    }
+                    Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let count = self.index(index).unwrap().last_entry() as u32;
+            for group in 0..count {
+                let compression = match self.group_size(index, group) {
+                    Ok(size) => size.compression,
+                    Err(FsError::EntryNotFound) => continue,
+                    Err(e) => return Err(e),
+                };
+
+                if !profile.codecs().contains(&compression) {
+                    mismatches.push(ProfileMismatch::Compression { index, group, compression });
+                }
+            }
+        }
+
+        Ok(mismatches)
+    }
+
+    /// Works out the standard prefetch list of a flavor for this cache with `prefetch_sizes`.
+    /// Indices and groups the cache doesn't have count as 0 bytes, as a server has to send a
+    /// number for every entry. A flavor without a list gives an empty one.
+    pub fn standard_prefetch_sizes(&mut self, profile: Profile) -> Result<Vec<u32>, FsError> {
+        let list = profile.prefetch_list().unwrap_or_default();
+        let mut sizes = Vec::with_capacity(list.len());
+        for prefetch in list {
+            match self.prefetch_sizes(&[prefetch]) {
+                Ok(size) => sizes.extend(size),
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => sizes.push(0),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sizes)
+    }
+
+    /// Restricts the codecs `CompressionMode::Best` may pick for every index of a flavor to those
+    /// its client can decompress.
+    pub fn apply_profile(&mut self, profile: Profile) {
+        for index in (0..=profile.max_index()).chain(Some(255)) {
+            self.set_allowed_codecs(index, profile.codecs().to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::js5::Prefetch;
+    use crate::reference_table::{ReferenceTable, ReferenceTableFlags};
+    use crate::test_dir::TestDir;
+    use super::{Profile, ProfileMismatch};
+
+    #[test]
+    fn mismatches_with_a_flavor_are_reported() {
+        let dir = TestDir::new("profile");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_reference_table(2, &ReferenceTable::new(7)).unwrap();
+        fs.write_group(2, 0, b"config", CompressionType::Gzip, None).unwrap();
+        fs.write_container(2, 1, &[3, 0, 0, 0, 1, 0, 0, 0, 1, 0]).unwrap();
+        fs.write_group(40, 0, b"new content", CompressionType::Bzip2, None).unwrap();
+
+        assert_eq!(fs.check_profile(Profile::Osrs).unwrap(), vec![
+            ProfileMismatch::TableVersion { index: 2, version: 7 },
+            ProfileMismatch::Compression { index: 2, group: 1, compression: CompressionType::Lzma },
+            ProfileMismatch::UnexpectedIndex(40),
+        ]);
+        assert_eq!(fs.check_profile(Profile::Rs3Legacy).unwrap(), vec![ProfileMismatch::Whirlpool { index: 2, present: false }]);
+
+        fs.apply_profile(Profile::Osrs);
+        assert!(!fs.allowed_codecs(2).contains(&CompressionType::Lzma));
+    }
+
+    #[test]
+    fn the_closest_flavor_is_detected() {
+        let dir = TestDir::new("detect");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        assert_eq!(fs.detect_revision().unwrap().profile, None);
+
+        for index in 0..=30 {
+            let mut table = ReferenceTable::new(6);
+            table.set_flags(ReferenceTableFlags::new().with_whirlpool(true)).unwrap();
+            table.set_revision(100 + index);
+            fs.write_reference_table(index, &table).unwrap();
+            fs.write_group(index, 0, b"content", CompressionType::Gzip, None).unwrap();
+        }
+
+        // Fits both the 667 and RS3 flavors, but the 667 one is the smaller
+        let estimate = fs.detect_revision().unwrap();
+        assert_eq!(estimate.profile, Some(Profile::Rs2_667));
+        assert_eq!(estimate.mismatches, vec![(Profile::Osrs, 37), (Profile::Rs2_667, 0), (Profile::Rs3Legacy, 0)]);
+        assert_eq!((estimate.highest_index, estimate.table_versions, estimate.revision), (Some(30), Some((6, 6)), Some(130)));
+
+        fs.write_container(3, 1, &[3, 0, 0, 0, 1, 0, 0, 0, 1, 0]).unwrap();
+        assert_eq!(fs.detect_revision().unwrap().profile, Some(Profile::Rs3Legacy));
+    }
+
+    #[test]
+    fn standard_prefetch_lists_are_sized_from_the_cache() {
+        let dir = TestDir::new("prefetch-profile");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_group(28, 0, &[1u8; 50], CompressionType::None, None).unwrap();
+        fs.write_group(10, 0, &[2u8; 114], CompressionType::None, None).unwrap();
+        fs.refresh_reference_tables().unwrap();
+        let mut table = fs.reference_table(10).unwrap();
+        table.set_flags(table.flags().with_names(true)).unwrap();
+        table.lookup_mut(0).unwrap().set_name_hash(ReferenceTable::hash_name("huffman"));
+        fs.write_reference_table(10, &table).unwrap();
+
+        let list = Profile::Rs2_667.prefetch_list().unwrap();
+        assert_eq!(list.len(), 27);
+        assert_eq!((&list[0], &list[22]), (&Prefetch::Index(28), &Prefetch::Named(10, "huffman".to_string())));
+
+        let sizes = fs.standard_prefetch_sizes(Profile::Rs2_667).unwrap();
+        assert_eq!((sizes.len(), sizes[0], sizes[22]), (27, 55, 119));
+        assert_eq!(sizes.iter().sum::<u32>(), 55 + 119);
+        assert!(Profile::Osrs.prefetch_list().is_none());
+        assert!(fs.standard_prefetch_sizes(Profile::Osrs).unwrap().is_empty());
+    }
+}
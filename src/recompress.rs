@@ -0,0 +1,158 @@
+use crate::bulk::{GroupFailure, OnError};
+use crate::container::{self, CompressionMode};
+use crate::filesystem::{FileSystem, FsError};
+use crate::filter::GroupFilter;
+use crate::update::ContainerUpdate;
+
+/// What `FileSystem::recompress` changed in the cache.
+#[derive(Debug, Default)]
+pub struct RecompressReport {
+    /// The number of containers that were re-encoded, reference tables included.
+    pub containers: usize,
+    /// The total size of those containers before re-encoding.
+    pub bytes_before: u64,
+    /// The total size of those containers after re-encoding.
+    pub bytes_after: u64,
+    /// The groups that were skipped because they couldn't be read or decompressed.
+    pub failures: Vec<GroupFailure>,
+}
+
+impl FileSystem {
+    /// Re-encodes every container in the cache with a different codec, at the compression level
+    /// configured for its index. Reference tables are patched with the new CRCs and have their
+    /// revision bumped, so clients download the new containers, and are then re-encoded as well.
+    /// Version trailers are kept as they are. Containers that come out byte-for-byte the same are
+    /// not rewritten, and encrypted ones are left alone. Groups that can't be read or
+    /// decompressed abort the run or are skipped and reported, depending on `on_error`.
+    pub fn recompress(&mut self, mode: CompressionMode, on_error: OnError) -> Result<RecompressReport, FsError> {
+        self.recompress_matching(&GroupFilter::new(), mode, on_error)
+    }
+
+    /// Re-encodes the containers of the groups a filter matches like `recompress` does. The
+    /// reference table of every index the filter covers is re-encoded too, as it is patched
+    /// whenever one of its groups changes.
+    pub fn recompress_matching(&mut self, filter: &GroupFilter, mode: CompressionMode, on_error: OnError) -> Result<RecompressReport, FsError> {
+        if !self.writable() {
+            return Err(FsError::ReadOnly);
+        }
+
+        self.recompress_groups(filter, mode, on_error)
+    }
+
+    /// Works out what `recompress_matching` would do, without writing anything: every
+    /// container is re-encoded and the reference tables patched as they would be, but kept in
+    /// memory and thrown away afterwards. The report is the one the real run would give. This
+    /// works on read-only filesystems too.
+    pub fn plan_recompress(&mut self, filter: &GroupFilter, mode: CompressionMode, on_error: OnError) -> Result<RecompressReport, FsError> {
+        self.dry_run(|fs| fs.recompress_groups(filter, mode, on_error))
+    }
+
+    fn recompress_groups(&mut self, filter: &GroupFilter, mode: CompressionMode, on_error: OnError) -> Result<RecompressReport, FsError> {
+        let count = self.index(255).map_or(0, |idx| idx.last_entry()) as u32;
+        let mut report = RecompressReport::default();
+
+        for index in (0..count).filter(|index| filter.covers_index(*index)) {
+            let table = match self.reference_table(index) {
+                Ok(table) => table,
+                Err(FsError::EntryNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+
+            let mut updates = Vec::new();
+            for group in table.folder_ids() {
+                let group = group as u32;
+                if !filter.matches(index, group, Some(&table)) {
+                    continue;
+                }
+                let result = match self.read_container(index, group) {
+                    Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => continue,
+                    result => result.and_then(|old| Ok(self.reencode(index, &old, mode)?.map(|new| (old, new)))),
+                };
+
+                if let Some((old, new)) = on_error.handle(index, group, result, &mut report.failures)?.flatten() {
+                    report.containers += 1;
+                    report.bytes_before += old.len() as u64;
+                    report.bytes_after += new.len() as u64;
+                    updates.push(ContainerUpdate::new(index, group, new));
+                }
+            }
+
+            if !updates.is_empty() {
+                self.apply_update(&updates)?;
+            }
+
+            let old = self.read_container(255, index)?;
+            let new = self.reencode(255, &old, mode);
+            if let Some(new) = on_error.handle(255, index, new, &mut report.failures)?.flatten() {
+                report.containers += 1;
+                report.bytes_before += old.len() as u64;
+                report.bytes_after += new.len() as u64;
+                self.write_container(255, index, &new)?;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Re-encodes a container the way `write_group` would for the index, keeping its version
+    /// trailer. Returns `None` if nothing would change, which is also the case for encrypted
+    /// containers since they can't be decompressed without their keys.
+    fn reencode(&self, index: u32, old: &[u8], mode: CompressionMode) -> Result<Option<Vec<u8>>, FsError> {
+        let data = match container::decode(old) {
+            Ok(data) => data,
+            Err(FsError::Encrypted) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let new = self.encode_group(index, &data, mode, container::version(old)?)?;
+        Ok(if new == old { None } else { Some(new) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::bulk::OnError;
+    use crate::container::{self, CompressionMode};
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::filter::GroupFilter;
+    use crate::reference_table::ReferenceTable;
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+
+    #[test]
+    fn recompress_bzip2_cache_to_gzip() {
+        let dir = TestDir::new("recompress");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_reference_table(3, &ReferenceTable::new(6)).unwrap();
+        let groups: Vec<ContainerUpdate> = (0..4)
+            .map(|g| ContainerUpdate::new(3, g, container::encode(&vec![g as u8; 3000], CompressionType::Bzip2, Some(2)).unwrap()))
+            .collect();
+        fs.apply_update(&groups).unwrap();
+        let revision = fs.reference_table(3).unwrap().revision();
+
+        // A dry run reports the same as the real thing, and leaves the cache as it was
+        let data = fs::read(dir.join("main_file_cache.dat2")).unwrap();
+        let mut reader = FileSystem::new(&dir).unwrap();
+        let plan = reader.plan_recompress(&GroupFilter::new(), CompressionMode::Fixed(CompressionType::Gzip), OnError::Abort).unwrap();
+        assert!(!reader.is_copy_on_write());
+        assert_eq!(CompressionType::from_code(reader.read_container(3, 0).unwrap()[0]), CompressionType::Bzip2);
+        assert_eq!(fs::read(dir.join("main_file_cache.dat2")).unwrap(), data);
+
+        let report = fs.recompress(CompressionMode::Fixed(CompressionType::Gzip), OnError::Abort).unwrap();
+        assert_eq!(report.containers, 4);
+        assert_eq!((plan.containers, plan.bytes_before, plan.bytes_after), (report.containers, report.bytes_before, report.bytes_after));
+
+        let table = fs.reference_table(3).unwrap();
+        assert_eq!(table.revision(), revision + 1);
+        for g in 0..4 {
+            let container = fs.read_container(3, g).unwrap();
+            assert_eq!(CompressionType::from_code(container[0]), CompressionType::Gzip);
+            assert_eq!(container::version(&container).unwrap(), Some(2));
+            assert_eq!(container::decode(&container).unwrap(), vec![g as u8; 3000]);
+            assert_eq!(table.lookup(g as i32).unwrap().crc32(), container::crc(&container).unwrap() as i32);
+        }
+
+        assert_eq!(fs.recompress(CompressionMode::Fixed(CompressionType::Gzip), OnError::Abort).unwrap().containers, 0);
+    }
+}
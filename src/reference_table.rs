@@ -1,6 +1,13 @@
-use std::{collections::HashMap, convert::TryInto};
-use std::io::{Read, Seek};
-use byteorder::{ReadBytesExt, BigEndian};
+use std::{collections::{BTreeMap, HashMap, HashSet}, convert::TryInto};
+use std::io::{Read, Seek, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+use crate::container;
+use crate::filesystem::{CompressionType, FileSystem, FsError};
+use crate::whirlpool;
+
+/// The most folders or files `ReferenceTable::decode` reserves room for up front. Larger tables
+/// still decode, their lists just grow as they are read.
+const MAX_PREALLOCATION: u32 = 0x10000;
 
 #[derive(Clone, Debug, Default)]
 pub struct ReferenceTable {
@@ -9,15 +16,117 @@ pub struct ReferenceTable {
     flags: ReferenceTableFlags,
     
     entries: HashMap<i32, ReferenceTableFolder>,
+
+    /// How the table was laid out when it was decoded, for `EncodeMode::Preserve`.
+    layout: Option<Layout>,
+}
+
+/// Two tables are equal if they hold the same things, however they were laid out.
+impl PartialEq for ReferenceTable {
+    fn eq(&self, other: &ReferenceTable) -> bool {
+        self.version == other.version && self.revision == other.revision && self.flags == other.flags && self.entries == other.entries
+    }
+}
+
+impl Eq for ReferenceTable {}
+
+/// The details of how a decoded table was laid out that its contents don't capture.
+#[derive(Clone, Debug, Default)]
+struct Layout {
+    /// The flag byte as it was read, bits this crate doesn't know included.
+    flag_bits: u8,
+    /// The ids, id deltas and counts that were stored in 4 bytes although they fit in 2.
+    wide: HashSet<IdField>,
+    /// Whatever came after the table.
+    trailing: Vec<u8>,
 }
 
-#[derive(Copy, Clone, Debug, Default)]
+/// An id, id delta or count field of a table, named by what it is of. Id deltas are named by
+/// the id they lead to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum IdField {
+    FolderCount,
+    Folder(i32),
+    FileCount(i32),
+    File(i32, i32),
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub struct ReferenceTableFlags {
 	has_names: bool,
 	has_whirlpool: bool,
+	has_lengths: bool,
+	has_uncompressed_crc: bool,
 }
 
-#[derive(Clone, Debug, Default)]
+impl ReferenceTableFlags {
+    /// Creates a set of flags with all of them off, for a table that only has CRCs and versions.
+    pub fn new() -> ReferenceTableFlags {
+        ReferenceTableFlags::default()
+    }
+
+    /// Sets whether folders and files have name hashes.
+    pub fn with_names(mut self, has_names: bool) -> ReferenceTableFlags {
+        self.has_names = has_names;
+        self
+    }
+
+    /// Sets whether folders have whirlpool digests.
+    pub fn with_whirlpool(mut self, has_whirlpool: bool) -> ReferenceTableFlags {
+        self.has_whirlpool = has_whirlpool;
+        self
+    }
+
+    /// Sets whether folders have compressed and uncompressed lengths.
+    pub fn with_lengths(mut self, has_lengths: bool) -> ReferenceTableFlags {
+        self.has_lengths = has_lengths;
+        self
+    }
+
+    /// Sets whether folders have CRCs of their uncompressed data.
+    pub fn with_uncompressed_crc(mut self, has_uncompressed_crc: bool) -> ReferenceTableFlags {
+        self.has_uncompressed_crc = has_uncompressed_crc;
+        self
+    }
+
+    /// Checks if folders and files have name hashes.
+    pub fn has_names(&self) -> bool {
+        self.has_names
+    }
+
+    /// Checks if folders have whirlpool digests.
+    pub fn has_whirlpool(&self) -> bool {
+        self.has_whirlpool
+    }
+
+    /// Checks if folders have compressed and uncompressed lengths.
+    pub fn has_lengths(&self) -> bool {
+        self.has_lengths
+    }
+
+    /// Checks if folders have CRCs of their uncompressed data.
+    pub fn has_uncompressed_crc(&self) -> bool {
+        self.has_uncompressed_crc
+    }
+}
+
+/// Controls how a reference table is laid out when encoding.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EncodeMode {
+    /// Keeps the protocol version and flags the table was decoded (or created) with.
+    Standard,
+    /// Produces byte-identical output for identical logical content: only flags whose data is
+    /// actually present are set, and the protocol is only raised when ids require it.
+    Canonical,
+    /// Lays the table out as it was when decoded, so an unmodified table encodes to exactly the
+    /// bytes it was decoded from: flag bits this crate doesn't know, ids and counts stored wider
+    /// than needed and anything after the table are kept. Folders and files added since are
+    /// encoded as in `Standard`, as is a table that wasn't decoded. Folders with the same id can
+    /// only be decoded as one, so a table with them doesn't come out the same.
+    Preserve,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct ReferenceTableFolder {
 	id: i32,
 	name_hash: i32,
@@ -26,28 +135,219 @@ pub struct ReferenceTableFolder {
 	version: u32,
 	files: HashMap<i32, ReferenceTableFile>,
 
-	// Internal only, used when decoding
-	file_count: i32,
+	// Only present when the table has the corresponding flags
+	compressed_length: u32,
+	uncompressed_length: u32,
+	uncompressed_crc32: i32,
 }
 impl ReferenceTableFolder {
     pub fn new(id: i32) -> ReferenceTableFolder {
         ReferenceTableFolder {
-            id: id,
+            id,
             name_hash: 0,
             crc32: 0,
             whirlpool: Vec::new(),
             version: 0,
             files: HashMap::new(),
-            file_count: 0,
+            compressed_length: 0,
+            uncompressed_length: 0,
+            uncompressed_crc32: 0,
         }
     }
+
+    /// Gets the id of this folder, which is the group id within the index.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Gets the hash of the name of this folder, or 0 if the table has no names.
+    pub fn name_hash(&self) -> i32 {
+        self.name_hash
+    }
+
+    /// Gets the CRC32 of the container of this folder, excluding the version trailer.
+    pub fn crc32(&self) -> i32 {
+        self.crc32
+    }
+
+    /// Gets the whirlpool digest of the container of this folder, or an empty slice if the table
+    /// has no whirlpool digests.
+    pub fn whirlpool(&self) -> &[u8] {
+        &self.whirlpool
+    }
+
+    /// Gets the version of this folder.
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Gets the compressed length of the container of this folder, or 0 if the table has no
+    /// lengths.
+    pub fn compressed_length(&self) -> u32 {
+        self.compressed_length
+    }
+
+    /// Gets the length of the decompressed data of this folder, or 0 if the table has no lengths.
+    pub fn uncompressed_length(&self) -> u32 {
+        self.uncompressed_length
+    }
+
+    /// Gets the CRC32 of the decompressed data of this folder, or 0 if the table has none.
+    pub fn uncompressed_crc32(&self) -> i32 {
+        self.uncompressed_crc32
+    }
+
+    pub fn set_name_hash(&mut self, name_hash: i32) {
+        self.name_hash = name_hash;
+    }
+
+    pub fn set_crc32(&mut self, crc32: i32) {
+        self.crc32 = crc32;
+    }
+
+    pub fn set_whirlpool(&mut self, whirlpool: Vec<u8>) {
+        self.whirlpool = whirlpool;
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.version = version;
+    }
+
+    pub fn set_lengths(&mut self, compressed_length: u32, uncompressed_length: u32) {
+        self.compressed_length = compressed_length;
+        self.uncompressed_length = uncompressed_length;
+    }
+
+    pub fn set_uncompressed_crc32(&mut self, uncompressed_crc32: i32) {
+        self.uncompressed_crc32 = uncompressed_crc32;
+    }
+
+    /// Adds a file to this folder, replacing any file with the same id.
+    pub fn insert_file(&mut self, file: ReferenceTableFile) {
+        self.files.insert(file.id, file);
+    }
+
+    /// Removes a file from this folder, returning it if it was present.
+    pub fn remove_file(&mut self, id: i32) -> Option<ReferenceTableFile> {
+        self.files.remove(&id)
+    }
+
+    /// Gets the ids of all files in this folder, in ascending order.
+    pub fn file_ids(&self) -> Vec<i32> {
+        let mut ids: Vec<i32> = self.files.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Gets the number of files in this folder.
+    pub fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Gets the file with a specific id, if this folder contains it.
+    pub fn file(&self, id: i32) -> Option<&ReferenceTableFile> {
+        self.files.get(&id)
+    }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct ReferenceTableFile {
 	id: i32,
 	name_hash: i32,
 }
+impl ReferenceTableFile {
+    pub fn new(id: i32, name_hash: i32) -> ReferenceTableFile {
+        ReferenceTableFile { id, name_hash }
+    }
+
+    /// Gets the id of this file within its folder.
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+
+    /// Gets the hash of the name of this file, or 0 if the table has no names.
+    pub fn name_hash(&self) -> i32 {
+        self.name_hash
+    }
+}
+
+/// Writes an id, id delta or count in the representation used by the given protocol version.
+/// From protocol 7 on it takes 4 bytes if it doesn't fit in 2 or if `wide`.
+fn write_id<W: Write>(w: &mut W, version: u8, value: i32, wide: bool) -> Result<(), FsError> {
+    if version >= 7 && wide {
+        Ok(w.write_u32::<BigEndian>(value as u32 | 0x8000_0000)?)
+    } else if version >= 7 {
+        Ok(w.write_vari32(value)?)
+    } else if (0..=0xFFFF).contains(&value) {
+        Ok(w.write_u16::<BigEndian>(value as u16)?)
+    } else {
+        Err(FsError::FormatOverflow)
+    }
+}
+
+/// The differences between two revisions of a reference table, as computed by `ReferenceTable::diff`.
+/// All id lists are in ascending order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReferenceTableDiff {
+    /// Folders only present in the new table.
+    pub added: Vec<i32>,
+    /// Folders only present in the old table.
+    pub removed: Vec<i32>,
+    /// Folders present in both tables whose CRC, version, digest or file list differ.
+    pub changed: Vec<FolderDiff>,
+}
+
+/// The differences of a single folder present in both tables of a `ReferenceTableDiff`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FolderDiff {
+    pub id: i32,
+    pub old_version: u32,
+    pub new_version: u32,
+    pub old_crc32: i32,
+    pub new_crc32: i32,
+    pub added_files: Vec<i32>,
+    pub removed_files: Vec<i32>,
+}
+
+impl ReferenceTableDiff {
+    /// Checks if the two tables were logically identical.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Figures about the folders of a reference table, as computed by `ReferenceTable::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReferenceTableStats {
+    pub folders: usize,
+    /// The number of files in all folders together.
+    pub files: usize,
+    /// The lowest folder id, or `None` if the table is empty.
+    pub min_id: Option<i32>,
+    /// The highest folder id, or `None` if the table is empty.
+    pub max_id: Option<i32>,
+    /// Folders with a name hash other than 0.
+    pub named: usize,
+    /// Folders with a whirlpool digest.
+    pub whirlpools: usize,
+    /// How many folders there are of each version.
+    pub versions: BTreeMap<u32, usize>,
+}
+
+trait VarIntWrite {
+    fn write_vari32(&mut self, value: i32) -> Result<(), std::io::Error>;
+}
+
+impl<W: Write> VarIntWrite for W {
+    fn write_vari32(&mut self, value: i32) -> Result<(), std::io::Error> {
+        // Mirrors read_vari32: values that don't fit in 15 bits get the sign bit set
+        if value > 0x7FFF {
+            self.write_u32::<BigEndian>(value as u32 | 0x8000_0000)
+        } else {
+            self.write_u16::<BigEndian>(value as u16)
+        }
+    }
+}
 
 trait VarIntRead {
     fn read_vari32(&mut self) -> Result<i32, std::io::Error>;
@@ -71,12 +371,42 @@ impl<R: Read + Seek> VarIntRead for R {
     }
 }
 
-impl ReferenceTable {
+/// Reads an id, id delta or count in the representation used by the given protocol version,
+/// and whether it was stored in 4 bytes although it fits in 2.
+fn read_id<R: Read + Seek>(r: &mut R, version: u8) -> Result<(i32, bool), FsError> {
+    if version < 7 {
+        return Ok((r.read_u16::<BigEndian>()? as i32, false));
+    }
+
+    let wide = r.read_u8()? & 0x80 != 0;
+    r.seek(std::io::SeekFrom::Current(-1))?;
+    let value = r.read_vari32()?;
+    Ok((value, wide && value <= 0x7FFF))
+}
 
-    pub fn decode<R: Read + Seek>(r: &mut R) -> Result<ReferenceTable, std::io::Error> {
-        let mut table = ReferenceTable::default();
+impl Layout {
+    fn note(&mut self, (value, wide): (i32, bool), field: impl FnOnce(i32) -> IdField) -> i32 {
+        if wide {
+            self.wide.insert(field(value));
+        }
+        value
+    }
+}
 
-        table.version = r.read_u8()?;
+impl ReferenceTable {
+    /// Creates an empty table using the given protocol version (5 to 7).
+    pub fn new(version: u8) -> ReferenceTable {
+        ReferenceTable {
+            version,
+            ..Default::default()
+        }
+    }
+
+    pub fn decode<R: Read + Seek>(r: &mut R) -> Result<ReferenceTable, FsError> {
+        let mut table = ReferenceTable {
+            version: r.read_u8()?,
+            ..Default::default()
+        };
 
         if table.version >= 5 && table.version <= 7 {
             if table.version >= 6 {
@@ -84,31 +414,25 @@ impl ReferenceTable {
             }
 
             let flags = r.read_u8()?;
+            let mut layout = Layout { flag_bits: flags, ..Layout::default() };
             table.flags.has_names = (flags & 0x1) != 0;
             table.flags.has_whirlpool = (flags & 0x2) != 0;
+            table.flags.has_lengths = (flags & 0x4) != 0;
+            table.flags.has_uncompressed_crc = (flags & 0x8) != 0;
 
-            // These are not yet identified (present in higher revisions)
-            let _unknown1 = (flags & 0x4) != 0;
-            let _unknown2 = (flags & 0x8) != 0;
-
-            let entry_count: u32;
-            if table.version >= 7 {
-                entry_count = r.read_vari32()?.try_into().unwrap();
-            } else {
-                entry_count = r.read_u16::<BigEndian>()?.into();
-            }
+            let entry_count: u32 = layout.note(read_id(r, table.version)?, |_| IdField::FolderCount)
+                .try_into().map_err(|_| FsError::CorruptedData)?;
 
-            // Translation table maps array indices to actual IDs
-            let mut entries = Vec::<ReferenceTableFolder>::with_capacity(entry_count.try_into().unwrap());
+            // Translation table maps array indices to actual IDs. The counts come from the data,
+            // so a corrupt one mustn't make us allocate more than the data could possibly hold
+            let mut entries = Vec::<ReferenceTableFolder>::with_capacity(entry_count.min(MAX_PREALLOCATION) as usize);
 
-            let mut id = 0;
+            let mut id: i32 = 0;
             for _ in 0..(entry_count as usize) {
                 // Type of data depends on the table version - only 7+ supports >65535
-                if table.version >= 7 {
-                    id += r.read_vari32()?;
-                } else {
-                    id += r.read_u16::<BigEndian>()? as i32;
-                }
+                let (delta, wide) = read_id(r, table.version)?;
+                id = id.checked_add(delta).ok_or(FsError::CorruptedData)?;
+                layout.note((id, wide), IdField::Folder);
 
                 entries.push(ReferenceTableFolder::new(id));
             }
@@ -125,25 +449,26 @@ impl ReferenceTable {
                 entries[i as usize].crc32 = r.read_i32::<BigEndian>()?;
             }
 
-            // Unidentified
-            if _unknown2 {
-                for _i in 0..entry_count {
-                    r.read_i32::<BigEndian>()?;
+            // Load CRC values of the uncompressed data
+            if table.flags.has_uncompressed_crc {
+                for i in 0..entry_count {
+                    entries[i as usize].uncompressed_crc32 = r.read_i32::<BigEndian>()?;
                 }
             }
 
             // Read whirlpool values
             if table.flags.has_whirlpool {
                 for i in 0..entry_count {
+                    entries[i as usize].whirlpool = vec![0; whirlpool::DIGEST_LENGTH];
                     r.read_exact(entries[i as usize].whirlpool.as_mut_slice())?;
                 }
             }
 
-            // Unidentified
-            if _unknown1 {
-                for _i in 0..entry_count {
-                    r.read_i32::<BigEndian>()?;
-                    r.read_i32::<BigEndian>()?;
+            // Load compressed and uncompressed lengths
+            if table.flags.has_lengths {
+                for i in 0..entry_count {
+                    entries[i as usize].compressed_length = r.read_u32::<BigEndian>()?;
+                    entries[i as usize].uncompressed_length = r.read_u32::<BigEndian>()?;
                 }
             }
 
@@ -152,52 +477,44 @@ impl ReferenceTable {
                 entries[i as usize].version = r.read_u32::<BigEndian>()?;
             }
 
-            let mut files = Vec::<Vec<ReferenceTableFile>>::with_capacity(entry_count.try_into().unwrap());
+            let mut files = Vec::<Vec<ReferenceTableFile>>::with_capacity(entries.len());
+            let mut file_counts = Vec::<usize>::with_capacity(entries.len());
 
             // Load file counts
-            for _ in 0..entry_count {
-                let file_count;
-
-                if table.version >= 7 {
-                    file_count = r.read_vari32()?;
-                } else {
-                    file_count = r.read_u16::<BigEndian>()? as i32;
-                }
+            for i in 0..entry_count {
+                let file_count = layout.note(read_id(r, table.version)?, |_| IdField::FileCount(entries[i as usize].id));
 
-                files.push(Vec::<ReferenceTableFile>::with_capacity(file_count as usize));
+                files.push(Vec::<ReferenceTableFile>::with_capacity(file_count.min(MAX_PREALLOCATION as i32) as usize));
+                file_counts.push(file_count as usize);
             }
 
             // Load file IDs
             for i in 0..entry_count {
-                let mut file_id = 0;
+                let mut file_id: i32 = 0;
 
-                for _ in 0..files[i as usize].len() {
-                    if table.version >= 7 {
-                        file_id += r.read_vari32()?;
-                    } else {
-                        file_id += r.read_u16::<BigEndian>()? as i32;
-                    }
+                for _ in 0..file_counts[i as usize] {
+                    let (delta, wide) = read_id(r, table.version)?;
+                    file_id = file_id.checked_add(delta).ok_or(FsError::CorruptedData)?;
+                    layout.note((file_id, wide), |file| IdField::File(entries[i as usize].id, file));
 
-                    let mut file = ReferenceTableFile::default();
-                    file.id = file_id;
-                    files[i as usize].push(file);
+                    files[i as usize].push(ReferenceTableFile { id: file_id, name_hash: 0 });
                 }
             }
 
             // Load file names
             if table.flags.has_names {
-                for i in 0..entry_count as usize {
-                    for file in 0..files[i as usize].len() {
-                        files[i][file].name_hash = r.read_i32::<BigEndian>()?;
+                for folder_files in files.iter_mut() {
+                    for file in folder_files.iter_mut() {
+                        file.name_hash = r.read_i32::<BigEndian>()?;
                     }
                 }
             }
 
             // Turn the entry array into a lookup map
-            table.entries = HashMap::with_capacity(entry_count as usize);
+            table.entries = HashMap::with_capacity(entries.len());
             for (i, v) in entries.iter_mut().enumerate() {
                 // Turn the children into lookup maps too
-                v.files = HashMap::with_capacity(files[i].len() as usize);
+                v.files = HashMap::with_capacity(files[i].len());
 
                 for file in &files[i] {
                     v.files.insert(file.id, *file);
@@ -206,16 +523,334 @@ impl ReferenceTable {
                 table.entries.insert(v.id, v.clone());
             }
 
+            r.read_to_end(&mut layout.trailing)?;
+            table.layout = Some(layout);
             Ok(table)
         } else {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid reference table version"))
+            Err(FsError::UnsupportedVersion)
+        }
+    }
+
+    /// Encodes the table with the protocol version and flags it currently has.
+    pub fn encode(&self) -> Result<Vec<u8>, FsError> {
+        self.encode_with(EncodeMode::Standard)
+    }
+
+    pub fn encode_with(&self, mode: EncodeMode) -> Result<Vec<u8>, FsError> {
+        // Ids are delta encoded, so ascending order is the only order that can be represented
+        let mut folders: Vec<&ReferenceTableFolder> = self.entries.values().collect();
+        folders.sort_unstable_by_key(|f| f.id);
+
+        let (version, flags) = match mode {
+            EncodeMode::Standard | EncodeMode::Preserve => (self.version, self.flags),
+            EncodeMode::Canonical => {
+                let flags = ReferenceTableFlags {
+                    has_names: folders.iter().any(|f| f.name_hash != 0 || f.files.values().any(|file| file.name_hash != 0)),
+                    has_whirlpool: folders.iter().any(|f| !f.whirlpool.is_empty()),
+                    has_lengths: folders.iter().any(|f| f.compressed_length != 0 || f.uncompressed_length != 0),
+                    has_uncompressed_crc: folders.iter().any(|f| f.uncompressed_crc32 != 0),
+                };
+
+                // Pick the lowest protocol that can represent the content
+                let version = if ReferenceTable::needs_smart_ids(&folders) {
+                    7
+                } else if self.revision != 0 {
+                    6
+                } else {
+                    5
+                };
+
+                (version, flags)
+            }
+        };
+
+        if !(5..=7).contains(&version) {
+            return Err(FsError::UnsupportedVersion);
+        }
+
+        let layout = match mode {
+            EncodeMode::Preserve => self.layout.as_ref(),
+            _ => None,
+        };
+        let wide = |field: IdField| layout.is_some_and(|layout| layout.wide.contains(&field));
+
+        let mut w = Vec::<u8>::new();
+        w.write_u8(version)?;
+
+        if version >= 6 {
+            w.write_u32::<BigEndian>(self.revision)?;
         }
+
+        let mut flag_bits = 0u8;
+        if flags.has_names { flag_bits |= 0x1; }
+        if flags.has_whirlpool { flag_bits |= 0x2; }
+        if flags.has_lengths { flag_bits |= 0x4; }
+        if flags.has_uncompressed_crc { flag_bits |= 0x8; }
+        flag_bits |= layout.map_or(0, |layout| layout.flag_bits & !0xF);
+        w.write_u8(flag_bits)?;
+
+        write_id(&mut w, version, folders.len() as i32, wide(IdField::FolderCount))?;
+
+        let mut last_id = 0;
+        for folder in &folders {
+            write_id(&mut w, version, folder.id - last_id, wide(IdField::Folder(folder.id)))?;
+            last_id = folder.id;
+        }
+
+        if flags.has_names {
+            for folder in &folders {
+                w.write_i32::<BigEndian>(folder.name_hash)?;
+            }
+        }
+
+        for folder in &folders {
+            w.write_i32::<BigEndian>(folder.crc32)?;
+        }
+
+        if flags.has_uncompressed_crc {
+            for folder in &folders {
+                w.write_i32::<BigEndian>(folder.uncompressed_crc32)?;
+            }
+        }
+
+        if flags.has_whirlpool {
+            for folder in &folders {
+                // Folders without a digest get an all-zero one, the field has a fixed size
+                let mut digest = [0u8; whirlpool::DIGEST_LENGTH];
+                let len = folder.whirlpool.len().min(digest.len());
+                digest[..len].copy_from_slice(&folder.whirlpool[..len]);
+                w.write_all(&digest)?;
+            }
+        }
+
+        if flags.has_lengths {
+            for folder in &folders {
+                w.write_u32::<BigEndian>(folder.compressed_length)?;
+                w.write_u32::<BigEndian>(folder.uncompressed_length)?;
+            }
+        }
+
+        for folder in &folders {
+            w.write_u32::<BigEndian>(folder.version)?;
+        }
+
+        for folder in &folders {
+            write_id(&mut w, version, folder.files.len() as i32, wide(IdField::FileCount(folder.id)))?;
+        }
+
+        for folder in &folders {
+            let mut last_file_id = 0;
+            for file_id in folder.file_ids() {
+                write_id(&mut w, version, file_id - last_file_id, wide(IdField::File(folder.id, file_id)))?;
+                last_file_id = file_id;
+            }
+        }
+
+        if flags.has_names {
+            for folder in &folders {
+                for file_id in folder.file_ids() {
+                    w.write_i32::<BigEndian>(folder.files[&file_id].name_hash)?;
+                }
+            }
+        }
+
+        if let Some(layout) = layout {
+            w.extend_from_slice(&layout.trailing);
+        }
+        Ok(w)
+    }
+
+    /// Encodes the table and compresses it into a container that is ready to be written to
+    /// index 255 as is. Reference tables are stored without a version trailer: the revision in
+    /// the table takes its place, and the CRC and digest the master checksum table lists for
+    /// the index are of exactly these bytes.
+    pub fn encode_container(&self, mode: EncodeMode, compression: CompressionType) -> Result<Vec<u8>, FsError> {
+        container::encode(&self.encode_with(mode)?, compression, None)
+    }
+
+    /// Checks if any id delta or count in the table is too large for the 16-bit fields used
+    /// before protocol 7.
+    fn needs_smart_ids(folders: &[&ReferenceTableFolder]) -> bool {
+        if folders.len() > 0xFFFF {
+            return true;
+        }
+
+        let mut last_id = 0;
+        for folder in folders {
+            if folder.id - last_id > 0xFFFF || folder.files.len() > 0xFFFF {
+                return true;
+            }
+            last_id = folder.id;
+
+            let mut last_file_id = 0;
+            for file_id in folder.file_ids() {
+                if file_id - last_file_id > 0xFFFF {
+                    return true;
+                }
+                last_file_id = file_id;
+            }
+        }
+
+        false
+    }
+
+    /// Computes the whirlpool digest of an encoded reference table container, exactly as it is
+    /// stored in index 255. This is the digest the master checksum table lists for each index.
+    pub fn digest(container: &[u8]) -> [u8; whirlpool::DIGEST_LENGTH] {
+        whirlpool::digest(container)
+    }
+
+    /// Hashes a name the way the client does before looking it up in a table: the name is
+    /// lowercased, and each byte taken as `hash = hash * 31 + byte`.
+    pub fn hash_name(name: &str) -> i32 {
+        name.to_lowercase().bytes().fold(0i32, |hash, byte| hash.wrapping_mul(31).wrapping_add(byte as i32))
+    }
+
+    /// Gets the protocol version the table is encoded with.
+    pub fn version(&self) -> u8 {
+        self.version
     }
 
     pub fn revision(&self) -> u32 {
         self.revision
     }
 
+    pub fn set_revision(&mut self, revision: u32) {
+        self.revision = revision;
+    }
+
+    pub fn flags(&self) -> ReferenceTableFlags {
+        self.flags
+    }
+
+    /// Sets the flags the table is encoded with in `EncodeMode::Standard`. Turning on whirlpool
+    /// digests or lengths fails with `FsError::MissingFlagData` unless every folder has them, as
+    /// they would otherwise be encoded as zeroes. Name hashes and uncompressed CRCs can be 0, so
+    /// those are taken as they are.
+    pub fn set_flags(&mut self, flags: ReferenceTableFlags) -> Result<(), FsError> {
+        let folders = || self.entries.values();
+        if flags.has_whirlpool && folders().any(|f| f.whirlpool.len() != whirlpool::DIGEST_LENGTH) {
+            return Err(FsError::MissingFlagData);
+        }
+        if flags.has_lengths && folders().any(|f| f.compressed_length == 0) {
+            return Err(FsError::MissingFlagData);
+        }
+
+        self.flags = flags;
+        Ok(())
+    }
+
+    /// Adds a folder to the table, replacing any folder with the same id.
+    pub fn insert(&mut self, folder: ReferenceTableFolder) {
+        self.entries.insert(folder.id, folder);
+    }
+
+    /// Removes a folder from the table, returning it if it was present.
+    pub fn remove(&mut self, id: i32) -> Option<ReferenceTableFolder> {
+        self.entries.remove(&id)
+    }
+
+    /// Keeps only the folders a predicate holds for, returning the ids of the others in
+    /// ascending order. Their files go with them, and the counts in the encoded table follow.
+    pub fn retain<F: FnMut(&ReferenceTableFolder) -> bool>(&mut self, mut keep: F) -> Vec<i32> {
+        let mut removed = Vec::new();
+        self.entries.retain(|id, folder| {
+            let kept = keep(folder);
+            if !kept {
+                removed.push(*id);
+            }
+            kept
+        });
+        removed.sort_unstable();
+        removed
+    }
+
+    /// Removes the folders whose group the idx file of `index` has no data for, such as after
+    /// groups were deleted or left out of a subset, returning their ids in ascending order. An idx
+    /// file that can't be read fails instead, as its groups can't be told missing or not.
+    pub fn remove_missing(&mut self, fs: &mut FileSystem, index: u32) -> Result<Vec<i32>, FsError> {
+        let idx = fs.index(index).ok_or(FsError::IndexNotFound)?;
+        let mut missing = HashSet::new();
+        for id in self.entries.keys() {
+            match idx.try_entry(*id as u32) {
+                Ok(entry) if entry.size() > 0 => {}
+                Ok(_) | Err(FsError::EntryNotFound) => {
+                    missing.insert(*id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.retain(|folder| !missing.contains(&folder.id)))
+    }
+
+    /// Gets the ids of all folders in this table, in ascending order.
+    pub fn folder_ids(&self) -> Vec<i32> {
+        let mut ids: Vec<i32> = self.entries.keys().copied().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Counts the folders and files of the table, and how many folders have names, digests and
+    /// each version.
+    pub fn stats(&self) -> ReferenceTableStats {
+        let mut stats = ReferenceTableStats { folders: self.entries.len(), ..ReferenceTableStats::default() };
+        for folder in self.entries.values() {
+            stats.files += folder.files.len();
+            stats.min_id = Some(stats.min_id.map_or(folder.id, |id| id.min(folder.id)));
+            stats.max_id = Some(stats.max_id.map_or(folder.id, |id| id.max(folder.id)));
+            stats.named += (folder.name_hash != 0) as usize;
+            stats.whirlpools += (folder.whirlpool.len() == whirlpool::DIGEST_LENGTH) as usize;
+            *stats.versions.entry(folder.version).or_insert(0) += 1;
+        }
+        stats
+    }
+
+    /// Compares two tables, reporting which folders were added or removed and which folders had
+    /// their CRC, version, digest or files changed.
+    pub fn diff(old: &ReferenceTable, new: &ReferenceTable) -> ReferenceTableDiff {
+        let mut diff = ReferenceTableDiff::default();
+
+        for id in old.folder_ids() {
+            if !new.entries.contains_key(&id) {
+                diff.removed.push(id);
+            }
+        }
+
+        for id in new.folder_ids() {
+            let new_folder = &new.entries[&id];
+            let old_folder = match old.entries.get(&id) {
+                Some(folder) => folder,
+                None => {
+                    diff.added.push(id);
+                    continue;
+                }
+            };
+
+            let added_files: Vec<i32> = new_folder.file_ids().into_iter()
+                .filter(|file| !old_folder.files.contains_key(file))
+                .collect();
+            let removed_files: Vec<i32> = old_folder.file_ids().into_iter()
+                .filter(|file| !new_folder.files.contains_key(file))
+                .collect();
+
+            if old_folder.crc32 != new_folder.crc32 || old_folder.version != new_folder.version
+                || old_folder.whirlpool != new_folder.whirlpool
+                || !added_files.is_empty() || !removed_files.is_empty() {
+                diff.changed.push(FolderDiff {
+                    id,
+                    old_version: old_folder.version,
+                    new_version: new_folder.version,
+                    old_crc32: old_folder.crc32,
+                    new_crc32: new_folder.crc32,
+                    added_files,
+                    removed_files,
+                });
+            }
+        }
+
+        diff
+    }
+
     pub fn lookup(&self, id: i32) -> Option<&ReferenceTableFolder> {
         self.entries.get(&id)
     }
@@ -227,13 +862,208 @@ impl ReferenceTable {
     pub fn last_id(&self) -> i32 {
         let mut last_id = 0;
     
-        for (_, v) in &self.entries {
+        for v in self.entries.values() {
             if v.id > last_id {
                 last_id = v.id
             }
         }
     
-        return last_id
+        last_id
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::test_dir::TestDir;
+    use super::*;
+
+    #[test]
+    fn decode_reads_file_ids() {
+        // Version 6, revision 1, no flags, folders 2 and 7 holding files 0 and 4, and file 1
+        let data = [6, 0, 0, 0, 1, 0, 0, 2, 0, 2, 0, 5, 0, 0, 0, 10, 0, 0, 0, 20, 0, 0, 0, 1, 0, 0, 0, 1,
+            0, 2, 0, 1, 0, 0, 0, 4, 0, 1];
+        let table = ReferenceTable::decode(&mut Cursor::new(&data[..])).unwrap();
+
+        let mut ids: Vec<i32> = table.entries[&2].files.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, [0, 4]);
+        assert_eq!(table.entries[&7].files.keys().collect::<Vec<_>>(), [&1]);
+    }
+
+    fn sample_table() -> ReferenceTable {
+        let mut table = ReferenceTable {
+            version: 6,
+            revision: 1234,
+            flags: ReferenceTableFlags { has_names: true, ..Default::default() },
+            entries: HashMap::new(),
+            layout: None,
+        };
+
+        for id in &[0, 3, 60000] {
+            let mut folder = ReferenceTableFolder::new(*id);
+            folder.name_hash = id * 31;
+            folder.crc32 = id ^ 0x5555;
+            folder.version = 7;
+            for file_id in &[0, 1, 5] {
+                folder.files.insert(*file_id, ReferenceTableFile { id: *file_id, name_hash: file_id + 1 });
+            }
+            table.entries.insert(*id, folder);
+        }
+
+        table
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let table = sample_table();
+        let encoded = table.encode().unwrap();
+        let decoded = ReferenceTable::decode(&mut Cursor::new(&encoded)).unwrap();
+
+        assert_eq!(decoded, table);
+        assert_eq!(decoded.encode().unwrap(), encoded);
+    }
+
+    #[test]
+    fn diff_reports_changes() {
+        let old = sample_table();
+        let mut new = sample_table();
+        new.entries.remove(&0);
+        new.entries.insert(9, ReferenceTableFolder::new(9));
+        let folder = new.entries.get_mut(&3).unwrap();
+        folder.version = 8;
+        folder.files.remove(&5);
+
+        let diff = ReferenceTable::diff(&old, &new);
+        assert_eq!(diff.added, vec![9]);
+        assert_eq!(diff.removed, vec![0]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].id, 3);
+        assert_eq!(diff.changed[0].new_version, 8);
+        assert_eq!(diff.changed[0].removed_files, vec![5]);
+        assert!(ReferenceTable::diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn stats_count_folders_and_files() {
+        let mut table = sample_table();
+        table.entries.get_mut(&0).unwrap().version = 2;
+        let stats = table.stats();
+        assert_eq!((stats.folders, stats.files, stats.min_id, stats.max_id), (3, 9, Some(0), Some(60000)));
+        assert_eq!((stats.named, stats.whirlpools), (2, 0));
+        assert_eq!(stats.versions.into_iter().collect::<Vec<_>>(), vec![(2, 1), (7, 2)]);
+        assert_eq!(ReferenceTable::new(6).stats(), ReferenceTableStats::default());
+    }
+
+    #[test]
+    fn preserved_encoding_is_byte_identical() {
+        let mut data = vec![7, 0, 0, 0, 9, 0x11];
+        data.extend_from_slice(&[0x80, 0, 0, 2, 0, 0, 0x80, 0, 0, 5]);
+        data.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2]);
+        data.extend_from_slice(&[0, 0, 0, 3, 0, 0, 0, 4]);
+        data.extend_from_slice(&[0, 0, 0, 5, 0, 0, 0, 6]);
+        data.extend_from_slice(&[0x80, 0, 0, 1, 0, 0, 0, 3]);
+        data.extend_from_slice(&[0, 0, 0, 7, 0xAB, 0xCD]);
+
+        let mut table = ReferenceTable::decode(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(table.encode_with(EncodeMode::Preserve).unwrap(), data);
+        assert_ne!(table.encode().unwrap(), data);
+
+        // Only the bytes of what was changed move
+        table.lookup_mut(5).unwrap().set_crc32(0x44);
+        let mut edited = data.clone();
+        edited[31] = 0x44;
+        assert_eq!(table.encode_with(EncodeMode::Preserve).unwrap(), edited);
+        assert_eq!(sample_table().encode_with(EncodeMode::Preserve).unwrap(), sample_table().encode().unwrap());
+    }
+
+    #[test]
+    fn folders_can_be_trimmed() {
+        let mut table = sample_table();
+        assert_eq!(table.retain(|folder| folder.id() != 3), vec![3]);
+        assert_eq!(table.folder_ids(), vec![0, 60000]);
+        let decoded = ReferenceTable::decode(&mut Cursor::new(table.encode().unwrap())).unwrap();
+        assert_eq!(decoded.folder_ids(), vec![0, 60000]);
+
+        let dir = TestDir::new("trim");
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_group(3, 60000, b"kept", CompressionType::None, None).unwrap();
+        assert_eq!(table.remove_missing(&mut fs, 3).unwrap(), vec![0]);
+        assert_eq!(table.folder_ids(), vec![60000]);
+        assert!(matches!(table.remove_missing(&mut fs, 4), Err(FsError::IndexNotFound)));
+
+        // An idx file cut off halfway through a record doesn't count as the group being gone
+        let idx = std::fs::OpenOptions::new().write(true).open(dir.join("main_file_cache.idx3")).unwrap();
+        idx.set_len(60000 * 6 + 3).unwrap();
+        assert!(table.remove_missing(&mut fs, 3).is_err());
+        assert_eq!(table.folder_ids(), vec![60000]);
     }
 
+    #[test]
+    fn containers_hold_the_encoded_table() {
+        let table = sample_table();
+        let container = table.encode_container(EncodeMode::Standard, CompressionType::Gzip).unwrap();
+        assert_eq!(container::version(&container).unwrap(), None);
+        assert_eq!(container::decode(&container).unwrap(), table.encode().unwrap());
+        assert!(matches!(table.encode_container(EncodeMode::Standard, CompressionType::Lzma), Err(FsError::UnsupportedCompression)));
+    }
+
+    #[test]
+    fn canonical_encoding_is_minimal() {
+        let mut table = sample_table();
+        table.flags.has_whirlpool = true;
+        table.flags.has_lengths = true;
+        table.entries.insert(200000, ReferenceTableFolder::new(200000));
+
+        let canonical = table.encode_with(EncodeMode::Canonical).unwrap();
+        let decoded = ReferenceTable::decode(&mut Cursor::new(&canonical)).unwrap();
+
+        assert_eq!(decoded.version, 7);
+        assert_eq!(decoded.flags, ReferenceTableFlags { has_names: true, ..Default::default() });
+        assert_eq!(decoded.encode_with(EncodeMode::Canonical).unwrap(), canonical);
+    }
+
+    #[test]
+    fn hostile_counts_and_ids_are_corrupt_data() {
+        // Two folders whose id deltas add up past i32::MAX
+        let overflow = [7, 0, 0, 0, 1, 0, 0x80, 0, 0, 2, 0xFF, 0xFF, 0xFF, 0xFF, 0, 1];
+        assert!(matches!(ReferenceTable::decode(&mut Cursor::new(&overflow[..])), Err(FsError::CorruptedData)));
+
+        // Billions of folders in a table that is a few bytes long
+        let huge = [7, 0, 0, 0, 1, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 1];
+        assert!(matches!(ReferenceTable::decode(&mut Cursor::new(&huge[..])), Err(FsError::CorruptedData)));
+    }
+
+    #[test]
+    fn decode_errors_are_filesystem_errors() {
+        let encoded = sample_table().encode().unwrap();
+        assert!(matches!(ReferenceTable::decode(&mut Cursor::new(&encoded[..encoded.len() - 3])), Err(FsError::CorruptedData)));
+        assert!(matches!(ReferenceTable::decode(&mut Cursor::new(vec![9, 0, 0])), Err(FsError::UnsupportedVersion)));
+
+        // Protocol 5 and 6 only have room for 16-bit id deltas
+        let mut table = ReferenceTable::new(5);
+        table.entries.insert(70000, ReferenceTableFolder::new(70000));
+        assert!(matches!(table.encode(), Err(FsError::FormatOverflow)));
+    }
+
+    #[test]
+    fn flags_need_their_data() {
+        let mut table = sample_table();
+        let flags = ReferenceTableFlags::new().with_names(true).with_whirlpool(true).with_lengths(true);
+        assert!(matches!(table.set_flags(flags), Err(FsError::MissingFlagData)));
+        assert_eq!(table.flags(), sample_table().flags());
+
+        for id in table.folder_ids() {
+            let folder = table.lookup_mut(id).unwrap();
+            folder.set_whirlpool(vec![id as u8; whirlpool::DIGEST_LENGTH]);
+            folder.set_lengths(100 + id as u32, 200);
+        }
+        table.set_flags(flags).unwrap();
+
+        let decoded = ReferenceTable::decode(&mut Cursor::new(table.encode().unwrap())).unwrap();
+        assert_eq!(decoded.flags(), flags);
+        assert_eq!(decoded.lookup(3).unwrap().whirlpool(), &[3u8; whirlpool::DIGEST_LENGTH][..]);
+        assert_eq!(decoded.lookup(3).unwrap().compressed_length(), 103);
+    }
 }
@@ -0,0 +1,285 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Write};
+use std::path::{Path, PathBuf};
+use crate::checksum_table::ChecksumTable;
+use crate::container;
+use crate::filesystem::{FileSystem, FsError};
+use crate::js5::{self, Remote, SyncReport};
+use crate::reference_table::ReferenceTable;
+
+/// How many groups `sync_resumable` asks the remote for at once. Whatever is in flight when the
+/// download is interrupted has to be fetched again.
+const BATCH_SIZE: usize = 64;
+
+/// The groups a download has already written to the cache, kept in a file so an interrupted
+/// download can pick up where it left off. Every line of the file names the index, group and CRC
+/// of one downloaded group, and is appended as soon as the group is written.
+#[derive(Debug)]
+pub struct DownloadProgress {
+    path: PathBuf,
+    done: BTreeSet<(u32, u32, i32)>,
+}
+
+impl DownloadProgress {
+    /// Loads the progress kept in a file, or starts afresh if there is no such file. Lines that
+    /// don't parse, such as one cut off by a crash, are ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<DownloadProgress, FsError> {
+        let path = path.as_ref().to_path_buf();
+        let mut done = BTreeSet::new();
+
+        if path.exists() {
+            let file = File::open(&path).map_err(|_| FsError::FileNotFound)?;
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if let [index, group, crc] = fields.as_slice() {
+                    if let (Ok(index), Ok(group), Ok(crc)) = (index.parse(), group.parse(), crc.parse()) {
+                        done.insert((index, group, crc));
+                    }
+                }
+            }
+        }
+
+        Ok(DownloadProgress { path, done })
+    }
+
+    /// Checks if a group was downloaded with the given CRC.
+    pub fn contains(&self, index: u32, group: u32, crc: i32) -> bool {
+        self.done.contains(&(index, group, crc))
+    }
+
+    /// Gets the number of groups downloaded so far.
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.done.is_empty()
+    }
+
+    /// Records a downloaded group and makes sure the record reached the disk.
+    pub fn record(&mut self, index: u32, group: u32, crc: i32) -> Result<(), FsError> {
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path).map_err(FsError::Io)?;
+        writeln!(file, "{} {} {}", index, group, crc).map_err(FsError::Io)?;
+        file.sync_data().map_err(FsError::Io)?;
+
+        self.done.insert((index, group, crc));
+        Ok(())
+    }
+
+    /// Forgets the groups of an index once its reference table is in place, since the table
+    /// vouches for them from then on.
+    pub fn finish_index(&mut self, index: u32) -> Result<(), FsError> {
+        self.done.retain(|(i, _, _)| *i != index);
+
+        let mut data = String::new();
+        for (index, group, crc) in &self.done {
+            data.push_str(&format!("{} {} {}\n", index, group, crc));
+        }
+        fs::write(&self.path, data).map_err(FsError::Io)
+    }
+
+    /// Deletes the progress file once there is nothing left to resume.
+    pub fn clear(self) -> Result<(), FsError> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(FsError::WriteFailed),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A file of records that is only ever appended to, which tells how far an interrupted
+/// operation got. Every line holds the numbers of one record. Records are only on disk once
+/// `checkpoint` returns.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl Journal {
+    /// Reads the records of a journal file, or none if there is no such file. Lines that don't
+    /// parse, such as one cut off by a crash, are skipped.
+    pub(crate) fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Vec<u64>>, FsError> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(FsError::Io(e)),
+        };
+
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let record: Option<Vec<u64>> = line?.split_whitespace().map(|field| field.parse().ok()).collect();
+            if let Some(record) = record.filter(|record| !record.is_empty()) {
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Creates an empty journal file, replacing any there was. Records worth keeping from the
+    /// old one have to be appended again.
+    pub(crate) fn create<P: AsRef<Path>>(path: P) -> Result<Journal, FsError> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::create(&path).map_err(FsError::Io)?;
+        Ok(Journal { path, file: BufWriter::new(file) })
+    }
+
+    pub(crate) fn append(&mut self, record: &[u64]) -> Result<(), FsError> {
+        let line: Vec<String> = record.iter().map(|field| field.to_string()).collect();
+        writeln!(self.file, "{}", line.join(" ")).map_err(FsError::Io)
+    }
+
+    /// Makes sure the records appended so far reached the disk.
+    pub(crate) fn checkpoint(&mut self) -> Result<(), FsError> {
+        self.file.flush().map_err(FsError::Io)?;
+        self.file.get_ref().sync_data().map_err(FsError::Io)
+    }
+
+    /// Deletes the journal once the operation completed.
+    pub(crate) fn remove(self) -> Result<(), FsError> {
+        drop(self.file);
+        fs::remove_file(&self.path).map_err(FsError::Io)
+    }
+}
+
+impl FileSystem {
+    /// Brings the cache up to date with a remote like `sync`, but writes every group as soon as
+    /// it arrives and keeps track of them in a progress file, so a download that gets
+    /// interrupted can be resumed by calling this again with the same file.
+    ///
+    /// Groups are skipped without reading them when the local reference table already lists
+    /// them with the CRC the remote advertises, or when the progress file does. The reference
+    /// table of an index is only written once all of its groups are in, so until then the local
+    /// table still describes the groups it listed before. The progress file is deleted when the
+    /// download completes.
+    pub fn sync_resumable<R: Remote, P: AsRef<Path>>(&mut self, remote: &mut R, progress: P) -> Result<SyncReport, FsError> {
+        if !self.writable() {
+            return Err(FsError::ReadOnly);
+        }
+
+        let mut progress = DownloadProgress::load(progress)?;
+        let remote_checksums = ChecksumTable::decode(&container::decode(&remote.fetch(255, 255)?)?)?;
+
+        let mut report = SyncReport::default();
+
+        for (index, remote_entry) in remote_checksums.entries().iter().enumerate() {
+            let index = index as u32;
+            if remote_entry.crc32() == 0 && remote_entry.revision() == 0 {
+                continue;
+            }
+
+            match self.container_crc(255, index) {
+                Ok(crc) if crc as i32 == remote_entry.crc32() => continue,
+                Ok(_) | Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => {}
+                Err(e) => return Err(e),
+            }
+
+            let (mut tables, retried) = js5::fetch_verified(remote, &[(255, index, remote_entry.crc32())], self.download_retries(), self.digest_set());
+            let table_container = tables.remove(0)?;
+            report.retries += retried;
+
+            let remote_table = ReferenceTable::decode(&mut Cursor::new(container::decode(&table_container)?))?;
+            let local_table = match self.reference_table(index) {
+                Ok(table) => Some(table),
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => None,
+                Err(e) => return Err(e),
+            };
+
+            let mut stale = Vec::new();
+            for group in remote_table.folder_ids() {
+                let folder = remote_table.lookup(group).unwrap();
+                let listed = local_table.as_ref().and_then(|table| table.lookup(group)).map(|f| f.crc32());
+                if listed != Some(folder.crc32()) && !progress.contains(index, group as u32, folder.crc32()) {
+                    stale.push((index, group as u32, folder.crc32()));
+                }
+            }
+
+            for batch in stale.chunks(BATCH_SIZE) {
+                let (containers, retried) = js5::fetch_verified(remote, batch, self.download_retries(), self.digest_set());
+                report.retries += retried;
+
+                // Keep whatever arrived before giving up on the first group that didn't
+                for (&(_, group, crc), data) in batch.iter().zip(containers) {
+                    let folder = remote_table.lookup(group as i32).unwrap();
+                    let mut data = data?;
+                    if container::version(&data)?.is_none() {
+                        data.extend(&(folder.version() as u16).to_be_bytes());
+                    }
+
+                    self.write_container(index, group, &data)?;
+                    self.mark_clean(index, group);
+                    progress.record(index, group, crc)?;
+                    report.groups += 1;
+                }
+            }
+
+            self.write_container(255, index, &table_container)?;
+            self.mark_clean(255, index);
+            progress.finish_index(index)?;
+            report.indices.push(index);
+        }
+
+        progress.clear()?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::js5::Remote;
+    use crate::reference_table::ReferenceTable;
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+    use super::DownloadProgress;
+
+    /// A remote that fails after serving a number of groups, like a dropped connection.
+    struct Flaky<'a> {
+        fs: &'a mut FileSystem,
+        groups_left: usize,
+    }
+
+    impl Remote for Flaky<'_> {
+        fn fetch(&mut self, index: u32, group: u32) -> Result<Vec<u8>, FsError> {
+            if index != 255 {
+                if self.groups_left == 0 {
+                    return Err(FsError::RemoteFailed);
+                }
+                self.groups_left -= 1;
+            }
+            self.fs.fetch(index, group)
+        }
+    }
+
+    #[test]
+    fn interrupted_download_resumes() {
+        let base = TestDir::new("resume");
+        fs::create_dir_all(base.join("remote")).unwrap();
+        fs::create_dir_all(base.join("local")).unwrap();
+        let progress = base.join("download.progress");
+
+        let mut remote = FileSystem::new_writable(base.join("remote")).unwrap();
+        remote.write_reference_table(3, &ReferenceTable::new(6)).unwrap();
+        let groups: Vec<ContainerUpdate> = (0..5)
+            .map(|g| ContainerUpdate::new(3, g, container::encode(&vec![g as u8; 600], CompressionType::Gzip, Some(2)).unwrap()))
+            .collect();
+        remote.apply_update(&groups).unwrap();
+
+        let mut local = FileSystem::new_writable(base.join("local")).unwrap();
+        let mut flaky = Flaky { fs: &mut remote, groups_left: 2 };
+        assert!(matches!(local.sync_resumable(&mut flaky, &progress), Err(FsError::RemoteFailed)));
+        assert_eq!(DownloadProgress::load(&progress).unwrap().len(), 2);
+
+        // Only the groups that didn't make it the first time are downloaded again
+        let mut flaky = Flaky { fs: &mut remote, groups_left: 3 };
+        let report = local.sync_resumable(&mut flaky, &progress).unwrap();
+        assert_eq!(report.groups, 3);
+        assert!(!progress.exists());
+        assert_eq!(local.checksum_table().unwrap(), remote.checksum_table().unwrap());
+    }
+}
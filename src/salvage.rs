@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use crate::container;
+use crate::filesystem::{BlockHeader, FileSystem, FsError, MainFile};
+use crate::options::FileSystemOptions;
+
+/// A container put back together from the blocks of the data file alone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SalvagedGroup {
+    pub index: u32,
+    pub group: u32,
+    /// The block the chain of the container starts at.
+    pub block: u32,
+    /// The raw container, with its version trailer if it looks like it has one.
+    pub container: Vec<u8>,
+}
+
+/// What `FileSystem::salvage` found in the data file.
+#[derive(Debug, Default)]
+pub struct SalvageReport {
+    /// Every container that could be put back together, by index, group and first block. A
+    /// group shows up more than once when older copies of it were left in the file.
+    pub groups: Vec<SalvagedGroup>,
+    /// The number of blocks that were scanned.
+    pub blocks: u64,
+    /// The number of blocks that aren't part of any container that was put back together.
+    pub orphaned: u64,
+}
+
+/// What `MainFile::carve` writes of each block.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Carve {
+    /// The whole block, header included.
+    Raw,
+    /// Only the data after the block header.
+    Payload,
+}
+
+/// The two ways a block header can be read, as it doesn't say whether its entry id takes 2 or
+/// 4 bytes. The wide one is only kept for ids that need it.
+struct Headers {
+    narrow: BlockHeader,
+    wide: Option<BlockHeader>,
+}
+
+impl Headers {
+    fn get(&self, big: bool) -> Option<&BlockHeader> {
+        if big { self.wide.as_ref() } else { Some(&self.narrow) }
+    }
+}
+
+/// The index, entry, sequence number and header width of a block.
+type BlockKey = (u8, u32, u32, bool);
+
+/// A container and the blocks it was put back together from.
+type Chain = (Vec<u8>, Vec<u32>);
+
+impl FileSystem {
+    /// Scans the data file block by block and puts back together every container whose chain
+    /// of blocks is complete, without looking at the idx files at all. This is for caches whose
+    /// idx files are missing, wrong or were stripped on purpose.
+    ///
+    /// A chain starts at any block with sequence number 0 that holds a container header, and
+    /// the container header says how many bytes to collect. Each next block is the one the
+    /// previous block points to if its header fits, or else the only block whose header has the
+    /// same index and entry and the next sequence number. The version trailer isn't covered by
+    /// the container header, so the two bytes after the container are taken as one if they are
+    /// there and not zero. The secondary data file isn't scanned.
+    pub fn salvage(&mut self) -> Result<SalvageReport, FsError> {
+        let data_file = self.mainfile();
+        let num_blocks = data_file.num_blocks().ok_or(FsError::NoFileHandle)?;
+        let format = data_file.format();
+
+        let mut headers: Vec<Headers> = Vec::with_capacity(num_blocks as usize);
+        let mut keys: HashMap<BlockKey, Vec<u32>> = HashMap::new();
+        for block in 0..num_blocks as u32 {
+            let data = data_file.read_block(block)?;
+            let narrow = BlockHeader::from_block_in(format, false, &data);
+            let wide = Some(BlockHeader::from_block_in(format, true, &data)).filter(|header| header.entry_id() > 0xFFFF);
+
+            // Block 0 is never part of a chain
+            if block > 0 {
+                for header in Some(&narrow).into_iter().chain(wide.as_ref()) {
+                    let key = (data_file.index_of_block(header), header.entry_id(), header.next_seq() as u32, header.big());
+                    keys.entry(key).or_default().push(block);
+                }
+            }
+            headers.push(Headers { narrow, wide });
+        }
+
+        let mut report = SalvageReport { blocks: num_blocks, ..SalvageReport::default() };
+        let mut used: HashSet<u32> = HashSet::new();
+        for block in 1..num_blocks as u32 {
+            for big in [false, true] {
+                let Some(header) = headers[block as usize].get(big) else {
+                    continue;
+                };
+                if header.next_seq() != 0 {
+                    continue;
+                }
+
+                let index = data_file.index_of_block(header);
+                let entry = header.entry_id();
+                if let Some((container, chain)) = follow_chain(data_file, &headers, &keys, block, big)? {
+                    used.extend(chain);
+                    report.groups.push(SalvagedGroup { index: index as u32, group: entry, block, container });
+                }
+            }
+        }
+
+        report.groups.sort_by_key(|group| (group.index, group.group, group.block));
+        report.orphaned = num_blocks.saturating_sub(1).saturating_sub(used.len() as u64);
+        Ok(report)
+    }
+
+    /// Salvages the data file like `salvage` does and writes what it found into a new cache in
+    /// another folder, with the same naming, block size and idx format. Of the copies of a
+    /// group, the last one in the file that decompresses or is encrypted wins, as chains are
+    /// appended to the end of the file when they outgrow their blocks. Every group but the
+    /// reference tables is left dirty, so the tables are brought up to date with what was
+    /// salvaged when the new cache is flushed, at the latest when it is dropped.
+    pub fn salvage_to<P: AsRef<Path>>(&mut self, dest: P) -> Result<SalvageReport, FsError> {
+        let report = self.salvage()?;
+
+        fs::create_dir_all(dest.as_ref()).map_err(FsError::Io)?;
+        let options = FileSystemOptions::new().with_writable(true).with_naming(self.naming().clone())
+            .with_block_size(self.mainfile().block_size()).with_index_format(self.index_format());
+        let mut target = FileSystem::open_with(dest, options)?;
+
+        let mut chosen: BTreeMap<(u32, u32), &SalvagedGroup> = BTreeMap::new();
+        for group in &report.groups {
+            let container = &group.container;
+            if container::looks_encrypted(container) || container::decode(container).is_ok() {
+                chosen.insert((group.index, group.group), group);
+            }
+        }
+
+        for ((index, group), salvaged) in chosen {
+            target.write_container(index, group, &salvaged.container)?;
+            if index == 255 {
+                target.mark_clean(index, group);
+            }
+        }
+
+        target.sync_all()?;
+        Ok(report)
+    }
+}
+
+impl MainFile {
+    /// Copies a range of blocks into a file as they are, or just their payloads, without
+    /// checking anything about them. The range is cut off at the end of the data file. Next to
+    /// the file, named like it with a ".headers" suffix, goes a text file with the header of
+    /// every block as stored: its number, index id, entry id, sequence number and next block.
+    /// `big` says whether the headers are read with 4-byte entry ids, which also decides where
+    /// the payload starts. Returns the number of blocks copied.
+    pub fn carve<P: AsRef<Path>>(&mut self, blocks: Range<u32>, what: Carve, big: bool, dest: P) -> Result<u64, FsError> {
+        let dest = dest.as_ref();
+        let end = (blocks.end as u64).min(self.num_blocks().ok_or(FsError::NoFileHandle)?) as u32;
+        let header_len = self.format().block_header_len(big);
+
+        let mut out = BufWriter::new(File::create(dest).map_err(FsError::Io)?);
+        let mut headers = String::new();
+        for block in blocks.start..end {
+            let data = self.read_block(block)?;
+            let header = BlockHeader::from_block_in(self.format(), big, &data);
+            let _ = writeln!(headers, "{} {} {} {} {}", block, header.index_id(), header.entry_id(), header.next_seq(), header.next_block());
+
+            let bytes = match what {
+                Carve::Raw => &data[..],
+                Carve::Payload => &data[header_len..],
+            };
+            out.write_all(bytes).map_err(FsError::Io)?;
+        }
+        out.flush().map_err(FsError::Io)?;
+
+        let mut sidecar = dest.as_os_str().to_owned();
+        sidecar.push(".headers");
+        fs::write(sidecar, headers).map_err(FsError::Io)?;
+        Ok(end.saturating_sub(blocks.start) as u64)
+    }
+}
+
+/// Follows the chain of blocks starting at a block until it holds the whole container, and
+/// returns the container and the blocks it came from, or `None` if the chain breaks off first.
+fn follow_chain(data_file: &mut MainFile, headers: &[Headers], keys: &HashMap<BlockKey, Vec<u32>>, first: u32,
+                big: bool) -> Result<Option<Chain>, FsError> {
+    let header_len = data_file.format().block_header_len(big);
+    let start = headers[first as usize].get(big).unwrap();
+    let (index, entry) = (data_file.index_of_block(start), start.entry_id());
+
+    let mut data = data_file.read_block(first)?.split_off(header_len);
+    if data[0] > 3 {
+        return Ok(None);
+    }
+    let payload = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as u64;
+    let length = payload + if data[0] == 0 { 5 } else { 9 };
+    if length.div_ceil(data.len() as u64) >= headers.len() as u64 {
+        return Ok(None);
+    }
+    let length = length as usize;
+
+    let mut chain = vec![first];
+    let mut visited: HashSet<u32> = HashSet::from([first]);
+    // The version trailer can spill over into one more block, which is only taken if pointed to
+    while data.len() < length + 2 {
+        let seq = chain.len() as u32 & 0xFFFF;
+        let fits = |block: u32| {
+            !visited.contains(&block) && headers.get(block as usize).and_then(|headers| headers.get(big)).is_some_and(|header| {
+                data_file.index_of_block(header) == index && header.entry_id() == entry && header.next_seq() as u32 == seq
+            })
+        };
+
+        let pointed = headers[*chain.last().unwrap() as usize].get(big).unwrap().next_block();
+        let next = if pointed != 0 && fits(pointed) {
+            pointed
+        } else if data.len() < length {
+            match keys.get(&(index, entry, seq, big)).map(|blocks| blocks.iter().copied().filter(|block| fits(*block)).collect::<Vec<u32>>()) {
+                Some(candidates) if candidates.len() == 1 => candidates[0],
+                _ => return Ok(None),
+            }
+        } else {
+            break;
+        };
+
+        data.extend_from_slice(&data_file.read_block(next)?[header_len..]);
+        chain.push(next);
+        visited.insert(next);
+    }
+
+    let trailer = data.len() >= length + 2 && data[length..length + 2] != [0, 0];
+    data.truncate(if trailer { length + 2 } else { length });
+    Ok(Some((data, chain)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+    use super::Carve;
+
+    #[test]
+    fn containers_are_recovered_without_idx_files() {
+        let dir = TestDir::new("salvage");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        let long: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+        fs.write_group(2, 4, &long, CompressionType::None, Some(9)).unwrap();
+        fs.write_group(2, 5, b"short", CompressionType::Gzip, None).unwrap();
+        fs.write_group(7, 70000, &long, CompressionType::Bzip2, Some(1)).unwrap();
+        let expected: Vec<Vec<u8>> = [(2, 4), (2, 5), (7, 70000)].iter().map(|(i, g)| fs.read_container(*i, *g).unwrap()).collect();
+
+        // The first group takes blocks 1 to 6, so carving its first two payloads gives its start
+        let block = fs.index(2).unwrap().entry(4).unwrap().block();
+        let carved = dir.join("carved.bin");
+        assert_eq!(fs.mainfile().carve(block..block + 2, Carve::Payload, false, &carved).unwrap(), 2);
+        assert_eq!(fs::read(&carved).unwrap(), expected[0][..1024]);
+        let headers = fs::read_to_string(dir.join("carved.bin.headers")).unwrap();
+        assert_eq!(headers.lines().next().unwrap(), format!("{} 2 4 0 {}", block, block + 1));
+        assert_eq!(fs.mainfile().carve(0..1000, Carve::Raw, false, &carved).unwrap(), fs.mainfile().num_blocks().unwrap());
+        assert_eq!(fs::read(&carved).unwrap().len() as u64, fs.mainfile().num_blocks().unwrap() * 520);
+        drop(fs);
+
+        // Nothing but the data file is left
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext.to_string_lossy().starts_with("idx")) {
+                fs::remove_file(path).unwrap();
+            }
+        }
+
+        let mut fs = FileSystem::new(&dir).unwrap();
+        assert_eq!(fs.index_count(), 0);
+        let report = fs.salvage().unwrap();
+        let found: Vec<(u32, u32)> = report.groups.iter().map(|group| (group.index, group.group)).filter(|(index, _)| *index != 255).collect();
+        assert_eq!(found, vec![(2, 4), (2, 5), (7, 70000)]);
+        let containers: Vec<Vec<u8>> = report.groups.iter().filter(|group| group.index != 255).map(|group| group.container.clone()).collect();
+        assert_eq!(containers, expected);
+        assert_eq!(report.orphaned, 0);
+
+        let out = dir.join("salvaged");
+        fs.salvage_to(&out).unwrap();
+        let mut salvaged = FileSystem::new(&out).unwrap();
+        assert_eq!(container::decode(&salvaged.read_container(7, 70000).unwrap()).unwrap(), long);
+        assert!(salvaged.verify_all(1).unwrap().is_ok());
+    }
+}
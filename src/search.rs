@@ -0,0 +1,140 @@
+use std::sync::Mutex;
+use std::thread;
+use crate::container;
+use crate::filesystem::{FileSystem, FsError};
+
+/// What `FileSystem::search` looks for in decompressed group data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SearchPattern {
+    /// An exact run of bytes.
+    Bytes(Vec<u8>),
+    /// A run of bytes in which `None` matches any byte, for magic numbers surrounded by fields
+    /// that vary.
+    Masked(Vec<Option<u8>>),
+}
+
+impl SearchPattern {
+    /// A pattern matching the bytes of a string.
+    pub fn text(text: &str) -> SearchPattern {
+        SearchPattern::Bytes(text.as_bytes().to_vec())
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            SearchPattern::Bytes(bytes) => bytes.len(),
+            SearchPattern::Masked(bytes) => bytes.len(),
+        }
+    }
+
+    fn matches_at(&self, data: &[u8]) -> bool {
+        match self {
+            SearchPattern::Bytes(bytes) => data.starts_with(bytes),
+            SearchPattern::Masked(bytes) => data.len() >= bytes.len()
+                && bytes.iter().zip(data).all(|(expected, actual)| expected.is_none_or(|e| e == *actual)),
+        }
+    }
+
+    /// Finds every offset in the data at which the pattern starts, overlapping ones included.
+    pub fn find_all(&self, data: &[u8]) -> Vec<usize> {
+        if self.len() == 0 || self.len() > data.len() {
+            return Vec::new();
+        }
+
+        (0..=data.len() - self.len()).filter(|&offset| self.matches_at(&data[offset..])).collect()
+    }
+}
+
+/// A group whose decompressed data contains the pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchMatch {
+    pub index: u32,
+    pub group: u32,
+    /// Where the pattern starts in the decompressed data, in ascending order.
+    pub offsets: Vec<usize>,
+}
+
+impl FileSystem {
+    /// Searches the decompressed data of every group of some indices for a pattern. Without any
+    /// indices, all of them except the reference tables are searched. Groups that can't be
+    /// decompressed, such as encrypted ones, are skipped.
+    ///
+    /// Reading the containers is done one at a time, but with more than one thread, each index is
+    /// decompressed and searched by that many threads at once. Matches are returned in cache
+    /// order either way.
+    pub fn search(&mut self, pattern: &SearchPattern, indices: &[u32], threads: usize) -> Result<Vec<SearchMatch>, FsError> {
+        let _bulk = self.bulk_read();
+        let indices = if indices.is_empty() {
+            self.indices().into_iter().filter(|index| *index != 255).collect()
+        } else {
+            indices.to_vec()
+        };
+
+        let mut matches = Vec::new();
+        for index in indices {
+            let count = self.index(index).ok_or(FsError::IndexNotFound)?.last_entry() as u32;
+            let mut containers = Vec::new();
+            for group in 0..count {
+                match self.read_container(index, group) {
+                    Ok(data) => containers.push((group, data)),
+                    Err(FsError::EntryNotFound) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let scan = |group: u32, data: &[u8]| -> Option<SearchMatch> {
+                let offsets = pattern.find_all(&container::decode(data).ok()?);
+                if offsets.is_empty() { None } else { Some(SearchMatch { index, group, offsets }) }
+            };
+
+            let mut found: Vec<SearchMatch> = if threads <= 1 {
+                containers.iter().filter_map(|(group, data)| scan(*group, data)).collect()
+            } else {
+                let found = Mutex::new(Vec::new());
+                let chunk = containers.len().div_ceil(threads).max(1);
+                thread::scope(|scope| {
+                    for part in containers.chunks(chunk) {
+                        let (found, scan) = (&found, &scan);
+                        scope.spawn(move || {
+                            let part: Vec<SearchMatch> = part.iter().filter_map(|(group, data)| scan(*group, data)).collect();
+                            found.lock().unwrap().extend(part);
+                        });
+                    }
+                });
+                found.into_inner().unwrap()
+            };
+
+            found.sort_by_key(|m| m.group);
+            matches.extend(found);
+        }
+
+        Ok(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+    use super::SearchPattern;
+
+    #[test]
+    fn search_decompressed_groups() {
+        let dir = TestDir::new("search");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        for group in 0..8u32 {
+            let data = if group % 3 == 0 { format!("{} Lumbridge Lumbridge", group) } else { format!("{} Varrock", group) };
+            fs.write_group(12, group, data.as_bytes(), CompressionType::Gzip, None).unwrap();
+        }
+        fs.write_group(13, 0, &[0x1F, 0x8B, 0x08, 0x00], CompressionType::Bzip2, None).unwrap();
+
+        let serial = fs.search(&SearchPattern::text("Lumbridge"), &[12], 1).unwrap();
+        assert_eq!(serial.iter().map(|m| m.group).collect::<Vec<_>>(), vec![0, 3, 6]);
+        assert_eq!(serial[0].offsets, vec![2, 12]);
+        assert_eq!(fs.search(&SearchPattern::text("Lumbridge"), &[12], 4).unwrap(), serial);
+
+        let magic = SearchPattern::Masked(vec![Some(0x1F), Some(0x8B), None]);
+        let found = fs.search(&magic, &[], 2).unwrap();
+        assert_eq!((found.len(), found[0].index), (1, 13));
+    }
+}
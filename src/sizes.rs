@@ -0,0 +1,204 @@
+use crate::filesystem::{CompressionType, FileSystem, FsError};
+
+/// How big the container of a group is, compressed and not.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GroupSize {
+    pub compression: CompressionType,
+    /// The length of the container without its version trailer.
+    pub compressed: u32,
+    /// The length of the data inside.
+    pub uncompressed: u32,
+}
+
+impl GroupSize {
+    /// Gets the compressed size as a fraction of the uncompressed size.
+    pub fn ratio(&self) -> f64 {
+        ratio(self.compressed as u64, self.uncompressed as u64)
+    }
+}
+
+/// The total size of the groups of an index, compressed and not.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct IndexSize {
+    pub groups: usize,
+    pub compressed: u64,
+    pub uncompressed: u64,
+}
+
+impl IndexSize {
+    /// Gets the compressed size as a fraction of the uncompressed size.
+    pub fn ratio(&self) -> f64 {
+        ratio(self.compressed, self.uncompressed)
+    }
+}
+
+/// How much of the data files one group or all groups of an index take up.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub groups: usize,
+    /// The number of blocks the chains take.
+    pub blocks: u64,
+    /// The bytes those blocks take, which is `stored + headers + padding`.
+    pub bytes: u64,
+    /// The bytes of the stored containers, as the idx entries give them.
+    pub stored: u64,
+    /// The bytes of the block headers.
+    pub headers: u64,
+    /// The unused bytes at the end of the last block of each chain.
+    pub padding: u64,
+}
+
+impl DiskUsage {
+    fn add(&mut self, other: DiskUsage) {
+        self.groups += other.groups;
+        self.blocks += other.blocks;
+        self.bytes += other.bytes;
+        self.stored += other.stored;
+        self.headers += other.headers;
+        self.padding += other.padding;
+    }
+}
+
+fn ratio(compressed: u64, uncompressed: u64) -> f64 {
+    if uncompressed == 0 { 1.0 } else { compressed as f64 / uncompressed as f64 }
+}
+
+impl FileSystem {
+    /// Gets the sizes of a group from the header of its container, which is all that is read.
+    pub fn group_size(&mut self, index: u32, group: u32) -> Result<GroupSize, FsError> {
+        let header = self.read_container_range(index, group, 0, 9)?;
+        if header.len() < 5 {
+            return Err(FsError::CorruptedData);
+        }
+
+        let compression = CompressionType::from_code(header[0]);
+        let payload = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        if compression == CompressionType::None {
+            let compressed = payload.checked_add(5).ok_or(FsError::CorruptedData)?;
+            return Ok(GroupSize { compression, compressed, uncompressed: payload });
+        }
+
+        if header.len() < 9 {
+            return Err(FsError::CorruptedData);
+        }
+        let uncompressed = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+        let compressed = payload.checked_add(9).ok_or(FsError::CorruptedData)?;
+        Ok(GroupSize { compression, compressed, uncompressed })
+    }
+
+    /// Adds up the sizes of every group of an index. When the reference table of the index lists
+    /// the lengths of its groups, they are taken from there without reading any container.
+    pub fn index_size(&mut self, index: u32) -> Result<IndexSize, FsError> {
+        match self.reference_table(index) {
+            Ok(table) if table.flags().has_lengths() => {
+                let folders = table.folder_ids();
+                let mut size = IndexSize { groups: folders.len(), ..IndexSize::default() };
+                for folder in folders.iter().map(|id| table.lookup(*id).unwrap()) {
+                    size.compressed += folder.compressed_length() as u64;
+                    size.uncompressed += folder.uncompressed_length() as u64;
+                }
+                return Ok(size);
+            }
+            Ok(_) | Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        let count = self.index(index).ok_or(FsError::IndexNotFound)?.last_entry() as u32;
+        let mut size = IndexSize::default();
+        for group in 0..count {
+            let group = match self.group_size(index, group) {
+                Ok(group) => group,
+                Err(FsError::EntryNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+
+            size.groups += 1;
+            size.compressed += group.compressed as u64;
+            size.uncompressed += group.uncompressed as u64;
+        }
+
+        Ok(size)
+    }
+
+    /// Works out how much of the data file a group takes up from its idx entry: every block of
+    /// its chain in full, headers and the padding of the last one included. Groups with ids above
+    /// 65535 have bigger block headers, so they take more blocks for the same size.
+    pub fn disk_usage(&mut self, index: u32, group: u32) -> Result<DiskUsage, FsError> {
+        let entry = self.index(index).ok_or(FsError::IndexNotFound)?.try_entry(group)?;
+        if entry.size() == 0 {
+            return Ok(DiskUsage::default());
+        }
+
+        let (data_file, entry) = self.route(entry);
+        let header = data_file.format().block_header_len(group > 0xFFFF) as u64;
+        let block_size = data_file.block_size() as u64;
+        let stored = entry.size() as u64;
+        let blocks = stored.div_ceil(block_size - header);
+        Ok(DiskUsage { groups: 1, blocks, bytes: blocks * block_size, stored, headers: blocks * header,
+            padding: blocks * (block_size - header) - stored })
+    }
+
+    /// Adds up how much of the data files every group of an index takes up, like `disk_usage`.
+    pub fn index_disk_usage(&mut self, index: u32) -> Result<DiskUsage, FsError> {
+        let count = self.index(index).ok_or(FsError::IndexNotFound)?.last_entry() as u32;
+        let mut usage = DiskUsage::default();
+        for group in 0..count {
+            match self.disk_usage(index, group) {
+                Ok(group) => usage.add(group),
+                Err(FsError::EntryNotFound) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::container::{self, CompressionLevel};
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::reference_table::{ReferenceTable, ReferenceTableFlags};
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+
+    #[test]
+    fn sizes_from_headers_and_tables() {
+        let dir = TestDir::new("sizes");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_group(1, 0, &[0u8; 5000], CompressionType::Gzip, Some(3)).unwrap();
+        fs.write_group(1, 1, &[1u8; 300], CompressionType::None, None).unwrap();
+
+        let stored = fs.read_container(1, 0).unwrap();
+        let size = fs.group_size(1, 0).unwrap();
+        assert_eq!((size.compressed as usize, size.uncompressed), (stored.len() - 2, 5000));
+        assert!(size.ratio() < 0.1);
+
+        let index = fs.index_size(1).unwrap();
+        assert_eq!((index.groups, index.uncompressed), (2, 5300));
+        assert_eq!(index.compressed, size.compressed as u64 + 305);
+
+        // Tables with lengths answer without reading the groups
+        let mut table = ReferenceTable::new(6);
+        table.set_flags(ReferenceTableFlags::new().with_lengths(true)).unwrap();
+        fs.write_reference_table(2, &table).unwrap();
+        let group = container::encode(&[7u8; 800], CompressionType::Bzip2, None).unwrap();
+        fs.apply_update(&[ContainerUpdate::new(2, 0, group.clone())]).unwrap();
+        let index = fs.index_size(2).unwrap();
+        assert_eq!((index.compressed as usize, index.uncompressed), (group.len(), 800));
+
+        // 5000 bytes of zeroes compress into one block, 305 bytes take one too
+        let usage = fs.disk_usage(1, 1).unwrap();
+        assert_eq!((usage.blocks, usage.bytes, usage.headers, usage.padding), (1, 520, 8, 207));
+        let usage = fs.index_disk_usage(1).unwrap();
+        assert_eq!((usage.groups, usage.blocks, usage.bytes), (2, 2, 1040));
+        assert_eq!(usage.bytes, usage.stored + usage.headers + usage.padding);
+
+        let estimate = container::estimate_compressed_size(&[0u8; 5000], CompressionType::Gzip, CompressionLevel::default()).unwrap();
+        assert_eq!(estimate, size.compressed as usize);
+
+        // A header claiming a length the container can't have is corrupt, not a panic
+        fs.write_container(1, 2, &[1, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0]).unwrap();
+        assert!(matches!(fs.group_size(1, 2), Err(FsError::CorruptedData)));
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use flate2::write::GzEncoder;
+use crate::backing::Backing;
+use crate::container::CompressionLevel;
+use crate::filesystem::{FileSystem, FsError};
+
+impl FileSystem {
+    /// Copies the mainfile and every idx file into another folder, creating it if needed. Pending
+    /// writes are flushed to disk first, and since the copy borrows the filesystem mutably no
+    /// write can slip in halfway through. The copies are named the same as the originals, and with
+    /// a compression level every file is also gzipped and gets a ".gz" suffix.
+    pub fn snapshot<P: AsRef<Path>>(&mut self, dest: P, compression: Option<CompressionLevel>) -> Result<(), FsError> {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest).map_err(FsError::Io)?;
+        self.sync_all()?;
+
+        let naming = self.naming().clone();
+        let file = self.mainfile().file().ok_or(FsError::NoFileHandle)?;
+        copy_file(file, &dest.join(naming.data_file()), compression)?;
+        if let Some(file) = self.secondary_mainfile().and_then(|secondary| secondary.file()) {
+            copy_file(file, &dest.join(naming.secondary_data_file()), compression)?;
+        }
+
+        for id in self.indices() {
+            let file = self.index(id).unwrap().file();
+            copy_file(file, &dest.join(naming.index_file(id)), compression)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Copies a whole file to a path and makes sure the copy reached the disk.
+fn copy_file(source: &mut Backing, dest: &Path, compression: Option<CompressionLevel>) -> Result<(), FsError> {
+    source.seek(SeekFrom::Start(0))?;
+
+    let copy = |source: &mut Backing| -> io::Result<()> {
+        match compression {
+            None => {
+                let mut out = File::create(dest)?;
+                io::copy(source, &mut out)?;
+                out.sync_all()
+            }
+            Some(level) => {
+                let mut name = dest.as_os_str().to_owned();
+                name.push(".gz");
+                let mut out = GzEncoder::new(File::create(name)?, flate2::Compression::new(level.level()));
+                io::copy(source, &mut out)?;
+                let mut out = out.finish()?;
+                out.flush()?;
+                out.sync_all()
+            }
+        }
+    };
+
+    copy(source).map_err(FsError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn snapshot_is_an_identical_cache() {
+        let base = TestDir::new("snapshot");
+        fs::create_dir_all(base.join("live")).unwrap();
+
+        let mut live = FileSystem::new_writable(base.join("live")).unwrap();
+        live.write_group(0, 1, &[1u8; 2000], CompressionType::Gzip, Some(1)).unwrap();
+        live.write_group(7, 3, &[3u8; 10], CompressionType::None, None).unwrap();
+        live.snapshot(base.join("backup"), None).unwrap();
+
+        // Changes made after the snapshot don't show up in it
+        live.write_group(0, 1, &[2u8; 2000], CompressionType::Gzip, Some(2)).unwrap();
+
+        let mut backup = FileSystem::new(base.join("backup")).unwrap();
+        assert_eq!(backup.indices(), vec![0, 7]);
+        assert_eq!(backup.container_reader(7, 3).unwrap().len(), 10);
+        assert_ne!(backup.read_container(0, 1).unwrap(), live.read_container(0, 1).unwrap());
+    }
+}
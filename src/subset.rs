@@ -0,0 +1,75 @@
+use std::fs;
+use std::path::Path;
+use crate::filesystem::{FileSystem, FsError};
+
+impl FileSystem {
+    /// Copies only some indices into a new cache in another folder, and returns it opened for
+    /// writing. Every group of the indices is copied as-is, along with their reference tables,
+    /// so the checksum table of the new cache lists just those indices.
+    pub fn subset<P: AsRef<Path>>(&mut self, indices: &[u32], dest: P) -> Result<FileSystem, FsError> {
+        // Index 255 is rebuilt from the chosen indices, so it can't be chosen itself
+        if indices.iter().any(|&index| index == 255 || self.index(index).is_none()) {
+            return Err(FsError::IndexNotFound);
+        }
+
+        fs::create_dir_all(dest.as_ref()).map_err(FsError::Io)?;
+        let mut target = FileSystem::new_writable(dest)?;
+
+        for &index in indices {
+            let count = self.index(index).unwrap().last_entry() as u32;
+            for group in 0..count {
+                match self.read_container(index, group) {
+                    Ok(container) => {
+                        target.write_container(index, group, &container)?;
+                        target.mark_clean(index, group);
+                    }
+                    Err(FsError::EntryNotFound) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            match self.read_container(255, index) {
+                Ok(container) => {
+                    target.write_container(255, index, &container)?;
+                    target.mark_clean(255, index);
+                }
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        target.sync_all()?;
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::reference_table::ReferenceTable;
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+
+    #[test]
+    fn subset_keeps_only_chosen_indices() {
+        let base = TestDir::new("subset");
+        fs::create_dir_all(base.join("full")).unwrap();
+
+        let mut full = FileSystem::new_writable(base.join("full")).unwrap();
+        for index in 0..3 {
+            full.write_reference_table(index, &ReferenceTable::new(6)).unwrap();
+            let group = container::encode(&[index as u8; 600], CompressionType::Gzip, Some(1)).unwrap();
+            full.apply_update(&[ContainerUpdate::new(index, 5, group)]).unwrap();
+        }
+
+        let mut subset = full.subset(&[1], base.join("subset")).unwrap();
+        assert_eq!(subset.indices(), vec![1, 255]);
+        assert_eq!(subset.read_container(1, 5).unwrap(), full.read_container(1, 5).unwrap());
+
+        let checksums = subset.checksum_table().unwrap();
+        assert_eq!(checksums.entry(0).unwrap().crc32(), 0);
+        assert_eq!(checksums.entry(1).unwrap(), full.checksum_table().unwrap().entry(1).unwrap());
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::sync::Arc;
+use crate::filesystem::{FileSystem, FsError};
+
+/// A reversible change made to containers as they are stored, such as the extra encryption or
+/// obfuscation some servers apply to their caches. `decode` turns the bytes in the data file back
+/// into a container, and `encode` does the opposite, so everything above the data file sees plain
+/// containers.
+///
+/// The transform sits right at the data file. Containers in the copy-on-write layer are kept as
+/// they were written, and checks that look at the data file directly, like validating idx entries,
+/// see the stored bytes.
+pub trait Transform: Send + Sync {
+    /// Turns the bytes stored for a group back into its container.
+    fn decode(&self, index: u32, group: u32, stored: Vec<u8>) -> Result<Vec<u8>, FsError>;
+
+    /// Turns the container of a group into the bytes to store for it.
+    fn encode(&self, index: u32, group: u32, container: Vec<u8>) -> Result<Vec<u8>, FsError>;
+}
+
+/// The transform installed on a filesystem, if any.
+#[derive(Clone, Default)]
+pub(crate) struct TransformSlot(Option<Arc<dyn Transform>>);
+
+impl TransformSlot {
+    pub(crate) fn get(&self) -> Option<&Arc<dyn Transform>> {
+        self.0.as_ref()
+    }
+}
+
+impl fmt::Debug for TransformSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "TransformSlot(installed)" } else { "TransformSlot(none)" })
+    }
+}
+
+impl From<Option<Arc<dyn Transform>>> for TransformSlot {
+    fn from(transform: Option<Arc<dyn Transform>>) -> TransformSlot {
+        TransformSlot(transform)
+    }
+}
+
+impl FileSystem {
+    /// Installs a transform applied to every container read from or written to the data file,
+    /// replacing any installed before, or removes it with `None`. Cached CRCs are dropped, as
+    /// they may have been taken from containers read with another transform.
+    pub fn set_transform(&mut self, transform: Option<Arc<dyn Transform>>) {
+        self.replace_transform(transform.into());
+    }
+
+    /// Gets the installed transform, if any.
+    pub fn transform(&self) -> Option<Arc<dyn Transform>> {
+        self.transform_slot().0.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::options::FileSystemOptions;
+    use crate::test_dir::TestDir;
+    use super::Transform;
+
+    struct Xor(u8);
+
+    impl Transform for Xor {
+        fn decode(&self, _index: u32, group: u32, mut stored: Vec<u8>) -> Result<Vec<u8>, FsError> {
+            stored.iter_mut().for_each(|b| *b ^= self.0 ^ group as u8);
+            Ok(stored)
+        }
+
+        fn encode(&self, index: u32, group: u32, container: Vec<u8>) -> Result<Vec<u8>, FsError> {
+            self.decode(index, group, container)
+        }
+    }
+
+    #[test]
+    fn transformed_caches_read_like_plain_ones() {
+        let dir = TestDir::new("transform");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.set_transform(Some(Arc::new(Xor(0x5a))));
+        fs.write_group(2, 3, b"obfuscated", CompressionType::Gzip, Some(4)).unwrap();
+        assert_eq!(container::decode(&fs.read_container(2, 3).unwrap()).unwrap(), b"obfuscated");
+        assert_eq!(fs.read_container_range(2, 3, 0, 1).unwrap(), vec![CompressionType::Gzip as u8]);
+        assert!(fs.verify_all(1).unwrap().is_ok());
+
+        // Without the transform the stored bytes are not a container
+        let container = fs.read_container(2, 3).unwrap();
+        fs.set_transform(None);
+        let stored = fs.read_container(2, 3).unwrap();
+        assert_ne!(stored, container);
+        assert!(container::decode(&stored).is_err());
+
+        // Readers opened with the transform see the containers again, and so do pools
+        let options = FileSystemOptions::new().with_transform(Arc::new(Xor(0x5a)));
+        let mut reader = FileSystem::open_with(&dir, options).unwrap();
+        assert_eq!(container::decode(&reader.read_container(2, 3).unwrap()).unwrap(), b"obfuscated");
+        assert_eq!(reader.reader_pool(1).unwrap().read_container(2, 3).unwrap(), container);
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, BTreeSet, btree_map::Entry};
+use std::io::Cursor;
+use crate::checksum_table::ChecksumTable;
+use crate::container;
+use crate::digest::Digests;
+use crate::filesystem::{FileSystem, FsError};
+use crate::reference_table::{ReferenceTable, ReferenceTableFile, ReferenceTableFolder};
+
+/// A downloaded container and the group it belongs to, as consumed by `FileSystem::apply_update`.
+/// Containers for index 255 are reference tables.
+#[derive(Clone, Debug)]
+pub struct ContainerUpdate {
+    pub index: u32,
+    pub group: u32,
+    pub container: Vec<u8>,
+}
+
+impl ContainerUpdate {
+    pub fn new(index: u32, group: u32, container: Vec<u8>) -> ContainerUpdate {
+        ContainerUpdate { index, group, container }
+    }
+}
+
+impl FileSystem {
+    /// Applies a set of downloaded containers to the cache and returns the resulting master
+    /// checksum table.
+    ///
+    /// Reference tables that are part of the update are authoritative: every group container for
+    /// their index must match the CRC they list. For indices whose reference table is not part of
+    /// the update, the local table is patched with the CRC and version of each new container and
+    /// its revision is bumped. Everything is validated before anything is written, so a rejected
+    /// update leaves the cache untouched. In copy-on-write mode, the update goes to the layer
+    /// like any other write, so it can be applied to a read-only filesystem too.
+    pub fn apply_update(&mut self, updates: &[ContainerUpdate]) -> Result<ChecksumTable, FsError> {
+        if !self.writable() && !self.is_copy_on_write() {
+            return Err(FsError::ReadOnly);
+        }
+
+        let mut tables: BTreeMap<u32, ReferenceTable> = BTreeMap::new();
+        let mut provided: BTreeSet<u32> = BTreeSet::new();
+
+        for update in updates.iter().filter(|u| u.index == 255) {
+            let data = container::decode(&update.container)?;
+            let table = ReferenceTable::decode(&mut Cursor::new(data))?;
+            tables.insert(update.group, table);
+            provided.insert(update.group);
+        }
+
+        for update in updates.iter().filter(|u| u.index != 255) {
+            if let Entry::Vacant(entry) = tables.entry(update.index) {
+                let table = match self.reference_table(update.index) {
+                    Ok(table) => table,
+                    Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => ReferenceTable::new(6),
+                    Err(e) => return Err(e),
+                };
+                entry.insert(table);
+            }
+
+            let table = tables.get_mut(&update.index).unwrap();
+            let crc = self.digest_set().container_crc(&update.container)? as i32;
+
+            if provided.contains(&update.index) {
+                match table.lookup(update.group as i32) {
+                    Some(folder) if folder.crc32() == crc => {}
+                    _ => return Err(FsError::CrcMismatch),
+                }
+            } else {
+                patch_folder(table, update.group, &update.container, self.digest_set())?;
+            }
+        }
+
+        // Everything checks out, so write the groups followed by the tables that describe them
+        for update in updates.iter().filter(|u| u.index != 255) {
+            self.write_container(update.index, update.group, &update.container)?;
+        }
+
+        for update in updates.iter().filter(|u| u.index == 255) {
+            self.write_container(255, update.group, &update.container)?;
+        }
+
+        for (index, table) in tables.iter_mut().filter(|(index, _)| !provided.contains(index)) {
+            table.set_revision(table.revision().wrapping_add(1));
+            self.write_reference_table(*index, table)?;
+        }
+
+        // The tables now describe every group that was written
+        for update in updates {
+            self.mark_clean(update.index, update.group);
+        }
+        for index in tables.keys() {
+            self.mark_clean(255, *index);
+        }
+
+        self.checksum_table()
+    }
+
+    /// Brings the reference tables up to date with the groups written since they last were, and
+    /// returns the resulting master checksum table. Only the dirty groups are read and hashed,
+    /// so this is cheap after a few edits to a large cache. Each table that changes gets its
+    /// revision bumped, and a dirty group that no longer exists is dropped from its table. An
+    /// index without a reference table gets a new one.
+    pub fn refresh_reference_tables(&mut self) -> Result<ChecksumTable, FsError> {
+        self.refresh_tables(true)?;
+        self.checksum_table()
+    }
+
+    /// Writes everything the cache on disk is missing: the reference tables are brought up to
+    /// date with the groups written since they last were, and every file is synced. Afterwards
+    /// the cache is consistent on disk. Unlike `refresh_reference_tables`, no table is made for
+    /// an index that has none, as caches that are being built or converted often don't have
+    /// them yet; its groups are just no longer dirty. idx records are never held back, they are
+    /// written along with their containers.
+    ///
+    /// A writable filesystem that is dropped with tables that need updating is flushed then.
+    pub fn flush(&mut self) -> Result<(), FsError> {
+        self.refresh_tables(false)?;
+        self.sync_all()
+    }
+
+    /// Checks if `flush` has reference tables to update.
+    pub(crate) fn has_pending_metadata(&mut self) -> bool {
+        let dirty = self.dirty_groups();
+        let tables = match self.index(255) {
+            Some(tables) => tables,
+            None => return false,
+        };
+        dirty.iter().any(|(index, _)| *index != 255 && tables.entry(*index).is_some_and(|entry| entry.size() > 0))
+    }
+
+    fn refresh_tables(&mut self, create: bool) -> Result<(), FsError> {
+        if !self.writable() {
+            return Err(FsError::ReadOnly);
+        }
+
+        let mut dirty: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for (index, group) in self.dirty_groups().into_iter().filter(|(index, _)| *index != 255) {
+            dirty.entry(index).or_default().push(group);
+        }
+
+        for (index, groups) in dirty {
+            let mut table = match self.reference_table(index) {
+                Ok(table) => table,
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) if create => ReferenceTable::new(6),
+                Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+
+            for group in groups {
+                match self.read_container(index, group) {
+                    Ok(container) => patch_folder(&mut table, group, &container, self.digest_set())?,
+                    Err(FsError::EntryNotFound) => { table.remove(group as i32); }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            table.set_revision(table.revision().wrapping_add(1));
+            self.write_reference_table(index, &table)?;
+        }
+
+        self.clear_dirty();
+        Ok(())
+    }
+}
+
+/// Updates the folder of a group in a reference table to describe a new container, adding the
+/// folder if the table doesn't list it yet. The hashes are taken with the digests of the
+/// filesystem.
+fn patch_folder(table: &mut ReferenceTable, group: u32, data: &[u8], digests: &Digests) -> Result<(), FsError> {
+    let flags = table.flags();
+    let id = group as i32;
+
+    if table.lookup(id).is_none() {
+        // A lone container says nothing about the files inside, so assume there is just one
+        let mut folder = ReferenceTableFolder::new(id);
+        folder.insert_file(ReferenceTableFile::new(0, 0));
+        table.insert(folder);
+    }
+
+    let length = container::length(data)?;
+    let folder = table.lookup_mut(id).unwrap();
+    folder.set_crc32(digests.crc32(&data[..length]) as i32);
+
+    if let Some(version) = container::version(data)? {
+        folder.set_version(version as u32);
+    }
+
+    if flags.has_whirlpool() {
+        folder.set_whirlpool(digests.whirlpool(&data[..length]).to_vec());
+    }
+
+    // Encrypted containers can't be decompressed here, so they keep their old values
+    if flags.has_lengths() || flags.has_uncompressed_crc() {
+        if let Ok(decoded) = container::decode(data) {
+            folder.set_lengths(length as u32, decoded.len() as u32);
+            folder.set_uncompressed_crc32(digests.crc32(&decoded) as i32);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::reference_table::ReferenceTable;
+    use crate::test_dir::TestDir;
+    use super::ContainerUpdate;
+
+    #[test]
+    fn apply_update_to_empty_cache() {
+        let dir = TestDir::new("update");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_reference_table(2, &ReferenceTable::new(6)).unwrap();
+
+        let group = container::encode(&vec![7u8; 2000], CompressionType::None, Some(4)).unwrap();
+        let checksums = fs.apply_update(&[ContainerUpdate::new(2, 10, group.clone())]).unwrap();
+
+        assert_eq!(fs.read_container(2, 10).unwrap(), group);
+        let table = fs.reference_table(2).unwrap();
+        assert_eq!(table.revision(), 1);
+        assert_eq!(table.lookup(10).unwrap().crc32(), container::crc(&group).unwrap() as i32);
+        assert_eq!(table.lookup(10).unwrap().version(), 4);
+        assert_eq!(checksums.entry(2).unwrap().crc32(), fs.container_crc(255, 2).unwrap() as i32);
+
+    }
+
+    #[test]
+    fn refresh_only_touches_dirty_groups() {
+        let dir = TestDir::new("dirty");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        let groups: Vec<ContainerUpdate> = (0..4)
+            .map(|g| ContainerUpdate::new(2, g, container::encode(&[g as u8; 100], CompressionType::Gzip, Some(1)).unwrap()))
+            .collect();
+        fs.apply_update(&groups).unwrap();
+        assert!(fs.dirty_groups().is_empty());
+
+        fs.write_group(2, 1, b"edited", CompressionType::Gzip, Some(2)).unwrap();
+        fs.write_group(5, 0, b"new index", CompressionType::None, None).unwrap();
+        assert_eq!(fs.dirty_groups(), vec![(2, 1), (5, 0)]);
+
+        // A group that isn't dirty keeps whatever its table says, even if that is stale
+        fs.write_group(2, 3, b"behind its back", CompressionType::Gzip, Some(3)).unwrap();
+        fs.mark_clean(2, 3);
+
+        let checksums = fs.refresh_reference_tables().unwrap();
+        assert!(fs.dirty_groups().is_empty());
+        let table = fs.reference_table(2).unwrap();
+        assert_eq!(table.revision(), 2);
+        assert_eq!(table.lookup(1).unwrap().crc32(), fs.container_crc(2, 1).unwrap() as i32);
+        assert_eq!(table.lookup(1).unwrap().version(), 2);
+        assert_eq!(table.lookup(3).unwrap().version(), 1);
+        assert_eq!(fs.reference_table(5).unwrap().lookup(0).unwrap().crc32(), fs.container_crc(5, 0).unwrap() as i32);
+        assert_eq!(checksums.entry(5).unwrap().crc32(), fs.container_crc(255, 5).unwrap() as i32);
+    }
+
+    #[test]
+    fn flush_leaves_a_consistent_cache() {
+        let dir = TestDir::new("flush");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_reference_table(2, &ReferenceTable::new(6)).unwrap();
+        fs.write_group(2, 0, b"flushed", CompressionType::Gzip, Some(1)).unwrap();
+        fs.write_group(3, 0, b"no table", CompressionType::Gzip, Some(1)).unwrap();
+        fs.flush().unwrap();
+        assert!(fs.dirty_groups().is_empty());
+        assert!(matches!(fs.reference_table(3), Err(FsError::EntryNotFound)));
+
+        // Edits the tables weren't brought up to date with are flushed when dropping
+        fs.write_group(2, 1, b"dropped", CompressionType::Gzip, Some(1)).unwrap();
+        drop(fs);
+
+        let mut fs = FileSystem::new(&dir).unwrap();
+        let table = fs.reference_table(2).unwrap();
+        assert_eq!(table.revision(), 2);
+        assert!(table.lookup(0).is_some() && table.lookup(1).is_some());
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::BTreeSet;
+use std::thread;
+use crate::checksum_cache::{ChecksumCache, GroupDigest};
+use crate::container;
+use crate::digest::Digests;
+use crate::filesystem::{FileSystem, FsError, IndexEntry};
+use crate::filter::GroupFilter;
+use crate::reference_table::{ReferenceTableFlags, ReferenceTableFolder};
+
+/// How many containers `verify_index` holds in memory at once while the threads check them.
+const BATCH_SIZE: usize = 256;
+
+/// The outcome of checking one group.
+#[derive(Debug)]
+pub struct GroupCheck {
+    pub index: u32,
+    pub group: u32,
+    /// `Ok` if the group checks out, or why it doesn't.
+    pub result: Result<(), FsError>,
+}
+
+/// The outcome of `FileSystem::verify_all`.
+#[derive(Debug, Default)]
+pub struct VerificationReport {
+    /// The indices that were checked, in order.
+    pub indices: Vec<u32>,
+    /// The number of groups that were checked, including the ones that failed.
+    pub groups: usize,
+    /// The groups that didn't check out, in cache order. A reference table that can't be read is
+    /// reported as its own group in index 255, and the groups of its index aren't checked.
+    pub failures: Vec<GroupCheck>,
+    /// The indices that have a reference table but no idx file, in order. Their groups can't be
+    /// checked, so they are skipped.
+    pub missing: Vec<u32>,
+}
+
+impl VerificationReport {
+    /// Checks if every group checked out.
+    pub fn is_ok(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// How the reference table of an index and its idx file disagree, as found by
+/// `FileSystem::check_table`.
+#[derive(Debug, Default)]
+pub struct TableConsistency {
+    pub index: u32,
+    /// The folders the table lists that the idx file has no entry for, in order.
+    pub absent: Vec<u32>,
+    /// The folders the table lists whose container can't be read, in order, with why.
+    pub unreadable: Vec<(u32, FsError)>,
+    /// The groups the idx file has data for that the table doesn't list, in order. The client
+    /// never asks for them, so they only take up space.
+    pub ghosts: Vec<u32>,
+}
+
+impl TableConsistency {
+    /// Checks if every folder of the table could be read, and the idx file has nothing else.
+    pub fn is_consistent(&self) -> bool {
+        self.absent.is_empty() && self.unreadable.is_empty() && self.ghosts.is_empty()
+    }
+}
+
+/// Checks a container against the folder describing it, if there is one: its CRC, its whirlpool
+/// digest and decompressed CRC where the table has them, and that it decompresses at all.
+/// Encrypted containers can't be decompressed without their keys, so that part is skipped for
+/// them.
+fn check(data: &[u8], digests: &Digests, expected: Option<&ReferenceTableFolder>, flags: ReferenceTableFlags) -> Result<(), FsError> {
+    let length = container::length(data)?;
+
+    if let Some(folder) = expected {
+        if digests.crc32(&data[..length]) as i32 != folder.crc32() {
+            return Err(FsError::CrcMismatch);
+        }
+        if flags.has_whirlpool() && digests.whirlpool(&data[..length]).as_slice() != folder.whirlpool() {
+            return Err(FsError::CrcMismatch);
+        }
+    }
+
+    if container::looks_encrypted(data) {
+        return Ok(());
+    }
+
+    let decoded = container::decode(data)?;
+    match expected {
+        Some(folder) if flags.has_uncompressed_crc() && digests.crc32(&decoded) as i32 != folder.uncompressed_crc32() => {
+            Err(FsError::CrcMismatch)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks the digest of a container like `check` does, without the container.
+fn check_digest(digest: &GroupDigest, expected: Option<&ReferenceTableFolder>, flags: ReferenceTableFlags) -> Result<(), FsError> {
+    let folder = match expected {
+        Some(folder) => folder,
+        None => return Ok(()),
+    };
+
+    if digest.crc32 as i32 != folder.crc32() {
+        return Err(FsError::CrcMismatch);
+    }
+    if flags.has_whirlpool() && digest.whirlpool.as_deref() != Some(folder.whirlpool()) {
+        return Err(FsError::CrcMismatch);
+    }
+    match digest.uncompressed_crc32 {
+        Some(crc) if flags.has_uncompressed_crc() && crc as i32 != folder.uncompressed_crc32() => Err(FsError::CrcMismatch),
+        _ => Ok(()),
+    }
+}
+
+/// Where the check of a group comes from: its container, or a digest of it taken before.
+enum Source {
+    Read(Result<Vec<u8>, FsError>),
+    Cached(GroupDigest),
+}
+
+impl FileSystem {
+    /// Checks every group of an index and returns the outcome for each in group order. Groups
+    /// the reference table lists are checked against it, and must exist. For an index without a
+    /// reference table, every group in the idx file is checked to decompress.
+    ///
+    /// The containers are read one batch at a time and checked by a number of threads at once,
+    /// which is where the time goes: CRCs, whirlpool digests and decompression.
+    pub fn verify_index(&mut self, index: u32, threads: usize) -> Result<Vec<GroupCheck>, FsError> {
+        self.verify_groups(index, &GroupFilter::new(), threads, None)
+    }
+
+    fn verify_groups(&mut self, index: u32, filter: &GroupFilter, threads: usize, mut cache: Option<&mut ChecksumCache>) -> Result<Vec<GroupCheck>, FsError> {
+        let _bulk = self.bulk_read();
+        let count = self.index(index).ok_or(FsError::IndexNotFound)?.last_entry() as u32;
+        let table = match self.reference_table(index) {
+            Ok(table) => Some(table),
+            Err(FsError::IndexNotFound) | Err(FsError::EntryNotFound) => None,
+            Err(e) => return Err(e),
+        };
+
+        let (groups, flags): (Vec<u32>, ReferenceTableFlags) = match &table {
+            Some(table) => (table.folder_ids().into_iter().map(|id| id as u32).collect(), table.flags()),
+            None => ((0..count).collect(), ReferenceTableFlags::default()),
+        };
+        let groups: Vec<u32> = groups.into_iter().filter(|group| filter.matches(index, *group, table.as_ref())).collect();
+        let digests = self.digest_set().clone();
+
+        let mut checks = Vec::with_capacity(groups.len());
+        for batch in groups.chunks(BATCH_SIZE) {
+            let mut containers = Vec::with_capacity(batch.len());
+            for &group in batch {
+                // Containers in a copy-on-write layer aren't where the idx entry says
+                let entry = self.index(index).and_then(|idx| idx.entry(group))
+                    .filter(|_| !self.cow_layer().is_some_and(|layer| layer.contains_key(&(index, group))));
+                let cached = match (&cache, &entry) {
+                    (Some(cache), Some(entry)) => cache.get(index, group, entry)
+                        .filter(|digest| !flags.has_whirlpool() || digest.whirlpool.is_some()).cloned(),
+                    _ => None,
+                };
+                if let Some(digest) = cached {
+                    containers.push((group, entry, Source::Cached(digest)));
+                    continue;
+                }
+
+                match self.read_container(index, group) {
+                    Ok(data) => containers.push((group, entry, Source::Read(Ok(data)))),
+                    // Groups missing from the idx file only matter when the table lists them
+                    Err(FsError::EntryNotFound) if table.is_none() => continue,
+                    Err(e) => containers.push((group, entry, Source::Read(Err(e)))),
+                }
+            }
+
+            let (table, digests, caching) = (&table, &digests, cache.is_some());
+            let run = move |part: Vec<(u32, Option<IndexEntry>, Source)>| -> Vec<(GroupCheck, Option<IndexEntry>, Option<GroupDigest>)> {
+                part.into_iter().map(|(group, entry, source)| {
+                    let expected = table.as_ref().and_then(|table| table.lookup(group as i32));
+                    let (result, digest) = match source {
+                        Source::Cached(digest) => (check_digest(&digest, expected, flags), None),
+                        Source::Read(Ok(data)) if caching => match GroupDigest::of(&data, digests, flags.has_whirlpool()) {
+                            Ok(Some(digest)) => (check_digest(&digest, expected, flags), Some(digest)),
+                            _ => (check(&data, digests, expected, flags), None),
+                        },
+                        Source::Read(data) => (data.and_then(|data| check(&data, digests, expected, flags)), None),
+                    };
+                    (GroupCheck { index, group, result }, entry, digest)
+                }).collect()
+            };
+
+            let mut results = Vec::new();
+            if threads <= 1 {
+                results.extend(run(containers));
+            } else {
+                // Every thread takes a consecutive part, so joining them in order keeps group order
+                let size = containers.len().div_ceil(threads).max(1);
+                let mut rest = containers.into_iter();
+                let parts: Vec<Vec<_>> = (0..threads).map(|_| rest.by_ref().take(size).collect()).collect();
+
+                thread::scope(|scope| {
+                    let workers: Vec<_> = parts.into_iter().map(|part| scope.spawn(move || run(part))).collect();
+                    for worker in workers {
+                        results.extend(worker.join().unwrap());
+                    }
+                });
+            }
+
+            for (check, entry, digest) in results {
+                if let (Some(cache), Some(entry), Some(digest)) = (cache.as_deref_mut(), entry, digest) {
+                    cache.insert(index, check.group, &entry, digest);
+                }
+                checks.push(check);
+            }
+        }
+
+        Ok(checks)
+    }
+
+    /// Checks every group of every index like `verify_index` does, which is every index that has
+    /// an idx file. Indices with a reference table but no idx file are listed as missing in the
+    /// report instead.
+    pub fn verify_all(&mut self, threads: usize) -> Result<VerificationReport, FsError> {
+        self.verify_matching(&GroupFilter::new(), threads)
+    }
+
+    /// Checks the groups a filter matches like `verify_all` does. Only the indices the filter
+    /// covers are checked, and reported as checked even if none of their groups match.
+    pub fn verify_matching(&mut self, filter: &GroupFilter, threads: usize) -> Result<VerificationReport, FsError> {
+        self.verify_indices(filter, threads, None)
+    }
+
+    /// Checks every group of every index like `verify_all` does, but skips reading and hashing
+    /// the containers whose digests a checksum cache holds, and adds the digests of the others
+    /// to it. The cache should be saved afterwards for the next run to benefit. Groups that don't
+    /// decompress are never cached, so they are read again every time.
+    pub fn verify_all_cached(&mut self, cache: &mut ChecksumCache, threads: usize) -> Result<VerificationReport, FsError> {
+        cache.check_stamps(self);
+        self.verify_indices(&GroupFilter::new(), threads, Some(cache))
+    }
+
+    /// Checks that every folder the reference table of an index lists has an entry in the idx
+    /// file and that its container can be read, and that the idx file has no groups the table
+    /// doesn't list. Unlike `verify_index`, the containers aren't checked against the table, so
+    /// it only finds groups that are absent, ghosts or whose block chain is broken, which is how
+    /// packers that forget to write or to list a group leave a cache.
+    pub fn check_table(&mut self, index: u32) -> Result<TableConsistency, FsError> {
+        let _bulk = self.bulk_read();
+        let table = self.reference_table(index)?;
+        let idx = self.index(index).ok_or(FsError::IndexNotFound)?;
+        let mut consistency = TableConsistency { index, ..TableConsistency::default() };
+
+        let stored: BTreeSet<u32> = (0..idx.last_entry() as u32).filter(|group| idx.entry(*group).is_some_and(|entry| entry.size() > 0)).collect();
+        let (present, absent): (Vec<u32>, Vec<u32>) = table.folder_ids().into_iter().map(|id| id as u32).partition(|group| stored.contains(group));
+        consistency.absent = absent;
+        consistency.ghosts = stored.into_iter().filter(|group| table.lookup(*group as i32).is_none()).collect();
+        for group in present {
+            if let Err(e) = self.read_container(index, group) {
+                consistency.unreadable.push((group, e));
+            }
+        }
+        Ok(consistency)
+    }
+
+    /// Checks the reference table of every index that has one and an idx file like
+    /// `check_table` does, in index order.
+    pub fn check_tables(&mut self) -> Result<Vec<TableConsistency>, FsError> {
+        let mut checks = Vec::new();
+        for index in self.indices().into_iter().filter(|index| *index != 255) {
+            match self.check_table(index) {
+                Ok(check) => checks.push(check),
+                Err(FsError::EntryNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(checks)
+    }
+
+    fn verify_indices(&mut self, filter: &GroupFilter, threads: usize, mut cache: Option<&mut ChecksumCache>) -> Result<VerificationReport, FsError> {
+        let indices: Vec<u32> = self.indices().into_iter().filter(|index| *index != 255 && filter.covers_index(*index)).collect();
+
+        let missing = self.missing_indices().into_iter().filter(|index| filter.covers_index(*index)).collect();
+        let mut report = VerificationReport { missing, ..VerificationReport::default() };
+        for index in indices {
+            match self.verify_groups(index, filter, threads, cache.as_deref_mut()) {
+                Ok(checks) => {
+                    report.groups += checks.len();
+                    report.failures.extend(checks.into_iter().filter(|check| check.result.is_err()));
+                }
+                Err(e) => {
+                    report.groups += 1;
+                    report.failures.push(GroupCheck { index: 255, group: index, result: Err(e) });
+                }
+            }
+            report.indices.push(index);
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use crate::container;
+    use crate::filesystem::{CompressionType, FileSystem, FsError};
+    use crate::reference_table::{ReferenceTable, ReferenceTableFolder};
+    use crate::test_dir::TestDir;
+    use crate::update::ContainerUpdate;
+    use super::GroupCheck;
+
+    #[test]
+    fn verify_reports_each_group_in_order() {
+        let dir = TestDir::new("verify");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_reference_table(3, &ReferenceTable::new(6)).unwrap();
+        let groups: Vec<ContainerUpdate> = (0..10)
+            .map(|g| ContainerUpdate::new(3, g, container::encode(&vec![g as u8; 700], CompressionType::Gzip, Some(1)).unwrap()))
+            .collect();
+        fs.apply_update(&groups).unwrap();
+
+        // Replace one group behind the reference table's back
+        fs.write_container(3, 4, &container::encode(&[0u8; 10], CompressionType::Gzip, Some(1)).unwrap()).unwrap();
+
+        let checks = fs.verify_index(3, 4).unwrap();
+        assert_eq!(checks.iter().map(|c| c.group).collect::<Vec<_>>(), (0..10).collect::<Vec<_>>());
+        let failed: Vec<&GroupCheck> = checks.iter().filter(|c| c.result.is_err()).collect();
+        assert_eq!(failed.len(), 1);
+        assert!(matches!(failed[0], GroupCheck { group: 4, result: Err(FsError::CrcMismatch), .. }));
+
+        let serial: Vec<u32> = fs.verify_index(3, 1).unwrap().iter().filter(|c| c.result.is_err()).map(|c| c.group).collect();
+        assert_eq!(serial, vec![4]);
+    }
+
+    #[test]
+    fn tables_are_checked_against_the_idx_file() {
+        let dir = TestDir::new("check-table");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        fs.write_group(3, 0, b"first", CompressionType::None, Some(1)).unwrap();
+        fs.write_group(3, 1, b"second", CompressionType::None, Some(1)).unwrap();
+        fs.write_group(4, 0, b"other", CompressionType::None, Some(1)).unwrap();
+        fs.refresh_reference_tables().unwrap();
+        assert!(fs.check_tables().unwrap().iter().all(|check| check.is_consistent()));
+
+        let mut table = fs.reference_table(3).unwrap();
+        table.insert(ReferenceTableFolder::new(9));
+        fs.write_reference_table(3, &table).unwrap();
+        fs.write_group(4, 2, b"ghost", CompressionType::None, Some(1)).unwrap();
+
+        // Point the block of group 1 at another group
+        let mut block = fs.mainfile().read_block(2).unwrap();
+        block[1] = 5;
+        let mut file = OpenOptions::new().write(true).open(dir.join("main_file_cache.dat2")).unwrap();
+        file.seek(SeekFrom::Start(2 * 520)).unwrap();
+        file.write_all(&block).unwrap();
+
+        let checks = fs.check_tables().unwrap();
+        assert_eq!(checks.iter().map(|check| check.index).collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(checks[0].absent, vec![9]);
+        assert_eq!(checks[0].unreadable.iter().map(|(group, _)| *group).collect::<Vec<_>>(), vec![1]);
+        assert!(checks[0].ghosts.is_empty());
+        assert_eq!((checks[1].absent.len(), checks[1].ghosts.clone()), (0, vec![2]));
+    }
+
+    #[test]
+    fn verify_all_sweeps_every_index() {
+        let dir = TestDir::new("verify-all");
+
+        let mut fs = FileSystem::new_writable(&dir).unwrap();
+        for index in [1, 2] {
+            fs.write_reference_table(index, &ReferenceTable::new(6)).unwrap();
+            let groups: Vec<ContainerUpdate> = (0..3)
+                .map(|g| ContainerUpdate::new(index, g, container::encode(&[g as u8; 50], CompressionType::Gzip, Some(1)).unwrap()))
+                .collect();
+            fs.apply_update(&groups).unwrap();
+        }
+        assert!(fs.verify_all(2).unwrap().is_ok());
+
+        fs.write_container(1, 2, &container::encode(b"swapped", CompressionType::Gzip, Some(1)).unwrap()).unwrap();
+        fs.write_group(255, 2, &[9, 0, 0], CompressionType::Gzip, None).unwrap();
+        fs.write_reference_table(4, &ReferenceTable::new(6)).unwrap();
+
+        let report = fs.verify_all(2).unwrap();
+        assert_eq!((report.indices.clone(), report.missing.clone()), (vec![1, 2], vec![4]));
+        assert_eq!(report.groups, 4);
+        let failures: Vec<(u32, u32)> = report.failures.iter().map(|check| (check.index, check.group)).collect();
+        assert_eq!(failures, vec![(1, 2), (255, 2)]);
+        assert!(matches!(report.failures[1].result, Err(FsError::UnsupportedVersion)));
+        assert_eq!(fs.missing_indices(), vec![4]);
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::filesystem::{FileSystem, FsError};
+use crate::naming::FileNaming;
+
+/// A file of the cache that changed on disk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CacheFile {
+    MainFile,
+    Index(u32),
+}
+
+/// A change to the files of a cache, as reported by `Watcher::poll`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChangeEvent {
+    Created(CacheFile),
+    Modified(CacheFile),
+    Removed(CacheFile),
+}
+
+/// The size and modification time of a file, which together tell whether it was rewritten.
+type FileState = (u64, Option<SystemTime>);
+
+/// Watches the folder of a cache for changes made by other processes, such as an external packer
+/// rewriting it. The folder is polled rather than subscribed to, so call `poll` periodically.
+#[derive(Debug)]
+pub struct Watcher {
+    path: PathBuf,
+    naming: FileNaming,
+    states: BTreeMap<CacheFile, FileState>,
+}
+
+impl Watcher {
+    /// Starts watching the folder of a filesystem, taking its current files as the baseline.
+    pub fn new(fs: &FileSystem) -> Result<Watcher, FsError> {
+        let path = fs.path().to_path_buf();
+        let naming = fs.naming().clone();
+        let states = scan(&path, &naming)?;
+        Ok(Watcher { path, naming, states })
+    }
+
+    /// Checks the folder for changes since the last poll. If anything changed, the filesystem is
+    /// reloaded so that it serves the new files, and the changes are returned in file order.
+    pub fn poll(&mut self, fs: &mut FileSystem) -> Result<Vec<ChangeEvent>, FsError> {
+        let states = scan(&self.path, &self.naming)?;
+        let mut events = Vec::new();
+
+        for (file, state) in &states {
+            match self.states.get(file) {
+                None => events.push(ChangeEvent::Created(*file)),
+                Some(old) if old != state => events.push(ChangeEvent::Modified(*file)),
+                Some(_) => {}
+            }
+        }
+
+        for file in self.states.keys().filter(|file| !states.contains_key(file)) {
+            events.push(ChangeEvent::Removed(*file));
+        }
+
+        if !events.is_empty() {
+            fs.reload()?;
+        }
+
+        self.states = states;
+        Ok(events)
+    }
+}
+
+/// Reads the state of every cache file in a folder.
+fn scan(path: &Path, naming: &FileNaming) -> Result<BTreeMap<CacheFile, FileState>, FsError> {
+    let mut states = BTreeMap::new();
+
+    for entry in fs::read_dir(path).map_err(|_| FsError::FileNotFound)? {
+        let entry = entry.map_err(|_| FsError::FileNotFound)?;
+        let name = entry.file_name();
+        let file = if name == naming.data_file() {
+            CacheFile::MainFile
+        } else if let Some(id) = naming.parse_index(&name) {
+            CacheFile::Index(id)
+        } else {
+            continue;
+        };
+
+        // Files that vanish between listing and reading are picked up as removed next time
+        if let Ok(metadata) = entry.metadata() {
+            states.insert(file, (metadata.len(), metadata.modified().ok()));
+        }
+    }
+
+    Ok(states)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filesystem::{CompressionType, FileSystem};
+    use crate::test_dir::TestDir;
+    use super::{CacheFile, ChangeEvent, Watcher};
+
+    #[test]
+    fn poll_reports_and_reloads_external_writes() {
+        let dir = TestDir::new("watch");
+
+        let mut writer = FileSystem::new_writable(&dir).unwrap();
+        let mut reader = FileSystem::new(&dir).unwrap();
+        let mut watcher = Watcher::new(&reader).unwrap();
+        assert!(watcher.poll(&mut reader).unwrap().is_empty());
+
+        writer.write_group(2, 0, &[5u8; 700], CompressionType::None, None).unwrap();
+
+        let events = watcher.poll(&mut reader).unwrap();
+        assert!(events.contains(&ChangeEvent::Created(CacheFile::Index(2))));
+        assert_eq!(reader.read_container(2, 0).unwrap(), writer.read_container(2, 0).unwrap());
+    }
+}
@@ -0,0 +1,244 @@
+/// The number of bytes in a whirlpool digest.
+pub const DIGEST_LENGTH: usize = 64;
+
+const ROUNDS: usize = 10;
+
+/// Builds the whirlpool S-box out of the E, E^-1 and R mini-boxes described in the specification.
+const fn sbox() -> [u8; 256] {
+    const E: [u8; 16] = [0x1, 0xB, 0x9, 0xC, 0xD, 0x6, 0xF, 0x3, 0xE, 0x8, 0x7, 0x4, 0xA, 0x2, 0x5, 0x0];
+    const R: [u8; 16] = [0x7, 0xC, 0xB, 0xD, 0xE, 0x4, 0x9, 0xF, 0x6, 0x3, 0x8, 0xA, 0x2, 0x5, 0x1, 0x0];
+
+    let mut e_inv = [0u8; 16];
+    let mut i = 0;
+    while i < 16 {
+        e_inv[E[i] as usize] = i as u8;
+        i += 1;
+    }
+
+    let mut sbox = [0u8; 256];
+    let mut u = 0;
+    while u < 256 {
+        let a = E[u >> 4];
+        let b = e_inv[u & 0xF];
+        let c = R[(a ^ b) as usize];
+        sbox[u] = (E[(a ^ c) as usize] << 4) | e_inv[(b ^ c) as usize];
+        u += 1;
+    }
+
+    sbox
+}
+
+/// Multiplies two elements of GF(2^8) using the whirlpool reduction polynomial x^8 + x^4 + x^3 + x^2 + 1.
+const fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1D;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Builds the eight lookup tables combining the S-box with the circulant MDS matrix (1, 1, 4, 1, 8, 5, 2, 9).
+const fn tables() -> [[u64; 256]; 8] {
+    const ROW: [u8; 8] = [1, 1, 4, 1, 8, 5, 2, 9];
+    let sbox = sbox();
+
+    let mut tables = [[0u64; 256]; 8];
+    let mut x = 0;
+    while x < 256 {
+        let mut value = 0u64;
+        let mut i = 0;
+        while i < 8 {
+            value = (value << 8) | gf_mul(sbox[x], ROW[i]) as u64;
+            i += 1;
+        }
+
+        let mut t = 0;
+        while t < 8 {
+            tables[t][x] = value.rotate_right(8 * t as u32);
+            t += 1;
+        }
+        x += 1;
+    }
+
+    tables
+}
+
+/// Builds the round constants, which are consecutive rows of the S-box.
+const fn round_constants() -> [u64; ROUNDS] {
+    let sbox = sbox();
+
+    let mut constants = [0u64; ROUNDS];
+    let mut r = 0;
+    while r < ROUNDS {
+        let mut value = 0u64;
+        let mut i = 0;
+        while i < 8 {
+            value = (value << 8) | sbox[8 * r + i] as u64;
+            i += 1;
+        }
+        constants[r] = value;
+        r += 1;
+    }
+
+    constants
+}
+
+static TABLES: [[u64; 256]; 8] = tables();
+static ROUND_CONSTANTS: [u64; ROUNDS] = round_constants();
+
+/// An incremental whirlpool hasher, for when the data to digest is not available in one slice.
+#[derive(Clone)]
+pub struct Whirlpool {
+    hash: [u64; 8],
+    buffer: [u8; 64],
+    buffer_len: usize,
+    total_len: u128,
+}
+
+impl Default for Whirlpool {
+    fn default() -> Self {
+        Whirlpool::new()
+    }
+}
+
+impl Whirlpool {
+    pub fn new() -> Whirlpool {
+        Whirlpool {
+            hash: [0; 8],
+            buffer: [0; 64],
+            buffer_len: 0,
+            total_len: 0,
+        }
+    }
+
+    /// Feeds more data into the hasher.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.total_len += data.len() as u128;
+
+        // Top up a partially filled buffer first
+        if self.buffer_len > 0 {
+            let take = (64 - self.buffer_len).min(data.len());
+            self.buffer[self.buffer_len..self.buffer_len + take].copy_from_slice(&data[..take]);
+            self.buffer_len += take;
+            data = &data[take..];
+
+            if self.buffer_len < 64 {
+                return;
+            }
+
+            let block = self.buffer;
+            self.process_block(&block);
+            self.buffer_len = 0;
+        }
+
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.process_block(block);
+        }
+
+        let rest = blocks.remainder();
+        self.buffer[..rest.len()].copy_from_slice(rest);
+        self.buffer_len = rest.len();
+    }
+
+    /// Pads the message and returns the final digest.
+    pub fn finalize(mut self) -> [u8; DIGEST_LENGTH] {
+        let bit_len = self.total_len.wrapping_mul(8);
+
+        // Append the '1' bit and pad with zeroes until 32 bytes remain in the block
+        let mut padding = [0u8; 128];
+        padding[0] = 0x80;
+        let pad_len = if self.buffer_len < 32 { 32 - self.buffer_len } else { 96 - self.buffer_len };
+
+        // The length trailer is 256 bits wide, of which we only fill the low 128
+        let mut trailer = [0u8; 32];
+        trailer[16..].copy_from_slice(&bit_len.to_be_bytes());
+
+        let total_len = self.total_len;
+        self.update(&padding[..pad_len]);
+        self.update(&trailer);
+        self.total_len = total_len;
+
+        let mut digest = [0u8; DIGEST_LENGTH];
+        for (i, word) in self.hash.iter().enumerate() {
+            digest[i * 8..i * 8 + 8].copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn process_block(&mut self, block: &[u8]) {
+        let mut message = [0u64; 8];
+        for (i, word) in message.iter_mut().enumerate() {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&block[i * 8..i * 8 + 8]);
+            *word = u64::from_be_bytes(bytes);
+        }
+
+        let mut key = self.hash;
+        let mut state = [0u64; 8];
+        for i in 0..8 {
+            state[i] = message[i] ^ key[i];
+        }
+
+        for constant in ROUND_CONSTANTS.iter() {
+            key = round(&key, *constant);
+            let mut next = round(&state, 0);
+            for i in 0..8 {
+                next[i] ^= key[i];
+            }
+            state = next;
+        }
+
+        // Miyaguchi-Preneel compression
+        for i in 0..8 {
+            self.hash[i] ^= state[i] ^ message[i];
+        }
+    }
+}
+
+/// Applies the combined SubBytes, ShiftColumns and MixRows steps to a state, then adds the
+/// round constant to the first row.
+fn round(input: &[u64; 8], constant: u64) -> [u64; 8] {
+    let mut output = [0u64; 8];
+    for (i, out) in output.iter_mut().enumerate() {
+        let mut value = 0u64;
+        for (t, table) in TABLES.iter().enumerate() {
+            let byte = (input[(i + 8 - t) & 7] >> (56 - 8 * t)) & 0xFF;
+            value ^= table[byte as usize];
+        }
+        *out = value;
+    }
+    output[0] ^= constant;
+    output
+}
+
+/// Computes the whirlpool digest of a slice of data.
+pub fn digest(data: &[u8]) -> [u8; DIGEST_LENGTH] {
+    let mut hasher = Whirlpool::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::digest;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02X}", b)).collect()
+    }
+
+    #[test]
+    fn known_vectors() {
+        assert_eq!(hex(&digest(b"")), "19FA61D75522A4669B44E39C1D2E1726C530232130D407F89AFEE0964997F7A73E83BE698B288FEBCF88E3E03C4F0757EA8964E59B63D93708B138CC42A66EB3");
+        assert_eq!(hex(&digest(b"a")), "8ACA2602792AEC6F11A67206531FB7D7F0DFF59413145E6973C45001D0087B42D11BC645413AEFF63A42391A39145A591A92200D560195E53B478584FDAE231A");
+        assert_eq!(hex(&digest(b"abc")), "4E2448A4C6F486BB16B6562C73B4020BF3043E3A731BCE721AE1B303D97E6D4C7181EEBDB6C57E277D0E34957114CBD6C797FC9D95D8B582D225292076D4EEF5");
+    }
+}
@@ -0,0 +1,48 @@
+const GOLDEN_RATIO: u32 = 0x9E37_79B9;
+const ROUNDS: u32 = 32;
+
+/// Checks if a key set is all zeroes, which the client treats as "not encrypted".
+pub fn is_empty(keys: &[i32; 4]) -> bool {
+    keys.iter().all(|k| *k == 0)
+}
+
+/// Decrypts data in place with the given keys. Only whole 8-byte blocks are encrypted, so any
+/// trailing bytes are left untouched.
+pub fn decrypt(data: &mut [u8], keys: &[i32; 4]) {
+    let keys = keys.map(|k| k as u32);
+
+    for block in data.chunks_exact_mut(8) {
+        let mut v0 = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+        let mut v1 = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+        let mut sum = GOLDEN_RATIO.wrapping_mul(ROUNDS);
+
+        for _ in 0..ROUNDS {
+            v1 = v1.wrapping_sub((((v0 << 4) ^ (v0 >> 5)).wrapping_add(v0)) ^ sum.wrapping_add(keys[((sum >> 11) & 3) as usize]));
+            sum = sum.wrapping_sub(GOLDEN_RATIO);
+            v0 = v0.wrapping_sub((((v1 << 4) ^ (v1 >> 5)).wrapping_add(v1)) ^ sum.wrapping_add(keys[(sum & 3) as usize]));
+        }
+
+        block[..4].copy_from_slice(&v0.to_be_bytes());
+        block[4..].copy_from_slice(&v1.to_be_bytes());
+    }
+}
+
+/// Encrypts data in place with the given keys. This is the inverse of `decrypt`.
+pub fn encrypt(data: &mut [u8], keys: &[i32; 4]) {
+    let keys = keys.map(|k| k as u32);
+
+    for block in data.chunks_exact_mut(8) {
+        let mut v0 = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+        let mut v1 = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+        let mut sum = 0u32;
+
+        for _ in 0..ROUNDS {
+            v0 = v0.wrapping_add((((v1 << 4) ^ (v1 >> 5)).wrapping_add(v1)) ^ sum.wrapping_add(keys[(sum & 3) as usize]));
+            sum = sum.wrapping_add(GOLDEN_RATIO);
+            v1 = v1.wrapping_add((((v0 << 4) ^ (v0 >> 5)).wrapping_add(v0)) ^ sum.wrapping_add(keys[((sum >> 11) & 3) as usize]));
+        }
+
+        block[..4].copy_from_slice(&v0.to_be_bytes());
+        block[4..].copy_from_slice(&v1.to_be_bytes());
+    }
+}